@@ -1,38 +1,74 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+
 use kube::{Api,Client,api::{Patch,PatchParams}};
 use kube::api::DeleteParams;
+use kube_metrics_mutli_tenancy_lib::logging::LogEvent;
+use kube_metrics_mutli_tenancy_lib::namespace;
+use kube_metrics_mutli_tenancy_lib::selector::RuleSelector;
+use kube_metrics_mutli_tenancy_lib::telemetry;
+use kube_metrics_mutli_tenancy_lib::tenant::normalize_tenant_id;
 use log::{debug,error,info,warn};
+use opentelemetry::trace::{SpanKind, TraceContextExt};
+use opentelemetry::{Context, KeyValue};
 use reqwest::Client as RClient;
-use reqwest::{Response,Error};
+use reqwest::{RequestBuilder,Response,Error};
 use serde::Deserialize;
 use sha1::{Sha1, Digest};
 
 use kube_metrics_mutli_tenancy_lib as kube_lib;
+use crate::lifecycle::lifecycle::audit;
 use crate::rules::rules;
 
 
+// Propagate W3C trace context of span to ruler or distributor.
+pub fn with_trace_headers(mut request: RequestBuilder, cx: &Context) -> RequestBuilder {
+    for (name, value) in telemetry::trace_headers(cx) {
+        request = request.header(name.as_str(), value);
+    }
+    request
+}
+
 // Ruler rule modification actions should return 202 on success.
-async fn check_response_202(response: Result<Response,Error>) {
-    match response {
+// Modification is audited with upstream status, error describes failed one.
+async fn check_response_202(response: Result<Response,Error>, cx: &Context, event: LogEvent) -> Result<(), String> {
+    let result = match response {
         Ok(r) => {
             let s = r.status();
+            telemetry::record_status(cx, s.as_u16());
+            event.status(s.as_u16()).emit();
             match r.text().await {
                 Ok(t) => {
                     info!("received ruler response {}, text {}", s.to_string(), t);
                     if s == 202 {
-                        debug!("successfully updated rule")
+                        debug!("successfully updated rule");
+                        Ok(())
                     } else {
-                        warn!("ruler response is not 202; text: {}", t)
+                        warn!("ruler response is not 202; text: {}", t);
+                        Err(format!("ruler responded with status {}: {}", s.as_u16(), t.trim()))
                     }
                 },
                 Err(e) => {
                     error!("failed to receive ruler response body: {}", e);
+                    if s == 202 {
+                        Ok(())
+                    } else {
+                        Err(format!("ruler responded with status {}", s.as_u16()))
+                    }
                 }
             }
         },
         Err(e) => {
+            telemetry::record_error(cx, &e.to_string());
+            event.status("error").error(&e.to_string()).emit();
             error!("failed to update ruler, abort: {}", e);
+            Err(format!("failed to reach ruler: {}", e))
         }
-    }
+    };
+    cx.span().end();
+    result
 }
 
 #[derive(Deserialize)]
@@ -77,11 +113,26 @@ pub async fn load_tenants_from_distributor(
     debug!("distributor URL is {}, going to read data", url);
 
 
-    let response = client.get(&url)
+    let cx = telemetry::start_span(
+        &Context::current(),
+        "distributor_user_stats",
+        SpanKind::Client,
+        vec![KeyValue::new("http.url", url.clone())],
+    );
+    let response = with_trace_headers(client.get(&url), &cx)
         .send()
         .await;
+    match &response {
+        Ok(r) => telemetry::record_status(&cx, r.status().as_u16()),
+        Err(e) => telemetry::record_error(&cx, &e.to_string()),
+    };
 
-    extract_tenants(response).await
+    let tenants = extract_tenants(response).await;
+    if let Ok(tenant_vec) = &tenants {
+        cx.span().set_attribute(KeyValue::new("tenants", tenant_vec.len() as i64));
+    }
+    cx.span().end();
+    tenants
 }
 
 // update ruler inside rule
@@ -90,24 +141,27 @@ pub async fn update_ruler_rule(
     ruler_api_url: &String,
     tenant_id: &String,
     namespace: &String,
-    rule_group: kube_lib::GroupSpec) {
+    rule_group: kube_lib::GroupSpec) -> Result<(), String> {
     let url = ruler_api_url.clone() + "api/v1/rules/" + &namespace.clone();
     debug!("ruler URL is {}, going to insert data", url);
     match serde_yaml::to_string(&rule_group) {
         Ok(body) => {
             debug!("ruler req body is {}", body);
-            let response = client.post(&url)
+            let cx = ruler_span("ruler_update_group", &url, tenant_id, &rule_group.name);
+            let started = Instant::now();
+            let response = with_trace_headers(client.post(&url), &cx)
                 .header("X-Scope-OrgID", tenant_id)
                 .body(body)
                 .send()
                 .await;
-            check_response_202(response).await;
-
+            let event = ruler_audit("ruler_update_group", &url, tenant_id, namespace, &rule_group.name, started);
+            check_response_202(response, &cx, event).await
         },
         Err(e) => {
             error!("failed to encode rule group, abort: {}", e);
+            Err(format!("failed to encode rule group {}: {}", rule_group.name, e))
         }
-    };
+    }
 }
 
 // remove rule from a Ruler
@@ -116,15 +170,49 @@ pub async fn remove_ruler_rule(
     ruler_api_url: &String,
     tenant_id: &String,
     namespace: &String,
-    rule_group: kube_lib::GroupSpec) {
+    rule_group: kube_lib::GroupSpec) -> Result<(), String> {
     let url = ruler_api_url.clone() + "api/v1/rules/"
         + &namespace.clone() + "/" + &rule_group.name;
     debug!("ruler URL is {}, going to delete group", url);
-    let response = client.delete(&url)
+    let cx = ruler_span("ruler_remove_group", &url, tenant_id, &rule_group.name);
+    let started = Instant::now();
+    let response = with_trace_headers(client.delete(&url), &cx)
         .header("X-Scope-OrgID", tenant_id)
         .send()
         .await;
-    check_response_202(response).await;
+    let event = ruler_audit("ruler_remove_group", &url, tenant_id, namespace, &rule_group.name, started);
+    check_response_202(response, &cx, event).await
+}
+
+// Audit event of ruler rule group modification, status is set once response is received.
+fn ruler_audit(action: &str, url: &str, tenant_id: &str, namespace: &str, group: &str, started: Instant) -> LogEvent {
+    audit(action)
+        .tenant(tenant_id)
+        .namespace(namespace)
+        .resource(group)
+        .upstream(url)
+        .duration_ms(started.elapsed().as_millis() as u64)
+}
+
+// Span of ruler rule group modification, child of current tick.
+fn ruler_span(name: &'static str, url: &str, tenant_id: &str, group: &str) -> Context {
+    telemetry::start_span(
+        &Context::current(),
+        name,
+        SpanKind::Client,
+        vec![
+            KeyValue::new("http.url", url.to_string()),
+            KeyValue::new("tenant_id", tenant_id.to_string()),
+            KeyValue::new("rule_group", group.to_string()),
+        ],
+    )
+}
+
+// Whether rule applies to tenant, comparing normalized tenant IDs.
+fn has_tenant(rule: &kube_lib::OpenMetricsRule, tenant_id: &String) -> bool {
+    rule.spec.tenants
+        .iter()
+        .any(|t| normalize_tenant_id(t).ok().as_ref() == Some(tenant_id))
 }
 
 // create or update resource in k8s
@@ -132,15 +220,16 @@ pub async fn create_or_update_k8s_resource(
     k8s_client: Client,
     tenant_id: &String,
     namespace: &String,
+    selector: &RuleSelector,
     rule_group: kube_lib::GroupSpec
 ) {
     let cli = k8s_client.clone();
-    match kube_lib::discover_open_metrics_rules(cli.clone(), namespace).await {
+    match kube_lib::discover_namespace_rules(cli.clone(), Some(namespace.as_str()), selector).await {
         Ok(rule_vec) => {
             let mut found: Option<kube_lib::OpenMetricsRule> = None;
             let rule_group_name = rule_group.name.clone();
             for mut rule in rule_vec.iter().cloned().into_iter() {
-                if rule.spec.tenants.contains(tenant_id) {
+                if has_tenant(&rule, tenant_id) {
                     let mut groups_with_idx = Vec::new();
                     for g in rule.spec.groups.iter().cloned().into_iter() {
                         groups_with_idx.push((g, -1));
@@ -160,7 +249,11 @@ pub async fn create_or_update_k8s_resource(
                 cli.clone(), namespace);
 
             let resource_name = if not_found {
-                let hash = Sha1::digest(&rule_group_name.replace("_", "-"));
+                // deployments with different classes never create resources with the same name
+                let hash = match &selector.controller_class {
+                    Some(class) => Sha1::digest(format!("{}/{}", class, rule_group_name.replace("_", "-")).as_bytes()),
+                    None => Sha1::digest(&rule_group_name.replace("_", "-")),
+                };
                 format!("{}-{:x}", tenant_id, hash)
             } else {
                 // It is safe to unwrap, since we just found this rule via discover_open_metrics()
@@ -170,21 +263,30 @@ pub async fn create_or_update_k8s_resource(
 
             let open_metrics_rule = if not_found {
                 // Group not exist before.
-                 kube_lib::OpenMetricsRule::new(
+                 let mut rule = kube_lib::OpenMetricsRule::new(
                      &resource_name,
                      kube_lib::OpenMetricsRuleSpec {
                          tenants: vec![tenant_id.clone()],
                          description: Some(String::from("open-metrics-multi-tenancy-kit-sourced-rule")),
-                         groups: vec![rule_group.clone()]
+                         groups: vec![rule_group.clone()],
+                         external_labels: HashMap::new(),
+                         controller_class: selector.controller_class.clone()
                      }
-                 )
+                 );
+                 rule.metadata.namespace = Some(namespace.clone());
+                 // labels of selector, so resource is found by this deployment again
+                 let labels = selector.required_labels();
+                 if !labels.is_empty() {
+                     rule.metadata.labels = Some(labels);
+                 }
+                 rule
             } else {
                 found.clone().unwrap()
             };
             // Mark recent status update.
             // Since group was sourced from ruler, it is safe to assume
             // it is identical.
-            resource_updated(&api, &resource_name, open_metrics_rule).await;
+            resource_updated(&api, &resource_name, open_metrics_rule, &RuleSync::synced(tenant_id)).await;
         },
         Err(msg) => {
             error!("failed to discover current rule set: {}", msg);
@@ -197,14 +299,15 @@ pub async fn remove_k8s_resource(
     k8s_client: Client,
     tenant_id: &String,
     namespace: &String,
+    selector: &RuleSelector,
     rule_group: kube_lib::GroupSpec
 ) {
     let cli = k8s_client.clone();
-    match kube_lib::discover_open_metrics_rules(cli.clone(), namespace).await {
+    match kube_lib::discover_namespace_rules(cli.clone(), Some(namespace.as_str()), selector).await {
         Ok(rule_vec) => {
             let rule_group_name = rule_group.name.clone();
             for mut rule in rule_vec.iter().cloned().into_iter() {
-                if rule.spec.tenants.contains(tenant_id) {
+                if has_tenant(&rule, tenant_id) {
                     let mut groups_with_idx = Vec::new();
                     for g in rule.spec.groups.iter().cloned().into_iter() {
                         groups_with_idx.push((g, -1));
@@ -217,10 +320,10 @@ pub async fn remove_k8s_resource(
                             cli.clone(), namespace);
 
                         if groups_with_idx.len() == 1 {
-                            remove_resource(&api, &resource_name).await;
+                            remove_resource(&api, namespace, &resource_name).await;
                         } else {
                             rule.spec.groups.remove(idx as usize);
-                            resource_updated(&api, &resource_name, rule.clone()).await;
+                            resource_updated(&api, &resource_name, rule.clone(), &RuleSync::synced(tenant_id)).await;
                         }
                         return;
                     };
@@ -235,47 +338,191 @@ pub async fn remove_k8s_resource(
     };
 }
 
-// renew resource status
-pub async fn resource_updated(api: &Api<kube_lib::OpenMetricsRule>, resource_name: &String, mut open_metrics_rule: kube_lib::OpenMetricsRule) {
-    let status = kube_lib::OpenMetricsRuleStatus {
-        ruler_updated: true
+// Outcome of syncing rule tenants with ruler, written into rule status
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RuleSync {
+    // result of tenants synced just now, others keep previous result
+    pub tenants: BTreeMap<String, Result<(), String>>,
+    // groups defined by other rules for the same tenant, previous condition is kept when None
+    pub conflicts: Option<Vec<String>>,
+}
+
+impl RuleSync {
+    // Tenant synced without errors.
+    pub fn synced(tenant_id: &str) -> RuleSync {
+        let mut tenants = BTreeMap::new();
+        tenants.insert(String::from(tenant_id), Ok(()));
+        RuleSync { tenants, conflicts: None }
+    }
+}
+
+// Status of rule after sync, with Synced, Valid and Conflicting conditions.
+pub fn rule_status(rule: &kube_lib::OpenMetricsRule, generation: Option<i64>, sync: &RuleSync, now: DateTime<Utc>) -> kube_lib::OpenMetricsRuleStatus {
+    let mut status = rule.status.clone().unwrap_or_default();
+    status.observed_generation = generation;
+
+    // invalid tenant IDs are never sent to ruler
+    let mut tenants = HashSet::new();
+    let mut invalid = Vec::new();
+    for raw_tenant_id in rule.spec.tenants.iter() {
+        match normalize_tenant_id(raw_tenant_id) {
+            Ok(tenant_id) => {
+                tenants.insert(tenant_id);
+            },
+            Err(e) => invalid.push(format!("{:?}: {}", raw_tenant_id, e)),
+        }
+    }
+    if !invalid.is_empty() {
+        status.set_condition(kube_lib::CONDITION_VALID, false, "InvalidTenantId", &invalid.join("; "), generation, now);
+    } else if tenants.is_empty() {
+        status.set_condition(kube_lib::CONDITION_VALID, false, "NoTenants", "rule has no tenants", generation, now);
+    } else {
+        status.set_condition(kube_lib::CONDITION_VALID, true, "Valid", "", generation, now);
+    }
+
+    match &sync.conflicts {
+        Some(conflicts) if !conflicts.is_empty() => status.set_condition(
+            kube_lib::CONDITION_CONFLICTING, true, "DuplicateGroup", &conflicts.join("; "), generation, now),
+        Some(_) => status.set_condition(kube_lib::CONDITION_CONFLICTING, false, "NoConflicts", "", generation, now),
+        None => {},
     };
+
+    status.tenants.retain(|tenant_id, _| tenants.contains(tenant_id));
+    for (tenant_id, result) in sync.tenants.iter().filter(|(t, _)| tenants.contains(*t)) {
+        status.tenants.insert(tenant_id.clone(), kube_lib::TenantSyncStatus {
+            synced: result.is_ok(),
+            error: result.clone().err(),
+        });
+        if let Err(e) = result {
+            status.last_ruler_error = Some(format!("{}: {}", tenant_id, e));
+        }
+    }
+    let errors: Vec<String> = status.tenants
+        .iter()
+        .filter_map(|(tenant_id, s)| s.error.as_ref().map(|e| format!("{}: {}", tenant_id, e)))
+        .collect();
+    status.ruler_updated = errors.is_empty();
+    if !errors.is_empty() {
+        status.set_condition(kube_lib::CONDITION_SYNCED, false, "RulerError", &errors.join("; "), generation, now);
+    } else if tenants.is_empty() {
+        status.set_condition(kube_lib::CONDITION_SYNCED, false, "NothingToSync", "rule has no valid tenants", generation, now);
+    } else {
+        let message = format!("synced {} tenants", status.tenants.len());
+        status.set_condition(kube_lib::CONDITION_SYNCED, true, "Synced", &message, generation, now);
+    }
+    status
+}
+
+// JSON merge patch turning current into desired value.
+// Merge patch never removes keys which are not set, so keys gone from desired value are set to null.
+fn merge_patch(current: &serde_json::Value, desired: &serde_json::Value) -> serde_json::Value {
+    match (current, desired) {
+        (serde_json::Value::Object(current), serde_json::Value::Object(desired)) => {
+            let mut patch = serde_json::Map::new();
+            for (key, value) in desired.iter() {
+                let value = match current.get(key) {
+                    Some(current_value) => merge_patch(current_value, value),
+                    None => value.clone(),
+                };
+                patch.insert(key.clone(), value);
+            }
+            for key in current.keys().filter(|key| !desired.contains_key(*key)) {
+                patch.insert(key.clone(), serde_json::Value::Null);
+            }
+            serde_json::Value::Object(patch)
+        },
+        _ => desired.clone(),
+    }
+}
+
+// Patch of rule status, removing fields and tenants which are not in status anymore.
+pub fn status_patch(current: Option<&kube_lib::OpenMetricsRuleStatus>, status: &kube_lib::OpenMetricsRuleStatus) -> serde_json::Value {
+    let current = current.map(|c| serde_json::json!(c)).unwrap_or_else(|| serde_json::json!({}));
+    serde_json::json!({ "status": merge_patch(&current, &serde_json::json!(status)) })
+}
+
+// renew resource status
+// Resource is applied first, then its generation is recorded as observed in status subresource, with sync outcome.
+pub async fn resource_updated(api: &Api<kube_lib::OpenMetricsRule>, resource_name: &String, mut open_metrics_rule: kube_lib::OpenMetricsRule, sync: &RuleSync) {
+    let current = open_metrics_rule.clone();
     // managed fields might be set when querying
     open_metrics_rule.metadata.managed_fields = None;
-    open_metrics_rule.status = Some(status);
+    // status is only written through status subresource
+    open_metrics_rule.status = None;
     let ssapply = PatchParams::apply("openmetricsrule").force();
-    match api.patch(
+    let cx = telemetry::start_span(
+        &Context::current(),
+        "k8s_patch_rule",
+        SpanKind::Client,
+        vec![KeyValue::new("resource_name", resource_name.clone())],
+    );
+    let started = Instant::now();
+    let result = match api.patch(
         &resource_name.clone(),
         &ssapply,
         &Patch::Apply(&open_metrics_rule)
     ).await {
+        Ok(applied) => {
+            let status = rule_status(&current, applied.metadata.generation, sync, Utc::now());
+            api.patch_status(
+                &resource_name.clone(),
+                &PatchParams::default(),
+                &Patch::Merge(status_patch(current.status.as_ref(), &status))
+            ).await
+        },
+        Err(e) => Err(e),
+    };
+    let event = audit("k8s_patch_rule")
+        .tenant(&open_metrics_rule.spec.tenants.join(","))
+        .namespace(&namespace::rule_namespace(&open_metrics_rule))
+        .resource(resource_name)
+        .duration_ms(started.elapsed().as_millis() as u64);
+    match result {
         Ok(resp) => {
+            event.status("ok").emit();
             // safe to unwrap since yaml received from k8s
             info!("patched k8s resource: {}", serde_yaml::to_string(&resp).unwrap());
         },
         Err(e) => {
+            event.status("error").error(&e.to_string()).emit();
+            telemetry::record_error(&cx, &e.to_string());
             error!("failed to apply rule: {} because of {:?}", &resource_name, e);
         }
     };
+    cx.span().end();
 }
 
 // remove resource
-pub async fn remove_resource(api: &Api<kube_lib::OpenMetricsRule>, resource_name: &String) {
-
-
-    match api.delete(
+pub async fn remove_resource(api: &Api<kube_lib::OpenMetricsRule>, namespace: &str, resource_name: &String) {
+    let cx = telemetry::start_span(
+        &Context::current(),
+        "k8s_delete_rule",
+        SpanKind::Client,
+        vec![KeyValue::new("resource_name", resource_name.clone())],
+    );
+    let started = Instant::now();
+    let result = api.delete(
         &resource_name.clone(),
         &DeleteParams::default()
-    ).await {
+    ).await;
+    let event = audit("k8s_delete_rule")
+        .namespace(namespace)
+        .resource(resource_name)
+        .duration_ms(started.elapsed().as_millis() as u64);
+    match result {
         Ok(result) => {
+            event.status("ok").emit();
             result
                 .map_left(|o| debug!("Deleting rule CRD: {:?}", o.status))
                 .map_right(|s| info!("Deleted rule CRD: {:?}", s));
         },
         Err(e) => {
+            event.status("error").error(&e.to_string()).emit();
+            telemetry::record_error(&cx, &e.to_string());
             error!("failed to delete rule: {} because of {:?}", &resource_name, e);
         }
     };
+    cx.span().end();
 }
 
 #[cfg(test)]
@@ -283,7 +530,7 @@ mod tests {
     use log::debug;
     use serde_json::Value;
 
-    use crate::crud::crud::load_tenants_from_distributor;
+    use crate::crud::crud::{load_tenants_from_distributor, rule_status, status_patch, RuleSync};
 
     fn init() {
         let _ = env_logger::builder().is_test(true).try_init();
@@ -397,4 +644,92 @@ mod tests {
 
         assert_eq!(tenant_vec.len(), 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_rule_status() {
+        use chrono::{Duration, TimeZone, Utc};
+        use kube_metrics_mutli_tenancy_lib as kube_lib;
+
+        let mut rule = kube_lib::OpenMetricsRule::new("rule1", kube_lib::OpenMetricsRuleSpec {
+            tenants: vec![String::from("tnt1"), String::from("tnt2"), String::from("..")],
+            description: None,
+            groups: vec![],
+            external_labels: std::collections::HashMap::new(),
+            controller_class: None,
+        });
+        let then = Utc.ymd(2021, 5, 1).and_hms(10, 0, 0);
+        let mut sync = RuleSync {
+            tenants: vec![
+                (String::from("tnt1"), Ok(())),
+                (String::from("tnt2"), Err(String::from("ruler responded with status 500: internal error"))),
+            ].into_iter().collect(),
+            conflicts: Some(vec![String::from("group group1 of tenant tnt1 is also defined in rule2")]),
+        };
+        let status = rule_status(&rule, Some(3), &sync, then);
+
+        assert_eq!(status.observed_generation, Some(3));
+        assert!(!status.ruler_updated);
+        assert!(status.tenants["tnt1"].synced);
+        assert!(!status.tenants["tnt2"].synced);
+        assert_eq!(status.last_ruler_error, Some(String::from("tnt2: ruler responded with status 500: internal error")));
+        let synced = status.condition(kube_lib::CONDITION_SYNCED).unwrap();
+        assert_eq!((synced.status.as_str(), synced.reason.as_str()), ("False", "RulerError"));
+        let valid = status.condition(kube_lib::CONDITION_VALID).unwrap();
+        assert_eq!((valid.status.as_str(), valid.reason.as_str()), ("False", "InvalidTenantId"));
+        assert_eq!(status.condition(kube_lib::CONDITION_CONFLICTING).unwrap().status, "True");
+
+        // only tnt2 synced again, tnt1 keeps its result, and last ruler error stays
+        rule.status = Some(status);
+        sync.tenants = vec![(String::from("tnt2"), Ok(()))].into_iter().collect();
+        sync.conflicts = None;
+        let later = then + Duration::minutes(1);
+        let status = rule_status(&rule, Some(3), &sync, later);
+        assert!(status.ruler_updated);
+        assert_eq!(status.tenants.len(), 2);
+        assert!(status.last_ruler_error.is_some());
+        let synced = status.condition(kube_lib::CONDITION_SYNCED).unwrap();
+        assert_eq!((synced.status.as_str(), synced.last_transition_time), ("True", later));
+        // conflicts are kept when not checked
+        let conflicting = status.condition(kube_lib::CONDITION_CONFLICTING).unwrap();
+        assert_eq!((conflicting.status.as_str(), conflicting.last_transition_time), ("True", then));
+    }
+
+    #[test]
+    fn test_status_patch_removes_tenant() {
+        use chrono::{TimeZone, Utc};
+        use kube_metrics_mutli_tenancy_lib as kube_lib;
+
+        let mut rule = kube_lib::OpenMetricsRule::new("rule1", kube_lib::OpenMetricsRuleSpec {
+            tenants: vec![String::from("tnt1"), String::from("tnt2")],
+            description: None,
+            groups: vec![],
+            external_labels: std::collections::HashMap::new(),
+            controller_class: None,
+        });
+        let now = Utc.ymd(2021, 5, 1).and_hms(10, 0, 0);
+        let sync = RuleSync {
+            tenants: vec![
+                (String::from("tnt1"), Ok(())),
+                (String::from("tnt2"), Err(String::from("ruler responded with status 500: internal error"))),
+            ].into_iter().collect(),
+            conflicts: Some(vec![]),
+        };
+        rule.status = Some(rule_status(&rule, Some(1), &sync, now));
+
+        // tenant with failed sync is dropped from spec
+        rule.spec.tenants = vec![String::from("tnt1")];
+        let status = rule_status(&rule, Some(2), &RuleSync::synced("tnt1"), now);
+        assert!(!status.tenants.contains_key("tnt2"));
+        assert_eq!(status.condition(kube_lib::CONDITION_SYNCED).unwrap().status, "True");
+
+        let patch = status_patch(rule.status.as_ref(), &status);
+        assert!(patch["status"]["tenants"].as_object().unwrap().contains_key("tnt2"));
+        assert_eq!(patch["status"]["tenants"]["tnt2"], serde_json::Value::Null);
+        assert_eq!(patch["status"]["tenants"]["tnt1"]["synced"], true);
+        assert_eq!(patch["status"]["observed_generation"], 2);
+
+        // nothing is removed from fresh status
+        let patch = status_patch(None, &status);
+        assert_eq!(patch["status"], serde_json::json!(status));
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, Utc};
+use k8s_openapi::api::core::v1::{Event, EventSource, ObjectReference};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+use k8s_openapi::Resource;
+use kube::api::{Meta, Patch, PatchParams, PostParams};
+use kube::{Api, Client};
+use log::{debug, warn};
+
+use kube_metrics_mutli_tenancy_lib as kube_lib;
+use kube_metrics_mutli_tenancy_lib::locks;
+use kube_metrics_mutli_tenancy_lib::namespace;
+
+pub const COMPONENT: &str = "open-metrics-informer";
+
+// Events are kept by k8s for an hour by default, repeats within it update the same event
+const AGGREGATION_WINDOW_MINUTES: i64 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventType {
+    Normal,
+    Warning,
+}
+
+impl EventType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            EventType::Normal => "Normal",
+            EventType::Warning => "Warning",
+        }
+    }
+}
+
+// Events are aggregated by involved object, type, reason and message, the way kubectl shows them
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct EventKey {
+    namespace: String,
+    name: String,
+    type_: &'static str,
+    reason: String,
+    message: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct SeenEvent {
+    // name of k8s Event object
+    name: String,
+    count: i32,
+    first_timestamp: DateTime<Utc>,
+    last_timestamp: DateTime<Utc>,
+}
+
+// Events written recently, so repeated one bumps count of existing k8s Event instead of creating new one.
+#[derive(Debug, Default)]
+struct EventAggregator {
+    seen: HashMap<EventKey, SeenEvent>,
+}
+
+impl EventAggregator {
+    // Account event, return what to write and whether k8s Event exists already.
+    fn observe(&mut self, key: &EventKey, now: DateTime<Utc>) -> (SeenEvent, bool) {
+        let window = Duration::minutes(AGGREGATION_WINDOW_MINUTES);
+        self.seen.retain(|_, seen| seen.last_timestamp + window > now);
+        match self.seen.get_mut(key) {
+            Some(seen) => {
+                seen.count += 1;
+                seen.last_timestamp = now;
+                (seen.clone(), true)
+            },
+            None => {
+                let seen = SeenEvent {
+                    name: format!("{}.{:x}", key.name, now.timestamp_nanos()),
+                    count: 1,
+                    first_timestamp: now,
+                    last_timestamp: now,
+                };
+                self.seen.insert(key.clone(), seen.clone());
+                (seen, false)
+            },
+        }
+    }
+
+    // k8s Event is gone, next repeat creates new one.
+    fn forget(&mut self, key: &EventKey) {
+        self.seen.remove(key);
+    }
+}
+
+// Records k8s Events against OpenMetricsRule resources, visible with `kubectl describe`.
+// Failures to record are only logged, events never block sync.
+pub struct EventRecorder {
+    k8s_client: Client,
+    instance: String,
+    aggregator: Mutex<EventAggregator>,
+}
+
+impl EventRecorder {
+    pub fn new(k8s_client: Client, instance: &str) -> EventRecorder {
+        EventRecorder {
+            k8s_client,
+            instance: String::from(instance),
+            aggregator: Mutex::new(EventAggregator::default()),
+        }
+    }
+
+    pub async fn record(&self, rule: &kube_lib::OpenMetricsRule, type_: EventType, reason: &str, message: &str) {
+        let key = EventKey {
+            namespace: namespace::rule_namespace(rule),
+            name: Meta::name(rule),
+            type_: type_.as_str(),
+            reason: String::from(reason),
+            message: String::from(message),
+        };
+        let now = Utc::now();
+        let (seen, repeated) = locks::lock(&self.aggregator).observe(&key, now);
+        let api: Api<Event> = Api::namespaced(self.k8s_client.clone(), &key.namespace);
+
+        if repeated {
+            let patch = serde_json::json!({
+                "count": seen.count,
+                "lastTimestamp": Time(seen.last_timestamp),
+            });
+            match api.patch(&seen.name, &PatchParams::default(), &Patch::Merge(&patch)).await {
+                Ok(_) => {
+                    debug!("event {} of {}/{} repeated {} times", reason, key.namespace, key.name, seen.count);
+                    return;
+                },
+                // expired already, created again below
+                Err(kube::Error::Api(e)) if e.code == 404 => {},
+                Err(e) => {
+                    warn!("failed to update event {} of {}/{}: {}", reason, key.namespace, key.name, e);
+                    return;
+                },
+            }
+        }
+
+        let seen = if repeated {
+            let mut aggregator = locks::lock(&self.aggregator);
+            aggregator.forget(&key);
+            aggregator.observe(&key, now).0
+        } else {
+            seen
+        };
+        let event = self.event(rule, &key, &seen);
+        if let Err(e) = api.create(&PostParams::default(), &event).await {
+            warn!("failed to record event {} of {}/{}: {}", reason, key.namespace, key.name, e);
+            locks::lock(&self.aggregator).forget(&key);
+        }
+    }
+
+    fn event(&self, rule: &kube_lib::OpenMetricsRule, key: &EventKey, seen: &SeenEvent) -> Event {
+        Event {
+            metadata: ObjectMeta {
+                name: Some(seen.name.clone()),
+                namespace: Some(key.namespace.clone()),
+                ..ObjectMeta::default()
+            },
+            involved_object: ObjectReference {
+                api_version: Some(kube_lib::OpenMetricsRule::API_VERSION.to_string()),
+                kind: Some(kube_lib::OpenMetricsRule::KIND.to_string()),
+                name: Some(key.name.clone()),
+                namespace: Some(key.namespace.clone()),
+                uid: rule.metadata.uid.clone(),
+                resource_version: rule.metadata.resource_version.clone(),
+                field_path: None,
+            },
+            type_: Some(String::from(key.type_)),
+            reason: Some(key.reason.clone()),
+            message: Some(key.message.clone()),
+            count: Some(seen.count),
+            first_timestamp: Some(Time(seen.first_timestamp)),
+            last_timestamp: Some(Time(seen.last_timestamp)),
+            source: Some(EventSource { component: Some(String::from(COMPONENT)), host: None }),
+            reporting_component: Some(String::from(COMPONENT)),
+            reporting_instance: Some(self.instance.clone()),
+            ..Event::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::events::events::{EventAggregator, EventKey};
+
+    fn key(message: &str) -> EventKey {
+        EventKey {
+            namespace: String::from("team-a"),
+            name: String::from("rule1"),
+            type_: "Warning",
+            reason: String::from("RuleGroupApplyFailed"),
+            message: String::from(message),
+        }
+    }
+
+    #[test]
+    fn test_event_aggregation() {
+        let mut aggregator = EventAggregator::default();
+        let then = Utc.ymd(2021, 5, 1).and_hms(10, 0, 0);
+
+        let (first, repeated) = aggregator.observe(&key("ruler responded with status 500"), then);
+        assert!(!repeated);
+        assert_eq!(first.count, 1);
+        assert!(first.name.starts_with("rule1."));
+
+        // repeats update the same event
+        let later = then + Duration::minutes(5);
+        let (second, repeated) = aggregator.observe(&key("ruler responded with status 500"), later);
+        assert!(repeated);
+        assert_eq!(second.name, first.name);
+        assert_eq!(second.count, 2);
+        assert_eq!((second.first_timestamp, second.last_timestamp), (then, later));
+
+        // other message is other event
+        let (other, repeated) = aggregator.observe(&key("ruler responded with status 502"), later);
+        assert!(!repeated);
+        assert_ne!(other.name, first.name);
+
+        // events not seen within aggregation window are created again
+        let (expired, repeated) = aggregator.observe(&key("ruler responded with status 500"), later + Duration::hours(2));
+        assert!(!repeated);
+        assert_eq!(expired.count, 1);
+
+        aggregator.forget(&key("ruler responded with status 500"));
+        assert!(!aggregator.observe(&key("ruler responded with status 500"), later + Duration::hours(2)).1);
+    }
+}
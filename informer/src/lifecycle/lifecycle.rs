@@ -0,0 +1,196 @@
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use chrono::{TimeZone, Utc};
+use kube_metrics_mutli_tenancy_lib::election::LeaderElector;
+use kube_metrics_mutli_tenancy_lib::health::{HealthCheck, HealthReport};
+use kube_metrics_mutli_tenancy_lib::locks;
+use kube_metrics_mutli_tenancy_lib::logging::{new_request_id, LogEvent};
+use tokio::sync::watch;
+
+
+// Whether process is going down
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+// Unix timestamps of last successful ticks, zero if never succeeded
+static TRACKER_LAST_SUCCESS: AtomicI64 = AtomicI64::new(0);
+static UPDATER_LAST_SUCCESS: AtomicI64 = AtomicI64::new(0);
+
+// Lease election of replica running tracker and updater, every replica runs them when not set
+static ELECTOR: RwLock<Option<Arc<LeaderElector>>> = RwLock::new(None);
+
+// Whether replica runs tracker and updater
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Leadership {
+    // leader election is disabled
+    Disabled,
+    Leader,
+    // other replica holds the lease
+    Standby,
+}
+
+impl Leadership {
+    pub fn is_active(&self) -> bool {
+        *self != Leadership::Standby
+    }
+}
+
+// Informer sync components
+pub enum Component {
+    Tracker,
+    Updater,
+}
+
+// Tick being run by tracker or updater task
+#[derive(Clone, Debug)]
+pub struct Tick {
+    pub component: &'static str,
+    // correlates audit events of single tick
+    pub request_id: String,
+}
+
+impl Tick {
+    pub fn new(component: &'static str) -> Tick {
+        Tick { component, request_id: new_request_id() }
+    }
+}
+
+tokio::task_local! {
+    // set for the duration of tick, see tracker() and updater()
+    pub static TICK: Tick;
+}
+
+// Audit event of mutation, attributed to current tick.
+pub fn audit(action: &str) -> LogEvent {
+    TICK.try_with(|t| LogEvent::audit(t.component, action, &t.request_id))
+        .unwrap_or_else(|_| LogEvent::audit("informer", action, &new_request_id()))
+}
+
+// Report process is going down.
+pub fn begin_shutdown() {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+}
+
+// Run tracker and updater only while replica holds the lease.
+pub fn init_leader_election(elector: Arc<LeaderElector>) {
+    *locks::write(&ELECTOR) = Some(elector);
+}
+
+// Current leadership of replica.
+pub fn leadership() -> Leadership {
+    match locks::read(&ELECTOR).as_ref() {
+        None => Leadership::Disabled,
+        Some(elector) if elector.is_leader() => Leadership::Leader,
+        Some(_) => Leadership::Standby,
+    }
+}
+
+// Remember that component tick completed without errors.
+pub fn mark_tick_success(component: Component) {
+    let now = Utc::now().timestamp();
+    match component {
+        Component::Tracker => TRACKER_LAST_SUCCESS.store(now, Ordering::SeqCst),
+        Component::Updater => UPDATER_LAST_SUCCESS.store(now, Ordering::SeqCst),
+    };
+}
+
+fn describe_last_success(name: &str, last_success: i64) -> String {
+    if last_success == 0 {
+        format!("{} never succeeded", name)
+    } else {
+        format!("{} last succeeded at {}", name, Utc.timestamp(last_success, 0).to_rfc3339())
+    }
+}
+
+// Informer is ready once tracker or updater tick succeeded, and until shutdown.
+// Standby replica is ready without syncing, so it is in place to take over.
+pub fn readiness_report() -> HealthReport {
+    leadership_readiness_report(leadership())
+}
+
+fn leadership_readiness_report(leadership: Leadership) -> HealthReport {
+    let shutdown = if SHUTTING_DOWN.load(Ordering::SeqCst) {
+        HealthCheck::failed("shutting down")
+    } else {
+        HealthCheck::ok("running")
+    };
+
+    let tracker_last_success = TRACKER_LAST_SUCCESS.load(Ordering::SeqCst);
+    let updater_last_success = UPDATER_LAST_SUCCESS.load(Ordering::SeqCst);
+    let message = format!(
+        "{}; {}",
+        describe_last_success("tracker", tracker_last_success),
+        describe_last_success("updater", updater_last_success)
+    );
+    let sync = if tracker_last_success > 0 || updater_last_success > 0 || !leadership.is_active() {
+        HealthCheck::ok(&message)
+    } else {
+        HealthCheck::failed(&message)
+    };
+
+    let leader_election = match leadership {
+        Leadership::Disabled => HealthCheck::ok("disabled"),
+        Leadership::Leader => HealthCheck::ok("leader"),
+        Leadership::Standby => HealthCheck::ok("standby, lease is held by other replica"),
+    };
+
+    HealthReport::new(vec![("shutdown", shutdown), ("sync", sync), ("leader_election", leader_election)])
+}
+
+
+// Wait for the next loop tick, unless shutdown is requested.
+// Returns false when loop should stop.
+pub async fn next_tick(interval: &mut tokio::time::Interval, shutdown: &mut watch::Receiver<bool>) -> bool {
+    if *shutdown.borrow() {
+        return false;
+    }
+    tokio::select! {
+        _ = interval.tick() => true,
+        // sender dropped or shutdown requested
+        _ = shutdown.changed() => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::watch;
+    use tokio::time::interval;
+
+    use crate::lifecycle::lifecycle::{
+        leadership_readiness_report, mark_tick_success, next_tick, readiness_report, Component, Leadership,
+    };
+
+    #[tokio::test]
+    async fn test_next_tick_stops_on_shutdown() {
+        let (tx, mut rx) = watch::channel(false);
+        let mut i = interval(Duration::from_secs(3600));
+
+        // first tick completes immediately
+        assert!(next_tick(&mut i, &mut rx).await);
+
+        tx.send(true).unwrap();
+        assert!(!next_tick(&mut i, &mut rx).await);
+    }
+
+    #[test]
+    fn test_readiness_report() {
+        let report = readiness_report();
+        assert!(!report.is_ok());
+        assert!(!report.checks["sync"].ok);
+        assert_eq!(report.checks["leader_election"].message, "disabled");
+
+        // standby is ready to take over before its first tick
+        let report = leadership_readiness_report(Leadership::Standby);
+        assert!(report.is_ok());
+        assert!(report.checks["leader_election"].message.starts_with("standby"));
+        assert!(!leadership_readiness_report(Leadership::Leader).is_ok());
+
+        mark_tick_success(Component::Updater);
+
+        let report = readiness_report();
+        assert!(report.is_ok());
+        assert!(report.checks["sync"].message.starts_with("tracker never succeeded; updater last succeeded at"));
+    }
+}
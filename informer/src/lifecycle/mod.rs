@@ -0,0 +1 @@
+pub mod lifecycle;
\ No newline at end of file
@@ -1,20 +1,28 @@
 #![deny(warnings)]
 #![deny(redundant_semicolons)]
-use log::{debug, error, warn};
+use log::{debug, error, info, warn};
 use argh::FromArgs;
 
+use std::sync::Arc;
 use std::time::Duration;
 use std::net::Ipv4Addr;
 use std::process::exit;
 use std::convert::Infallible;
 
 use kube::Client;
+use kube_metrics_mutli_tenancy_lib::election::{default_identity, LeaderElector};
+use kube_metrics_mutli_tenancy_lib::health::{HealthCheck, HealthReport};
+use kube_metrics_mutli_tenancy_lib::logging::{init_logging, LogFormat};
+use kube_metrics_mutli_tenancy_lib::selector::RuleSelector;
+use kube_metrics_mutli_tenancy_lib::shutdown::shutdown_signal;
+use kube_metrics_mutli_tenancy_lib::tenant::{init_normalizer, TenantIdNormalizer};
+use kube_metrics_mutli_tenancy_lib::telemetry::{init_tracing, shutdown_tracing, TracingConfig, TracingExporter};
 use prometheus::{
-    IntCounterVec, Encoder, Opts, Registry, TextEncoder,
+    IntCounterVec, IntGauge, IntGaugeVec, Encoder, Opts, Registry, TextEncoder,
 };
 use tokio;
-use tokio::time::interval;
-use env_logger;
+use tokio::sync::watch;
+use tokio::time::{interval, timeout};
 use reqwest::header::ACCEPT;
 use reqwest::header::HeaderValue;
 use warp::log as http_log;
@@ -22,8 +30,13 @@ use warp::Filter;
 
 // common routines
 mod crud;
+mod events;
 mod rules;
 
+// process lifecycle component
+mod lifecycle;
+use lifecycle::lifecycle::{begin_shutdown, init_leader_election, leadership, readiness_report};
+
 // ruler -> k8s sync component
 mod tracker;
 use tracker::tracker::tracker;
@@ -55,13 +68,77 @@ struct OpenMetricsInformerArgs {
     #[argh(option, default = "default_tracker_interval()")]
     tracker_poll_interval_seconds: u32,
 
-    /// start Kubernetes controller for IngestionTenant CRD
+    /// seconds between full updater reconciliations, changed rules are synced as soon as they are watched (default 300)
     #[argh(option, default = "default_updater_interval()")]
     updater_poll_interval_seconds: u32,
 
     /// enable removing rules for updater
     #[argh(switch)]
     enable_updater_remove_rules: bool,
+
+    /// max seconds to wait for tracker and updater ticks on shutdown (default 60)
+    #[argh(option, default = "default_shutdown_drain_seconds()")]
+    shutdown_drain_seconds: u32,
+
+    /// run tracker and updater only on replica holding Lease, others stand by
+    #[argh(switch)]
+    enable_leader_election: bool,
+
+    /// namespace of Lease electing informer replica, falls back to POD_NAMESPACE (default default)
+    #[argh(option, default = "String::from(\"\")")]
+    leader_election_namespace: String,
+
+    /// name of Lease electing informer replica (default open-metrics-informer)
+    #[argh(option, default = "String::from(\"open-metrics-informer\")")]
+    leader_election_lease_name: String,
+
+    /// seconds elected replica holds Lease without renewing it (default 15)
+    #[argh(option, default = "default_leader_election_lease_seconds()")]
+    leader_election_lease_seconds: u32,
+
+    /// seconds elected replica acts as leader since last Lease renew, below lease seconds (default 10)
+    #[argh(option, default = "default_leader_election_renew_deadline_seconds()")]
+    leader_election_renew_deadline_seconds: u32,
+
+    /// only act on OpenMetricsRule resources matching this label selector (optional)
+    #[argh(option, default = "String::from(\"\")")]
+    rule_label_selector: String,
+
+    /// only act on OpenMetricsRule resources matching this field selector (optional)
+    #[argh(option, default = "String::from(\"\")")]
+    rule_field_selector: String,
+
+    /// only act on OpenMetricsRule resources with this controller_class, or without class when not set (optional)
+    #[argh(option, default = "String::from(\"\")")]
+    controller_class: String,
+
+    /// lowercase tenant IDs before validation
+    #[argh(switch)]
+    tenant_id_lowercase: bool,
+
+    /// replace characters Cortex does not allow in tenant IDs with this one, instead of rejecting them (optional)
+    #[argh(option, default = "String::from(\"\")")]
+    tenant_id_replacement: String,
+
+    /// log format: text or json (default text)
+    #[argh(option, default = "LogFormat::Text")]
+    log_format: LogFormat,
+
+    /// span exporter: none, otlp or file (default none)
+    #[argh(option, default = "TracingExporter::Disabled")]
+    tracing_exporter: TracingExporter,
+
+    /// OTLP collector gRPC endpoint (default http://127.0.0.1:4317)
+    #[argh(option, default = "String::from(\"http://127.0.0.1:4317\")")]
+    tracing_otlp_endpoint: String,
+
+    /// file to append spans to as JSON lines, for file exporter (default traces.json)
+    #[argh(option, default = "String::from(\"traces.json\")")]
+    tracing_file: String,
+
+    /// share of ticks to trace, from 0 to 1 (default 1)
+    #[argh(option, default = "default_tracing_sample_ratio()")]
+    tracing_sample_ratio: f64,
 }
 
 // port
@@ -69,19 +146,51 @@ fn default_port() -> u16 {
     20093
 }
 
-// k8s -> ruler interval
-fn default_updater_interval() -> u32 { 40 }
+// k8s -> ruler full reconciliation interval
+fn default_updater_interval() -> u32 { 300 }
 
 // ruler -> k8s interval
 fn default_tracker_interval() -> u32 { 93 }
 
+// shutdown drain deadline
+fn default_shutdown_drain_seconds() -> u32 { 60 }
+
+// lease duration
+fn default_leader_election_lease_seconds() -> u32 { 15 }
+
+// time leader acts without renewing lease
+fn default_leader_election_renew_deadline_seconds() -> u32 { 10 }
+
+// traces sample ratio
+fn default_tracing_sample_ratio() -> f64 { 1.0 }
+
 
 #[tokio::main]
 pub async fn main() {
-    env_logger::init();
+    let args: OpenMetricsInformerArgs = argh::from_env();
+
+    init_logging("informer", args.log_format);
     let http_log_wrapper = http_log("Open-Metrics-multi-tenancy-Informer");
 
-    let args: OpenMetricsInformerArgs = argh::from_env();
+    // tenant IDs from OpenMetricsRule resources are normalized the same way as in proxy
+    match TenantIdNormalizer::new(args.tenant_id_lowercase, &args.tenant_id_replacement) {
+        Ok(n) => init_normalizer(n),
+        Err(e) => {
+            error!("Invalid tenant ID normalization: {}", e);
+            exit(2);
+        }
+    };
+
+    let tracing_config = TracingConfig {
+        exporter: args.tracing_exporter,
+        otlp_endpoint: args.tracing_otlp_endpoint.clone(),
+        file: args.tracing_file.clone(),
+        sample_ratio: args.tracing_sample_ratio,
+    };
+    if let Err(e) = init_tracing("open-metrics-multi-tenancy-informer", &tracing_config) {
+        error!("Failed to initialize tracing: {}", e);
+        exit(2);
+    }
 
     let interface = args.interface.clone().to_owned();
     let ruler_upstream_url = args.ruler_upstream_url.clone().to_owned();
@@ -91,6 +200,11 @@ pub async fn main() {
 
     let enable_updater_remove_rules = args.enable_updater_remove_rules.clone();
 
+    // resources of other deployments in the same cluster are never touched
+    let rule_selector = RuleSelector::new(
+        &args.rule_label_selector, &args.rule_field_selector, &args.controller_class);
+    info!("acting on OpenMetricsRule resources with {}", rule_selector);
+
     let r = Registry::new();
 
     let num_rules_opts = Opts::new("open_metrics_informer_tracker_rules", "rules detected");
@@ -109,6 +223,28 @@ pub async fn main() {
     let tenants_updated = IntCounterVec::new(tenants_updated_opts, &["tenant_id"]).unwrap();
     r.register(Box::new(tenants_updated.clone())).unwrap();
 
+    let rejected_tenant_ids_opts = Opts::new(
+        "open_metrics_informer_rejected_tenant_ids",
+        "number of invalid tenant IDs skipped, per reason",
+    );
+    let rejected_tenant_ids = IntCounterVec::new(rejected_tenant_ids_opts, &["reason"]).unwrap();
+    r.register(Box::new(rejected_tenant_ids.clone())).unwrap();
+
+    let namespace_rules_opts = Opts::new(
+        "open_metrics_informer_k8s_rules",
+        "number of OpenMetricsRule resources, per namespace",
+    );
+    let namespace_rules = IntGaugeVec::new(namespace_rules_opts, &["namespace"]).unwrap();
+    r.register(Box::new(namespace_rules.clone())).unwrap();
+
+    // set from leadership on scrape
+    let leader = IntGauge::new(
+        "open_metrics_informer_leader",
+        "whether replica runs tracker and updater, 0 while standing by",
+    )
+    .unwrap();
+    r.register(Box::new(leader.clone())).unwrap();
+
     fn with_registry(
         __r: Registry,
     ) -> impl Filter<Extract = (Registry,), Error = Infallible> + Clone {
@@ -118,7 +254,8 @@ pub async fn main() {
     let metrics = warp::path!("metrics")
         .and(warp::get())
         .and(with_registry(r))
-        .map(|_r: Registry| {
+        .map(move |_r: Registry| {
+            leader.set(leadership().is_active() as i64);
             // Gather the metrics.
             let mut buffer = vec![];
             let encoder = TextEncoder::new();
@@ -127,7 +264,30 @@ pub async fn main() {
 
             // Output to http body
             String::from_utf8(buffer).unwrap()
-        }).with(http_log_wrapper);
+        });
+
+    // liveness: process is up and serving http
+    let healthy = warp::path!("-" / "healthy")
+        .and(warp::get())
+        .map(|| {
+            let report = HealthReport::new(vec![("process", HealthCheck::ok("serving requests"))]);
+            warp::reply::json(&report)
+        });
+
+    // readiness: tracker or updater synced at least once, and not shutting down
+    let ready = warp::path!("-" / "ready")
+        .and(warp::get())
+        .map(|| {
+            let report = readiness_report();
+            let status = if report.is_ok() {
+                warp::http::StatusCode::OK
+            } else {
+                warp::http::StatusCode::SERVICE_UNAVAILABLE
+            };
+            warp::reply::with_status(warp::reply::json(&report), status)
+        });
+
+    let routes = metrics.or(healthy).or(ready).with(http_log_wrapper);
 
     let k8s_client = match Client::try_default().await {
         Ok(k_c) => {
@@ -176,8 +336,44 @@ pub async fn main() {
 
         let cloned_client = k8s_client.unwrap().clone();
 
+        // loops stop between ticks once shutdown is requested
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut loop_handles = Vec::new();
+
+        // standby replicas take over once leader lease expires
+        let elector = if args.enable_leader_election {
+            let lease_namespace = if args.leader_election_namespace.is_empty() {
+                std::env::var("POD_NAMESPACE").unwrap_or_else(|_| String::from("default"))
+            } else {
+                args.leader_election_namespace.clone()
+            };
+            let elector = Arc::new(match LeaderElector::new(
+                cloned_client.clone(),
+                &lease_namespace,
+                &args.leader_election_lease_name,
+                &default_identity(),
+                Duration::from_secs(args.leader_election_lease_seconds.into()),
+                Duration::from_secs(args.leader_election_renew_deadline_seconds.into()),
+            ) {
+                Ok(elector) => elector,
+                Err(e) => {
+                    error!("Invalid leader election: {}", e);
+                    exit(2);
+                }
+            });
+            init_leader_election(elector.clone());
+            // first tick only runs on replica which got the lease already
+            if let Err(e) = elector.try_acquire_or_renew().await {
+                warn!("{}", e);
+            }
+            tokio::task::spawn(elector.clone().run());
+            Some(elector)
+        } else {
+            None
+        };
+
         if tracker_poll_interval_seconds > 0 {
-            tokio::task::spawn(tracker(
+            loop_handles.push(tokio::task::spawn(tracker(
                 // It is safe to unwrap, since client should be inited by the point.
                 cloned_client.clone(),
                 ruler_client.clone(),
@@ -186,35 +382,69 @@ pub async fn main() {
                 distributor_upstream_url.clone(),
                 Box::new(num_rules.clone()),
                 Box::new(tenants_detected.clone()),
-                (tracker_poll_interval_seconds * 1000 ).into()
-            ));
+                Box::new(rejected_tenant_ids.clone()),
+                Box::new(namespace_rules.clone()),
+                rule_selector.clone(),
+                (tracker_poll_interval_seconds * 1000 ).into(),
+                shutdown_rx.clone()
+            )));
         } else {
             warn!("tracker component disabled");
         };
 
         if updater_poll_interval_seconds > 0 {
-            tokio::task::spawn(updater(
+            loop_handles.push(tokio::task::spawn(updater(
                 // It is safe to unwrap, since client should be inited by the point.
                 cloned_client.clone(),
                 ruler_client.clone(),
                 ruler_upstream_url.clone(),
                 Box::new(num_rules_updated.clone()),
                 Box::new(tenants_updated.clone()),
+                Box::new(rejected_tenant_ids.clone()),
+                Box::new(namespace_rules.clone()),
+                rule_selector.clone(),
                 (updater_poll_interval_seconds * 1000 ).into(),
-                !enable_updater_remove_rules
-            ));
+                !enable_updater_remove_rules,
+                shutdown_rx.clone()
+            )));
         } else {
             warn!("updater component disabled");
         };
 
         let exit_code = match listen_addr {
             Ok(ip) => {
-                // Spawn rule tracker
-
-                // Spawn rule updater
-                // tokio::task::spawn(updater(Box::new(), Box::new()));
-                warp::serve(metrics)
-                    .run((ip, args.port)).await;
+                let (server_shutdown_tx, server_shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+                let (_addr, server) = warp::serve(routes)
+                    .bind_with_graceful_shutdown((ip, args.port), async move {
+                        server_shutdown_rx.await.ok();
+                    });
+                let server_handle = tokio::task::spawn(server);
+
+                let signal_name = shutdown_signal().await;
+                info!("received {}, shutting down", signal_name);
+
+                // report unready, and let tracker and updater finish current tick
+                begin_shutdown();
+                let _ = shutdown_tx.send(true);
+                let drain_deadline = Duration::from_secs(args.shutdown_drain_seconds.into());
+                match timeout(drain_deadline, futures::future::join_all(loop_handles)).await {
+                    Ok(_) => info!("tracker and updater stopped"),
+                    Err(_) => warn!("shutdown deadline exceeded, tracker or updater tick interrupted"),
+                };
+
+                // let standby replica take over right away
+                if let Some(elector) = &elector {
+                    elector.stop();
+                    if let Err(e) = elector.release().await {
+                        warn!("{}", e);
+                    }
+                }
+
+                let _ = server_shutdown_tx.send(());
+                let _ = server_handle.await;
+                // flush pending spans
+                shutdown_tracing().await;
+                info!("shutdown complete");
                 0
             }
             Err(e) => {
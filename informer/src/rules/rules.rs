@@ -1,9 +1,15 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
+use kube_metrics_mutli_tenancy_lib::telemetry;
+use kube_metrics_mutli_tenancy_lib::tenant::normalize_tenant_id;
 use log::{debug,error,trace,warn};
+use opentelemetry::trace::{SpanKind, TraceContextExt};
+use opentelemetry::{Context, KeyValue};
+use prometheus::IntCounterVec;
 use reqwest::Client as RClient;
 
 use kube_metrics_mutli_tenancy_lib as kube_lib;
+use crate::crud::crud;
 
 
 // This method is complex, since it is instruments both k8s and cortex.
@@ -24,18 +30,32 @@ pub async fn discover_ruler_rules(
     let mut failed = false;
     for tenant_id in tenants {
 
-        let response = r_client.get(&url)
+        let cx = telemetry::start_span(
+            &Context::current(),
+            "ruler_list_rules",
+            SpanKind::Client,
+            vec![
+                KeyValue::new("http.url", url.clone()),
+                KeyValue::new("tenant_id", tenant_id.clone()),
+            ],
+        );
+        let response = crud::with_trace_headers(r_client.get(&url), &cx)
             .header("X-Scope-OrgID", tenant_id)
             .send()
             .await;
 
         let resp = match response {
-            Ok(r) => Some(r),
+            Ok(r) => {
+                telemetry::record_status(&cx, r.status().as_u16());
+                Some(r)
+            },
             Err(f) => {
+                telemetry::record_error(&cx, &f.to_string());
                 error!("failed to communicate with ruler: {}", f);
                 None
             }
         };
+        cx.span().end();
         if resp.is_some() {
             let r = resp.unwrap();
             let s = r.status();
@@ -247,12 +267,22 @@ pub fn diff_rule_groups(
 }
 
 // Given list of OpenMetricsRule, get hash map of (tenant_id) -> (list of egligible rules)
-pub fn get_tenant_map_from_rules_list(open_metrics_rules: Vec<kube_lib::OpenMetricsRule>)
+// Tenant IDs are normalized, invalid ones are skipped and counted by reason.
+pub fn get_tenant_map_from_rules_list(open_metrics_rules: Vec<kube_lib::OpenMetricsRule>,
+                                      rejected_tenant_ids: &IntCounterVec)
     -> HashMap<String, Vec<(kube_lib::GroupSpec, i64)>> {
     let mut tenant_specs_k8s: HashMap<String, Vec<(kube_lib::GroupSpec, i64)>> = HashMap::new();
     let mut ctr = 0;
     for tenant_rule in open_metrics_rules.iter().cloned().into_iter() {
-        for tenant_id in tenant_rule.spec.tenants {
+        for raw_tenant_id in tenant_rule.spec.tenants {
+            let tenant_id = match normalize_tenant_id(&raw_tenant_id) {
+                Ok(t) => t,
+                Err(e) => {
+                    warn!("skipping tenant {:?} of {:?}: {}", raw_tenant_id, tenant_rule.metadata.name, e);
+                    rejected_tenant_ids.with_label_values(&[e.reason()]).inc();
+                    continue;
+                }
+            };
             if !tenant_specs_k8s.contains_key(&tenant_id) {
                 let vec = Vec::new();
                 tenant_specs_k8s.insert(tenant_id.clone(), vec);
@@ -267,6 +297,37 @@ pub fn get_tenant_map_from_rules_list(open_metrics_rules: Vec<kube_lib::OpenMetr
     return tenant_specs_k8s
 }
 
+// Groups every rule shares with other rules, or defines twice, for the same tenant.
+// Only one of them ends up in ruler, so each rule gets description of its conflicts, in rules order.
+pub fn find_rule_conflicts(open_metrics_rules: &[kube_lib::OpenMetricsRule]) -> Vec<Vec<String>> {
+    // rule indexes by tenant and group name
+    let mut definitions: BTreeMap<(String, String), Vec<usize>> = BTreeMap::new();
+    for (idx, rule) in open_metrics_rules.iter().enumerate() {
+        for tenant_id in rule.spec.tenants.iter().filter_map(|t| normalize_tenant_id(t).ok()) {
+            for group in rule.spec.groups.iter() {
+                definitions.entry((tenant_id.clone(), group.name.clone())).or_default().push(idx);
+            }
+        }
+    }
+    let mut conflicts = vec![Vec::new(); open_metrics_rules.len()];
+    for ((tenant_id, group_name), indexes) in definitions.iter().filter(|(_, i)| i.len() > 1) {
+        for idx in indexes.iter().collect::<BTreeSet<_>>() {
+            let others: BTreeSet<String> = indexes
+                .iter()
+                .filter(|i| *i != idx)
+                .map(|i| open_metrics_rules[*i].metadata.name.clone().unwrap_or_default())
+                .collect();
+            conflicts[*idx].push(if others.is_empty() {
+                format!("group {} of tenant {} is defined more than once", group_name, tenant_id)
+            } else {
+                format!("group {} of tenant {} is also defined in {}",
+                        group_name, tenant_id, others.into_iter().collect::<Vec<_>>().join(", "))
+            });
+        }
+    }
+    conflicts
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -278,7 +339,7 @@ mod tests {
     use env_logger;
     use mockito;
     use kube_metrics_mutli_tenancy_lib as kube_lib;
-    use crate::rules::rules::{discover_ruler_rules, diff_rule_groups};
+    use crate::rules::rules::{discover_ruler_rules, diff_rule_groups, find_rule_conflicts, get_tenant_map_from_rules_list};
     use serde_yaml;
     use serde_yaml::Value;
     use std::collections::HashMap;
@@ -612,4 +673,61 @@ test:
         assert_eq!(g_r.name, "cortexrulegroup1");
         assert_eq!(i_r, &(0 as i64));
     }
+
+    #[test]
+    fn test_get_tenant_map_skips_invalid_tenants() {
+        let rejected = prometheus::IntCounterVec::new(
+            prometheus::Opts::new("rejected_tenant_ids", "help"), &["reason"]).unwrap();
+        let rule = kube_lib::OpenMetricsRule::new(
+            "rule1",
+            kube_lib::OpenMetricsRuleSpec {
+                tenants: vec![String::from("tnt1"), String::from(".."), String::from("tnt 2")],
+                description: None,
+                groups: vec![kube_lib::GroupSpec {
+                    name: String::from("group1"),
+                    interval: None,
+                    rules: vec![],
+                }],
+                external_labels: HashMap::new(),
+                controller_class: None,
+            },
+        );
+
+        let tenant_map = get_tenant_map_from_rules_list(vec![rule], &rejected);
+
+        assert_eq!(tenant_map.keys().collect::<Vec<&String>>(), vec!["tnt1"]);
+        assert_eq!(rejected.with_label_values(&["reserved"]).get(), 1);
+        assert_eq!(rejected.with_label_values(&["invalid_character"]).get(), 1);
+    }
+
+    #[test]
+    fn test_find_rule_conflicts() {
+        let rule = |name: &str, tenants: &[&str], groups: &[&str]| kube_lib::OpenMetricsRule::new(
+            name,
+            kube_lib::OpenMetricsRuleSpec {
+                tenants: tenants.iter().map(|t| String::from(*t)).collect(),
+                description: None,
+                groups: groups.iter().map(|g| kube_lib::GroupSpec {
+                    name: String::from(*g),
+                    interval: None,
+                    rules: vec![],
+                }).collect(),
+                external_labels: HashMap::new(),
+                controller_class: None,
+            },
+        );
+
+        let conflicts = find_rule_conflicts(&[
+            rule("rule1", &["tnt1"], &["group1", "group2"]),
+            rule("rule2", &["tnt1", "tnt2"], &["group1"]),
+            rule("rule3", &["tnt2"], &["group3", "group3"]),
+            rule("rule4", &["tnt3"], &["group1"]),
+        ]);
+
+        assert_eq!(conflicts[0], vec![String::from("group group1 of tenant tnt1 is also defined in rule2")]);
+        assert_eq!(conflicts[1], vec![String::from("group group1 of tenant tnt1 is also defined in rule1")]);
+        assert_eq!(conflicts[2], vec![String::from("group group3 of tenant tnt2 is defined more than once")]);
+        // same group of other tenant is fine
+        assert!(conflicts[3].is_empty());
+    }
 }
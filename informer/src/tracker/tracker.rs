@@ -1,16 +1,159 @@
 use std::time::Duration;
 
 use kube::Client;
+use kube_metrics_mutli_tenancy_lib::namespace::{self, NamespaceScope};
+use kube_metrics_mutli_tenancy_lib::selector::RuleSelector;
+use kube_metrics_mutli_tenancy_lib::telemetry;
 use log::{debug,error,info};
-use prometheus::IntCounterVec;
+use opentelemetry::trace::{FutureExt, SpanKind, TraceContextExt};
+use opentelemetry::{Context, KeyValue};
+use prometheus::{IntCounterVec, IntGaugeVec};
 use reqwest::Client as RClient;
+use tokio::sync::watch;
 use tokio::time::interval;
 
 use crate::crud::crud;
+use crate::lifecycle::lifecycle::{leadership, mark_tick_success, next_tick, Component, Tick, TICK};
 use crate::rules::rules;
 use kube_metrics_mutli_tenancy_lib as kube_lib;
 
 
+// Tracker parameters, shared by ticks
+struct Tracker {
+    k8s_client: Client,
+    ruler_client: RClient,
+    ruler_api_url: String,
+    distributor_client: RClient,
+    distributor_api_url: String,
+    num_rules: Box<IntCounterVec>,
+    num_tenants: Box<IntCounterVec>,
+    rejected_tenant_ids: Box<IntCounterVec>,
+    namespace_rules: Box<IntGaugeVec>,
+    selector: RuleSelector,
+}
+
+impl Tracker {
+    // Sync ruler rules of every observed namespace into k8s.
+    async fn tick(&self, scope: &NamespaceScope) {
+        let Tracker {
+            k8s_client,
+            ruler_client,
+            ruler_api_url,
+            distributor_client,
+            distributor_api_url,
+            num_rules,
+            num_tenants,
+            rejected_tenant_ids,
+            namespace_rules,
+            selector,
+        } = self;
+
+        let namespaces = match namespace::list_namespaces(k8s_client.clone(), scope).await {
+            Ok(namespaces) => namespaces,
+            Err(msg) => {
+                error!("tracker: failed to resolve namespaces: {}", msg);
+                return;
+            }
+        };
+        match crud::load_tenants_from_distributor(
+            distributor_client.clone(), &distributor_api_url.clone()).await {
+            Ok(tenant_vec) => {
+                // namespaces are tracked independently, each from ruler namespace of the same name
+                let mut all_synced = true;
+                for namespace in namespaces.iter() {
+                    debug!("Going to discover rules for {} tenants in {} namespace.", tenant_vec.len(), namespace);
+                    match rules::discover_ruler_rules(
+                        &tenant_vec.clone(),
+                        ruler_client.clone(),
+                        &ruler_api_url.clone(),
+                        namespace,
+                    ).await {
+                        Ok(r) => {
+                            let mut tenants_found: u32 = 0;
+                            let mut groups_found: u32 = 0;
+                            let mut rules_found: u32 = 0;
+                            for k in r.keys().into_iter() {
+                                tenants_found += 1;
+                                // Safe to unwrap atomic
+                                (*num_tenants).with_label_values(&[k.as_str()]).inc();
+                                let vg = r.get(k).unwrap();
+                                for (g, _i) in vg {
+                                    groups_found += 1;
+                                    for _r in g.rules.iter().cloned().into_iter() {
+                                        rules_found += 1;
+                                        // Safe to unwrap atomic
+                                        (*num_rules).with_label_values(&[k.as_str()]).inc();
+                                    }
+                                }
+                            };
+                            info!("tracker: discovered {} rules in {} groups for {} tenants in {} namespace",
+                                  rules_found, groups_found, tenants_found, namespace);
+
+                            match kube_lib::discover_namespace_rules(k8s_client.clone(), Some(namespace.as_str()), selector).await {
+                                Ok(k8s_rules) => {
+                                    (*namespace_rules).with_label_values(&[namespace.as_str()]).set(k8s_rules.len() as i64);
+                                    let tenant_k8s_specs = rules::get_tenant_map_from_rules_list(k8s_rules, rejected_tenant_ids);
+                                    let (updates, removals) =
+                                        rules::diff_rule_groups(tenant_k8s_specs, r);
+
+                                    let mut updates_num = 0;
+                                    for (tenant_id, update_groups) in updates.clone().into_iter() {
+                                        for (group, _i) in update_groups {
+                                            info!("TRACKER: Going to ADD {:?} to k8s {} tenant in {} namespace",
+                                                  group, tenant_id, namespace);
+                                            crud::create_or_update_k8s_resource(
+                                                k8s_client.clone(),
+                                                &tenant_id,
+                                                namespace,
+                                                selector,
+                                                group
+                                            ).await;
+                                            updates_num += 1;
+                                        }
+                                    };
+                                    info!("done k8s updates in {} namespace: {} in total", namespace, updates_num);
+
+                                    let mut removes_num = 0;
+                                    for (tenant_id, remove_groups) in removals.clone().into_iter() {
+                                        for (group, _i) in remove_groups {
+                                            info!("TRACKER: Ruler group {:?} is ABSENT from k8s {} tenant in {} namespace",
+                                                  group, tenant_id, namespace);
+                                            crud::remove_k8s_resource(
+                                                k8s_client.clone(),
+                                                &tenant_id,
+                                                namespace,
+                                                selector,
+                                                group
+                                            ).await;
+                                            removes_num += 1;
+                                        }
+                                    };
+                                    info!("done k8s removes in {} namespace: {} in total", namespace, removes_num);
+                                },
+                                Err(msg) => {
+                                    error!("tracker: failed to discover k8s rules of {} namespace: {}", namespace, msg);
+                                    all_synced = false;
+                                }
+                            }
+
+                        },
+                        Err(msg) => {
+                            error!("tracker: failed to discover ruler rules of {} namespace: {}", namespace, msg);
+                            all_synced = false;
+                        }
+                    };
+                }
+                if all_synced {
+                    mark_tick_success(Component::Tracker);
+                }
+            },
+            Err(msg) => {
+                error!("tracker: tenants acquire from k8s failed: {}", msg);
+            }
+        };
+    }
+}
+
 // Periodically discover rules from Cortex.
 // Update Rules CRD in k8s
 pub async fn tracker(k8s_client: Client,
@@ -20,98 +163,56 @@ pub async fn tracker(k8s_client: Client,
                      distributor_api_url: String,
                      num_rules: Box<IntCounterVec>,
                      num_tenants: Box<IntCounterVec>,
-                     ms: u64) {
+                     rejected_tenant_ids: Box<IntCounterVec>,
+                     namespace_rules: Box<IntGaugeVec>,
+                     selector: RuleSelector,
+                     ms: u64,
+                     mut shutdown: watch::Receiver<bool>) {
 
     let mut interval = interval(Duration::from_millis(ms));
-    let namespace = std::env::var("OPEN_METRICS_INFORMER_NAMESPACE")
-        .unwrap_or("default".into());
-
-    loop {
-        interval.tick().await;
+    let scope = NamespaceScope::from_env(
+        "OPEN_METRICS_INFORMER_NAMESPACE", "OPEN_METRICS_INFORMER_NAMESPACE_SELECTOR");
+    info!("tracker observes {} namespaces", scope);
 
-        match crud::load_tenants_from_distributor(
-            distributor_client.clone(), &distributor_api_url.clone()).await {
-            Ok(tenant_vec) => {
-                debug!("Going to discover rules for {} tenants.", tenant_vec.len());
-                match rules::discover_ruler_rules(
-                    &tenant_vec.clone(),
-                    ruler_client.clone(),
-                    &ruler_api_url.clone(),
-                    &namespace,
-                ).await {
-                    Ok(r) => {
-                        let mut tenants_found: u32 = 0;
-                        let mut groups_found: u32 = 0;
-                        let mut rules_found: u32 = 0;
-                        for k in r.keys().into_iter() {
-                            tenants_found += 1;
-                            // Safe to unwrap atomic
-                            (*num_tenants).with_label_values(&[k.as_str()]).inc();
-                            let vg = r.get(k).unwrap();
-                            for (g, _i) in vg {
-                                groups_found += 1;
-                                for _r in g.rules.iter().cloned().into_iter() {
-                                    rules_found += 1;
-                                    // Safe to unwrap atomic
-                                    (*num_rules).with_label_values(&[k.as_str()]).inc();
-                                }
-                            }
-                        };
-                        info!("tracker: discovered {} rules in {} groups for {} tenants",
-                              rules_found, groups_found, tenants_found);
-
-                        match kube_lib::discover_open_metrics_rules(k8s_client.clone(), &namespace).await {
-                            Ok(k8s_rules) => {
-                                let tenant_k8s_specs = rules::get_tenant_map_from_rules_list(k8s_rules);
-                                let (updates, removals) =
-                                    rules::diff_rule_groups(tenant_k8s_specs, r);
-
-                                let mut updates_num = 0;
-                                for (tenant_id, update_groups) in updates.clone().into_iter() {
-                                    for (group, _i) in update_groups {
-                                        info!("TRACKER: Going to ADD {:?} to k8s {} tenant", group, tenant_id);
-                                        crud::create_or_update_k8s_resource(
-                                            k8s_client.clone(),
-                                            &tenant_id,
-                                            &namespace,
-                                            group
-                                        ).await;
-                                        updates_num += 1;
-                                    }
-                                };
-                                info!("done k8s updates: {} in total", updates_num);
-
-                                let mut removes_num = 0;
-                                for (tenant_id, remove_groups) in removals.clone().into_iter() {
-                                    for (group, _i) in remove_groups {
-                                        info!("TRACKER: Ruler group {:?} is ABSENT from k8s {} tenant", group, tenant_id);
-                                        crud::remove_k8s_resource(
-                                            k8s_client.clone(),
-                                            &tenant_id,
-                                            &namespace,
-                                            group
-                                        ).await;
-                                        removes_num += 1;
-                                    }
-                                };
-                                info!("done k8s removes: {} in total", removes_num);
-                            },
-                            Err(msg) => {
-                                error!("tracker: failed to discover k8s rules: {}", msg);
-                            }
-                        }
+    let tracker = Tracker {
+        k8s_client,
+        ruler_client,
+        ruler_api_url,
+        distributor_client,
+        distributor_api_url,
+        num_rules,
+        num_tenants,
+        rejected_tenant_ids,
+        namespace_rules,
+        selector,
+    };
 
-                    },
-                    Err(msg) => {
-                        error!("tracker: failed to discover ruler rules: {}", msg)
-                    }
-                };
-            },
-            Err(msg) => {
-                error!("tracker: tenants acquire from k8s failed: {}", msg);
-            }
+    loop {
+        // stop between ticks, so ruler and k8s updates are never interrupted halfway
+        if !next_tick(&mut interval, &mut shutdown).await {
+            info!("tracker stopped");
+            break;
         };
+        // standby replica waits for leader lease to expire
+        if !leadership().is_active() {
+            debug!("tracker: standby, skipping tick");
+            continue;
+        }
 
+        let tick = Tick::new("tracker");
+        let tick_cx = telemetry::start_span(
+            &Context::new(),
+            "tracker_tick",
+            SpanKind::Internal,
+            vec![
+                KeyValue::new("namespace", scope.to_string()),
+                KeyValue::new("request_id", tick.request_id.clone()),
+            ],
+        );
+        // k8s, ruler and distributor calls of tick are recorded as children of tick span,
+        // and their mutations are audited under tick request id
+        TICK.scope(tick, tracker.tick(&scope).with_context(tick_cx.clone())).await;
+        tick_cx.span().end();
 
         debug!("Done tracker tick!");
 
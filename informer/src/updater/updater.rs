@@ -1,120 +1,626 @@
+use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
 use std::time::Duration;
 
+use chrono::Utc;
+use futures::future::FutureExt as _;
+use futures::stream::{self, BoxStream, StreamExt};
+use kube::api::Meta;
 use kube::{Api,Client};
-use log::{debug,info,error};
+use kube_metrics_mutli_tenancy_lib::election::default_identity;
+use kube_metrics_mutli_tenancy_lib::namespace::{self, NamespaceScope};
+use kube_metrics_mutli_tenancy_lib::selector::RuleSelector;
+use kube_metrics_mutli_tenancy_lib::telemetry;
+use kube_metrics_mutli_tenancy_lib::tenant::normalize_tenant_id;
+use kube_runtime::watcher::{watcher, Event};
+use log::{debug,info,error,warn};
+use opentelemetry::trace::{FutureExt, SpanKind, TraceContextExt};
+use opentelemetry::{Context, KeyValue};
 use reqwest::Client as RClient;
-use prometheus::IntCounterVec;
-use tokio::time::interval;
+use prometheus::{IntCounterVec, IntGaugeVec};
+use tokio::sync::watch;
+use tokio::time::{interval, sleep};
 
 use crate::crud::crud;
+use crate::events::events::{EventRecorder, EventType};
+use crate::lifecycle::lifecycle::{leadership, mark_tick_success, Component, Tick, TICK};
 use crate::rules::rules;
 use kube_metrics_mutli_tenancy_lib as kube_lib;
 
-// Periodically discover rules from Cortex.
-// Update Rules CRD in k8s
+// Back off after failed watch, watcher lists resources again on next poll
+const WATCH_RETRY: Duration = Duration::from_secs(5);
+
+// Tenants touched by changed OpenMetricsRule resources, by namespace.
+// Resource is changed when its generation is not observed by informer yet.
+#[derive(Debug, Default)]
+pub struct RuleChanges {
+    // tenants of every watched resource, by namespace/name
+    known: HashMap<String, (String, Vec<String>)>,
+    touched: HashMap<String, HashSet<String>>,
+}
+
+// Valid tenant IDs of rule, normalized.
+fn rule_tenants(rule: &kube_lib::OpenMetricsRule) -> Vec<String> {
+    rule.spec.tenants.iter().filter_map(|t| normalize_tenant_id(t).ok()).collect()
+}
+
+impl RuleChanges {
+    fn touch(&mut self, namespace: &str, tenants: &[String]) {
+        self.touched
+            .entry(String::from(namespace))
+            .or_default()
+            .extend(tenants.iter().cloned());
+    }
+
+    // Remember resource, and touch its tenants when spec changed.
+    // Tenants resource had before are touched as well, so groups moved out of them are seen.
+    fn applied(&mut self, rule: &kube_lib::OpenMetricsRule, selector: &RuleSelector) {
+        let namespace = namespace::rule_namespace(rule);
+        let key = format!("{}/{}", namespace, Meta::name(rule));
+        if !selector.matches(rule) {
+            // resource of other controller class now
+            self.deleted(&key);
+            return;
+        }
+        let tenants = rule_tenants(rule);
+        let previous = self.known.insert(key, (namespace.clone(), tenants.clone()));
+        if !rule.is_generation_observed() {
+            self.touch(&namespace, &tenants);
+            if let Some((_, previous_tenants)) = previous {
+                self.touch(&namespace, &previous_tenants);
+            }
+        }
+    }
+
+    fn deleted(&mut self, key: &str) {
+        if let Some((namespace, tenants)) = self.known.remove(key) {
+            self.touch(&namespace, &tenants);
+        }
+    }
+
+    // Account watch event.
+    pub fn observe(&mut self, event: &Event<kube_lib::OpenMetricsRule>, selector: &RuleSelector) {
+        match event {
+            Event::Applied(rule) => self.applied(rule, selector),
+            Event::Deleted(rule) => {
+                let key = format!("{}/{}", namespace::rule_namespace(rule), Meta::name(rule));
+                self.deleted(&key);
+            },
+            Event::Restarted(rules) => {
+                // resources deleted while watch was down are gone from the list
+                let listed: HashSet<String> = rules
+                    .iter()
+                    .map(|rule| format!("{}/{}", namespace::rule_namespace(rule), Meta::name(rule)))
+                    .collect();
+                let gone: Vec<String> = self.known.keys().filter(|k| !listed.contains(*k)).cloned().collect();
+                for key in gone.iter() {
+                    self.deleted(key);
+                }
+                for rule in rules.iter() {
+                    self.applied(rule, selector);
+                }
+            },
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.touched.is_empty()
+    }
+
+    // Take touched tenants, they are reconciled by caller.
+    pub fn take(&mut self) -> HashMap<String, HashSet<String>> {
+        std::mem::take(&mut self.touched)
+    }
+}
+
+// Rule groups by tenant, with index of k8s rule defining the group
+type TenantGroups = HashMap<String, Vec<(kube_lib::GroupSpec, i64)>>;
+
+// Rule groups and IDs of tenants to reconcile, only of given tenants unless None.
+// Given tenants are reconciled even when no k8s rule has them anymore, so their groups are removed from ruler.
+fn tenants_to_reconcile(
+    mut tenant_k8s_specs: TenantGroups,
+    tenants: Option<&HashSet<String>>,
+) -> (TenantGroups, HashSet<String>) {
+    match tenants {
+        Some(tenants) => {
+            tenant_k8s_specs.retain(|tenant_id, _| tenants.contains(tenant_id));
+            (tenant_k8s_specs, tenants.clone())
+        },
+        None => {
+            let reconciled = tenant_k8s_specs.keys().cloned().collect();
+            (tenant_k8s_specs, reconciled)
+        },
+    }
+}
+
+// Watch OpenMetricsRule resources of observed namespaces, every namespace is watched at once for "*".
+fn watch_rules(
+    k8s_client: Client,
+    scope: &NamespaceScope,
+    namespaces: &[String],
+    selector: &RuleSelector,
+) -> BoxStream<'static, Result<Event<kube_lib::OpenMetricsRule>, String>> {
+    let targets: Vec<Option<String>> = match scope {
+        NamespaceScope::All => vec![None],
+        _ => namespaces.iter().cloned().map(Some).collect(),
+    };
+    if targets.is_empty() {
+        return stream::pending().boxed();
+    }
+    let watches: Vec<_> = targets
+        .into_iter()
+        .map(|namespace| {
+            let api: Api<kube_lib::OpenMetricsRule> = match namespace {
+                Some(namespace) => Api::namespaced(k8s_client.clone(), &namespace),
+                None => Api::all(k8s_client.clone()),
+            };
+            watcher(api, selector.list_params()).map(|e| e.map_err(|e| e.to_string())).boxed()
+        })
+        .collect();
+    stream::select_all(watches).boxed()
+}
+
+// Updater parameters, shared by full and event-driven reconciliation
+struct Updater {
+    k8s_client: Client,
+    ruler_client: RClient,
+    ruler_api_url: String,
+    num_rules: Box<IntCounterVec>,
+    num_tenants: Box<IntCounterVec>,
+    rejected_tenant_ids: Box<IntCounterVec>,
+    namespace_rules: Box<IntGaugeVec>,
+    selector: RuleSelector,
+    skip_ruler_group_removal: bool,
+    recorder: EventRecorder,
+}
+
+impl Updater {
+    // Sync rules of namespace into ruler, only of given tenants unless None.
+    // Return whether namespace synced without errors.
+    async fn reconcile_namespace(&self, namespace: &String, tenants: Option<&HashSet<String>>) -> bool {
+        let k8s_rules = match kube_lib::discover_namespace_rules(
+            self.k8s_client.clone(),
+            Some(namespace.as_str()),
+            &self.selector
+        ).await {
+            Ok(k8s_rules) => k8s_rules,
+            Err(msg) => {
+                error!("failed to discover k8s rules of {} namespace: {}", namespace, msg);
+                return false;
+            }
+        };
+        (*self.namespace_rules).with_label_values(&[namespace.as_str()]).set(k8s_rules.len() as i64);
+        let rules_clone = Vec::from_iter(k8s_rules.iter().cloned().into_iter());
+        // only valid tenant IDs are sent to ruler
+        let (tenant_k8s_specs, reconciled) = tenants_to_reconcile(
+            rules::get_tenant_map_from_rules_list(k8s_rules, &self.rejected_tenant_ids),
+            tenants,
+        );
+        // result of every reconciled tenant, last ruler error when some call failed
+        let mut results: HashMap<String, Result<(), String>> =
+            reconciled.iter().map(|tenant_id| (tenant_id.clone(), Ok(()))).collect();
+        let synced = match rules::discover_ruler_rules(
+            &Vec::from_iter(reconciled.iter().cloned()),
+            self.ruler_client.clone(),
+            &self.ruler_api_url.clone(),
+            &namespace.clone()
+        ).await {
+            Ok(tenant_specs_ruler) => {
+                self.apply_rule_groups(namespace, &rules_clone, tenant_specs_ruler, tenant_k8s_specs, &mut results).await;
+                true
+            },
+            Err(msg) => {
+                error!("failed to discover ruler rules of {} namespace: {}", namespace, msg);
+                for result in results.values_mut() {
+                    *result = Err(msg.clone());
+                }
+                false
+            }
+        };
+
+        // status tells outcome of every resource of reconciled tenants, and of changed ones
+        let api : Api<kube_lib::OpenMetricsRule> = Api::namespaced(
+            self.k8s_client.clone(), &namespace.clone());
+        let conflicts = rules::find_rule_conflicts(&rules_clone);
+        let now = Utc::now();
+        for (rule, conflicts) in rules_clone.iter().zip(conflicts) {
+            let rule_tenants = rule_tenants(rule);
+            let is_reconciled = tenants.is_none() || rule_tenants.iter().any(|t| reconciled.contains(t));
+            if !is_reconciled && rule.is_generation_observed() {
+                continue;
+            }
+            let sync = crud::RuleSync {
+                tenants: rule_tenants
+                    .iter()
+                    .filter_map(|t| results.get(t).map(|r| (t.clone(), r.clone())))
+                    .collect(),
+                conflicts: Some(conflicts),
+            };
+            // nothing is written when status stays the same
+            let status = crud::rule_status(rule, rule.metadata.generation, &sync, now);
+            if rule.status.as_ref() == Some(&status) {
+                continue;
+            }
+            self.record_rejection(rule, &status).await;
+            crud::resource_updated(&api, &Meta::name(rule), rule.clone(), &sync).await;
+        }
+        synced
+    }
+
+    // Warn about rule validation failure, once it is seen with given reason and message.
+    async fn record_rejection(&self, rule: &kube_lib::OpenMetricsRule, status: &kube_lib::OpenMetricsRuleStatus) {
+        let valid = match status.condition(kube_lib::CONDITION_VALID) {
+            Some(valid) if valid.status == "False" => valid,
+            _ => return,
+        };
+        let previous = rule.status.as_ref().and_then(|s| s.condition(kube_lib::CONDITION_VALID));
+        if previous.map(|p| (&p.status, &p.message)) != Some((&valid.status, &valid.message)) {
+            self.recorder.record(rule, EventType::Warning, &valid.reason, &valid.message).await;
+        }
+    }
+
+    // Record ruler operation result against rules, successful operations as normal events.
+    async fn record_ruler_result(
+        &self,
+        rules: &[&kube_lib::OpenMetricsRule],
+        result: &Result<(), String>,
+        reasons: (&str, &str),
+        message: String,
+    ) {
+        let (event_type, reason, message) = match result {
+            Ok(_) => (EventType::Normal, reasons.0, message),
+            Err(e) => (EventType::Warning, reasons.1, format!("{}: {}", message, e)),
+        };
+        for rule in rules.iter() {
+            self.recorder.record(rule, event_type, reason, &message).await;
+        }
+    }
+
+    // Send rule group changes to ruler, and record failures by tenant.
+    // Operations are recorded as events against rules defining the group,
+    // or against changed rules of the tenant for removed groups.
+    async fn apply_rule_groups(
+        &self,
+        namespace: &String,
+        k8s_rules: &[kube_lib::OpenMetricsRule],
+        tenant_specs_ruler: HashMap<String, Vec<(kube_lib::GroupSpec, i64)>>,
+        tenant_k8s_specs: HashMap<String, Vec<(kube_lib::GroupSpec, i64)>>,
+        results: &mut HashMap<String, Result<(), String>>,
+    ) {
+        let (
+            rule_updates_add,
+            rule_updates_remove
+        ) = rules::diff_rule_groups(
+            tenant_specs_ruler,
+            tenant_k8s_specs);
+
+        for (tenant_id, update_groups)
+                in rule_updates_add.clone().into_iter() {
+            // Safe to unwrap atomic
+            (*self.num_tenants).with_label_values(&[tenant_id.as_str()]).inc();
+            for (group, k8s_idx) in update_groups {
+                info!("UPDATER: Going to ADD {:?} to {} tenant in {} namespace",
+                      group, tenant_id, namespace);
+                let message = format!("rule group {} of tenant {}", group.name, tenant_id);
+                let result = crud::update_ruler_rule(
+                    self.ruler_client.clone(),
+                    &self.ruler_api_url.clone(),
+                    &tenant_id.clone(),
+                    &namespace.clone(),
+                    group
+                ).await;
+                let owners: Vec<&kube_lib::OpenMetricsRule> = k8s_rules.get(k8s_idx as usize).into_iter().collect();
+                self.record_ruler_result(&owners, &result, ("RuleGroupApplied", "RuleGroupApplyFailed"), message).await;
+                if let Err(e) = result {
+                    results.insert(tenant_id.clone(), Err(e));
+                }
+                // Safe to unwrap atomic
+                let _ = &self.num_rules.with_label_values(&[tenant_id.as_str()]).inc();
+            }
+        };
+
+        if !self.skip_ruler_group_removal {
+            for (tenant_id, remove_groups)
+                    in rule_updates_remove.clone().into_iter() {
+                // group is gone from k8s, so the change is attributed to changed rules of the tenant
+                let owners: Vec<&kube_lib::OpenMetricsRule> = k8s_rules
+                    .iter()
+                    .filter(|rule| !rule.is_generation_observed() && rule_tenants(rule).contains(&tenant_id))
+                    .collect();
+                for (group, _k8s_idx) in remove_groups {
+                    info!(
+                        "UPDATER: Going to REMOVE {:?} from {} tenant in {} namespace",
+                        group, tenant_id, namespace);
+                    let message = format!("rule group {} of tenant {}", group.name, tenant_id);
+                    let result = crud::remove_ruler_rule(
+                        self.ruler_client.clone(),
+                        &self.ruler_api_url.clone(),
+                        &tenant_id.clone(),
+                        &namespace.clone(),
+                        group
+                    ).await;
+                    self.record_ruler_result(&owners, &result, ("RuleGroupRemoved", "RuleGroupRemoveFailed"), message).await;
+                    if let Err(e) = result {
+                        results.insert(tenant_id.clone(), Err(e));
+                    }
+                };
+            };
+        };
+    }
+
+    // Sync namespaces, each into ruler namespace of the same name, or only touched tenants of touched ones.
+    async fn tick(&self, namespaces: &[String], touched: Option<&HashMap<String, HashSet<String>>>) {
+        let mut all_synced = true;
+        for namespace in namespaces.iter() {
+            let tenants = match touched {
+                None => None,
+                Some(touched) => match touched.get(namespace) {
+                    Some(tenants) => Some(tenants),
+                    None => continue,
+                },
+            };
+            if !self.reconcile_namespace(namespace, tenants).await {
+                all_synced = false;
+            }
+        }
+        if all_synced && touched.is_none() {
+            mark_tick_success(Component::Updater);
+        }
+    }
+
+    // Sync every namespace, or only touched tenants of touched namespaces.
+    async fn reconcile(&self, scope: &NamespaceScope, namespaces: &[String], touched: Option<HashMap<String, HashSet<String>>>) {
+        let full = touched.is_none();
+        let tick = Tick::new("updater");
+        let tick_cx = telemetry::start_span(
+            &Context::new(),
+            "updater_tick",
+            SpanKind::Internal,
+            vec![
+                KeyValue::new("namespace", scope.to_string()),
+                KeyValue::new("request_id", tick.request_id.clone()),
+                KeyValue::new("full", full),
+            ],
+        );
+        // k8s, ruler and distributor calls of tick are recorded as children of tick span,
+        // and their mutations are audited under tick request id
+        TICK.scope(tick, self.tick(namespaces, touched.as_ref()).with_context(tick_cx.clone())).await;
+        tick_cx.span().end();
+    }
+}
+
+// Sync rules from k8s into Cortex ruler.
+// Tenants of changed OpenMetricsRule resources are synced as soon as watch reports the change,
+// and every tenant is synced every ms milliseconds, in case some change was missed.
 pub async fn updater(k8s_client: Client,
                      ruler_client: RClient,
                      ruler_api_url: String,
                      num_rules: Box<IntCounterVec>,
                      num_tenants: Box<IntCounterVec>,
+                     rejected_tenant_ids: Box<IntCounterVec>,
+                     namespace_rules: Box<IntGaugeVec>,
+                     selector: RuleSelector,
                      ms: u64,
-                     skip_ruler_group_removal: bool) {
+                     skip_ruler_group_removal: bool,
+                     mut shutdown: watch::Receiver<bool>) {
     let mut interval = interval(Duration::from_millis(ms));
-    let namespace = std::env::var("OPEN_METRICS_INFORMER_NAMESPACE")
-        .unwrap_or("default".into());
-
-    loop {
-        interval.tick().await;
-        let cli = k8s_client.clone();
-
-        match kube_lib::discover_open_metrics_rules(
-            k8s_client.clone(),
-            &namespace.clone()
-        ).await {
-            Ok(k8s_rules) => {
-                let mut rules_clone = Vec::from_iter(k8s_rules.iter().cloned().into_iter());
-                let tenants = kube_lib::discover_tenant_ids(k8s_rules);
-                match rules::discover_ruler_rules(
-                    &Vec::from_iter(tenants),
-                    ruler_client.clone(),
-                    &ruler_api_url.clone(),
-                    &namespace.clone()
-                ).await {
-                    Ok(tenant_specs_ruler) => {
-                        let tenant_k8s_specs =
-                            rules::get_tenant_map_from_rules_list(rules_clone.clone());
-
-                        let (
-                            rule_updates_add,
-                            rule_updates_remove
-                        ) = rules::diff_rule_groups(
-                            tenant_specs_ruler,
-                            tenant_k8s_specs);
-
-                        let api : Api<kube_lib::OpenMetricsRule> = Api::namespaced(
-                            cli.clone(), &namespace.clone());
-                        for (tenant_id, update_groups)
-                                in rule_updates_add.clone().into_iter() {
-                            // Safe to unwrap atomic
-                            (*num_tenants).with_label_values(&[tenant_id.as_str()]).inc();
-                            for (group, k8s_idx) in update_groups {
-                                info!("UPDATER: Going to ADD {:?} to {} tenant", group, tenant_id);
-                                crud::update_ruler_rule(
-                                    ruler_client.clone(),
-                                    &ruler_api_url.clone(),
-                                    &tenant_id.clone(),
-                                    &namespace.clone(),
-                                    group
-                                ).await;
-                                // Safe to unwrap atomic
-                                let _ = &num_rules.with_label_values(&[tenant_id.as_str()]).inc();
-
-                                if k8s_idx >= 0 {
-                                    // safe to unwrap since update came from resource
-                                    let rule_updated = rules_clone.get_mut(k8s_idx as usize).cloned().unwrap();
-                                    let resource_name = rule_updated.metadata.name.clone().unwrap();
-                                    crud::resource_updated(
-                                        &api,
-                                        &resource_name,
-                                        rule_updated.clone()).await;
-                                }
-
-                            }
-                        };
-
-                        if !skip_ruler_group_removal {
-                            for (tenant_id, remove_groups)
-                                    in rule_updates_remove.clone().into_iter() {
-                                for (group, _k8s_idx) in remove_groups {
-                                    info!(
-                                        "UPDATER: Going to REMOVE {:?} from {} tenant",
-                                        group, tenant_id);
-                                    crud::remove_ruler_rule(
-
-                                        ruler_client.clone(),
-                                        &ruler_api_url.clone(),
-                                        &tenant_id.clone(),
-                                        &namespace.clone(),
-                                        group
-                                    ).await;
-                                };
-                            };
-                        };
+    let scope = NamespaceScope::from_env(
+        "OPEN_METRICS_INFORMER_NAMESPACE", "OPEN_METRICS_INFORMER_NAMESPACE_SELECTOR");
+    info!("updater observes {} namespaces", scope);
 
+    let updater = Updater {
+        k8s_client: k8s_client.clone(),
+        ruler_client,
+        ruler_api_url,
+        num_rules,
+        num_tenants,
+        rejected_tenant_ids,
+        namespace_rules,
+        selector: selector.clone(),
+        skip_ruler_group_removal,
+        recorder: EventRecorder::new(k8s_client.clone(), &default_identity()),
+    };
+    let mut namespaces: Vec<String> = Vec::new();
+    let mut events = stream::pending().boxed();
+    let mut watching = false;
+    let mut changes = RuleChanges::default();
+    let mut was_active = false;
 
+    loop {
+        if *shutdown.borrow() {
+            info!("updater stopped");
+            break;
+        }
+        // stop between reconciliations, so ruler and k8s updates are never interrupted halfway
+        let full = tokio::select! {
+            _ = interval.tick() => true,
+            event = events.next() => {
+                match event {
+                    Some(Ok(event)) => changes.observe(&event, &selector),
+                    Some(Err(e)) => {
+                        warn!("failed to watch k8s rules: {}", e);
+                        sleep(WATCH_RETRY).await;
                     },
-                    Err(msg) => {
-                        error!("failed to discover ruler rules: {}", msg);
-                    }
+                    None => {
+                        // nothing to watch, watch is started again on next full reconciliation
+                        events = stream::pending().boxed();
+                        watching = false;
+                    },
+                };
+                // take every event which is ready already, so a burst of changes is reconciled at once
+                while let Some(Some(Ok(event))) = events.next().now_or_never() {
+                    changes.observe(&event, &selector);
                 }
+                false
+            },
+            // sender dropped or shutdown requested
+            _ = shutdown.changed() => {
+                info!("updater stopped");
+                break;
             },
-            Err(msg) => {
-                error!("failed to discover k8s rules: {}", msg);
-            }
         };
 
-        debug!("Done updater tick");
+        // standby replica waits for leader lease to expire
+        let active = leadership().is_active();
+        if !active {
+            if full {
+                debug!("updater: standby, skipping tick");
+            }
+            changes.take();
+            was_active = false;
+            continue;
+        }
+        // new leader syncs everything, changes might have been missed by previous one
+        let full = full || !was_active;
+        was_active = true;
 
+        if full {
+            match namespace::list_namespaces(k8s_client.clone(), &scope).await {
+                Ok(found) => {
+                    if found != namespaces || !watching {
+                        debug!("watching k8s rules of {:?} namespaces", found);
+                        events = watch_rules(k8s_client.clone(), &scope, &found, &selector);
+                        watching = true;
+                    }
+                    namespaces = found;
+                },
+                Err(msg) => {
+                    error!("failed to resolve namespaces: {}", msg);
+                    continue;
+                }
+            };
+            // full reconciliation covers every change seen so far
+            changes.take();
+            updater.reconcile(&scope, &namespaces, None).await;
+            debug!("Done updater tick");
+        } else if !changes.is_empty() {
+            let touched = changes.take();
+            debug!("reconciling changed k8s rules: {:?}", touched);
+            updater.reconcile(&scope, &namespaces, Some(touched)).await;
+        }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use kube_metrics_mutli_tenancy_lib::selector::RuleSelector;
+    use kube_metrics_mutli_tenancy_lib::{GroupSpec, OpenMetricsRule, OpenMetricsRuleSpec, OpenMetricsRuleStatus};
+    use kube_runtime::watcher::Event;
+    use prometheus::{IntCounterVec, Opts};
+
+    use crate::rules::rules::{diff_rule_groups, get_tenant_map_from_rules_list};
+    use crate::updater::updater::{tenants_to_reconcile, RuleChanges};
+
+    fn rule(name: &str, tenants: &[&str], generation: i64, observed_generation: Option<i64>) -> OpenMetricsRule {
+        let mut rule = OpenMetricsRule::new(name, OpenMetricsRuleSpec {
+            tenants: tenants.iter().map(|t| String::from(*t)).collect(),
+            description: None,
+            groups: vec![],
+            external_labels: Default::default(),
+            controller_class: None,
+        });
+        rule.metadata.namespace = Some(String::from("team-a"));
+        rule.metadata.generation = Some(generation);
+        rule.status = Some(OpenMetricsRuleStatus { ruler_updated: true, observed_generation, ..Default::default() });
+        rule
+    }
+
+    fn tenants(values: &[&str]) -> HashSet<String> {
+        values.iter().map(|v| String::from(*v)).collect()
+    }
+
+    #[test]
+    fn test_rule_changes() {
+        let selector = RuleSelector::default();
+        let mut changes = RuleChanges::default();
+
+        // synced resources are not touched when listed
+        changes.observe(&Event::Restarted(vec![
+            rule("a", &["tenant1"], 2, Some(2)),
+            rule("b", &["tenant2", "tenant3"], 1, None),
+        ]), &selector);
+        let touched = changes.take();
+        assert_eq!(touched.len(), 1);
+        assert_eq!(touched["team-a"], tenants(&["tenant2", "tenant3"]));
+        assert!(changes.is_empty());
+
+        // tenants before and after change are touched
+        changes.observe(&Event::Applied(rule("a", &["tenant4"], 3, Some(2))), &selector);
+        assert_eq!(changes.take()["team-a"], tenants(&["tenant1", "tenant4"]));
+        // status update alone is not a change
+        changes.observe(&Event::Applied(rule("a", &["tenant4"], 3, Some(3))), &selector);
+        assert!(changes.is_empty());
+
+        changes.observe(&Event::Deleted(rule("b", &["tenant2", "tenant3"], 1, None)), &selector);
+        assert_eq!(changes.take()["team-a"], tenants(&["tenant2", "tenant3"]));
+
+        // resources deleted while watch was down are touched on relist
+        changes.observe(&Event::Restarted(vec![]), &selector);
+        assert_eq!(changes.take()["team-a"], tenants(&["tenant4"]));
+
+        // resource moved to other controller class is like deleted one
+        changes.observe(&Event::Applied(rule("c", &["tenant5"], 1, Some(1))), &selector);
+        let mut moved = rule("c", &["tenant5"], 2, Some(1));
+        moved.spec.controller_class = Some(String::from("staging"));
+        changes.observe(&Event::Applied(moved), &selector);
+        assert_eq!(changes.take()["team-a"], tenants(&["tenant5"]));
+    }
+
+    fn group(name: &str) -> GroupSpec {
+        GroupSpec { name: String::from(name), interval: None, rules: vec![] }
+    }
+
+    #[test]
+    fn test_tenants_to_reconcile() {
+        let selector = RuleSelector::default();
+        let mut changes = RuleChanges::default();
+        let mut a = rule("a", &["tenant1", "tenant3"], 1, Some(1));
+        a.spec.groups = vec![group("group-a")];
+        let mut b = rule("b", &["tenant2"], 1, Some(1));
+        b.spec.groups = vec![group("group-b")];
+        let mut c = rule("c", &["tenant4"], 1, Some(1));
+        c.spec.groups = vec![group("group-c")];
+        changes.observe(&Event::Restarted(vec![a.clone(), b.clone(), c.clone()]), &selector);
+
+        // tenant3 is removed from rule a, and rule b is deleted
+        a.spec.tenants = vec![String::from("tenant1")];
+        a.metadata.generation = Some(2);
+        changes.observe(&Event::Applied(a.clone()), &selector);
+        changes.observe(&Event::Deleted(b), &selector);
+        let touched = changes.take();
+        assert_eq!(touched["team-a"], tenants(&["tenant1", "tenant2", "tenant3"]));
+
+        let rejected = IntCounterVec::new(Opts::new("rejected_tenant_ids", "help"), &["reason"]).unwrap();
+        let (k8s_specs, reconciled) = tenants_to_reconcile(
+            get_tenant_map_from_rules_list(vec![a, c], &rejected),
+            touched.get("team-a"),
+        );
+        // tenants without k8s rules left are reconciled, untouched ones are not
+        assert_eq!(reconciled, tenants(&["tenant1", "tenant2", "tenant3"]));
+        assert_eq!(k8s_specs.keys().cloned().collect::<HashSet<String>>(), tenants(&["tenant1"]));
+
+        // so their groups are removed from ruler
+        let ruler_specs: HashMap<String, Vec<(GroupSpec, i64)>> = vec![
+            (String::from("tenant1"), vec![(group("group-a"), 0)]),
+            (String::from("tenant2"), vec![(group("group-b"), 0)]),
+            (String::from("tenant3"), vec![(group("group-a"), 0)]),
+        ]
+        .into_iter()
+        .collect();
+        let (updates, removals) = diff_rule_groups(ruler_specs, k8s_specs);
+        assert!(updates.is_empty());
+        assert_eq!(removals.keys().cloned().collect::<HashSet<String>>(), tenants(&["tenant2", "tenant3"]));
+        assert_eq!(removals["tenant2"][0].0.name, "group-b");
+        assert_eq!(removals["tenant3"][0].0.name, "group-a");
+
+        // every tenant with k8s rules is reconciled on full sync
+        let (_, reconciled) = tenants_to_reconcile(
+            get_tenant_map_from_rules_list(vec![rule("d", &["tenant5"], 1, Some(1))], &rejected),
+            None,
+        );
+        assert_eq!(reconciled, tenants(&["tenant5"]));
+    }
+}
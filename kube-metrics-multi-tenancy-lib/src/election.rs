@@ -0,0 +1,347 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use kube::api::PostParams;
+use kube::{Api, Client};
+use log::{debug, info, warn};
+use rand::Rng;
+
+use crate::locks;
+
+// Lease-based leader election, one replica holds coordination.k8s.io/v1 Lease at a time.
+// Holder renews lease every third of renew deadline, and steps down once it could not renew it within the deadline.
+// Others take lease over when its record has not changed for lease duration by their own clock,
+// so clocks of replicas need not agree, and lease duration past renew deadline covers clock drift.
+pub struct LeaderElector {
+    api: Api<Lease>,
+    lease_name: String,
+    identity: String,
+    lease_duration: Duration,
+    renew_deadline: Duration,
+    // time of last successful acquire or renew, None when lease is held by other replica
+    renewed: Mutex<Option<Instant>>,
+    // lease record of other replica, and time it was first seen
+    observed: Mutex<Option<(LeaseRecord, Instant)>>,
+    stopped: AtomicBool,
+}
+
+// Lease fields changed by every renew
+#[derive(Clone, Debug, PartialEq)]
+struct LeaseRecord {
+    holder: String,
+    renew_time: Option<DateTime<Utc>>,
+}
+
+// Replica identity, pod name when running in k8s.
+pub fn default_identity() -> String {
+    for var in ["POD_NAME", "HOSTNAME"].iter() {
+        if let Ok(value) = std::env::var(var) {
+            if !value.trim().is_empty() {
+                return String::from(value.trim());
+            }
+        }
+    }
+    format!("replica-{:08x}", rand::thread_rng().gen::<u32>())
+}
+
+impl LeaderElector {
+    pub fn new(
+        k8s_client: Client,
+        namespace: &str,
+        lease_name: &str,
+        identity: &str,
+        lease_duration: Duration,
+        renew_deadline: Duration,
+    ) -> Result<LeaderElector, String> {
+        if renew_deadline == Duration::from_secs(0) || renew_deadline >= lease_duration {
+            return Err(format!(
+                "renew deadline {:?} should be positive and below lease duration {:?}",
+                renew_deadline, lease_duration
+            ));
+        }
+        Ok(LeaderElector {
+            api: Api::namespaced(k8s_client, namespace),
+            lease_name: String::from(lease_name),
+            identity: String::from(identity),
+            lease_duration,
+            renew_deadline,
+            renewed: Mutex::new(None),
+            observed: Mutex::new(None),
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    pub fn identity(&self) -> &str {
+        &self.identity
+    }
+
+    // Replica holds the lease, and it was renewed within renew deadline.
+    pub fn is_leader(&self) -> bool {
+        match *locks::lock(&self.renewed) {
+            Some(renewed) => renewed.elapsed() < self.renew_deadline,
+            None => false,
+        }
+    }
+
+    // Lease of other replica is expired once its record has not changed for lease duration.
+    fn is_expired(&self, record: LeaseRecord) -> bool {
+        let mut observed = locks::lock(&self.observed);
+        match &*observed {
+            Some((seen, at)) if *seen == record => at.elapsed() > self.lease_duration,
+            _ => {
+                *observed = Some((record, Instant::now()));
+                false
+            },
+        }
+    }
+
+    fn lease_spec(&self, acquire_time: DateTime<Utc>, renew_time: DateTime<Utc>, transitions: i32) -> LeaseSpec {
+        LeaseSpec {
+            holder_identity: Some(self.identity.clone()),
+            lease_duration_seconds: Some(self.lease_duration.as_secs() as i32),
+            acquire_time: Some(MicroTime(acquire_time)),
+            renew_time: Some(MicroTime(renew_time)),
+            lease_transitions: Some(transitions),
+        }
+    }
+
+    // Acquire lease when it is free or expired, renew it when it is held already.
+    // Return whether replica is leader now. Leadership is kept on error until renew deadline passes.
+    pub async fn try_acquire_or_renew(&self) -> Result<bool, String> {
+        let was_leader = locks::lock(&self.renewed).is_some();
+        let attempted = Instant::now();
+        let result = self.acquire_or_renew().await;
+        {
+            let mut renewed = locks::lock(&self.renewed);
+            match result {
+                Ok(leader) => *renewed = if leader { Some(attempted) } else { None },
+                Err(_) => {
+                    if renewed.map(|r| r.elapsed() >= self.renew_deadline).unwrap_or(false) {
+                        *renewed = None;
+                    }
+                },
+            }
+        }
+        let leader = self.is_leader();
+        if leader && !was_leader {
+            info!("{} became leader of lease {}", self.identity, self.lease_name);
+        } else if !leader && was_leader {
+            warn!("{} lost leadership of lease {}", self.identity, self.lease_name);
+        }
+        result.map(|_| leader)
+    }
+
+    async fn acquire_or_renew(&self) -> Result<bool, String> {
+        let now = Utc::now();
+        let lease = match self.api.get(&self.lease_name).await {
+            Ok(lease) => lease,
+            Err(kube::Error::Api(e)) if e.code == 404 => {
+                let lease = Lease {
+                    metadata: ObjectMeta { name: Some(self.lease_name.clone()), ..ObjectMeta::default() },
+                    spec: Some(self.lease_spec(now, now, 0)),
+                };
+                return match self.api.create(&PostParams::default(), &lease).await {
+                    Ok(_) => Ok(true),
+                    // other replica created it first
+                    Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+                    Err(e) => Err(format!("failed to create lease {}: {}", self.lease_name, e)),
+                };
+            },
+            Err(e) => return Err(format!("failed to get lease {}: {}", self.lease_name, e)),
+        };
+
+        let spec = lease.spec.clone().unwrap_or_default();
+        let holder = spec.holder_identity.clone().unwrap_or_default();
+        let held = holder == self.identity;
+        let record = LeaseRecord { holder: holder.clone(), renew_time: spec.renew_time.as_ref().map(|t| t.0) };
+        if !held && !holder.is_empty() && !self.is_expired(record) {
+            debug!("lease {} is held by {}", self.lease_name, holder);
+            return Ok(false);
+        }
+
+        let (acquire_time, transitions) = if held {
+            (spec.acquire_time.map(|t| t.0).unwrap_or(now), spec.lease_transitions.unwrap_or(0))
+        } else {
+            (now, spec.lease_transitions.unwrap_or(0) + 1)
+        };
+        let mut renewed = lease;
+        renewed.spec = Some(self.lease_spec(acquire_time, now, transitions));
+        // resource version is kept, so concurrent takeover by other replica fails with conflict
+        match self.api.replace(&self.lease_name, &PostParams::default(), &renewed).await {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+            Err(e) => Err(format!("failed to update lease {}: {}", self.lease_name, e)),
+        }
+    }
+
+    // Give the lease up, so standby replica takes over without waiting for expiry.
+    pub async fn release(&self) -> Result<(), String> {
+        if !self.is_leader() {
+            return Ok(());
+        }
+        *locks::lock(&self.renewed) = None;
+        let mut lease = match self.api.get(&self.lease_name).await {
+            Ok(lease) => lease,
+            Err(e) => return Err(format!("failed to get lease {}: {}", self.lease_name, e)),
+        };
+        let mut spec = lease.spec.clone().unwrap_or_default();
+        if spec.holder_identity.as_deref() != Some(self.identity.as_str()) {
+            return Ok(());
+        }
+        spec.holder_identity = None;
+        lease.spec = Some(spec);
+        match self.api.replace(&self.lease_name, &PostParams::default(), &lease).await {
+            Ok(_) => {
+                info!("{} released lease {}", self.identity, self.lease_name);
+                Ok(())
+            },
+            Err(e) => Err(format!("failed to release lease {}: {}", self.lease_name, e)),
+        }
+    }
+
+    // Stop election loop, lease is released by it.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    // Keep acquiring or renewing lease until stopped.
+    pub async fn run(self: Arc<Self>) {
+        let retry_period = self.renew_deadline / 3;
+        info!("{} takes part in election for lease {}", self.identity, self.lease_name);
+        while !self.stopped.load(Ordering::SeqCst) {
+            if let Err(e) = self.try_acquire_or_renew().await {
+                warn!("{}", e);
+            }
+            tokio::time::sleep(retry_period).await;
+        }
+        if let Err(e) = self.release().await {
+            warn!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::pin_mut;
+    use http::{Method, Request, Response};
+    use hyper::Body;
+    use kube::{Client, Service};
+    use tower_test::mock;
+
+    use crate::election::LeaderElector;
+
+    const LEASE_PATH: &str = "/apis/coordination.k8s.io/v1/namespaces/default/leases/proxy";
+
+    fn lease(holder: &str, renew_time: &str) -> serde_json::Value {
+        serde_json::json!({
+            "apiVersion": "coordination.k8s.io/v1",
+            "kind": "Lease",
+            "metadata": { "name": "proxy", "namespace": "default", "resourceVersion": "7" },
+            "spec": {
+                "holderIdentity": holder,
+                "leaseDurationSeconds": 15,
+                "acquireTime": renew_time,
+                "renewTime": renew_time,
+                "leaseTransitions": 2
+            }
+        })
+    }
+
+    fn response(status: u16, body: serde_json::Value) -> Response<Body> {
+        Response::builder().status(status).body(Body::from(body.to_string())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_leader_election() {
+        let (mock_service, handle) = mock::pair::<Request<Body>, Response<Body>>();
+        let spawned = tokio::spawn(async move {
+            pin_mut!(handle);
+            // missing lease is created
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert_eq!(request.uri().path(), LEASE_PATH);
+            send.send_response(response(404, serde_json::json!({
+                "kind": "Status", "apiVersion": "v1", "status": "Failure",
+                "message": "leases \"proxy\" not found", "reason": "NotFound", "code": 404
+            })));
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert_eq!(request.method(), Method::POST);
+            let body: serde_json::Value = serde_json::from_slice(
+                &hyper::body::to_bytes(request.into_body()).await.unwrap()).unwrap();
+            assert_eq!(body["spec"]["holderIdentity"], "replica-a");
+            assert_eq!(body["spec"]["leaseDurationSeconds"], 15);
+            send.send_response(response(201, body));
+
+            // fresh lease of other replica is left alone
+            let (_, send) = handle.next_request().await.expect("service not called");
+            let now = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true);
+            send.send_response(response(200, lease("replica-b", &now)));
+
+            // lease renewed long ago by clock of other replica is not expired until it is not renewed for lease duration
+            let (_, send) = handle.next_request().await.expect("service not called");
+            send.send_response(response(200, lease("replica-b", "2021-05-01T10:00:00.000000Z")));
+
+            // expired lease is taken over
+            let (_, send) = handle.next_request().await.expect("service not called");
+            send.send_response(response(200, lease("replica-b", "2021-05-01T10:00:00.000000Z")));
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert_eq!(request.method(), Method::PUT);
+            assert_eq!(request.uri().path(), LEASE_PATH);
+            let body: serde_json::Value = serde_json::from_slice(
+                &hyper::body::to_bytes(request.into_body()).await.unwrap()).unwrap();
+            assert_eq!(body["metadata"]["resourceVersion"], "7");
+            assert_eq!(body["spec"]["holderIdentity"], "replica-a");
+            assert_eq!(body["spec"]["leaseTransitions"], 3);
+            send.send_response(response(200, body));
+
+            // failed renew
+            let (_, send) = handle.next_request().await.expect("service not called");
+            send.send_response(response(500, serde_json::json!({
+                "kind": "Status", "apiVersion": "v1", "status": "Failure",
+                "message": "etcdserver: request timed out", "reason": "InternalError", "code": 500
+            })));
+        });
+
+        let client = Client::new(Service::new(mock_service));
+        let elector = LeaderElector::new(
+            client, "default", "proxy", "replica-a", Duration::from_secs(15), Duration::from_secs(10)).unwrap();
+        assert!(!elector.is_leader());
+        assert_eq!(elector.try_acquire_or_renew().await, Ok(true));
+        assert!(elector.is_leader());
+        assert_eq!(elector.try_acquire_or_renew().await, Ok(false));
+        assert!(!elector.is_leader());
+        assert_eq!(elector.try_acquire_or_renew().await, Ok(false));
+
+        // lease record is unchanged for lease duration
+        elector.observed.lock().unwrap().as_mut().unwrap().1 -= Duration::from_secs(16);
+        assert_eq!(elector.try_acquire_or_renew().await, Ok(true));
+        assert!(elector.is_leader());
+
+        // leader steps down once renew deadline passes, before other replicas see lease expired
+        *elector.renewed.lock().unwrap().as_mut().unwrap() -= Duration::from_secs(11);
+        assert!(!elector.is_leader());
+        assert!(elector.try_acquire_or_renew().await.is_err());
+        assert!(!elector.is_leader());
+        assert!(elector.renewed.lock().unwrap().is_none());
+
+        spawned.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_renew_deadline_below_lease_duration() {
+        let (mock_service, _) = mock::pair::<Request<Body>, Response<Body>>();
+        let client = Client::new(Service::new(mock_service));
+        let elector = |lease: u64, renew: u64| {
+            LeaderElector::new(
+                client.clone(), "default", "proxy", "replica-a", Duration::from_secs(lease), Duration::from_secs(renew))
+        };
+        assert!(elector(15, 10).is_ok());
+        assert!(elector(15, 15).is_err());
+        assert!(elector(15, 0).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+
+// Result of a single health or readiness check
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct HealthCheck {
+    pub ok: bool,
+    pub message: String,
+}
+
+impl HealthCheck {
+    pub fn ok(message: &str) -> HealthCheck {
+        HealthCheck { ok: true, message: String::from(message) }
+    }
+
+    pub fn failed(message: &str) -> HealthCheck {
+        HealthCheck { ok: false, message: String::from(message) }
+    }
+}
+
+// Health or readiness report, served as JSON by /-/healthy and /-/ready endpoints.
+// Report is ok only when every check is ok.
+#[derive(Serialize, Clone, Debug)]
+pub struct HealthReport {
+    pub status: String,
+    pub checks: BTreeMap<String, HealthCheck>,
+}
+
+impl HealthReport {
+    pub fn new(checks: Vec<(&str, HealthCheck)>) -> HealthReport {
+        let checks: BTreeMap<String, HealthCheck> = checks
+            .into_iter()
+            .map(|(name, check)| (String::from(name), check))
+            .collect();
+        let status = if checks.values().all(|c| c.ok) { "ok" } else { "failed" };
+        HealthReport { status: String::from(status), checks }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.checks.values().all(|c| c.ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::health::{HealthCheck, HealthReport};
+
+    #[test]
+    fn test_health_report() {
+        let report = HealthReport::new(vec![
+            ("first", HealthCheck::ok("fine")),
+            ("second", HealthCheck::failed("not synced yet")),
+        ]);
+        assert!(!report.is_ok());
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({
+                "status": "failed",
+                "checks": {
+                    "first": {"ok": true, "message": "fine"},
+                    "second": {"ok": false, "message": "not synced yet"}
+                }
+            })
+        );
+
+        assert!(HealthReport::new(vec![("first", HealthCheck::ok("fine"))]).is_ok());
+    }
+}
@@ -1,30 +1,62 @@
 #![deny(warnings)]
 #![deny(redundant_semicolons)]
-use std::collections::{HashMap,HashSet};
+use std::collections::{BTreeMap,HashMap,HashSet};
 
-use kube::{Api, Client, CustomResource, api::ListParams};
+use chrono::{DateTime, Utc};
+use kube::api::{ListParams, Meta};
+use kube::{Api, Client, CustomResource};
 use log::{debug, error, info};
+use opentelemetry::trace::{SpanKind, TraceContextExt};
+use opentelemetry::{Context, KeyValue};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use namespace::{list_namespaces, NamespaceScope};
+use selector::RuleSelector;
+
+// lease-based leader election
+pub mod election;
+// health and readiness reports
+pub mod health;
+// mutexes recovering from poisoning
+pub mod locks;
+// JSON log format, access and audit events
+pub mod logging;
+// namespaces observed for rules
+pub mod namespace;
+// rules belonging to deployment
+pub mod selector;
+// process shutdown signals
+pub mod shutdown;
+// tracing setup and span helpers
+pub mod telemetry;
+// tenant ID validation and normalization
+pub mod tenant;
+
 
 // Get rules and tenants from k8s.
 // Return a tuple containing
 // bool -- status whether successfully refreshed tenants, or there was an error
 // Vec<String> -- list of tenants ID to be ingested
 // Option<String> -- optional continue token value
+// Rules of all namespaces are listed when namespace is None.
+// Label and field selectors are applied by k8s, controller class is not checked here.
 pub async fn refresh_open_metrics_rules(k8s_client: Client,
                                         continue_token: Option<String>,
-                                        namespace:&String) -> (bool, Vec<OpenMetricsRule>, Option<String>) {
+                                        namespace: Option<&str>,
+                                        selector: &RuleSelector) -> (bool, Vec<OpenMetricsRule>, Option<String>) {
     // It is safe to do unwrap() since this function is called only when k8s client was inited
     let client = k8s_client.clone();
     // Call Kubernetes to check ingestion tenant resources.
-    let api : Api<OpenMetricsRule> = Api::namespaced(client, namespace);
+    let api : Api<OpenMetricsRule> = match namespace {
+        Some(namespace) => Api::namespaced(client, namespace),
+        None => Api::all(client),
+    };
     let lp = if continue_token.is_some() {
         // It is safe to do unwrap() since is_some() was checked
-        ListParams::default().continue_token(&continue_token.unwrap().clone())
+        selector.list_params().continue_token(&continue_token.unwrap().clone())
     } else {
-        ListParams::default()
+        selector.list_params()
     };
 
     let mut rules: Vec<OpenMetricsRule> = Vec::new();
@@ -39,7 +71,7 @@ pub async fn refresh_open_metrics_rules(k8s_client: Client,
             true
         },
         Err(_i) => {
-            error!("Failed to get k8s API response.");
+            error!("Failed to get k8s API response for namespace {}.", namespace.unwrap_or(namespace::ALL_NAMESPACES));
             false
         }
     };
@@ -48,18 +80,44 @@ pub async fn refresh_open_metrics_rules(k8s_client: Client,
 }
 
 
-// Retrieve ingestion tenants to be used for metrics discovery
-pub async fn discover_open_metrics_rules(k8s_client: Client, namespace: &String)
+// Retrieve rules of every namespace in scope, which match selector.
+// Fails when rules of any namespace can not be listed, so callers never act on partial state.
+pub async fn discover_open_metrics_rules(k8s_client: Client, scope: &NamespaceScope, selector: &RuleSelector)
+    -> Result<Vec<OpenMetricsRule>,String> {
+    if scope == &NamespaceScope::All {
+        // single cluster-wide list
+        return discover_namespace_rules(k8s_client, None, selector).await;
+    }
+    let mut found_rules = Vec::new();
+    for namespace in list_namespaces(k8s_client.clone(), scope).await? {
+        let rules = discover_namespace_rules(k8s_client.clone(), Some(&namespace), selector).await
+            .map_err(|e| format!("{} in namespace {}", e, namespace))?;
+        debug!("found {} rules in namespace {}", rules.len(), namespace);
+        found_rules.extend(rules);
+    }
+    Ok(found_rules)
+}
+
+// Retrieve rules of single namespace, or of all namespaces when namespace is None,
+// which match selector. Rules of other controller classes are left out.
+pub async fn discover_namespace_rules(k8s_client: Client, namespace: Option<&str>, selector: &RuleSelector)
     -> Result<Vec<OpenMetricsRule>,String> {
 
     let mut found_rules = Vec::new();
     let client = k8s_client.clone();
+    let cx = telemetry::start_span(
+        &Context::current(),
+        "k8s_list_rules",
+        SpanKind::Client,
+        vec![KeyValue::new("namespace", String::from(namespace.unwrap_or(namespace::ALL_NAMESPACES)))],
+    );
     // Call Kubernetes to check ingestion tenant resources.
 
     let (tenants_acquired, rules_portion, next_token) = refresh_open_metrics_rules(
             client.clone(),
             None,
-            &namespace.clone()).await;
+            namespace,
+            selector).await;
 
     for rule in rules_portion {
         found_rules.push(rule);
@@ -74,7 +132,7 @@ pub async fn discover_open_metrics_rules(k8s_client: Client, namespace: &String)
                     let token = t.clone();
                     let (_tenants_acquired_inner,
                         rules_portion, _t) = refresh_open_metrics_rules(
-                        client.clone(), Some(token), &namespace.clone()).await;
+                        client.clone(), Some(token), namespace, selector).await;
                     for rule in rules_portion {
                         found_rules.push(rule);
                     };
@@ -93,8 +151,15 @@ pub async fn discover_open_metrics_rules(k8s_client: Client, namespace: &String)
     }
 
     if tenants_acquired {
+        let listed = found_rules.len();
+        found_rules.retain(|rule| selector.matches(rule));
+        if found_rules.len() < listed {
+            debug!("skipped {} rules of other controller classes", listed - found_rules.len());
+        }
+        cx.span().set_attribute(KeyValue::new("rules", found_rules.len() as i64));
         Ok(found_rules)
     } else {
+        telemetry::record_error(&cx, "k8s communication failure");
         Err(String::from("k8s communication failure"))
     }
 
@@ -111,9 +176,57 @@ pub fn discover_tenant_ids(tenants_rules: Vec<OpenMetricsRule>) -> HashSet<Strin
     return tenant_ids;
 }
 
+// Extract external labels for each tenant from rules
+// Labels of rules processed later take precedence for the same tenant and label name
+pub fn discover_tenant_labels(tenants_rules: &Vec<OpenMetricsRule>) -> HashMap<String, HashMap<String, String>> {
+    let mut tenant_labels: HashMap<String, HashMap<String, String>> = HashMap::new();
+    for tenant_rule in tenants_rules.iter() {
+        if tenant_rule.spec.external_labels.is_empty() {
+            continue;
+        }
+        for tenant_id in tenant_rule.spec.tenants.iter() {
+            let labels = tenant_labels.entry(tenant_id.clone()).or_default();
+            for (name, value) in tenant_rule.spec.external_labels.iter() {
+                labels.insert(name.clone(), value.clone());
+            }
+        }
+    };
+    return tenant_labels;
+}
+
+// Retrieve tenant resources of every namespace in scope, which match selector.
+// Fails when resources of any namespace can not be listed, like discover_open_metrics_rules().
+pub async fn discover_open_metrics_tenants(k8s_client: Client, scope: &NamespaceScope, selector: &RuleSelector)
+    -> Result<Vec<OpenMetricsTenant>,String> {
+    let apis: Vec<(String, Api<OpenMetricsTenant>)> = match scope {
+        NamespaceScope::All => vec![(String::from(namespace::ALL_NAMESPACES), Api::all(k8s_client))],
+        _ => list_namespaces(k8s_client.clone(), scope).await?
+            .into_iter()
+            .map(|n| (n.clone(), Api::namespaced(k8s_client.clone(), &n)))
+            .collect(),
+    };
+    let mut found_tenants = Vec::new();
+    for (namespace, api) in apis.iter() {
+        let mut lp: ListParams = selector.list_params();
+        loop {
+            let list = api.list(&lp).await
+                .map_err(|e| format!("failed to list tenants in namespace {}: {}", namespace, e))?;
+            found_tenants.extend(list.items);
+            match list.metadata.continue_ {
+                Some(token) if !token.is_empty() => lp = lp.continue_token(&token),
+                _ => break,
+            }
+        }
+    }
+    // resources of other controller classes are left out
+    found_tenants.retain(|tenant| selector.matches_class(&tenant.spec.controller_class));
+    debug!("found {} tenant resources", found_tenants.len());
+    Ok(found_tenants)
+}
+
 //  Discover all tenant IDs necessary for ingestion
-pub async fn get_tenant_ids(k8s_client: Client, namespace: &String) -> Result<HashSet<String>,String> {
-    match discover_open_metrics_rules(k8s_client.clone(), namespace).await {
+pub async fn get_tenant_ids(k8s_client: Client, scope: &NamespaceScope, selector: &RuleSelector) -> Result<HashSet<String>,String> {
+    match discover_open_metrics_rules(k8s_client.clone(), scope, selector).await {
         Ok(tenants_rules) => {
             Ok(discover_tenant_ids(tenants_rules))
         },
@@ -136,13 +249,191 @@ pub struct OpenMetricsRuleSpec {
     // Rule groups list
 
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
-    pub groups: Vec<GroupSpec>
+    pub groups: Vec<GroupSpec>,
+    // Labels added by proxy to every series sent by rule tenants, optional
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub external_labels: HashMap<String, String>,
+    // Deployment meant to act on the rule, optional
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub controller_class: Option<String>
 }
 
 // A specification for a rule status
-#[derive(Serialize, Clone,PartialEq, Eq, Debug,  Deserialize, JsonSchema)]
+#[derive(Serialize, Clone,PartialEq, Eq, Debug, Default, Deserialize, JsonSchema)]
 pub struct OpenMetricsRuleStatus {
+    #[serde(default)]
     pub ruler_updated: bool,
+    // Resource generation last synced with ruler by informer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+    // Synced, Valid and Conflicting conditions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<RuleCondition>,
+    // Result of last sync with ruler, by tenant
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tenants: BTreeMap<String, TenantSyncStatus>,
+    // Last error returned by ruler for rule tenants
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_ruler_error: Option<String>,
+}
+
+// Rule condition types
+pub const CONDITION_SYNCED: &str = "Synced";
+pub const CONDITION_VALID: &str = "Valid";
+pub const CONDITION_CONFLICTING: &str = "Conflicting";
+
+// A specification for a rule condition, in the shape of k8s conditions
+#[derive(Serialize, Clone, PartialEq, Eq, Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RuleCondition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    // "True", "False" or "Unknown"
+    pub status: String,
+    pub reason: String,
+    #[serde(default)]
+    pub message: String,
+    pub last_transition_time: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+}
+
+// A specification for result of syncing single tenant of a rule
+#[derive(Serialize, Clone, PartialEq, Eq, Debug, Deserialize, JsonSchema)]
+pub struct TenantSyncStatus {
+    pub synced: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl OpenMetricsRuleStatus {
+    // Set condition, transition time is only changed when condition status changes.
+    pub fn set_condition(&mut self, type_: &str, status: bool, reason: &str, message: &str, generation: Option<i64>, now: DateTime<Utc>) {
+        let status = String::from(if status { "True" } else { "False" });
+        let last_transition_time = match self.condition(type_) {
+            Some(c) if c.status == status => c.last_transition_time,
+            _ => now,
+        };
+        let condition = RuleCondition {
+            type_: String::from(type_),
+            status,
+            reason: String::from(reason),
+            message: String::from(message),
+            last_transition_time,
+            observed_generation: generation,
+        };
+        match self.conditions.iter_mut().find(|c| c.type_ == type_) {
+            Some(c) => *c = condition,
+            None => self.conditions.push(condition),
+        }
+    }
+
+    pub fn condition(&self, type_: &str) -> Option<&RuleCondition> {
+        self.conditions.iter().find(|c| c.type_ == type_)
+    }
+}
+
+impl OpenMetricsRule {
+    // Spec changed since informer last synced resource with ruler.
+    pub fn is_generation_observed(&self) -> bool {
+        self.metadata.generation.is_some()
+            && self.metadata.generation == self.status.as_ref().and_then(|s| s.observed_generation)
+    }
+}
+
+// An OpenMetricsTenant CRD. Ingestion settings of a single tenant.
+#[derive(CustomResource, Deserialize, Serialize, Clone, PartialEq, Debug, JsonSchema)]
+#[kube(status = "OpenMetricsTenantStatus")]
+#[kube(group = "open-metrics.vgs.io", version = "v1", kind = "OpenMetricsTenant", namespaced)]
+pub struct OpenMetricsTenantSpec {
+    // A tenant identifier, resource name is used when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<String>,
+    // Series of suspended tenant are dropped by proxy
+    #[serde(default)]
+    pub suspended: bool,
+    // Ingestion limits, optional
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits: Option<TenantLimitsSpec>,
+    // Name of upstream from proxy configuration, default ingester when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+    // Labels added by proxy to every series of tenant, optional
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub external_labels: HashMap<String, String>,
+    // Tenants every series of tenant is replicated into, optional
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replicate_to: Vec<String>,
+    // Deployment meant to act on the resource, optional
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub controller_class: Option<String>
+}
+
+// A specification for tenant ingestion limits, enforced by every proxy replica on its own
+#[derive(Serialize, Clone, PartialEq, Debug, Deserialize, JsonSchema)]
+pub struct TenantLimitsSpec {
+    // Samples accepted per second
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub samples_per_second: Option<f64>,
+    // Samples accepted at once, twice samples_per_second when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub samples_burst: Option<u64>,
+    // Active series, new series above the limit are dropped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_series: Option<u64>,
+}
+
+// A specification for tenant status, ingestion statistics summed up over proxy replicas
+#[derive(Serialize, Clone, PartialEq, Debug, Default, Deserialize, JsonSchema)]
+pub struct OpenMetricsTenantStatus {
+    // Last time tenant series were accepted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_write_time: Option<DateTime<Utc>>,
+    // Samples accepted per second, since previous status update
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub samples_per_second: Option<f64>,
+    // Series written within idle timeout
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_series: Option<u64>,
+    // Last error of forwarding tenant series upstream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_upstream_error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_upstream_error_time: Option<DateTime<Utc>>,
+    // Elected replica which summed up the status
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reporter: Option<String>,
+    // Shares of replicas which recently accepted tenant series, by replica
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub replicas: BTreeMap<String, ReplicaUsage>,
+}
+
+// A specification for share of tenant ingestion seen by single proxy replica
+#[derive(Serialize, Clone, PartialEq, Debug, Default, Deserialize, JsonSchema)]
+pub struct ReplicaUsage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_write_time: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub samples_per_second: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_series: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_upstream_error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_upstream_error_time: Option<DateTime<Utc>>,
+    // Time replica reported its share, shares not reported again are dropped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub report_time: Option<DateTime<Utc>>,
+}
+
+impl OpenMetricsTenant {
+    // Tenant identifier, before normalization.
+    pub fn tenant_id(&self) -> String {
+        match &self.spec.tenant_id {
+            Some(tenant_id) => tenant_id.clone(),
+            None => Meta::name(self),
+        }
+    }
 }
 
 
@@ -183,7 +474,11 @@ mod tests {
     use tower_test::mock;
     use std::collections::HashSet;
     use std::iter::FromIterator;
-    use crate::{discover_open_metrics_rules, discover_tenant_ids, get_tenant_ids};
+    use std::collections::HashMap;
+    use crate::{discover_open_metrics_rules, discover_open_metrics_tenants, discover_tenant_ids, discover_tenant_labels, get_tenant_ids};
+    use crate::{OpenMetricsRule, OpenMetricsRuleSpec};
+    use crate::namespace::NamespaceScope;
+    use crate::selector::RuleSelector;
     use k8s_openapi::serde_json::Value;
 
     fn init() {
@@ -259,7 +554,7 @@ mod tests {
 
         // Verify discovery works for tenants without rules
         let discovered_rules = discover_open_metrics_rules(
-            Client::new(service), &String::from("test")).await.unwrap();
+            Client::new(service), &NamespaceScope::parse("test", ""), &RuleSelector::default()).await.unwrap();
 
         assert_eq!(discovered_rules.len(), 2);
 
@@ -302,7 +597,7 @@ mod tests {
         let service = Service::new(mock_service);
 
         // Verify tenant ID discovery works
-        let discovered_tenant_ids = get_tenant_ids(Client::new(service), &String::from("test")).await.unwrap();
+        let discovered_tenant_ids = get_tenant_ids(Client::new(service), &NamespaceScope::parse("test", ""), &RuleSelector::default()).await.unwrap();
 
         let expected_tenants = vec![
             String::from("tenant1"),
@@ -317,4 +612,232 @@ mod tests {
         spawned.await.unwrap();
     }
 
+    fn namespace_rules_fixture(namespace: &str, tenant_id: &str) -> Value {
+        serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "List",
+            "metadata": { "resourceVersion": "" },
+            "items": [
+                {
+                    "apiVersion": "open-metrics.vgs.io",
+                    "kind": "OpenMetricsRule",
+                    "metadata": { "name": "rule", "namespace": namespace },
+                    "spec": { "tenants": [tenant_id] }
+                }
+            ]
+        })
+    }
+
+    #[tokio::test]
+    async fn test_discover_open_metrics_rules_by_namespace_selector() {
+        init();
+        let (mock_service, handle) = mock::pair::<Request<Body>, Response<Body>>();
+        let spawned = tokio::spawn(async move {
+            pin_mut!(handle);
+            // namespaces are resolved first
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert_eq!(request.uri().to_string(), "/api/v1/namespaces?&labelSelector=open-metrics%3Denabled");
+            let namespaces = serde_json::json!({
+                "apiVersion": "v1",
+                "kind": "NamespaceList",
+                "metadata": { "resourceVersion": "" },
+                "items": [
+                    { "metadata": { "name": "team-b" } },
+                    { "metadata": { "name": "team-a" } }
+                ]
+            });
+            send.send_response(Response::builder().body(Body::from(namespaces.to_string())).unwrap());
+            for (namespace, tenant_id) in [("team-a", "tenant1"), ("team-b", "tenant2")].iter() {
+                let (request, send) = handle.next_request().await.expect("service not called");
+                assert_eq!(
+                    request.uri().to_string(),
+                    format!("/apis/open-metrics.vgs.io/v1/namespaces/{}/openmetricsrules?", namespace));
+                let rules = namespace_rules_fixture(namespace, tenant_id).to_string();
+                send.send_response(Response::builder().body(Body::from(rules)).unwrap());
+            }
+        });
+
+        let scope = NamespaceScope::parse("", "open-metrics=enabled");
+        let discovered_rules = discover_open_metrics_rules(
+            Client::new(Service::new(mock_service)), &scope, &RuleSelector::default()).await.unwrap();
+
+        // rules of both namespaces are aggregated, origin is kept
+        let namespaces: Vec<String> = discovered_rules.iter().map(|r| r.metadata.namespace.clone().unwrap()).collect();
+        assert_eq!(namespaces, vec![String::from("team-a"), String::from("team-b")]);
+        assert_eq!(discover_tenant_ids(discovered_rules).len(), 2);
+
+        spawned.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_discover_open_metrics_rules_fails_on_any_namespace() {
+        init();
+        let (mock_service, handle) = mock::pair::<Request<Body>, Response<Body>>();
+        let spawned = tokio::spawn(async move {
+            pin_mut!(handle);
+            let (_request, send) = handle.next_request().await.expect("service not called");
+            let rules = namespace_rules_fixture("team-a", "tenant1").to_string();
+            send.send_response(Response::builder().body(Body::from(rules)).unwrap());
+            let (_request, send) = handle.next_request().await.expect("service not called");
+            send.send_response(Response::builder().status(403).body(Body::from("{}")).unwrap());
+        });
+
+        let scope = NamespaceScope::parse("team-a,team-b", "");
+        let discovered_rules = discover_open_metrics_rules(Client::new(Service::new(mock_service)), &scope, &RuleSelector::default()).await;
+        assert!(discovered_rules.unwrap_err().contains("team-b"));
+
+        spawned.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_discover_open_metrics_rules_with_selector() {
+        init();
+        let (mock_service, handle) = mock::pair::<Request<Body>, Response<Body>>();
+        let spawned = tokio::spawn(async move {
+            pin_mut!(handle);
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert_eq!(
+                request.uri().to_string(),
+                "/apis/open-metrics.vgs.io/v1/namespaces/test/openmetricsrules?&fieldSelector=metadata.name%21%3Dskipped&labelSelector=cortex%3Dprod");
+            let mut l = test_fixture_1();
+            l["items"][0]["spec"]["controller_class"] = serde_json::json!("prod");
+            send.send_response(Response::builder().body(Body::from(l.to_string())).unwrap());
+        });
+
+        let selector = RuleSelector::new("cortex=prod", "metadata.name!=skipped", "prod");
+        let discovered_rules = discover_open_metrics_rules(
+            Client::new(Service::new(mock_service)), &NamespaceScope::parse("test", ""), &selector).await.unwrap();
+
+        // rule without controller class belongs to another deployment
+        assert_eq!(discovered_rules.len(), 1);
+        assert_eq!(discovered_rules[0].metadata.name, Some(String::from("test1")));
+
+        spawned.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_discover_open_metrics_tenants() {
+        init();
+        let (mock_service, handle) = mock::pair::<Request<Body>, Response<Body>>();
+        let spawned = tokio::spawn(async move {
+            pin_mut!(handle);
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert_eq!(request.uri().to_string(), "/apis/open-metrics.vgs.io/v1/namespaces/test/openmetricstenants?");
+            let page = serde_json::json!({
+                "apiVersion": "v1",
+                "kind": "List",
+                "metadata": { "resourceVersion": "", "continue": "page2" },
+                "items": [{
+                    "apiVersion": "open-metrics.vgs.io/v1",
+                    "kind": "OpenMetricsTenant",
+                    "metadata": { "name": "tenant1", "namespace": "test" },
+                    "spec": { "suspended": true, "limits": { "samples_per_second": 100.0, "max_series": 1000 } }
+                }]
+            });
+            send.send_response(Response::builder().body(Body::from(page.to_string())).unwrap());
+            // rest is listed with continue token
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert!(request.uri().to_string().contains("continue=page2"));
+            let page = serde_json::json!({
+                "apiVersion": "v1",
+                "kind": "List",
+                "metadata": { "resourceVersion": "" },
+                "items": [{
+                    "apiVersion": "open-metrics.vgs.io/v1",
+                    "kind": "OpenMetricsTenant",
+                    "metadata": { "name": "team-b", "namespace": "test" },
+                    "spec": { "tenant_id": "tenant2", "upstream": "dedicated", "replicate_to": ["audit"] }
+                }, {
+                    "apiVersion": "open-metrics.vgs.io/v1",
+                    "kind": "OpenMetricsTenant",
+                    "metadata": { "name": "staging", "namespace": "test" },
+                    "spec": { "controller_class": "staging" }
+                }]
+            });
+            send.send_response(Response::builder().body(Body::from(page.to_string())).unwrap());
+        });
+
+        let tenants = discover_open_metrics_tenants(
+            Client::new(Service::new(mock_service)), &NamespaceScope::parse("test", ""), &RuleSelector::default()).await.unwrap();
+
+        // resource of other controller class is left out
+        let tenant_ids: Vec<String> = tenants.iter().map(|t| t.tenant_id()).collect();
+        assert_eq!(tenant_ids, vec![String::from("tenant1"), String::from("tenant2")]);
+        assert!(tenants[0].spec.suspended);
+        assert_eq!(tenants[0].spec.limits.as_ref().unwrap().max_series, Some(1000));
+        assert_eq!(tenants[1].spec.upstream, Some(String::from("dedicated")));
+
+        spawned.await.unwrap();
+    }
+
+    #[test]
+    fn test_set_condition() {
+        use chrono::{Duration, TimeZone, Utc};
+        use crate::{OpenMetricsRuleStatus, CONDITION_SYNCED, CONDITION_VALID};
+
+        let mut status = OpenMetricsRuleStatus::default();
+        let then = Utc.ymd(2021, 5, 1).and_hms(10, 0, 0);
+        status.set_condition(CONDITION_SYNCED, false, "RulerError", "ruler responded with status 500", Some(1), then);
+        status.set_condition(CONDITION_VALID, true, "Valid", "", Some(1), then);
+        assert_eq!(status.conditions.len(), 2);
+
+        // transition time is kept while status is the same
+        let later = then + Duration::minutes(5);
+        status.set_condition(CONDITION_SYNCED, false, "RulerError", "ruler responded with status 502", Some(2), later);
+        let synced = status.condition(CONDITION_SYNCED).unwrap();
+        assert_eq!(synced.last_transition_time, then);
+        assert_eq!(synced.message, "ruler responded with status 502");
+        assert_eq!(synced.observed_generation, Some(2));
+        status.set_condition(CONDITION_SYNCED, true, "Synced", "", Some(2), later);
+        assert_eq!(status.condition(CONDITION_SYNCED).unwrap().last_transition_time, later);
+
+        let value = serde_json::to_value(&status.conditions[0]).unwrap();
+        assert_eq!(value["type"], "Synced");
+        assert_eq!(value["status"], "True");
+        assert!(value["lastTransitionTime"].is_string());
+    }
+
+    #[test]
+    fn test_discover_tenant_labels() {
+        let mut labels_1 = HashMap::new();
+        labels_1.insert(String::from("org"), String::from("vgs"));
+        labels_1.insert(String::from("env"), String::from("dev"));
+        let mut labels_2 = HashMap::new();
+        labels_2.insert(String::from("env"), String::from("prod"));
+
+        let rules = vec![
+            OpenMetricsRule::new("test1", OpenMetricsRuleSpec {
+                tenants: vec![String::from("tenant1"), String::from("tenant2")],
+                description: None,
+                groups: vec![],
+                external_labels: labels_1,
+                controller_class: None,
+            }),
+            OpenMetricsRule::new("test2", OpenMetricsRuleSpec {
+                tenants: vec![String::from("tenant2")],
+                description: None,
+                groups: vec![],
+                external_labels: labels_2,
+                controller_class: None,
+            }),
+            OpenMetricsRule::new("test3", OpenMetricsRuleSpec {
+                tenants: vec![String::from("tenant3")],
+                description: None,
+                groups: vec![],
+                external_labels: HashMap::new(),
+                controller_class: None,
+            }),
+        ];
+
+        let tenant_labels = discover_tenant_labels(&rules);
+
+        assert_eq!(tenant_labels.len(), 2);
+        assert_eq!(tenant_labels["tenant1"]["env"], "dev");
+        assert_eq!(tenant_labels["tenant1"]["org"], "vgs");
+        // labels of the later rule take precedence
+        assert_eq!(tenant_labels["tenant2"]["env"], "prod");
+        assert_eq!(tenant_labels["tenant2"]["org"], "vgs");
+        assert!(!tenant_labels.contains_key("tenant3"));
+    }
+
 }
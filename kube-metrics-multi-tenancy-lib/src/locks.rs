@@ -0,0 +1,41 @@
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+
+// Locks below recover from poisoning: state is only replaced as a whole while lock is held,
+// so a panic of another holder does not leave it half updated.
+
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Mutex, RwLock};
+
+    use crate::locks::{lock, read, write};
+
+    #[test]
+    fn test_poisoned_locks_recover() {
+        let mutex = Mutex::new(1);
+        let rw = RwLock::new(1);
+        let _ = std::panic::catch_unwind(|| {
+            let _m = mutex.lock().unwrap();
+            let _w = rw.write().unwrap();
+            panic!("poison");
+        });
+        assert!(mutex.is_poisoned() && rw.is_poisoned());
+
+        *lock(&mutex) += 1;
+        *write(&rw) += 1;
+        assert_eq!(*lock(&mutex), 2);
+        assert_eq!(*read(&rw), 2);
+    }
+}
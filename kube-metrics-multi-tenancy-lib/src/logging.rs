@@ -0,0 +1,249 @@
+use std::io::Write;
+use std::str::FromStr;
+
+use chrono::{SecondsFormat, Utc};
+use log::info;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+// Log target of per-request access lines
+pub const ACCESS_TARGET: &str = "access";
+// Log target of mutation audit events
+pub const AUDIT_TARGET: &str = "audit";
+
+// Format chosen on start, events are rendered accordingly
+static LOG_FORMAT: OnceCell<LogFormat> = OnceCell::new();
+
+// How log lines are written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    // env_logger default free-form text
+    Text,
+    // one JSON object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format: {}", s)),
+        }
+    }
+}
+
+// Initialize env_logger, RUST_LOG still controls levels.
+pub fn init_logging(component: &'static str, format: LogFormat) {
+    let _ = LOG_FORMAT.set(format);
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(move |buf, record| {
+            let target = record.target();
+            let message = record.args().to_string();
+            // access and audit events are already rendered as JSON objects
+            let line = if target == ACCESS_TARGET || target == AUDIT_TARGET {
+                message
+            } else {
+                json!({
+                    "timestamp": now(),
+                    "level": record.level().to_string().to_lowercase(),
+                    "component": component,
+                    "target": target,
+                    "message": message,
+                })
+                .to_string()
+            };
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+// Random identifier correlating log lines of single request or tick.
+pub fn new_request_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+// Structured log event with consistent field names across binaries.
+// Fields which are not known for the event are left out.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct LogEvent {
+    // proxy, tracker or updater
+    pub component: String,
+    // access or audit
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    // origin namespace of Kubernetes resource
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resource: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl LogEvent {
+    // Access line of request forwarded to upstream.
+    pub fn access(component: &str, request_id: &str) -> LogEvent {
+        LogEvent {
+            component: String::from(component),
+            kind: String::from(ACCESS_TARGET),
+            request_id: Some(String::from(request_id)),
+            ..LogEvent::default()
+        }
+    }
+
+    // Audit event of rule or resource mutation.
+    pub fn audit(component: &str, action: &str, request_id: &str) -> LogEvent {
+        LogEvent {
+            component: String::from(component),
+            kind: String::from(AUDIT_TARGET),
+            action: Some(String::from(action)),
+            request_id: Some(String::from(request_id)),
+            ..LogEvent::default()
+        }
+    }
+
+    pub fn tenant(mut self, tenant: &str) -> LogEvent {
+        self.tenant = Some(String::from(tenant));
+        self
+    }
+
+    pub fn namespace(mut self, namespace: &str) -> LogEvent {
+        self.namespace = Some(String::from(namespace));
+        self
+    }
+
+    pub fn resource(mut self, resource: &str) -> LogEvent {
+        self.resource = Some(String::from(resource));
+        self
+    }
+
+    pub fn upstream(mut self, upstream: &str) -> LogEvent {
+        self.upstream = Some(String::from(upstream));
+        self
+    }
+
+    pub fn status<S: ToString>(mut self, status: S) -> LogEvent {
+        self.status = Some(status.to_string());
+        self
+    }
+
+    pub fn duration_ms(mut self, duration_ms: u64) -> LogEvent {
+        self.duration_ms = Some(duration_ms);
+        self
+    }
+
+    pub fn error(mut self, error: &str) -> LogEvent {
+        self.error = Some(String::from(error));
+        self
+    }
+
+    // JSON object with timestamp, as written in JSON log format.
+    pub fn to_json(&self) -> Value {
+        let mut fields = Map::new();
+        fields.insert(String::from("timestamp"), json!(now()));
+        fields.insert(String::from("level"), json!("info"));
+        // safe to unwrap, since event is a plain struct
+        if let Value::Object(event) = serde_json::to_value(self).unwrap() {
+            fields.extend(event);
+        }
+        Value::Object(fields)
+    }
+
+    // key=value line, as written in text log format.
+    pub fn to_text(&self) -> String {
+        let duration_ms = self.duration_ms.map(|d| d.to_string());
+        let fields = vec![
+            ("component", Some(&self.component)),
+            ("kind", Some(&self.kind)),
+            ("action", self.action.as_ref()),
+            ("tenant", self.tenant.as_ref()),
+            ("namespace", self.namespace.as_ref()),
+            ("resource", self.resource.as_ref()),
+            ("upstream", self.upstream.as_ref()),
+            ("status", self.status.as_ref()),
+            ("duration_ms", duration_ms.as_ref()),
+            ("request_id", self.request_id.as_ref()),
+        ];
+        let mut line: Vec<String> = fields
+            .iter()
+            .filter_map(|(k, v)| v.map(|v| format!("{}={}", k, v)))
+            .collect();
+        // error message is free-form, so it is quoted
+        if let Some(e) = &self.error {
+            line.push(format!("error={:?}", e));
+        }
+        line.join(" ")
+    }
+
+    // Write event under access or audit log target.
+    pub fn emit(&self) {
+        let line = match LOG_FORMAT.get() {
+            Some(LogFormat::Json) => self.to_json().to_string(),
+            _ => self.to_text(),
+        };
+        if self.kind == AUDIT_TARGET {
+            info!(target: AUDIT_TARGET, "{}", line);
+        } else {
+            info!(target: ACCESS_TARGET, "{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logging::{LogEvent, LogFormat};
+
+    #[test]
+    fn test_log_event_formats() {
+        let event = LogEvent::access("proxy", "abc")
+            .tenant("tenant1")
+            .upstream("http://distributor/api/v1/push")
+            .status(200)
+            .duration_ms(12);
+
+        let line = event.to_json();
+        assert_eq!(line["component"], "proxy");
+        assert_eq!(line["kind"], "access");
+        assert_eq!(line["tenant"], "tenant1");
+        assert_eq!(line["status"], "200");
+        assert_eq!(line["duration_ms"], 12);
+        assert_eq!(line["request_id"], "abc");
+        assert_eq!(line["level"], "info");
+        assert!(line["timestamp"].is_string());
+        // unknown fields are left out
+        assert!(line.get("error").is_none());
+
+        assert_eq!(
+            LogEvent::audit("updater", "ruler_remove_group", "t1").tenant("tenant1").to_text(),
+            "component=updater kind=audit action=ruler_remove_group tenant=tenant1 request_id=t1"
+        );
+        assert_eq!(
+            LogEvent::audit("tracker", "k8s_delete_rule", "t2").namespace("team-a").resource("rule").to_text(),
+            "component=tracker kind=audit action=k8s_delete_rule namespace=team-a resource=rule request_id=t2"
+        );
+
+        assert_eq!("json".parse::<LogFormat>(), Ok(LogFormat::Json));
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+}
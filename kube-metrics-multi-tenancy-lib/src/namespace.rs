@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use k8s_openapi::api::core::v1::Namespace;
+use kube::api::{ListParams, Meta};
+use kube::{Api, Client};
+
+use crate::OpenMetricsRule;
+
+// Value of namespace variable selecting every namespace
+pub const ALL_NAMESPACES: &str = "*";
+
+// Namespace used when nothing is configured
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+// Namespaces observed for OpenMetricsRule resources
+#[derive(Clone, Debug, PartialEq)]
+pub enum NamespaceScope {
+    // listed namespaces
+    Namespaces(Vec<String>),
+    // every namespace in the cluster
+    All,
+    // namespaces matching label selector, resolved on every discovery
+    Selector(String),
+}
+
+impl NamespaceScope {
+    // Build scope from comma-separated namespaces (or "*") and optional label selector.
+    // Label selector takes precedence, empty namespace list means default namespace.
+    pub fn parse(namespaces: &str, selector: &str) -> NamespaceScope {
+        if !selector.trim().is_empty() {
+            return NamespaceScope::Selector(String::from(selector.trim()));
+        }
+        let namespaces: Vec<String> = namespaces
+            .split(',')
+            .map(|n| n.trim())
+            .filter(|n| !n.is_empty())
+            .map(String::from)
+            .collect();
+        if namespaces.iter().any(|n| n == ALL_NAMESPACES) {
+            NamespaceScope::All
+        } else if namespaces.is_empty() {
+            NamespaceScope::Namespaces(vec![String::from(DEFAULT_NAMESPACE)])
+        } else {
+            NamespaceScope::Namespaces(namespaces)
+        }
+    }
+
+    // Read scope from namespace and namespace selector environment variables.
+    pub fn from_env(namespace_var: &str, selector_var: &str) -> NamespaceScope {
+        NamespaceScope::parse(
+            &std::env::var(namespace_var).unwrap_or_default(),
+            &std::env::var(selector_var).unwrap_or_default(),
+        )
+    }
+}
+
+impl fmt::Display for NamespaceScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NamespaceScope::Namespaces(namespaces) => write!(f, "{}", namespaces.join(",")),
+            NamespaceScope::All => write!(f, "{}", ALL_NAMESPACES),
+            NamespaceScope::Selector(selector) => write!(f, "selector {}", selector),
+        }
+    }
+}
+
+// Resolve scope to namespace names, sorted.
+pub async fn list_namespaces(k8s_client: Client, scope: &NamespaceScope) -> Result<Vec<String>, String> {
+    let lp = match scope {
+        NamespaceScope::Namespaces(namespaces) => return Ok(namespaces.clone()),
+        NamespaceScope::All => ListParams::default(),
+        NamespaceScope::Selector(selector) => ListParams::default().labels(selector),
+    };
+    let api: Api<Namespace> = Api::all(k8s_client);
+    match api.list(&lp).await {
+        Ok(list) => {
+            let mut namespaces: Vec<String> = list.items.iter().map(Meta::name).collect();
+            namespaces.sort();
+            Ok(namespaces)
+        },
+        Err(e) => Err(format!("failed to list namespaces: {}", e)),
+    }
+}
+
+// Namespace resource was found in.
+pub fn rule_namespace(rule: &OpenMetricsRule) -> String {
+    Meta::namespace(rule).unwrap_or_else(|| String::from(DEFAULT_NAMESPACE))
+}
+
+// Number of rules per origin namespace.
+pub fn count_rules_by_namespace(rules: &[OpenMetricsRule]) -> BTreeMap<String, usize> {
+    let mut counts = BTreeMap::new();
+    for rule in rules.iter() {
+        *counts.entry(rule_namespace(rule)).or_insert(0) += 1;
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::namespace::{count_rules_by_namespace, NamespaceScope};
+    use crate::{OpenMetricsRule, OpenMetricsRuleSpec};
+
+    #[test]
+    fn test_parse_namespace_scope() {
+        assert_eq!(
+            NamespaceScope::parse("team-a, team-b,", ""),
+            NamespaceScope::Namespaces(vec![String::from("team-a"), String::from("team-b")])
+        );
+        assert_eq!(NamespaceScope::parse("", ""), NamespaceScope::Namespaces(vec![String::from("default")]));
+        assert_eq!(NamespaceScope::parse("*", ""), NamespaceScope::All);
+        assert_eq!(
+            NamespaceScope::parse("*", "open-metrics=enabled"),
+            NamespaceScope::Selector(String::from("open-metrics=enabled"))
+        );
+        assert_eq!(NamespaceScope::parse("a,b", "").to_string(), "a,b");
+    }
+
+    #[test]
+    fn test_count_rules_by_namespace() {
+        let rule = |name: &str, namespace: Option<&str>| {
+            let mut rule = OpenMetricsRule::new(name, OpenMetricsRuleSpec {
+                tenants: vec![],
+                description: None,
+                groups: vec![],
+                external_labels: HashMap::new(),
+                controller_class: None,
+            });
+            rule.metadata.namespace = namespace.map(String::from);
+            rule
+        };
+        let counts = count_rules_by_namespace(&[
+            rule("a", Some("team-a")),
+            rule("b", Some("team-a")),
+            rule("c", Some("team-b")),
+            rule("d", None),
+        ]);
+        assert_eq!(counts["team-a"], 2);
+        assert_eq!(counts["team-b"], 1);
+        assert_eq!(counts["default"], 1);
+    }
+}
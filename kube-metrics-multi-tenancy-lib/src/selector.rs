@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use kube::api::ListParams;
+
+use crate::OpenMetricsRule;
+
+// Which OpenMetricsRule and OpenMetricsTenant resources belong to this deployment
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RuleSelector {
+    // label selector passed to k8s when listing and watching rules
+    pub labels: Option<String>,
+    // field selector passed to k8s when listing and watching rules
+    pub fields: Option<String>,
+    // rules are matched by spec.controller_class,
+    // deployment without class only takes rules without class
+    pub controller_class: Option<String>,
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    match value.trim() {
+        "" => None,
+        v => Some(String::from(v)),
+    }
+}
+
+impl RuleSelector {
+    // Build selector from command line values, empty values are not applied.
+    pub fn new(labels: &str, fields: &str, controller_class: &str) -> RuleSelector {
+        RuleSelector {
+            labels: non_empty(labels),
+            fields: non_empty(fields),
+            controller_class: non_empty(controller_class),
+        }
+    }
+
+    // List parameters with label and field selectors applied.
+    pub fn list_params(&self) -> ListParams {
+        let mut lp = ListParams::default();
+        if let Some(labels) = &self.labels {
+            lp = lp.labels(labels);
+        }
+        if let Some(fields) = &self.fields {
+            lp = lp.fields(fields);
+        }
+        lp
+    }
+
+    // Check whether rule is meant for deployment controller class.
+    pub fn matches(&self, rule: &OpenMetricsRule) -> bool {
+        self.matches_class(&rule.spec.controller_class)
+    }
+
+    // Check whether resource controller class is the one of deployment.
+    pub fn matches_class(&self, controller_class: &Option<String>) -> bool {
+        controller_class == &self.controller_class
+    }
+
+    // Labels every matching resource carries, taken from equality terms of label selector.
+    // Resources created by informer get them, so they are found again by the same selector.
+    pub fn required_labels(&self) -> BTreeMap<String, String> {
+        let mut labels = BTreeMap::new();
+        for term in self.labels.iter().flat_map(|l| l.split(',')) {
+            if term.contains("!=") {
+                continue;
+            }
+            let mut parts = term.splitn(2, '=');
+            if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                labels.insert(
+                    String::from(name.trim()),
+                    String::from(value.trim_start_matches('=').trim()),
+                );
+            }
+        }
+        labels
+    }
+}
+
+impl fmt::Display for RuleSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(labels) = &self.labels {
+            parts.push(format!("labels {}", labels));
+        }
+        if let Some(fields) = &self.fields {
+            parts.push(format!("fields {}", fields));
+        }
+        match &self.controller_class {
+            Some(class) => parts.push(format!("controller class {}", class)),
+            None => parts.push(String::from("no controller class")),
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::selector::RuleSelector;
+    use crate::{OpenMetricsRule, OpenMetricsRuleSpec};
+
+    #[test]
+    fn test_rule_selector() {
+        let selector = RuleSelector::new("cortex=prod, team==a,env!=dev,tier in (1)", "", "prod");
+        assert_eq!(selector.fields, None);
+        let labels = selector.required_labels();
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels["cortex"], "prod");
+        assert_eq!(labels["team"], "a");
+        assert_eq!(RuleSelector::default().required_labels().len(), 0);
+
+        let rule = |class: Option<&str>| OpenMetricsRule::new("rule", OpenMetricsRuleSpec {
+            tenants: vec![],
+            description: None,
+            groups: vec![],
+            external_labels: HashMap::new(),
+            controller_class: class.map(String::from),
+        });
+        assert!(selector.matches(&rule(Some("prod"))));
+        assert!(!selector.matches(&rule(Some("staging"))));
+        assert!(!selector.matches(&rule(None)));
+        // deployment without class never takes rules of other deployments
+        assert!(RuleSelector::default().matches(&rule(None)));
+        assert!(!RuleSelector::default().matches(&rule(Some("prod"))));
+    }
+}
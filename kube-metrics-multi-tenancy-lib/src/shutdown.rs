@@ -0,0 +1,13 @@
+use tokio::signal::unix::{signal, SignalKind};
+
+
+// Wait for SIGTERM or SIGINT, return signal name.
+pub async fn shutdown_signal() -> &'static str {
+    // it is safe to unwrap, signal handlers are registered once on start
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+    let mut sigint = signal(SignalKind::interrupt()).unwrap();
+    tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigint.recv() => "SIGINT",
+    }
+}
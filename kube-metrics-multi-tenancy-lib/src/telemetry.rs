@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use log::{info, warn};
+use opentelemetry::global;
+use opentelemetry::sdk;
+use opentelemetry::sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::trace::{SpanKind, StatusCode, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue};
+use serde_json::{json, Map, Value};
+
+// Instrumentation library name spans are reported under
+const TRACER_NAME: &str = "open-metrics-multi-tenancy-kit";
+
+// Where finished spans are sent
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TracingExporter {
+    // spans are not recorded
+    Disabled,
+    // spans are sent to OpenTelemetry collector over gRPC
+    Otlp,
+    // spans are appended to local file, one JSON object per line
+    File,
+}
+
+impl FromStr for TracingExporter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(TracingExporter::Disabled),
+            "otlp" => Ok(TracingExporter::Otlp),
+            "file" => Ok(TracingExporter::File),
+            _ => Err(format!("unknown tracing exporter: {}", s)),
+        }
+    }
+}
+
+// Tracing settings, shared by proxy and informer
+#[derive(Clone, Debug)]
+pub struct TracingConfig {
+    pub exporter: TracingExporter,
+    // OTLP collector gRPC endpoint
+    pub otlp_endpoint: String,
+    // JSON file path for file exporter
+    pub file: String,
+    // share of traces to sample, from 0 to 1
+    pub sample_ratio: f64,
+}
+
+// Install global tracer provider and W3C trace context propagator.
+pub fn init_tracing(service_name: &'static str, config: &TracingConfig) -> Result<(), String> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let trace_config = sdk::trace::config()
+        .with_sampler(sdk::trace::Sampler::ParentBased(Box::new(
+            sdk::trace::Sampler::TraceIdRatioBased(config.sample_ratio.clamp(0.0, 1.0)),
+        )))
+        .with_resource(sdk::Resource::new(vec![KeyValue::new("service.name", service_name)]));
+
+    match config.exporter {
+        TracingExporter::Disabled => {
+            return Ok(());
+        },
+        TracingExporter::Otlp => {
+            opentelemetry_otlp::new_pipeline()
+                .with_endpoint(config.otlp_endpoint.clone())
+                .with_trace_config(trace_config)
+                .with_tonic()
+                .install_batch(opentelemetry::runtime::Tokio)
+                .map_err(|e| format!("failed to install OTLP exporter: {}", e))?;
+            info!("exporting spans to {}", config.otlp_endpoint);
+        },
+        TracingExporter::File => {
+            let exporter = JsonFileExporter::new(&config.file)?;
+            let provider = sdk::trace::TracerProvider::builder()
+                .with_default_batch_exporter(exporter, opentelemetry::runtime::Tokio)
+                .with_config(trace_config)
+                .build();
+            let _ = global::set_tracer_provider(provider);
+            info!("exporting spans to {}", config.file);
+        },
+    };
+    Ok(())
+}
+
+// Flush pending spans, and stop exporting.
+// Blocks until exporter is done, so it runs on blocking thread pool.
+pub async fn shutdown_tracing() {
+    if let Err(e) = tokio::task::spawn_blocking(global::shutdown_tracer_provider).await {
+        warn!("failed to flush spans: {}", e);
+    }
+}
+
+// Start span as a child of parent context, return context carrying new span.
+pub fn start_span(parent: &Context, name: &'static str, kind: SpanKind, attributes: Vec<KeyValue>) -> Context {
+    let tracer = global::tracer(TRACER_NAME);
+    let span = tracer
+        .span_builder(name)
+        .with_kind(kind)
+        .with_attributes(attributes)
+        .with_parent_context(parent.clone())
+        .start(&tracer);
+    parent.with_span(span)
+}
+
+// Record HTTP status of request span, server errors mark span as failed.
+pub fn record_status(cx: &Context, status: u16) {
+    let span = cx.span();
+    span.set_attribute(KeyValue::new("http.status_code", status as i64));
+    if status >= 500 {
+        span.set_status(StatusCode::Error, format!("upstream responded with {}", status));
+    }
+}
+
+// Mark span as failed.
+pub fn record_error(cx: &Context, message: &str) {
+    cx.span().set_status(StatusCode::Error, String::from(message));
+}
+
+// W3C trace context headers to propagate span to upstream.
+pub fn trace_headers(cx: &Context) -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(cx, &mut headers));
+    headers
+}
+
+// Span exporter writing JSON lines into local file, for offline debugging
+#[derive(Debug)]
+pub struct JsonFileExporter {
+    writer: BufWriter<File>,
+}
+
+impl JsonFileExporter {
+    pub fn new(path: &str) -> Result<JsonFileExporter, String> {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(f) => Ok(JsonFileExporter { writer: BufWriter::new(f) }),
+            Err(e) => Err(format!("failed to open {}: {}", path, e)),
+        }
+    }
+}
+
+#[async_trait]
+impl SpanExporter for JsonFileExporter {
+    async fn export(&mut self, batch: Vec<SpanData>) -> ExportResult {
+        for span in batch {
+            let line = span_to_json(&span).to_string();
+            if let Err(e) = writeln!(self.writer, "{}", line) {
+                warn!("failed to write span: {}", e);
+            }
+        }
+        if let Err(e) = self.writer.flush() {
+            warn!("failed to flush spans: {}", e);
+        }
+        Ok(())
+    }
+}
+
+fn unix_nanos(t: SystemTime) -> u128 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0)
+}
+
+fn span_to_json(span: &SpanData) -> Value {
+    let attributes: Map<String, Value> = span
+        .attributes
+        .iter()
+        .map(|(k, v)| (String::from(k.as_str()), attribute_to_json(v)))
+        .collect();
+    let resource: Map<String, Value> = span
+        .resource
+        .iter()
+        .map(|(k, v)| (String::from(k.as_str()), attribute_to_json(v)))
+        .collect();
+    json!({
+        "trace_id": span.span_context.trace_id().to_hex(),
+        "span_id": span.span_context.span_id().to_hex(),
+        "parent_span_id": span.parent_span_id.to_hex(),
+        "name": span.name,
+        "kind": format!("{:?}", span.span_kind).to_lowercase(),
+        "start_time_unix_nano": unix_nanos(span.start_time) as u64,
+        "end_time_unix_nano": unix_nanos(span.end_time) as u64,
+        "attributes": attributes,
+        "status_code": format!("{:?}", span.status_code).to_lowercase(),
+        "status_message": span.status_message,
+        "resource": resource,
+    })
+}
+
+fn attribute_to_json(value: &opentelemetry::Value) -> Value {
+    match value {
+        opentelemetry::Value::Bool(b) => json!(b),
+        opentelemetry::Value::I64(i) => json!(i),
+        opentelemetry::Value::F64(f) => json!(f),
+        other => json!(other.as_str()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use opentelemetry::global;
+    use opentelemetry::sdk;
+    use opentelemetry::sdk::propagation::TraceContextPropagator;
+    use opentelemetry::trace::{SpanKind, TraceContextExt};
+    use opentelemetry::{Context, KeyValue};
+
+    use crate::telemetry::{record_status, start_span, trace_headers, JsonFileExporter};
+
+    #[test]
+    fn test_json_file_exporter() {
+        let f = tempfile::NamedTempFile::new().unwrap();
+        let provider = sdk::trace::TracerProvider::builder()
+            .with_simple_exporter(JsonFileExporter::new(f.path().to_str().unwrap()).unwrap())
+            .build();
+        let _ = global::set_tracer_provider(provider);
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let parent = start_span(&Context::new(), "remote_write", SpanKind::Server, vec![]);
+        let child = start_span(&parent, "forward", SpanKind::Client, vec![KeyValue::new("tenant_id", "tenant1")]);
+
+        // child span context is propagated as traceparent header
+        let headers = trace_headers(&child);
+        let trace_id = parent.span().span_context().trace_id().to_hex();
+        assert!(headers["traceparent"].contains(&trace_id));
+
+        record_status(&child, 502);
+        child.span().end();
+        parent.span().end();
+
+        let mut content = String::new();
+        f.reopen().unwrap().read_to_string(&mut content).unwrap();
+        let spans: Vec<serde_json::Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0]["name"], "forward");
+        assert_eq!(spans[0]["attributes"]["tenant_id"], "tenant1");
+        assert_eq!(spans[0]["attributes"]["http.status_code"], 502);
+        assert_eq!(spans[0]["status_code"], "error");
+        assert_eq!(spans[0]["parent_span_id"], spans[1]["span_id"]);
+        assert_eq!(spans[1]["trace_id"], serde_json::json!(trace_id));
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt;
+
+use once_cell::sync::OnceCell;
+
+// Cortex refuses longer tenant IDs
+pub const MAX_TENANT_ID_LENGTH: usize = 150;
+
+// Special characters Cortex allows in tenant ID, besides ASCII letters and digits
+const ALLOWED_SPECIAL_CHARACTERS: &str = "!-_.*'()";
+
+// Normalization applied by both proxy and informer, set once on start
+static NORMALIZER: OnceCell<TenantIdNormalizer> = OnceCell::new();
+
+// Why tenant ID was rejected
+#[derive(Clone, Debug, PartialEq)]
+pub enum TenantIdError {
+    Empty,
+    TooLong(usize),
+    // "." and ".." are unsafe as path segments
+    Reserved(String),
+    InvalidCharacter(char),
+}
+
+impl TenantIdError {
+    // Short reason, used as metrics label value.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            TenantIdError::Empty => "empty",
+            TenantIdError::TooLong(_) => "too_long",
+            TenantIdError::Reserved(_) => "reserved",
+            TenantIdError::InvalidCharacter(_) => "invalid_character",
+        }
+    }
+}
+
+impl fmt::Display for TenantIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TenantIdError::Empty => write!(f, "tenant ID is empty"),
+            TenantIdError::TooLong(len) => write!(
+                f, "tenant ID is too long: {} characters, max {}", len, MAX_TENANT_ID_LENGTH),
+            TenantIdError::Reserved(id) => write!(f, "tenant ID '{}' is not allowed", id),
+            TenantIdError::InvalidCharacter(c) => write!(f, "tenant ID has unsupported character '{}'", c),
+        }
+    }
+}
+
+fn is_allowed_character(c: char) -> bool {
+    c.is_ascii_alphanumeric() || ALLOWED_SPECIAL_CHARACTERS.contains(c)
+}
+
+// Check tenant ID with the same rules Cortex applies to X-Scope-OrgID.
+pub fn validate_tenant_id(tenant_id: &str) -> Result<(), TenantIdError> {
+    if tenant_id.is_empty() {
+        return Err(TenantIdError::Empty);
+    }
+    if let Some(c) = tenant_id.chars().find(|c| !is_allowed_character(*c)) {
+        return Err(TenantIdError::InvalidCharacter(c));
+    }
+    if tenant_id.len() > MAX_TENANT_ID_LENGTH {
+        return Err(TenantIdError::TooLong(tenant_id.len()));
+    }
+    if tenant_id == "." || tenant_id == ".." {
+        return Err(TenantIdError::Reserved(String::from(tenant_id)));
+    }
+    Ok(())
+}
+
+// Optional rewriting of tenant IDs before validation
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TenantIdNormalizer {
+    // lowercase ASCII letters
+    pub lowercase: bool,
+    // replace unsupported characters with this one, instead of rejecting tenant ID
+    pub replacement: Option<char>,
+}
+
+impl TenantIdNormalizer {
+    pub fn new(lowercase: bool, replacement: &str) -> Result<TenantIdNormalizer, String> {
+        let mut chars = replacement.chars();
+        let replacement = match (chars.next(), chars.next()) {
+            (None, _) => None,
+            (Some(c), None) if is_allowed_character(c) => Some(c),
+            _ => return Err(format!(
+                "tenant ID replacement should be single letter, digit or one of {}", ALLOWED_SPECIAL_CHARACTERS)),
+        };
+        Ok(TenantIdNormalizer { lowercase, replacement })
+    }
+
+    // Normalize and validate tenant ID, return ID to use upstream.
+    pub fn normalize(&self, tenant_id: &str) -> Result<String, TenantIdError> {
+        let mut normalized: String = match self.replacement {
+            Some(r) => tenant_id
+                .chars()
+                .map(|c| if is_allowed_character(c) { c } else { r })
+                .collect(),
+            None => String::from(tenant_id),
+        };
+        if self.lowercase {
+            normalized = normalized.to_ascii_lowercase();
+        }
+        validate_tenant_id(&normalized)?;
+        Ok(normalized)
+    }
+}
+
+// Set process-wide normalization, only the first call has effect.
+pub fn init_normalizer(normalizer: TenantIdNormalizer) {
+    let _ = NORMALIZER.set(normalizer);
+}
+
+// Normalize and validate tenant ID with process-wide normalization,
+// tenant IDs are only validated unless init_normalizer() was called.
+pub fn normalize_tenant_id(tenant_id: &str) -> Result<String, TenantIdError> {
+    match NORMALIZER.get() {
+        Some(n) => n.normalize(tenant_id),
+        None => validate_tenant_id(tenant_id).map(|_| String::from(tenant_id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tenant::{validate_tenant_id, TenantIdError, TenantIdNormalizer};
+
+    #[test]
+    fn test_validate_tenant_id() {
+        assert_eq!(validate_tenant_id("tenant-1_A.b*c!'(d)"), Ok(()));
+        assert_eq!(validate_tenant_id(""), Err(TenantIdError::Empty));
+        assert_eq!(validate_tenant_id(".."), Err(TenantIdError::Reserved(String::from(".."))));
+        assert_eq!(validate_tenant_id("."), Err(TenantIdError::Reserved(String::from("."))));
+        assert_eq!(validate_tenant_id("..."), Ok(()));
+        assert_eq!(validate_tenant_id("../"), Err(TenantIdError::InvalidCharacter('/')));
+        assert_eq!(validate_tenant_id("a|b"), Err(TenantIdError::InvalidCharacter('|')));
+        assert_eq!(validate_tenant_id(&"a".repeat(150)), Ok(()));
+        assert_eq!(validate_tenant_id(&"a".repeat(200)), Err(TenantIdError::TooLong(200)));
+        assert_eq!(TenantIdError::TooLong(200).reason(), "too_long");
+    }
+
+    #[test]
+    fn test_normalize_tenant_id() {
+        let normalizer = TenantIdNormalizer::new(true, "_").unwrap();
+        assert_eq!(normalizer.normalize("Team A/Prod"), Ok(String::from("team_a_prod")));
+        // replacement does not make reserved names valid
+        assert_eq!(normalizer.normalize(".."), Err(TenantIdError::Reserved(String::from(".."))));
+
+        let normalizer = TenantIdNormalizer::default();
+        assert_eq!(normalizer.normalize("Tenant1"), Ok(String::from("Tenant1")));
+        assert_eq!(normalizer.normalize("tenant 1"), Err(TenantIdError::InvalidCharacter(' ')));
+
+        assert!(TenantIdNormalizer::new(false, "/").is_err());
+        assert!(TenantIdNormalizer::new(false, "__").is_err());
+        assert_eq!(TenantIdNormalizer::new(false, "").unwrap(), TenantIdNormalizer::default());
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+
+use bytes::Bytes;
+use log::{info, warn};
+use serde::Serialize;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use crate::config::config;
+use crate::controller::controller::{controller_iteration, SyncStatus, TenantStatus, CONTROLLER};
+use crate::explain::explain::{explain_time_serie, time_serie_from_labels, SeriesExplanation};
+use crate::forward::forward::decode_write_request;
+use crate::labels::labels::TenantLabels;
+
+
+// Rejection for admin requests without valid token
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+// Effective configuration report
+#[derive(Serialize)]
+struct EffectiveConfig {
+    config: config::ProxyConfig,
+    // labels from configuration merged with ones found in k8s
+    external_labels: TenantLabels,
+}
+
+// Admin API routes, served under /admin prefix:
+//
+// GET  /admin/tenants  -- allowed tenants and their source
+// GET  /admin/config   -- effective configuration
+// GET  /admin/sync     -- last k8s sync time and error
+// POST /admin/sync     -- force k8s sync
+// POST /admin/explain  -- explain routing of remote write payload, or JSON list of label sets
+//
+// var:token is expected as bearer token in Authorization header, empty token disables check
+pub fn routes(token: String) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let tenants = warp::path!("tenants")
+        .and(warp::get())
+        .and_then(list_tenants);
+
+    let effective_config = warp::path!("config")
+        .and(warp::get())
+        .and_then(show_config);
+
+    let sync_status = warp::path!("sync")
+        .and(warp::get())
+        .and_then(show_sync_status);
+
+    let force_sync = warp::path!("sync")
+        .and(warp::post())
+        .and_then(force_sync);
+
+    let explain = warp::path!("explain")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(config::current().limits.content_length_limit))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::bytes())
+        .and_then(explain);
+
+    warp::path("admin")
+        .and(authorized(token))
+        .and(tenants.or(effective_config).or(sync_status).or(force_sync).or(explain))
+        .recover(handle_unauthorized)
+}
+
+// Check bearer token, if configured.
+fn authorized(token: String) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let expected = token.clone();
+            async move {
+                if expected.is_empty() {
+                    return Ok(());
+                }
+                let provided = header
+                    .as_deref()
+                    .and_then(|h| h.strip_prefix("Bearer "))
+                    .unwrap_or("");
+                if constant_time_eq(provided.as_bytes(), expected.as_bytes()) {
+                    Ok(())
+                } else {
+                    warn!("admin request rejected: invalid token");
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+// Compare tokens without leaking matching prefix length via timing.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Turn token rejection into 401, and let others fall through to next routes.
+async fn handle_unauthorized(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        Ok(warp::reply::with_status(
+            String::from("unauthorized"),
+            StatusCode::UNAUTHORIZED,
+        ))
+    } else {
+        Err(rejection)
+    }
+}
+
+async fn list_tenants() -> Result<impl Reply, Infallible> {
+    let tenants: Vec<TenantStatus> = CONTROLLER.read().await.get_tenant_statuses();
+    Ok(warp::reply::json(&tenants))
+}
+
+async fn show_config() -> Result<impl Reply, Infallible> {
+    let c = CONTROLLER.read().await;
+    let report = EffectiveConfig {
+        config: config::current().redacted(),
+        external_labels: c.get_external_labels().clone(),
+    };
+    drop(c);
+    Ok(warp::reply::json(&report))
+}
+
+async fn show_sync_status() -> Result<impl Reply, Infallible> {
+    let status: SyncStatus = CONTROLLER.read().await.sync_status();
+    Ok(warp::reply::json(&status))
+}
+
+async fn force_sync() -> Result<impl Reply, Infallible> {
+    if !CONTROLLER.read().await.sync_status().enabled {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&String::from("kubernetes controller disabled")),
+            StatusCode::CONFLICT,
+        ));
+    }
+    info!("forced kubernetes sync requested");
+    controller_iteration().await;
+
+    let status = CONTROLLER.read().await.sync_status();
+    let code = if status.last_error.is_some() {
+        StatusCode::BAD_GATEWAY
+    } else {
+        StatusCode::OK
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&status), code))
+}
+
+// Explain routing of every series in payload, without forwarding it.
+async fn explain(content_type: Option<String>, body: Bytes) -> Result<impl Reply, Infallible> {
+    let is_json = content_type
+        .map(|c| c.starts_with("application/json"))
+        .unwrap_or(false);
+
+    let time_series = if is_json {
+        match serde_json::from_slice::<Vec<BTreeMap<String, String>>>(&body) {
+            Ok(label_sets) => Ok(label_sets.iter().map(time_serie_from_labels).collect::<Vec<_>>()),
+            Err(e) => Err(e.to_string()),
+        }
+    } else {
+        decode_write_request(&body).map(|req| req.timeseries.into_vec())
+    };
+    let time_series = match time_series {
+        Ok(ts) => ts,
+        Err(e) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&e),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+
+    // same tenants and configuration as forwarding would use
+    let c = CONTROLLER.read().await;
+    let snapshot = c.snapshot().load();
+    let allowed: Vec<String> = c.get_tenants().iter().filter(|t| !snapshot.is_suspended(t)).cloned().collect();
+    drop(c);
+    let config = config::current();
+    let replicate_to = config.replicate_to();
+    let explanations: Vec<SeriesExplanation> = time_series
+        .iter()
+        .map(|ts| {
+            let mut explanation = explain_time_serie(
+                ts,
+                &config.tenant_labels,
+                &allowed,
+                config.allow_list.enabled,
+                &replicate_to,
+                &snapshot.replicate_to,
+            );
+            // series of suspended tenants are dropped
+            explanation.tenants.retain(|t| !snapshot.is_suspended(&t.tenant_id));
+            explanation
+        })
+        .collect();
+    Ok(warp::reply::with_status(warp::reply::json(&explanations), StatusCode::OK))
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+    use warp::http::StatusCode;
+
+    use crate::admin::admin::routes;
+    use crate::controller::controller::CONTROLLER;
+
+    #[tokio::test]
+    #[serial]
+    async fn test_admin_token() {
+        let api = routes(String::from("s3cret"));
+
+        let resp = warp::test::request().path("/admin/tenants").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = warp::test::request()
+            .path("/admin/tenants")
+            .header("Authorization", "Bearer wrong")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = warp::test::request()
+            .path("/admin/tenants")
+            .header("Authorization", "Bearer s3cret")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // other paths are not handled by admin routes
+        let resp = warp::test::request().path("/metrics").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_admin_tenants_and_sync() {
+        let api = routes(String::new());
+        CONTROLLER.write().await.set_initial_allowed_tenants(vec![String::from("0")]);
+
+        let resp = warp::test::request().path("/admin/tenants").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let tenants: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(tenants, serde_json::json!([{"tenant_id": "0", "source": "cli"}]));
+
+        let resp = warp::test::request().path("/admin/sync").reply(&api).await;
+        let status: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(status["enabled"], serde_json::json!(false));
+        assert!(status["last_success_time"].is_null());
+
+        // forced sync is refused when there is no k8s controller
+        let resp = warp::test::request().method("POST").path("/admin/sync").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let resp = warp::test::request().path("/admin/config").reply(&api).await;
+        let config: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(config["config"]["tenant_labels"], serde_json::json!(["tenant_id"]));
+
+        CONTROLLER.write().await.clean();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_admin_explain() {
+        let api = routes(String::new());
+        CONTROLLER.write().await.set_initial_allowed_tenants(vec![String::from("0"), String::from("tenant1")]);
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/admin/explain")
+            .header("content-type", "application/json")
+            .body(r#"[{"__name__": "up", "tenant_id": "tenant1"}, {"__name__": "up", "tenant_id": "tenant9"}]"#)
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let explanations: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(explanations[0]["tenants"], serde_json::json!([
+            {"tenant_id": "0", "matched_label": null, "replicated": true},
+            {"tenant_id": "tenant1", "matched_label": "tenant_id", "replicated": false},
+        ]));
+        assert_eq!(explanations[1]["matched_labels"][0]["allowed"], serde_json::json!(false));
+
+        // payload which is not snappy-compressed protobuf
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/admin/explain")
+            .body("garbage")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        CONTROLLER.write().await.clean();
+    }
+}
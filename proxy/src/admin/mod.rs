@@ -0,0 +1 @@
+pub mod admin;
\ No newline at end of file
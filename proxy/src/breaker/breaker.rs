@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use kube_metrics_mutli_tenancy_lib::locks;
+use log::{debug, info, warn};
+use prometheus::IntGaugeVec;
+use serde::Serialize;
+
+
+// Circuit breaker state, exposed as gauge value
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    // requests flow to upstream
+    Closed = 0,
+    // a limited number of probe requests flow to upstream
+    HalfOpen = 1,
+    // requests are rejected without calling upstream
+    Open = 2,
+}
+
+// Single upstream breaker
+struct CircuitBreaker {
+    state: BreakerState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    probes_in_flight: u32,
+    // changed on every state transition, results of requests allowed in other epoch are ignored
+    epoch: u64,
+}
+
+impl CircuitBreaker {
+    fn new() -> CircuitBreaker {
+        CircuitBreaker {
+            state: BreakerState::Closed,
+            consecutive_failures: 0,
+            opened_at: None,
+            probes_in_flight: 0,
+            epoch: 0,
+        }
+    }
+
+    fn transition(&mut self, state: BreakerState) {
+        self.state = state;
+        self.epoch += 1;
+    }
+}
+
+type Breakers = Arc<Mutex<HashMap<String, CircuitBreaker>>>;
+
+// Request allowed to upstream, tells breaker state which admitted it.
+// Probe slot of permit dropped without record() call is released, so breaker never waits for it forever.
+pub struct BreakerPermit {
+    breakers: Option<Breakers>,
+    upstream: String,
+    epoch: u64,
+    // request is half-open probe
+    probe: bool,
+}
+
+impl Drop for BreakerPermit {
+    fn drop(&mut self) {
+        let breakers = match (&self.breakers, self.probe) {
+            (Some(breakers), true) => breakers,
+            _ => return,
+        };
+        let mut breakers = locks::lock(breakers);
+        if let Some(breaker) = breakers.get_mut(&self.upstream) {
+            if breaker.epoch == self.epoch && breaker.probes_in_flight > 0 {
+                debug!("releasing unused probe of {}", self.upstream);
+                breaker.probes_in_flight -= 1;
+            }
+        }
+    }
+}
+
+// Breaker state report, served by status endpoint
+#[derive(Clone, Debug, Serialize)]
+pub struct BreakerStatus {
+    pub upstream: String,
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    pub open_for_ms: Option<u128>,
+}
+
+// Circuit breakers for every upstream proxy talks to.
+// Breaker opens after number of consecutive failures, rejects requests for a while,
+// then lets probe requests through (half-open) to decide whether to close or open again.
+pub struct CircuitBreakers {
+    failure_threshold: u32,
+    open_duration: Duration,
+    half_open_max_probes: u32,
+    breakers: Breakers,
+    state_gauge: IntGaugeVec,
+}
+
+impl CircuitBreakers {
+    // var:failure_threshold number of consecutive failures to open breaker, zero disables breakers
+    // var:state_gauge exposes breaker state by upstream
+    pub fn new(
+        failure_threshold: u32,
+        open_duration: Duration,
+        half_open_max_probes: u32,
+        state_gauge: IntGaugeVec,
+    ) -> CircuitBreakers {
+        CircuitBreakers {
+            failure_threshold,
+            open_duration,
+            half_open_max_probes: half_open_max_probes.max(1),
+            breakers: Arc::new(Mutex::new(HashMap::new())),
+            state_gauge,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.failure_threshold > 0
+    }
+
+    // Decide whether request to upstream is allowed.
+    // Outcome of allowed request is passed to record() with returned permit.
+    pub fn allow(&self, upstream: &str) -> Option<BreakerPermit> {
+        let permit = |epoch: u64, probe: bool| BreakerPermit {
+            breakers: Some(self.breakers.clone()),
+            upstream: String::from(upstream),
+            epoch,
+            probe,
+        };
+        if !self.is_enabled() {
+            return Some(permit(0, false));
+        }
+        let mut breakers = locks::lock(&self.breakers);
+        let breaker = breakers
+            .entry(String::from(upstream))
+            .or_insert_with(CircuitBreaker::new);
+
+        match breaker.state {
+            BreakerState::Closed => Some(permit(breaker.epoch, false)),
+            BreakerState::Open => {
+                let cooled_down = breaker
+                    .opened_at
+                    .map(|t| t.elapsed() >= self.open_duration)
+                    .unwrap_or(true);
+                if cooled_down {
+                    info!("circuit breaker for {} is half-open, probing", upstream);
+                    breaker.transition(BreakerState::HalfOpen);
+                    breaker.probes_in_flight = 1;
+                    self.expose(upstream, breaker.state);
+                    Some(permit(breaker.epoch, true))
+                } else {
+                    None
+                }
+            },
+            BreakerState::HalfOpen => {
+                if breaker.probes_in_flight < self.half_open_max_probes {
+                    breaker.probes_in_flight += 1;
+                    Some(permit(breaker.epoch, true))
+                } else {
+                    None
+                }
+            },
+        }
+    }
+
+    // Record outcome of request to upstream.
+    // Requests allowed before the last state transition are late, and their outcome is ignored,
+    // so neither they skip cool down, nor they free half-open probe slots.
+    pub fn record(&self, mut permit: BreakerPermit, success: bool) {
+        // slot is released here, not when permit is dropped
+        permit.breakers = None;
+        if !self.is_enabled() {
+            return;
+        }
+        let upstream = permit.upstream.as_str();
+        let mut breakers = locks::lock(&self.breakers);
+        let breaker = breakers
+            .entry(String::from(upstream))
+            .or_insert_with(CircuitBreaker::new);
+
+        if permit.epoch != breaker.epoch {
+            debug!("ignoring late outcome of request to {}", upstream);
+            return;
+        }
+        if permit.probe && breaker.probes_in_flight > 0 {
+            breaker.probes_in_flight -= 1;
+        }
+
+        if success {
+            if breaker.state != BreakerState::Closed {
+                info!("circuit breaker for {} is closed", upstream);
+                breaker.transition(BreakerState::Closed);
+            }
+            breaker.consecutive_failures = 0;
+            breaker.opened_at = None;
+        } else {
+            breaker.consecutive_failures += 1;
+            let should_open = match breaker.state {
+                BreakerState::Closed => breaker.consecutive_failures >= self.failure_threshold,
+                BreakerState::HalfOpen => true,
+                // keep initial opening time while open
+                BreakerState::Open => false,
+            };
+            if should_open {
+                warn!(
+                    "circuit breaker for {} is open after {} consecutive failures",
+                    upstream, breaker.consecutive_failures
+                );
+                breaker.transition(BreakerState::Open);
+                breaker.opened_at = Some(Instant::now());
+                breaker.probes_in_flight = 0;
+            }
+        }
+        self.expose(upstream, breaker.state);
+    }
+
+    // Report state of every known upstream breaker.
+    pub fn status(&self) -> Vec<BreakerStatus> {
+        let breakers = locks::lock(&self.breakers);
+        let mut status: Vec<BreakerStatus> = breakers
+            .iter()
+            .map(|(upstream, breaker)| BreakerStatus {
+                upstream: upstream.clone(),
+                state: breaker.state,
+                consecutive_failures: breaker.consecutive_failures,
+                open_for_ms: match breaker.state {
+                    BreakerState::Open => breaker.opened_at.map(|t| t.elapsed().as_millis()),
+                    _ => None,
+                },
+            })
+            .collect();
+        status.sort_by(|a, b| a.upstream.cmp(&b.upstream));
+        status
+    }
+
+    fn expose(&self, upstream: &str, state: BreakerState) {
+        self.state_gauge
+            .with_label_values(&[upstream])
+            .set(state as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use prometheus::{IntGaugeVec, Opts};
+
+    use crate::breaker::breaker::{BreakerState, CircuitBreakers};
+
+    const UPSTREAM: &str = "http://127.0.0.1:5000";
+
+    fn breakers(failure_threshold: u32) -> CircuitBreakers {
+        CircuitBreakers::new(
+            failure_threshold,
+            Duration::from_millis(100),
+            1,
+            IntGaugeVec::new(Opts::new("breaker_state", "help"), &["upstream"]).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_breaker_disabled() {
+        let b = breakers(0);
+        for _ in 0..10 {
+            let permit = b.allow(UPSTREAM).unwrap();
+            b.record(permit, false);
+        }
+        assert!(b.status().is_empty());
+    }
+
+    #[test]
+    fn test_breaker_opens_and_closes() {
+        let b = breakers(3);
+
+        // failures below threshold keep breaker closed
+        for _ in 0..2 {
+            let permit = b.allow(UPSTREAM).unwrap();
+            b.record(permit, false);
+        }
+        assert_eq!(b.status()[0].state, BreakerState::Closed);
+
+        let permit = b.allow(UPSTREAM).unwrap();
+        b.record(permit, false);
+        assert_eq!(b.status()[0].state, BreakerState::Open);
+        assert_eq!(b.state_gauge.with_label_values(&[UPSTREAM]).get(), 2);
+        assert!(b.allow(UPSTREAM).is_none());
+
+        // after cool down, a single probe is let through
+        sleep(Duration::from_millis(150));
+        let probe = b.allow(UPSTREAM).unwrap();
+        assert_eq!(b.status()[0].state, BreakerState::HalfOpen);
+        assert!(b.allow(UPSTREAM).is_none());
+
+        // successful probe closes breaker
+        b.record(probe, true);
+        assert_eq!(b.status()[0].state, BreakerState::Closed);
+        assert_eq!(b.state_gauge.with_label_values(&[UPSTREAM]).get(), 0);
+        assert!(b.allow(UPSTREAM).is_some());
+    }
+
+    #[test]
+    fn test_breaker_failed_probe_reopens() {
+        let b = breakers(1);
+
+        let permit = b.allow(UPSTREAM).unwrap();
+        b.record(permit, false);
+        assert!(b.allow(UPSTREAM).is_none());
+
+        sleep(Duration::from_millis(150));
+        let probe = b.allow(UPSTREAM).unwrap();
+        b.record(probe, false);
+
+        assert_eq!(b.status()[0].state, BreakerState::Open);
+        assert!(b.allow(UPSTREAM).is_none());
+    }
+
+    #[test]
+    fn test_breaker_ignores_late_results() {
+        let b = breakers(1);
+
+        // all requests are sent while closed, the first failure opens breaker
+        let first = b.allow(UPSTREAM).unwrap();
+        let late = b.allow(UPSTREAM).unwrap();
+        let late_failure = b.allow(UPSTREAM).unwrap();
+        b.record(first, false);
+        assert_eq!(b.status()[0].state, BreakerState::Open);
+
+        // late success does not skip cool down
+        b.record(late, true);
+        assert_eq!(b.status()[0].state, BreakerState::Open);
+        assert!(b.allow(UPSTREAM).is_none());
+
+        // late result does not free probe slot
+        sleep(Duration::from_millis(150));
+        let probe = b.allow(UPSTREAM).unwrap();
+        b.record(late_failure, false);
+        assert_eq!(b.status()[0].state, BreakerState::HalfOpen);
+        assert!(b.allow(UPSTREAM).is_none());
+
+        b.record(probe, true);
+        assert_eq!(b.status()[0].state, BreakerState::Closed);
+    }
+
+    #[test]
+    fn test_breaker_unused_probe_is_released() {
+        let b = breakers(1);
+        let permit = b.allow(UPSTREAM).unwrap();
+        b.record(permit, false);
+        sleep(Duration::from_millis(150));
+
+        // probe is allowed, but no request is sent
+        let probe = b.allow(UPSTREAM).unwrap();
+        assert!(b.allow(UPSTREAM).is_none());
+        drop(probe);
+        assert_eq!(b.status()[0].state, BreakerState::HalfOpen);
+
+        let probe = b.allow(UPSTREAM).unwrap();
+        b.record(probe, true);
+        assert_eq!(b.status()[0].state, BreakerState::Closed);
+    }
+
+    #[test]
+    fn test_breaker_dropped_request_releases_probe() {
+        let b = breakers(1);
+        let permit = b.allow(UPSTREAM).unwrap();
+        b.record(permit, false);
+        sleep(Duration::from_millis(150));
+
+        // request future is dropped before upstream responds, as on client disconnect
+        let probe = b.allow(UPSTREAM).unwrap();
+        let request = async {
+            std::future::pending::<()>().await;
+            b.record(probe, true);
+        };
+        assert!(b.allow(UPSTREAM).is_none());
+        drop(request);
+
+        assert_eq!(b.status()[0].state, BreakerState::HalfOpen);
+        assert!(b.allow(UPSTREAM).is_some());
+    }
+}
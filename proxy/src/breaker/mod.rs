@@ -0,0 +1 @@
+pub mod breaker;
\ No newline at end of file
@@ -0,0 +1,771 @@
+use std::collections::BTreeMap;
+use std::fs::{metadata, read_to_string};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use kube_metrics_mutli_tenancy_lib::locks;
+use kube_metrics_mutli_tenancy_lib::tenant::normalize_tenant_id;
+use log::{debug, error, info};
+use once_cell::sync::Lazy;
+use prometheus::{IntCounterVec, IntGauge};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::admin::admin::constant_time_eq;
+use crate::controller::controller::CONTROLLER;
+use crate::labels::labels::{LabelConflictPolicy, TenantLabels};
+use crate::mirror::mirror::Mirror;
+use crate::promql::promql::is_valid_label_name;
+
+
+// Effective proxy configuration singleton.
+// Configuration is immutable, reload swaps it as a whole,
+// so every request sees either old or new configuration, never a mix.
+pub static CONFIG: Lazy<RwLock<Arc<ProxyConfig>>> =
+    Lazy::new(|| RwLock::new(Arc::new(ProxyConfig::default())));
+
+// Get current configuration snapshot.
+pub fn current() -> Arc<ProxyConfig> {
+    locks::read(&CONFIG).clone()
+}
+
+// Replace current configuration.
+pub fn swap(config: ProxyConfig) -> Arc<ProxyConfig> {
+    let config = Arc::new(config);
+    *locks::write(&CONFIG) = config.clone();
+    config
+}
+
+// Proxy configuration file
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyConfig {
+    #[serde(default)]
+    pub upstreams: UpstreamsConfig,
+    // labels which values identify tenant
+    #[serde(default = "default_tenant_labels")]
+    pub tenant_labels: Vec<String>,
+    #[serde(default)]
+    pub replication: ReplicationConfig,
+    #[serde(default)]
+    pub allow_list: AllowListConfig,
+    #[serde(default)]
+    pub external_labels: ExternalLabelsConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub query: QueryConfig,
+}
+
+// Upstreams to forward metrics to
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamsConfig {
+    // cortex ingester deployment url
+    #[serde(default = "default_ingester_url")]
+    #[schemars(schema_with = "url_schema")]
+    pub ingester_url: String,
+    // secondary upstreams to mirror traffic to
+    #[serde(default)]
+    pub mirror_urls: Vec<String>,
+    // percentage of each tenant requests to mirror
+    #[serde(default)]
+    #[schemars(schema_with = "percentage_schema")]
+    pub mirror_percentage: f64,
+    // upstreams tenants could be pinned to by name, with OpenMetricsTenant resources
+    #[serde(default)]
+    pub named: BTreeMap<String, String>,
+}
+
+// Tenants to replicate whole stream into
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ReplicationConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default = "default_replication_tenants")]
+    pub tenants: Vec<String>,
+}
+
+// Tenants allowed to be detected from labels, in addition to ones found in k8s
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AllowListConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub tenants: Vec<String>,
+}
+
+// Labels added to every series of a tenant
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ExternalLabelsConfig {
+    #[serde(default = "default_conflict_policy")]
+    pub conflict_policy: LabelConflictPolicy,
+    #[serde(default)]
+    pub tenants: TenantLabels,
+}
+
+// Request processing limits
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    // max content length allowed to be posted
+    #[serde(default = "default_content_length_limit")]
+    #[schemars(schema_with = "positive_schema::<u64>")]
+    pub content_length_limit: u64,
+    // maximum number of requests per single payload to invoke in parallel
+    #[serde(default = "default_max_parallel_request_per_load")]
+    #[schemars(schema_with = "positive_schema::<u16>")]
+    pub max_parallel_request_per_load: u16,
+}
+
+// Read path, served on --query-port
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct QueryConfig {
+    // cortex query-frontend url, API paths like api/v1/query are appended to it
+    #[serde(default = "default_query_frontend_url")]
+    #[schemars(schema_with = "url_schema")]
+    pub frontend_url: String,
+    // callers allowed to query, identified by bearer token
+    #[serde(default)]
+    pub callers: Vec<QueryCaller>,
+    // label matcher added to every selector of caller queries, for teams sharing a tenant (optional)
+    #[serde(default)]
+    pub enforced_label: String,
+}
+
+// Query caller and tenants it may read
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct QueryCaller {
+    // caller name, used in metrics and logs
+    pub name: String,
+    // bearer token caller authenticates with
+    pub token: String,
+    // tenants queried together, as federated query when there are several
+    pub tenants: Vec<String>,
+    // enforced label value, caller name by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label_value: Option<String>,
+}
+
+impl QueryCaller {
+    // X-Scope-OrgID value: normalized tenant IDs joined with pipe.
+    pub fn org_id(&self) -> String {
+        self.tenant_ids().join("|")
+    }
+
+    // Normalized IDs of tenants caller may read.
+    pub fn tenant_ids(&self) -> Vec<String> {
+        normalized(self.tenants.iter())
+    }
+
+    // Value of enforced label caller queries are restricted to.
+    pub fn enforced_label_value(&self) -> &str {
+        self.label_value.as_deref().unwrap_or(&self.name)
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_tenant_labels() -> Vec<String> {
+    vec![String::from("tenant_id")]
+}
+
+fn default_ingester_url() -> String {
+    String::from("http://127.0.0.1:5000")
+}
+
+fn default_replication_tenants() -> Vec<String> {
+    vec![String::from("0")]
+}
+
+fn default_conflict_policy() -> LabelConflictPolicy {
+    LabelConflictPolicy::Override
+}
+
+pub fn default_content_length_limit() -> u64 {
+    100 * 1024 * 1024
+}
+
+pub fn default_max_parallel_request_per_load() -> u16 {
+    64
+}
+
+fn default_query_frontend_url() -> String {
+    String::from("http://127.0.0.1:8080/prometheus/")
+}
+
+// Schemas of fields constrained by validate(), test_schema_agrees_with_validate keeps both in sync.
+
+fn url_schema(gen: &mut SchemaGenerator) -> Schema {
+    let mut schema = String::json_schema(gen).into_object();
+    schema.format = Some(String::from("uri"));
+    schema.into()
+}
+
+fn percentage_schema(gen: &mut SchemaGenerator) -> Schema {
+    let mut schema = f64::json_schema(gen).into_object();
+    schema.number().minimum = Some(0.0);
+    schema.number().maximum = Some(100.0);
+    schema.into()
+}
+
+fn positive_schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    let mut schema = T::json_schema(gen).into_object();
+    schema.number().minimum = Some(1.0);
+    schema.into()
+}
+
+impl Default for UpstreamsConfig {
+    fn default() -> Self {
+        UpstreamsConfig {
+            ingester_url: default_ingester_url(),
+            mirror_urls: vec![],
+            mirror_percentage: 0.0,
+            named: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        ReplicationConfig { enabled: true, tenants: default_replication_tenants() }
+    }
+}
+
+impl Default for AllowListConfig {
+    fn default() -> Self {
+        AllowListConfig { enabled: true, tenants: vec![] }
+    }
+}
+
+impl Default for ExternalLabelsConfig {
+    fn default() -> Self {
+        ExternalLabelsConfig { conflict_policy: default_conflict_policy(), tenants: TenantLabels::new() }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            content_length_limit: default_content_length_limit(),
+            max_parallel_request_per_load: default_max_parallel_request_per_load(),
+        }
+    }
+}
+
+impl Default for QueryConfig {
+    fn default() -> Self {
+        QueryConfig {
+            frontend_url: default_query_frontend_url(),
+            callers: vec![],
+            enforced_label: String::new(),
+        }
+    }
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            upstreams: UpstreamsConfig::default(),
+            tenant_labels: default_tenant_labels(),
+            replication: ReplicationConfig::default(),
+            allow_list: AllowListConfig::default(),
+            external_labels: ExternalLabelsConfig::default(),
+            limits: LimitsConfig::default(),
+            query: QueryConfig::default(),
+        }
+    }
+}
+
+impl ProxyConfig {
+    // Parse and validate YAML configuration.
+    pub fn parse(content: &str) -> Result<ProxyConfig, String> {
+        let config: ProxyConfig = match serde_yaml::from_str(content) {
+            Ok(c) => c,
+            Err(e) => return Err(format!("invalid configuration: {}", e)),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Load configuration from YAML file.
+    pub fn load(path: &str) -> Result<ProxyConfig, String> {
+        match read_to_string(path) {
+            Ok(content) => ProxyConfig::parse(&content),
+            Err(e) => Err(format!("failed to read {}: {}", path, e)),
+        }
+    }
+
+    // Check constraints not expressed by configuration types.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.upstreams.named.keys().any(|name| name.is_empty()) {
+            return Err(String::from("empty named upstream name"));
+        }
+        let urls = std::iter::once(&self.upstreams.ingester_url)
+            .chain(self.upstreams.mirror_urls.iter())
+            .chain(self.upstreams.named.values());
+        for url in urls {
+            if let Err(e) = reqwest::Url::parse(url) {
+                return Err(format!("invalid upstream url {}: {}", url, e));
+            }
+        }
+        if !(0.0..=100.0).contains(&self.upstreams.mirror_percentage) {
+            return Err(format!(
+                "mirror_percentage should be between 0 and 100, got {}",
+                self.upstreams.mirror_percentage
+            ));
+        }
+        if self.tenant_labels.iter().all(|l| l.is_empty()) {
+            return Err(String::from("at least one tenant label is required"));
+        }
+        if self.limits.content_length_limit == 0 {
+            return Err(String::from("content_length_limit should be positive"));
+        }
+        if self.limits.max_parallel_request_per_load == 0 {
+            return Err(String::from("max_parallel_request_per_load should be positive"));
+        }
+        for (tenant_id, labels) in self.external_labels.tenants.iter() {
+            if labels.keys().any(|name| name.is_empty()) {
+                return Err(format!("empty external label name for tenant {}", tenant_id));
+            }
+        }
+        if let Err(e) = reqwest::Url::parse(&self.query.frontend_url) {
+            return Err(format!("invalid query frontend url {}: {}", self.query.frontend_url, e));
+        }
+        if !self.query.enforced_label.is_empty() && !is_valid_label_name(&self.query.enforced_label) {
+            return Err(format!("invalid query enforced label name {}", self.query.enforced_label));
+        }
+        let mut names = std::collections::HashSet::new();
+        let mut tokens = std::collections::HashSet::new();
+        for caller in self.query.callers.iter() {
+            if caller.name.is_empty() || !names.insert(&caller.name) {
+                return Err(format!("query caller name should be unique and non-empty: {:?}", caller.name));
+            }
+            if caller.token.is_empty() || !tokens.insert(&caller.token) {
+                return Err(format!("query caller {} token should be unique and non-empty", caller.name));
+            }
+            if caller.tenants.is_empty() {
+                return Err(format!("query caller {} should be allowed at least one tenant", caller.name));
+            }
+        }
+        let tenant_ids = self.replication.tenants.iter()
+            .chain(self.allow_list.tenants.iter())
+            .chain(self.external_labels.tenants.keys())
+            .chain(self.query.callers.iter().flat_map(|c| c.tenants.iter()));
+        for tenant_id in tenant_ids {
+            if let Err(e) = normalize_tenant_id(tenant_id) {
+                return Err(format!("invalid tenant {}: {}", tenant_id, e));
+            }
+        }
+        Ok(())
+    }
+
+    // Upstream url tenant series are forwarded to, ingester url unless tenant is pinned to named upstream.
+    // None when named upstream is not configured.
+    pub fn upstream_url(&self, name: Option<&String>) -> Option<&String> {
+        match name {
+            Some(name) => self.upstreams.named.get(name),
+            None => Some(&self.upstreams.ingester_url),
+        }
+    }
+
+    // Tenants to replicate every series into.
+    pub fn replicate_to(&self) -> Vec<String> {
+        if self.replication.enabled {
+            normalized(self.replication.tenants.iter())
+        } else {
+            vec![]
+        }
+    }
+
+    // Tenants allowed regardless of k8s resources.
+    pub fn allow_listed_tenants(&self) -> Vec<String> {
+        if self.allow_list.enabled {
+            self.replicate_to()
+                .into_iter()
+                .chain(normalized(self.allow_list.tenants.iter()))
+                .collect()
+        } else {
+            vec![]
+        }
+    }
+
+    // External labels by normalized tenant ID.
+    pub fn tenant_external_labels(&self) -> TenantLabels {
+        self.external_labels
+            .tenants
+            .iter()
+            .filter_map(|(tenant_id, labels)| {
+                normalize_tenant_id(tenant_id).ok().map(|t| (t, labels.clone()))
+            })
+            .collect()
+    }
+
+    // Find query caller by bearer token.
+    pub fn query_caller(&self, token: &str) -> Option<&QueryCaller> {
+        self.query
+            .callers
+            .iter()
+            .find(|c| constant_time_eq(c.token.as_bytes(), token.as_bytes()))
+    }
+
+    // Configuration safe to report, with secrets hidden.
+    pub fn redacted(&self) -> ProxyConfig {
+        let mut config = self.clone();
+        for caller in config.query.callers.iter_mut() {
+            caller.token = String::from("<redacted>");
+        }
+        config
+    }
+
+    // Configuration JSON schema.
+    pub fn schema() -> String {
+        // it is safe to unwrap, schema is generated from static types
+        serde_json::to_string_pretty(&schemars::schema_for!(ProxyConfig)).unwrap()
+    }
+}
+
+// Normalize tenant IDs, configuration is validated so none is dropped.
+fn normalized<'a>(tenant_ids: impl Iterator<Item = &'a String>) -> Vec<String> {
+    tenant_ids.filter_map(|t| normalize_tenant_id(t).ok()).collect()
+}
+
+// Configuration file reloader.
+// Invalid configuration is never applied, previous one stays in effect.
+pub struct ConfigReloader {
+    path: String,
+    modified: Option<SystemTime>,
+    reloads: IntCounterVec,
+    last_successful: IntGauge,
+}
+
+impl ConfigReloader {
+    // var:reloads counts reloads by result
+    // var:last_successful is 1 when last reload succeeded, 0 otherwise
+    pub fn new(path: &str, reloads: IntCounterVec, last_successful: IntGauge) -> ConfigReloader {
+        last_successful.set(1);
+        ConfigReloader {
+            path: String::from(path),
+            modified: modified_at(path),
+            reloads,
+            last_successful,
+        }
+    }
+
+    // Check whether file modification time changed since last check.
+    fn changed(&mut self) -> bool {
+        let modified = modified_at(&self.path);
+        if modified != self.modified {
+            self.modified = modified;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Load configuration file, and swap current configuration if it is valid.
+    pub async fn reload(&self, mirror: &Mirror) -> Result<Arc<ProxyConfig>, String> {
+        match ProxyConfig::load(&self.path) {
+            Ok(config) => {
+                let config = apply(config, mirror).await;
+                self.reloads.with_label_values(&["success"]).inc();
+                self.last_successful.set(1);
+                info!("configuration reloaded from {}", self.path);
+                Ok(config)
+            },
+            Err(e) => {
+                self.reloads.with_label_values(&["failure"]).inc();
+                self.last_successful.set(0);
+                error!("configuration reload failed, keeping previous one: {}", e);
+                Err(e)
+            }
+        }
+    }
+}
+
+fn modified_at(path: &str) -> Option<SystemTime> {
+    metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// Make configuration current, and propagate it to components keeping own state.
+// Routes check limits.content_length_limit per request, raising it above the one set on start needs restart.
+pub async fn apply(config: ProxyConfig, mirror: &Mirror) -> Arc<ProxyConfig> {
+    // requests read configuration under controller read lock,
+    // so holding write lock makes them see either old or new state of both
+    let mut c = CONTROLLER.write().await;
+    c.replace_initial_allowed_tenants(config.allow_listed_tenants())
+        .replace_initial_external_labels(config.tenant_external_labels());
+    mirror.reconfigure(config.upstreams.mirror_urls.clone(), config.upstreams.mirror_percentage);
+    let config = swap(config);
+    drop(c);
+    config
+}
+
+// Reload configuration on SIGHUP, or when file changes.
+// var:check_interval is file modification check interval, zero disables checks
+pub async fn reload_worker(mut reloader: ConfigReloader, check_interval: Duration, mirror: Arc<Mirror>) {
+    // it is safe to unwrap, signal handlers are registered once on start
+    let mut sighup = signal(SignalKind::hangup()).unwrap();
+    let polls = check_interval > Duration::from_millis(0);
+    let mut ticks = tokio::time::interval(if polls { check_interval } else { Duration::from_secs(3600) });
+    loop {
+        tokio::select! {
+            _ = sighup.recv() => {
+                info!("received SIGHUP, reloading configuration");
+                // keep modification time in sync, so the same change is not reloaded twice
+                reloader.changed();
+            },
+            _ = ticks.tick() => {
+                if !polls || !reloader.changed() {
+                    continue;
+                }
+                debug!("configuration file {} changed", reloader.path);
+            },
+        }
+        let _ = reloader.reload(&mirror).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts};
+    use serde_json::{json, Value};
+    use serial_test::serial;
+
+    use crate::breaker::breaker::CircuitBreakers;
+    use crate::config::config::{current, swap, ConfigReloader, ProxyConfig};
+    use crate::controller::controller::CONTROLLER;
+    use crate::labels::labels::LabelConflictPolicy;
+    use crate::mirror::mirror::Mirror;
+
+    #[test]
+    fn test_parse_config() {
+        let config = ProxyConfig::parse(r#"
+upstreams:
+  ingester_url: http://distributor:8080/api/v1/push
+  mirror_urls:
+    - http://mimir:8080/api/v1/push
+  mirror_percentage: 5
+  named:
+    dedicated: http://dedicated-distributor:8080/api/v1/push
+tenant_labels: [tenant_id, tnt]
+replication:
+  tenants: ["0", "1"]
+allow_list:
+  tenants: [tenant1]
+external_labels:
+  conflict_policy: rename
+  tenants:
+    tenant1:
+      org: vgs
+limits:
+  max_parallel_request_per_load: 8
+"#).unwrap();
+
+        assert_eq!(config.upstreams.ingester_url, "http://distributor:8080/api/v1/push");
+        assert_eq!(config.upstreams.mirror_percentage, 5.0);
+        assert_eq!(
+            config.upstream_url(Some(&String::from("dedicated"))).unwrap(),
+            "http://dedicated-distributor:8080/api/v1/push");
+        assert_eq!(config.upstream_url(None).unwrap(), "http://distributor:8080/api/v1/push");
+        assert!(config.upstream_url(Some(&String::from("unknown"))).is_none());
+        assert_eq!(config.tenant_labels, vec!["tenant_id", "tnt"]);
+        assert_eq!(config.replicate_to(), vec!["0", "1"]);
+        assert_eq!(config.allow_listed_tenants(), vec!["0", "1", "tenant1"]);
+        assert_eq!(config.external_labels.conflict_policy, LabelConflictPolicy::Rename);
+        assert_eq!(config.external_labels.tenants["tenant1"]["org"], "vgs");
+        assert_eq!(config.limits.max_parallel_request_per_load, 8);
+        // defaults are used for omitted values
+        assert_eq!(config.limits.content_length_limit, 100 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_query_callers() {
+        let config = ProxyConfig::parse(r#"
+query:
+  frontend_url: http://query-frontend:8080/prometheus/
+  callers:
+    - name: team-a
+      token: token-a
+      tenants: [tenant1, tenant2]
+"#).unwrap();
+
+        let caller = config.query_caller("token-a").unwrap();
+        assert_eq!(caller.name, "team-a");
+        assert_eq!(caller.org_id(), "tenant1|tenant2");
+        assert!(config.query_caller("token-b").is_none());
+        assert_eq!(config.redacted().query.callers[0].token, "<redacted>");
+        assert_eq!(caller.enforced_label_value(), "team-a");
+
+        // duplicate tokens, and callers without tenants are refused
+        assert!(ProxyConfig::parse(r#"
+query:
+  callers:
+    - {name: a, token: t, tenants: [tenant1]}
+    - {name: b, token: t, tenants: [tenant1]}
+"#).is_err());
+        assert!(ProxyConfig::parse("query: {callers: [{name: a, token: t, tenants: []}]}").is_err());
+        assert!(ProxyConfig::parse("query: {enforced_label: team-name}").is_err());
+    }
+
+    #[test]
+    fn test_example_config() {
+        let config = ProxyConfig::load("../config/proxy-config.yaml").unwrap();
+        assert_eq!(config.allow_listed_tenants(), vec!["0", "tenant1"]);
+    }
+
+    #[test]
+    fn test_parse_empty_config() {
+        assert_eq!(ProxyConfig::parse("{}").unwrap(), ProxyConfig::default());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        // unknown field
+        assert!(ProxyConfig::parse("tenant_label: [tenant_id]").is_err());
+        // wrong type
+        assert!(ProxyConfig::parse("limits: {max_parallel_request_per_load: many}").is_err());
+        // invalid values
+        assert!(ProxyConfig::parse("upstreams: {ingester_url: not a url}").is_err());
+        assert!(ProxyConfig::parse("upstreams: {mirror_percentage: 150}").is_err());
+        assert!(ProxyConfig::parse("upstreams: {named: {dedicated: not a url}}").is_err());
+        assert!(ProxyConfig::parse("limits: {max_parallel_request_per_load: 0}").is_err());
+        assert!(ProxyConfig::parse("external_labels: {conflict_policy: drop}").is_err());
+        // tenant IDs Cortex would refuse
+        assert!(ProxyConfig::parse("allow_list: {tenants: [\"../\"]}").is_err());
+        assert!(ProxyConfig::parse("replication: {tenants: [\"..\"]}").is_err());
+        assert!(ProxyConfig::parse("external_labels: {tenants: {\"a b\": {org: vgs}}}").is_err());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_reload_rolls_back_invalid_config() {
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        write!(f, "tenant_labels: [tnt]\nallow_list: {{tenants: [tenant1]}}\n").unwrap();
+        let reloader = ConfigReloader::new(
+            f.path().to_str().unwrap(),
+            IntCounterVec::new(Opts::new("config_reloads", "help"), &["result"]).unwrap(),
+            IntGauge::new("config_last_reload_successful", "help").unwrap(),
+        );
+        let m = Mirror::new(
+            vec![],
+            0.0,
+            1,
+            IntCounterVec::new(Opts::new("mirror_requests", "help"), &["target", "status"]).unwrap(),
+            HistogramVec::new(HistogramOpts::new("mirror_latency", "help"), &["target"]).unwrap(),
+            IntCounterVec::new(Opts::new("mirror_dropped", "help"), &["target"]).unwrap(),
+            Arc::new(CircuitBreakers::new(
+                0,
+                Duration::from_secs(1),
+                1,
+                IntGaugeVec::new(Opts::new("breaker_state", "help"), &["upstream"]).unwrap(),
+            )),
+        );
+
+        let config = reloader.reload(&m).await.unwrap();
+        assert_eq!(config.tenant_labels, vec!["tnt"]);
+        assert_eq!(current().tenant_labels, vec!["tnt"]);
+        assert!(CONTROLLER.read().await.get_tenants().contains(&String::from("tenant1")));
+
+        // invalid configuration keeps previous one in effect
+        let mut f2 = f.reopen().unwrap();
+        f2.set_len(0).unwrap();
+        writeln!(f2, "tenant_labels: []").unwrap();
+        assert!(reloader.reload(&m).await.is_err());
+        assert_eq!(current().tenant_labels, vec!["tnt"]);
+        assert_eq!(reloader.reloads.with_label_values(&["success"]).get(), 1);
+        assert_eq!(reloader.reloads.with_label_values(&["failure"]).get(), 1);
+        assert_eq!(reloader.last_successful.get(), 0);
+
+        swap(ProxyConfig::default());
+        CONTROLLER.write().await.clean();
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema: serde_json::Value = serde_json::from_str(&ProxyConfig::schema()).unwrap();
+        assert_eq!(schema["additionalProperties"], serde_json::json!(false));
+        assert!(schema["properties"]["upstreams"].is_object());
+    }
+
+    // Collect paths of constrained properties, following references to nested sections.
+    fn constrained(schema: &Value, definitions: &Value, path: Vec<String>, found: &mut Vec<(Vec<String>, Value)>) {
+        let reference = schema["$ref"].as_str().or_else(|| schema["allOf"][0]["$ref"].as_str());
+        if let Some(reference) = reference {
+            let name = reference.trim_start_matches("#/definitions/");
+            return constrained(&definitions[name], definitions, path, found);
+        }
+        if let Some(properties) = schema["properties"].as_object() {
+            for (name, property) in properties.iter() {
+                let mut property_path = path.clone();
+                property_path.push(name.clone());
+                constrained(property, definitions, property_path, found);
+            }
+        } else if schema.get("minimum").is_some() || schema.get("maximum").is_some() || schema.get("format") == Some(&json!("uri")) {
+            found.push((path, schema.clone()));
+        }
+    }
+
+    fn config_with(path: &[String], value: Value) -> Result<ProxyConfig, String> {
+        let mut config = json!({});
+        let mut section = &mut config;
+        for name in path[..path.len() - 1].iter() {
+            section = section.as_object_mut().unwrap().entry(name.clone()).or_insert(json!({}));
+        }
+        section[path.last().unwrap()] = value;
+        ProxyConfig::parse(&config.to_string())
+    }
+
+    // whole bounds as integers, so integer fields accept them
+    fn number(n: f64) -> Value {
+        if n.fract() == 0.0 { json!(n as i64) } else { json!(n) }
+    }
+
+    #[test]
+    fn test_schema_agrees_with_validate() {
+        let schema: Value = serde_json::from_str(&ProxyConfig::schema()).unwrap();
+        let mut found = vec![];
+        constrained(&schema, &schema["definitions"], vec![], &mut found);
+        let paths: Vec<String> = found.iter().map(|(path, _)| path.join(".")).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "limits.content_length_limit",
+                "limits.max_parallel_request_per_load",
+                "query.frontend_url",
+                "upstreams.ingester_url",
+                "upstreams.mirror_percentage",
+            ]
+        );
+
+        // values schema refuses are refused by validate(), and bounds themselves are accepted
+        for (path, property) in found.iter() {
+            if let Some(minimum) = property["minimum"].as_f64() {
+                assert!(config_with(path, number(minimum)).is_ok(), "{:?}", path);
+                assert!(config_with(path, number(minimum - 1.0)).is_err(), "{:?}", path);
+            }
+            if let Some(maximum) = property["maximum"].as_f64() {
+                assert!(config_with(path, number(maximum)).is_ok(), "{:?}", path);
+                assert!(config_with(path, number(maximum + 1.0)).is_err(), "{:?}", path);
+            }
+            if property["format"] == json!("uri") {
+                assert!(config_with(path, json!("http://127.0.0.1:9009/")).is_ok(), "{:?}", path);
+                assert!(config_with(path, json!("not a url")).is_err(), "{:?}", path);
+            }
+        }
+    }
+}
@@ -1,13 +1,31 @@
-use std::collections::HashSet;
-use std::time::Duration;
-
-use kube::Client;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock as SyncRwLock};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use futures::{pin_mut, stream, StreamExt};
+use kube::api::Meta;
+use kube::{Api, Client};
+use kube_runtime::reflector::{reflector, store::{Store, Writer}};
+use kube_runtime::watcher::{watcher, Event};
 use log::{debug,error,info,warn};
+use serde::Serialize;
 use tokio::sync::RwLock;
 use tokio::time::sleep;
 use once_cell::sync::Lazy;
+use prometheus::{IntCounterVec, IntGaugeVec};
 
 use kube_metrics_mutli_tenancy_lib as kube_lib;
+use kube_lib::health::HealthCheck;
+use kube_lib::locks;
+use kube_lib::namespace::{count_rules_by_namespace, list_namespaces, NamespaceScope};
+use kube_lib::selector::RuleSelector;
+use kube_lib::tenant::{normalize_tenant_id, TenantIdError};
+use kube_lib::{OpenMetricsRule, OpenMetricsTenant, TenantLimitsSpec};
+
+use crate::config::config;
+use crate::labels::labels::TenantLabels;
+use crate::limits::limits::{TenantLimits, LIMITER};
 
 // An ingestion controller singleton
 // It is protected by global rw lock, which is acquired for writers
@@ -16,14 +34,124 @@ use kube_metrics_mutli_tenancy_lib as kube_lib;
 pub static CONTROLLER: Lazy<RwLock<IngestionTenantController>> =
     Lazy::new(|| RwLock::new(IngestionTenantController::new()));
 
+// Tenants and external labels in effect, built by controller on every change
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TenantSnapshot {
+    // allowed tenants, suspended ones are left out
+    pub tenants: HashSet<String>,
+    pub external_labels: TenantLabels,
+    // settings of tenants with OpenMetricsTenant resource
+    pub settings: HashMap<String, TenantSettings>,
+    // tenants series of a tenant are replicated into, besides replication tenants
+    pub replicate_to: HashMap<String, Vec<String>>,
+}
+
+impl TenantSnapshot {
+    pub fn is_suspended(&self, tenant_id: &str) -> bool {
+        self.settings.get(tenant_id).map(|s| s.suspended).unwrap_or(false)
+    }
+}
+
+// Ingestion settings of tenant, applied from OpenMetricsTenant resource
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct TenantSettings {
+    // namespace/name of resource
+    pub resource: String,
+    pub suspended: bool,
+    pub limits: TenantLimits,
+    // named upstream, ingester url when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub replicate_to: Vec<String>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub external_labels: HashMap<String, String>,
+}
+
+// Resources found in k8s, of every observed namespace
+#[derive(Default)]
+pub struct FoundResources {
+    pub rules: Vec<OpenMetricsRule>,
+    pub tenants: Vec<OpenMetricsTenant>,
+}
+
+// Current snapshot, replaced as a whole.
+// Readers only clone the pointer under the lock and route series with their own copy,
+// so requests never wait for controller updates, and updates never wait for requests.
+#[derive(Debug, Default)]
+pub struct SharedTenantSnapshot {
+    current: SyncRwLock<Arc<TenantSnapshot>>,
+}
+
+impl SharedTenantSnapshot {
+    // Get current snapshot.
+    pub fn load(&self) -> Arc<TenantSnapshot> {
+        locks::read(&self.current).clone()
+    }
+
+    fn store(&self, snapshot: TenantSnapshot) {
+        *locks::write(&self.current) = Arc::new(snapshot);
+    }
+}
+
+// Where allowed tenant comes from
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TenantSource {
+    // command line or configuration file
+    Cli,
+    // OpenMetricsRule or OpenMetricsTenant resources
+    Kubernetes,
+}
+
+// Allowed tenant report
+#[derive(Clone, Debug, Serialize)]
+pub struct TenantStatus {
+    pub tenant_id: String,
+    pub source: TenantSource,
+    // settings applied from OpenMetricsTenant resource
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub settings: Option<TenantSettings>,
+    // series written recently, tracked by this replica for tenants with series limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub active_series: Option<usize>,
+}
+
+// Kubernetes sync report
+#[derive(Clone, Debug, Serialize)]
+pub struct SyncStatus {
+    pub enabled: bool,
+    pub namespace: String,
+    pub rule_selector: String,
+    // whether OpenMetricsTenant resources are observed
+    pub tenant_resources: bool,
+    pub last_attempt_time: Option<DateTime<Utc>>,
+    pub last_success_time: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
 // Ingestion controller state
 pub struct IngestionTenantController {
     pub k8s_client: Option<Client>,
     k8s_poll_ms: u64,
+    k8s_resync_ms: u64,
     initial_tenants: HashSet<String>,
     tenants: HashSet<String>,
     tenants_vec: Vec<String>,
-    namespace: String,
+    initial_labels: TenantLabels,
+    k8s_labels: TenantLabels,
+    labels: TenantLabels,
+    namespaces: NamespaceScope,
+    rule_selector: RuleSelector,
+    tenant_resources: bool,
+    settings: HashMap<String, TenantSettings>,
+    synced: bool,
+    last_attempt_time: Option<DateTime<Utc>>,
+    last_success_time: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+    rejected_tenant_ids: Option<IntCounterVec>,
+    namespace_rules: Option<IntGaugeVec>,
+    snapshot: Arc<SharedTenantSnapshot>,
     stopping: Option<bool>
 }
 
@@ -35,11 +163,25 @@ impl IngestionTenantController {
     pub fn new() -> IngestionTenantController   {
         return IngestionTenantController{
             k8s_poll_ms: 0,
+            k8s_resync_ms: 0,
             initial_tenants: HashSet::new(),
             k8s_client: None,
             tenants: HashSet::new(),
             tenants_vec: Vec::new(),
-            namespace: std::env::var("OPEN_METRICS_PROXY_NAMESPACE").unwrap_or("default".into()),
+            initial_labels: TenantLabels::new(),
+            k8s_labels: TenantLabels::new(),
+            labels: TenantLabels::new(),
+            namespaces: NamespaceScope::parse("", ""),
+            rule_selector: RuleSelector::default(),
+            tenant_resources: false,
+            settings: HashMap::new(),
+            synced: false,
+            last_attempt_time: None,
+            last_success_time: None,
+            last_error: None,
+            rejected_tenant_ids: None,
+            namespace_rules: None,
+            snapshot: Arc::new(SharedTenantSnapshot::default()),
             stopping: None
         }
     }
@@ -49,15 +191,154 @@ impl IngestionTenantController {
         self.initial_tenants = HashSet::new();
         self.tenants = HashSet::new();
         self.tenants_vec = Vec::new();
+        self.initial_labels = TenantLabels::new();
+        self.k8s_labels = TenantLabels::new();
+        self.labels = TenantLabels::new();
+        self.settings = HashMap::new();
+        self.synced = false;
+        self.last_attempt_time = None;
+        self.last_success_time = None;
+        self.last_error = None;
         self.stopping = None;
+        self.publish();
+    }
+
+    // Replace snapshot read by request path with current tenants, labels and settings.
+    fn publish(&self) {
+        let is_suspended = |tenant_id: &String| self.settings.get(tenant_id).map(|s| s.suspended).unwrap_or(false);
+        self.snapshot.store(TenantSnapshot {
+            tenants: self.tenants_vec.iter().filter(|t| !is_suspended(t)).cloned().collect(),
+            external_labels: self.labels.clone(),
+            settings: self.settings.clone(),
+            replicate_to: self.settings
+                .iter()
+                .filter(|(_, s)| !s.suspended && !s.replicate_to.is_empty())
+                .map(|(tenant_id, s)| (tenant_id.clone(), s.replicate_to.clone()))
+                .collect(),
+        });
+        // usage of tenants which are not limited anymore is not needed
+        let settings = &self.settings;
+        LIMITER.retain_tenants(|tenant_id| settings.get(tenant_id).map(|s| !s.limits.is_empty()).unwrap_or(false));
     }
 
-    // Initialize poll seconds parameter.
+    // Get snapshot holder, it stays the same for controller lifetime.
+    pub fn snapshot(&self) -> Arc<SharedTenantSnapshot> {
+        self.snapshot.clone()
+    }
+
+    // Initialize back off after failed k8s watch, zero disables controller.
     pub fn set_k8s_poll_delay(&mut self, k8s_poll_ms: u64) -> &mut IngestionTenantController {
         self.k8s_poll_ms = k8s_poll_ms;
         return self
     }
 
+    // Initialize interval of full k8s resync, when watch is restarted with fresh list.
+    pub fn set_k8s_resync_interval(&mut self, k8s_resync_ms: u64) -> &mut IngestionTenantController {
+        self.k8s_resync_ms = k8s_resync_ms;
+        return self
+    }
+
+    // Initialize counter of invalid tenant IDs found in k8s, by source and reason.
+    pub fn set_rejected_tenant_ids_counter(&mut self, counter: IntCounterVec) -> &mut IngestionTenantController {
+        self.rejected_tenant_ids = Some(counter);
+        return self
+    }
+
+    // Initialize gauge of rules found in k8s, by namespace.
+    pub fn set_namespace_rules_gauge(&mut self, gauge: IntGaugeVec) -> &mut IngestionTenantController {
+        self.namespace_rules = Some(gauge);
+        return self
+    }
+
+    // Initialize namespaces to observe for rules.
+    pub fn set_namespace_scope(&mut self, namespaces: NamespaceScope) -> &mut IngestionTenantController {
+        self.namespaces = namespaces;
+        return self
+    }
+
+    // Initialize selector of rules meant for this deployment.
+    pub fn set_rule_selector(&mut self, rule_selector: RuleSelector) -> &mut IngestionTenantController {
+        self.rule_selector = rule_selector;
+        return self
+    }
+
+    // Initialize whether OpenMetricsTenant resources are observed, besides rules.
+    pub fn set_tenant_resources(&mut self, tenant_resources: bool) -> &mut IngestionTenantController {
+        self.tenant_resources = tenant_resources;
+        return self
+    }
+
+    fn reject_tenant_id(&self, tenant_id: &str, e: TenantIdError) {
+        warn!("ignoring tenant {:?} found in k8s: {}", tenant_id, e);
+        if let Some(counter) = &self.rejected_tenant_ids {
+            counter.with_label_values(&["kubernetes", e.reason()]).inc();
+        }
+    }
+
+    // Build settings from tenant resources, by normalized tenant ID.
+    // When several resources set up the same tenant, the first one by namespace and name is used.
+    fn settings_from_resources(&self, mut resources: Vec<OpenMetricsTenant>) -> HashMap<String, TenantSettings> {
+        resources.sort_by_key(|r| (Meta::namespace(r), Meta::name(r)));
+        let upstreams = config::current();
+        let mut settings: HashMap<String, TenantSettings> = HashMap::new();
+        for resource in resources.into_iter() {
+            let name = format!("{}/{}", Meta::namespace(&resource).unwrap_or_default(), Meta::name(&resource));
+            let tenant_id = match normalize_tenant_id(&resource.tenant_id()) {
+                Ok(t) => t,
+                Err(e) => {
+                    self.reject_tenant_id(&resource.tenant_id(), e);
+                    continue;
+                }
+            };
+            if let Some(existing) = settings.get(&tenant_id) {
+                warn!("tenant {} of {} is already set up by {}, ignoring it", tenant_id, name, existing.resource);
+                continue;
+            }
+            if upstreams.upstream_url(resource.spec.upstream.as_ref()).is_none() {
+                warn!("upstream {:?} of {} is not configured, tenant series will be dropped", resource.spec.upstream, name);
+            }
+            let mut replicate_to = Vec::new();
+            for target in resource.spec.replicate_to.iter() {
+                match normalize_tenant_id(target) {
+                    Ok(t) => replicate_to.push(t),
+                    Err(e) => self.reject_tenant_id(target, e),
+                }
+            }
+            let limits = resource.spec.limits.unwrap_or(TenantLimitsSpec {
+                samples_per_second: None,
+                samples_burst: None,
+                max_series: None,
+            });
+            settings.insert(tenant_id, TenantSettings {
+                resource: name,
+                suspended: resource.spec.suspended,
+                limits: TenantLimits {
+                    samples_per_second: limits.samples_per_second,
+                    samples_burst: limits.samples_burst,
+                    max_series: limits.max_series,
+                },
+                upstream: resource.spec.upstream,
+                replicate_to,
+                external_labels: resource.spec.external_labels,
+            });
+        }
+        settings
+    }
+
+    // Normalize tenant IDs found in k8s, invalid ones are never allowed.
+    fn normalize_found_tenants(&self, found_tenants: HashSet<String>) -> HashSet<String> {
+        let mut normalized = HashSet::new();
+        for tenant_id in found_tenants.iter() {
+            match normalize_tenant_id(tenant_id) {
+                Ok(t) => {
+                    normalized.insert(t);
+                },
+                Err(e) => self.reject_tenant_id(tenant_id, e),
+            }
+        }
+        normalized
+    }
+
     // Initialize tenants from command line.
     pub fn set_initial_allowed_tenants(&mut self, initial_allowed_tenants: Vec<String>) -> &mut IngestionTenantController {
         for tenant_id in  initial_allowed_tenants.iter().cloned().into_iter() {
@@ -67,6 +348,34 @@ impl IngestionTenantController {
         for tenant_id in self.initial_tenants.iter().cloned().into_iter() {
             self.tenants_vec.push(tenant_id);
         }
+        self.publish();
+        return self
+    }
+
+    // Initialize external labels from file.
+    pub fn set_initial_external_labels(&mut self, initial_labels: TenantLabels) -> &mut IngestionTenantController {
+        self.initial_labels = initial_labels.clone();
+        self.labels = initial_labels;
+        self.publish();
+        return self
+    }
+
+    // Replace tenants from configuration, keeping the ones found in k8s.
+    pub fn replace_initial_allowed_tenants(&mut self, initial_allowed_tenants: Vec<String>) -> &mut IngestionTenantController {
+        self.initial_tenants = initial_allowed_tenants.into_iter().collect();
+        // k8s tenants which became initial are not tracked separately anymore,
+        // and initial tenants which were dropped are added back on next k8s tick
+        let initial_tenants = &self.initial_tenants;
+        self.tenants.retain(|tenant_id| !initial_tenants.contains(tenant_id));
+        self.tenants_vec = self.initial_tenants.iter().chain(self.tenants.iter()).cloned().collect();
+        self.publish();
+        return self
+    }
+
+    // Replace external labels from configuration, keeping the ones found in k8s.
+    pub fn replace_initial_external_labels(&mut self, initial_labels: TenantLabels) -> &mut IngestionTenantController {
+        self.initial_labels = initial_labels;
+        self.merge_labels();
         return self
     }
 
@@ -186,7 +495,35 @@ impl IngestionTenantController {
         }
 
         debug!("--- Done observe(), got {} tenants ---", self.tenants_vec.len());
+        self.publish();
+
+    }
+
+    // Merge external labels found in k8s over the ones loaded from file.
+    // Labels from k8s take precedence for the same tenant and label name.
+    pub fn observe_labels(&mut self, found_labels: TenantLabels) {
+        self.k8s_labels = found_labels;
+        self.merge_labels();
+    }
+
+    // Replace settings of tenants found in k8s.
+    pub fn observe_settings(&mut self, settings: HashMap<String, TenantSettings>) {
+        debug!("settings found for {} tenants", settings.len());
+        self.settings = settings;
+        self.publish();
+    }
 
+    fn merge_labels(&mut self) {
+        let mut labels = self.initial_labels.clone();
+        for (tenant_id, tenant_labels) in self.k8s_labels.iter() {
+            labels
+                .entry(tenant_id.clone())
+                .or_default()
+                .extend(tenant_labels.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        debug!("external labels configured for {} tenants", labels.len());
+        self.labels = labels;
+        self.publish();
     }
 
     // Get tenants vector to use.
@@ -194,6 +531,67 @@ impl IngestionTenantController {
         return &self.tenants_vec;
     }
 
+    // Get external labels to use.
+    pub fn get_external_labels(&self) -> &TenantLabels {
+        return &self.labels;
+    }
+
+    // Report allowed and suspended tenants along with their source and settings, sorted by tenant ID.
+    pub fn get_tenant_statuses(&self) -> Vec<TenantStatus> {
+        let now = Instant::now();
+        let suspended = self.settings.keys().filter(|t| !self.tenants.contains(*t) && !self.initial_tenants.contains(*t));
+        let mut statuses: Vec<TenantStatus> = self.tenants_vec
+            .iter()
+            .chain(suspended)
+            .map(|tenant_id| TenantStatus {
+                tenant_id: tenant_id.clone(),
+                source: if self.initial_tenants.contains(tenant_id) {
+                    TenantSource::Cli
+                } else {
+                    TenantSource::Kubernetes
+                },
+                settings: self.settings.get(tenant_id).cloned(),
+                active_series: self.settings
+                    .get(tenant_id)
+                    .filter(|s| s.limits.max_series.is_some())
+                    .map(|_| LIMITER.active_series(tenant_id, now)),
+            })
+            .collect();
+        statuses.sort_by(|a, b| a.tenant_id.cmp(&b.tenant_id));
+        statuses
+    }
+
+    // Report k8s sync state.
+    pub fn sync_status(&self) -> SyncStatus {
+        SyncStatus {
+            enabled: self.k8s_poll_ms > 0 && self.k8s_client.is_some(),
+            namespace: self.namespaces.to_string(),
+            rule_selector: self.rule_selector.to_string(),
+            tenant_resources: self.tenant_resources,
+            last_attempt_time: self.last_attempt_time,
+            last_success_time: self.last_success_time,
+            last_error: self.last_error.clone(),
+        }
+    }
+
+    // Check whether tenants have been synced from k8s at least once.
+    pub fn sync_check(&self) -> HealthCheck {
+        if self.k8s_poll_ms == 0 {
+            HealthCheck::ok("kubernetes controller disabled")
+        } else if self.k8s_client.is_none() {
+            HealthCheck::failed("kubernetes client is not initialized")
+        } else if !self.synced {
+            HealthCheck::failed("first kubernetes sync is not completed")
+        } else {
+            HealthCheck::ok("kubernetes sync completed")
+        }
+    }
+
+    // Make worker loop to stop on next tick.
+    pub fn stop(&mut self) {
+        self.stopping = Some(true);
+    }
+
     // Initialize k8s if necessary
     pub fn init_k8s(&mut self, cli: Option<Client>) {
 
@@ -211,28 +609,230 @@ pub async fn controller_iteration() -> bool {
         true
     } else {
         let cli = ctrl.k8s_client.clone().unwrap();
-        match kube_lib::get_tenant_ids(
-            cli.clone(), &ctrl.namespace).await {
-            Ok(found_tenants) => {
-                debug!("preparing to write tenants");
-                // Drop read lock for current thread.
-                drop(ctrl);
-
-                // Acquire write lock for current thread..
-                let mut ctrl = CONTROLLER.write().await;
-                // Compute in memory state change.
-                ctrl.observe(found_tenants);
-                // Drop write lock.
-                drop(ctrl);
-            },
-            Err(msg) => {
-                error!("failed to acquire tenants, will not observe(): {}", msg);
-            }
+        let namespaces = ctrl.namespaces.clone();
+        let rule_selector = ctrl.rule_selector.clone();
+        let tenant_resources = ctrl.tenant_resources;
+        // Drop read lock, resources are applied under write lock.
+        drop(ctrl);
+        let found = match kube_lib::discover_open_metrics_rules(cli.clone(), &namespaces, &rule_selector).await {
+            Ok(rules) if tenant_resources => kube_lib::discover_open_metrics_tenants(cli, &namespaces, &rule_selector)
+                .await
+                .map(|tenants| FoundResources { rules, tenants }),
+            Ok(rules) => Ok(FoundResources { rules, tenants: vec![] }),
+            Err(msg) => Err(msg),
         };
+        apply_found_resources(found).await;
         false
     }
 }
 
+// Update tenants, external labels and settings from complete set of resources found in k8s,
+// or record sync failure.
+async fn apply_found_resources(found: Result<FoundResources, String>) {
+    match found {
+        Ok(found) => {
+            let namespace_rules = count_rules_by_namespace(&found.rules);
+            for (namespace, count) in namespace_rules.iter() {
+                debug!("found {} rules in {} namespace", count, namespace);
+            }
+            let mut found_labels: TenantLabels = kube_lib::discover_tenant_labels(&found.rules)
+                .into_iter()
+                .filter_map(|(tenant_id, labels)| {
+                    normalize_tenant_id(&tenant_id).ok().map(|t| (t, labels))
+                })
+                .collect();
+            debug!("preparing to write tenants");
+
+            // Acquire write lock for current thread..
+            let mut ctrl = CONTROLLER.write().await;
+            let settings = ctrl.settings_from_resources(found.tenants);
+            let mut found_tenants = ctrl.normalize_found_tenants(
+                kube_lib::discover_tenant_ids(found.rules));
+            for (tenant_id, tenant_settings) in settings.iter() {
+                // suspension takes precedence over rules listing tenant
+                if tenant_settings.suspended {
+                    found_tenants.remove(tenant_id);
+                } else {
+                    found_tenants.insert(tenant_id.clone());
+                }
+                // labels of tenant resource take precedence over the ones of rules
+                found_labels
+                    .entry(tenant_id.clone())
+                    .or_default()
+                    .extend(tenant_settings.external_labels.iter().map(|(k, v)| (k.clone(), v.clone())));
+            }
+            found_labels.retain(|_, labels| !labels.is_empty());
+            // Compute in memory state change.
+            ctrl.observe(found_tenants);
+            ctrl.observe_settings(settings);
+            ctrl.observe_labels(found_labels);
+            if let Some(gauge) = &ctrl.namespace_rules {
+                // namespaces without rules are not reported
+                gauge.reset();
+                for (namespace, count) in namespace_rules.iter() {
+                    gauge.with_label_values(&[namespace.as_str()]).set(*count as i64);
+                }
+            }
+            ctrl.synced = true;
+            ctrl.last_attempt_time = Some(Utc::now());
+            ctrl.last_success_time = ctrl.last_attempt_time;
+            ctrl.last_error = None;
+            // Drop write lock.
+            drop(ctrl);
+        },
+        Err(msg) => {
+            error!("failed to acquire tenants, will not observe(): {}", msg);
+            let mut ctrl = CONTROLLER.write().await;
+            ctrl.last_attempt_time = Some(Utc::now());
+            ctrl.last_error = Some(msg);
+            drop(ctrl);
+        }
+    };
+}
+
+// Api of single namespace, or of all namespaces when namespace is None.
+fn resource_api<K: k8s_openapi::Resource>(client: Client, namespace: &Option<String>) -> Api<K> {
+    match namespace {
+        Some(namespace) => Api::namespaced(client, namespace),
+        None => Api::all(client),
+    }
+}
+
+// Log watch event, and tell whether resources were listed again.
+fn is_relisted<K: Meta>(kind: &str, event: &Event<K>) -> bool {
+    match event {
+        Event::Applied(resource) => debug!(
+            "{} {} applied in {} namespace", kind, Meta::name(resource), Meta::namespace(resource).unwrap_or_default()),
+        Event::Deleted(resource) => debug!(
+            "{} {} deleted in {} namespace", kind, Meta::name(resource), Meta::namespace(resource).unwrap_or_default()),
+        Event::Restarted(resources) => {
+            debug!("{} resources listed: {}", kind, resources.len());
+            return true;
+        },
+    };
+    false
+}
+
+// Watch rules, and tenant resources when enabled, until full resync is due,
+// return true when controller is stopping.
+// Watcher resumes from last seen resourceVersion after watch timeouts and errors,
+// and bookmarks keep that version fresh, so resources are listed again only on resync
+// or when version is too old.
+// Every namespace is watched separately, namespaces matching selector are resolved again on resync.
+async fn watch_resources(
+    client: Client,
+    namespaces: &NamespaceScope,
+    rule_selector: &RuleSelector,
+    tenant_resources: bool,
+    retry_ms: u64,
+    resync_ms: u64,
+) -> bool {
+    let targets: Vec<Option<String>> = match namespaces {
+        NamespaceScope::All => vec![None],
+        _ => match list_namespaces(client.clone(), namespaces).await {
+            Ok(found) => found.into_iter().map(Some).collect(),
+            Err(msg) => {
+                apply_found_resources(Err(msg)).await;
+                sleep(Duration::from_millis(retry_ms)).await;
+                return CONTROLLER.read().await.stopping.is_some();
+            }
+        },
+    };
+    if targets.is_empty() {
+        warn!("no namespaces match {}", namespaces);
+        apply_found_resources(Ok(FoundResources::default())).await;
+    }
+    // each namespace and kind has own store, since relisting namespace replaces whole store
+    let mut rule_stores: Vec<Store<OpenMetricsRule>> = Vec::new();
+    let mut tenant_stores: Vec<Store<OpenMetricsTenant>> = Vec::new();
+    let mut watches = Vec::new();
+    for namespace in targets.iter() {
+        let idx = watches.len();
+        let writer = Writer::<OpenMetricsRule>::default();
+        rule_stores.push(writer.as_reader());
+        let api = resource_api(client.clone(), namespace);
+        watches.push(
+            reflector(writer, watcher(api, rule_selector.list_params().allow_bookmarks()))
+                .map(move |event| (idx, event.map(|e| is_relisted("rule", &e)).map_err(|e| e.to_string())))
+                .boxed()
+        );
+        if tenant_resources {
+            let idx = watches.len();
+            let writer = Writer::<OpenMetricsTenant>::default();
+            tenant_stores.push(writer.as_reader());
+            let api = resource_api(client.clone(), namespace);
+            watches.push(
+                reflector(writer, watcher(api, rule_selector.list_params().allow_bookmarks()))
+                    .map(move |event| (idx, event.map(|e| is_relisted("tenant", &e)).map_err(|e| e.to_string())))
+                    .boxed()
+            );
+        }
+    }
+    let mut listed = vec![false; watches.len()];
+    let events = stream::select_all(watches);
+    pin_mut!(events);
+
+    // zero interval disables periodic resync
+    let resync = async {
+        if resync_ms > 0 {
+            sleep(Duration::from_millis(resync_ms)).await
+        } else {
+            futures::future::pending::<()>().await
+        }
+    };
+    pin_mut!(resync);
+    let mut stop_check = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        tokio::select! {
+            event = events.next() => match event {
+                Some((idx, Ok(relisted))) => {
+                    if relisted {
+                        listed[idx] = true;
+                    }
+                    // stores already reflect the event, tenants are derived from all resources,
+                    // once every namespace is listed so none of them is missing meanwhile
+                    if listed.iter().all(|l| *l) {
+                        // resources of other controller classes are left out
+                        let found = FoundResources {
+                            rules: rule_stores
+                                .iter()
+                                .flat_map(|s| s.state())
+                                .filter(|rule| rule_selector.matches(rule))
+                                .collect(),
+                            tenants: tenant_stores
+                                .iter()
+                                .flat_map(|s| s.state())
+                                .filter(|tenant| rule_selector.matches_class(&tenant.spec.controller_class))
+                                .collect(),
+                        };
+                        apply_found_resources(Ok(found)).await;
+                    }
+                },
+                Some((_, Err(e))) => {
+                    apply_found_resources(Err(e)).await;
+                    // back off, watcher recovers on next poll
+                    sleep(Duration::from_millis(retry_ms)).await;
+                },
+                None => {
+                    // nothing to watch, look for namespaces again later
+                    sleep(Duration::from_millis(retry_ms)).await;
+                    return false;
+                },
+            },
+            _ = &mut resync => {
+                debug!("full k8s resync");
+                return false;
+            },
+            _ = stop_check.tick() => {
+                if CONTROLLER.read().await.stopping.is_some() {
+                    return true;
+                }
+            },
+        }
+    }
+}
+
 // Controller worker logic.
 // On start, acquire write lock
 pub async fn worker(k8s_client: Option<Client>) {
@@ -244,14 +844,19 @@ pub async fn worker(k8s_client: Option<Client>) {
     let ctrl = CONTROLLER.read().await;
 
     if ctrl.k8s_client.is_some() {
-        debug!("starting k8s poll loop");
-        let ms = ctrl.k8s_poll_ms;
+        debug!("starting k8s watch loop");
+        // it is safe to unwrap, since is_some() was checked
+        let client = ctrl.k8s_client.clone().unwrap();
+        let namespaces = ctrl.namespaces.clone();
+        let rule_selector = ctrl.rule_selector.clone();
+        let tenant_resources = ctrl.tenant_resources;
+        let retry_ms = ctrl.k8s_poll_ms;
+        let resync_ms = ctrl.k8s_resync_ms;
         drop(ctrl);
-        // Poll every two minutes
+        // Restart watch with fresh list on every resync
         loop {
-            sleep(Duration::from_millis(ms)).await;
-            debug!("next tick start");
-            let do_break = controller_iteration().await;
+            let do_break = watch_resources(
+                client.clone(), &namespaces, &rule_selector, tenant_resources, retry_ms, resync_ms).await;
             if do_break {
                 break;
             } else {
@@ -287,6 +892,9 @@ mod tests {
     use crate::controller::controller::worker;
     use std::borrow::Borrow;
     use serial_test::serial;
+    use kube_metrics_mutli_tenancy_lib::namespace::NamespaceScope;
+    use kube_metrics_mutli_tenancy_lib::selector::RuleSelector;
+    use kube_metrics_mutli_tenancy_lib::OpenMetricsTenant;
 
     fn init() {
         let _ = env_logger::builder().is_test(true).try_init();
@@ -511,4 +1119,316 @@ mod tests {
         drop(controller);
     }
 
-}
+    #[tokio::test]
+    #[serial]
+    pub async fn test_controller_watch_events() {
+        init();
+        let (mock_service, handle) = mock::pair::<Request<Body>, Response<Body>>();
+
+        let spawned = tokio::spawn(async move {
+            pin_mut!(handle);
+            // initial list
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert_eq!(request.uri().to_string(), "/apis/open-metrics.vgs.io/v1/namespaces/default/openmetricsrules?");
+            let mut l = test_fixture_2();
+            l["metadata"]["resourceVersion"] = serde_json::json!("10");
+            send.send_response(Response::builder().body(Body::from(serde_json::to_string(&l).unwrap())).unwrap());
+
+            // watch resumes from listed version, and receives added rule
+            let (request, send) = handle.next_request().await.expect("service not called");
+            let uri = request.uri().to_string();
+            assert!(uri.contains("watch=true&resourceVersion=10"), "{}", uri);
+            assert!(uri.contains("allowWatchBookmarks=true"), "{}", uri);
+            let added = serde_json::json!({
+                "type": "ADDED",
+                "object": {
+                    "apiVersion": "open-metrics.vgs.io/v1",
+                    "kind": "OpenMetricsRule",
+                    "metadata": {"name": "test2", "namespace": "default", "resourceVersion": "11"},
+                    "spec": {"tenants": ["tenant10"]}
+                }
+            });
+            let bookmark = serde_json::json!({
+                "type": "BOOKMARK",
+                "object": {
+                    "apiVersion": "open-metrics.vgs.io/v1",
+                    "kind": "OpenMetricsRule",
+                    "metadata": {"resourceVersion": "12"}
+                }
+            });
+            send.send_response(
+                Response::builder()
+                    .body(Body::from(format!("{}\n{}\n", added, bookmark)))
+                    .unwrap(),
+            );
+
+            // watch is restarted from bookmarked version once stream ends
+            let (request, _send) = handle.next_request().await.expect("service not called");
+            assert!(request.uri().to_string().contains("resourceVersion=12"));
+        });
+
+        let service = Service::new(mock_service);
+        let k8s_client = Client::new(service);
+
+        let mut controller = crate::CONTROLLER.write().await;
+        controller.set_k8s_poll_delay(800).set_k8s_resync_interval(0);
+        drop(controller);
+
+        let worker_handle = tokio::spawn(worker(Some(k8s_client.clone())));
+        sleep(Duration::from_millis(500)).await;
+
+        let controller = crate::CONTROLLER.read().await;
+        let expected_tenants: HashSet<String> = HashSet::from_iter(
+            vec![
+                String::from("tenant7"),
+                String::from("tenant9"),
+                String::from("tenant10"),
+            ]
+        );
+        let found_tenants: HashSet<String> = HashSet::from_iter(controller.get_tenants().iter().cloned().into_iter());
+        assert_eq!(expected_tenants, found_tenants);
+        drop(controller);
+        spawned.await.unwrap();
+
+        // Clean up global state
+        let mut controller = crate::CONTROLLER.write().await;
+        controller.stopping = Some(true);
+        sleep(Duration::from_secs(1)).await;
+        controller.clean();
+        worker_handle.abort();
+        drop(controller);
+    }
+
+    #[test]
+    fn test_replace_initial_allowed_tenants() {
+        let mut controller = crate::controller::controller::IngestionTenantController::new();
+        controller.set_initial_allowed_tenants(vec![String::from("0"), String::from("tenant1")]);
+        controller.add_tenant(&String::from("tenant2"));
+        controller.add_tenant(&String::from("tenant3"));
+
+        controller.replace_initial_allowed_tenants(vec![String::from("0"), String::from("tenant3")]);
+
+        let expected_tenants: HashSet<String> = HashSet::from_iter(
+            vec![
+                String::from("0"),
+                String::from("tenant2"),
+                String::from("tenant3"),
+            ]
+        );
+        let found_tenants: HashSet<String> = HashSet::from_iter(controller.get_tenants().iter().cloned().into_iter());
+        assert_eq!(expected_tenants, found_tenants);
+        assert_eq!(controller.get_tenants().len(), 3);
+        // request path sees the same tenants
+        assert_eq!(controller.snapshot().load().tenants, expected_tenants);
+    }
+
+    #[tokio::test]
+    #[serial]
+    pub async fn test_controller_watch_namespaces() {
+        init();
+        let (mock_service, handle) = mock::pair::<Request<Body>, Response<Body>>();
+
+        let spawned = tokio::spawn(async move {
+            pin_mut!(handle);
+            // namespaces are listed separately, in any order
+            for _ in 0..2 {
+                let (request, send) = handle.next_request().await.expect("service not called");
+                let uri = request.uri().to_string();
+                assert!(uri.contains("labelSelector=cortex%3Dprod"), "{}", uri);
+                let (namespace, tenant_id) = if uri.starts_with("/apis/open-metrics.vgs.io/v1/namespaces/team-a/") {
+                    ("team-a", "tenant1")
+                } else {
+                    assert!(uri.starts_with("/apis/open-metrics.vgs.io/v1/namespaces/team-b/"), "{}", uri);
+                    ("team-b", "tenant2")
+                };
+                let l = serde_json::json!({
+                    "apiVersion": "v1",
+                    "kind": "List",
+                    "metadata": {"resourceVersion": "10"},
+                    "items": [{
+                        "apiVersion": "open-metrics.vgs.io/v1",
+                        "kind": "OpenMetricsRule",
+                        "metadata": {"name": "rule", "namespace": namespace},
+                        "spec": {"tenants": [tenant_id], "controller_class": "prod"}
+                    }, {
+                        "apiVersion": "open-metrics.vgs.io/v1",
+                        "kind": "OpenMetricsRule",
+                        "metadata": {"name": "staging-rule", "namespace": namespace},
+                        "spec": {"tenants": ["staging"], "controller_class": "staging"}
+                    }]
+                });
+                send.send_response(Response::builder().body(Body::from(l.to_string())).unwrap());
+            }
+        });
+
+        let k8s_client = Client::new(Service::new(mock_service));
+        let gauge = prometheus::IntGaugeVec::new(
+            prometheus::Opts::new("k8s_rules", "help"), &["namespace"]).unwrap();
+
+        let mut controller = crate::CONTROLLER.write().await;
+        controller
+            .set_k8s_poll_delay(800)
+            .set_k8s_resync_interval(0)
+            .set_namespace_rules_gauge(gauge.clone())
+            .set_namespace_scope(NamespaceScope::parse("team-a,team-b", ""))
+            .set_rule_selector(RuleSelector::new("cortex=prod", "", "prod"));
+        drop(controller);
+
+        let worker_handle = tokio::spawn(worker(Some(k8s_client.clone())));
+        spawned.await.unwrap();
+        sleep(Duration::from_millis(300)).await;
+
+        let controller = crate::CONTROLLER.read().await;
+        let expected_tenants: HashSet<String> = HashSet::from_iter(
+            vec![String::from("tenant1"), String::from("tenant2")]
+        );
+        let found_tenants: HashSet<String> = controller.get_tenants().iter().cloned().collect();
+        assert_eq!(expected_tenants, found_tenants);
+        assert_eq!(controller.sync_status().namespace, "team-a,team-b");
+        assert_eq!(gauge.with_label_values(&["team-a"]).get(), 1);
+        assert_eq!(gauge.with_label_values(&["team-b"]).get(), 1);
+        drop(controller);
+
+        // Clean up global state
+        let mut controller = crate::CONTROLLER.write().await;
+        controller.stopping = Some(true);
+        sleep(Duration::from_secs(1)).await;
+        controller.clean();
+        controller.namespace_rules = None;
+        controller
+            .set_namespace_scope(NamespaceScope::parse("", ""))
+            .set_rule_selector(RuleSelector::default());
+        worker_handle.abort();
+        drop(controller);
+    }
+
+    #[tokio::test]
+    #[serial]
+    pub async fn test_controller_watch_tenant_resources() {
+        init();
+        let (mock_service, handle) = mock::pair::<Request<Body>, Response<Body>>();
+
+        let spawned = tokio::spawn(async move {
+            pin_mut!(handle);
+            // rules and tenant resources are listed separately, in any order
+            for _ in 0..2 {
+                let (request, send) = handle.next_request().await.expect("service not called");
+                let uri = request.uri().to_string();
+                let l = if uri.starts_with("/apis/open-metrics.vgs.io/v1/namespaces/default/openmetricsrules?") {
+                    test_fixture_2()
+                } else {
+                    assert!(uri.starts_with("/apis/open-metrics.vgs.io/v1/namespaces/default/openmetricstenants?"), "{}", uri);
+                    serde_json::json!({
+                        "apiVersion": "v1",
+                        "kind": "List",
+                        "metadata": {"resourceVersion": "10"},
+                        "items": [{
+                            "apiVersion": "open-metrics.vgs.io/v1",
+                            "kind": "OpenMetricsTenant",
+                            "metadata": {"name": "tenant7", "namespace": "default"},
+                            "spec": {"suspended": true}
+                        }, {
+                            "apiVersion": "open-metrics.vgs.io/v1",
+                            "kind": "OpenMetricsTenant",
+                            "metadata": {"name": "team-a", "namespace": "default"},
+                            "spec": {
+                                "tenant_id": "tenant10",
+                                "limits": {"samples_per_second": 100.0},
+                                "external_labels": {"team": "a"},
+                                "replicate_to": ["audit"]
+                            }
+                        }]
+                    })
+                };
+                send.send_response(Response::builder().body(Body::from(l.to_string())).unwrap());
+            }
+        });
+
+        let k8s_client = Client::new(Service::new(mock_service));
+
+        let mut controller = crate::CONTROLLER.write().await;
+        controller
+            .set_k8s_poll_delay(800)
+            .set_k8s_resync_interval(0)
+            .set_tenant_resources(true);
+        drop(controller);
+
+        let worker_handle = tokio::spawn(worker(Some(k8s_client.clone())));
+        spawned.await.unwrap();
+        sleep(Duration::from_millis(300)).await;
+
+        let controller = crate::CONTROLLER.read().await;
+        // suspended tenant is not allowed, though rule lists it
+        let expected_tenants: HashSet<String> = HashSet::from_iter(
+            vec![String::from("tenant9"), String::from("tenant10")]
+        );
+        let snapshot = controller.snapshot().load();
+        assert_eq!(snapshot.tenants, expected_tenants);
+        assert!(snapshot.is_suspended("tenant7"));
+        assert_eq!(snapshot.external_labels["tenant10"]["team"], "a");
+        assert_eq!(snapshot.replicate_to["tenant10"], vec![String::from("audit")]);
+        assert_eq!(snapshot.settings["tenant10"].resource, "default/team-a");
+        assert_eq!(snapshot.settings["tenant10"].limits.samples_per_second, Some(100.0));
+        // suspended tenant is reported along with its settings
+        let statuses = controller.get_tenant_statuses();
+        let tenant_ids: Vec<&String> = statuses.iter().map(|s| &s.tenant_id).collect();
+        assert_eq!(tenant_ids, vec!["tenant10", "tenant7", "tenant9"]);
+        assert!(statuses[1].settings.as_ref().unwrap().suspended);
+        assert!(statuses[2].settings.is_none());
+        assert!(controller.sync_status().tenant_resources);
+        drop(controller);
+
+        // Clean up global state
+        let mut controller = crate::CONTROLLER.write().await;
+        controller.stopping = Some(true);
+        sleep(Duration::from_secs(1)).await;
+        controller.clean();
+        controller.set_tenant_resources(false);
+        worker_handle.abort();
+        drop(controller);
+    }
+
+    #[test]
+    fn test_settings_from_resources() {
+        let controller = crate::controller::controller::IngestionTenantController::new();
+        let resource = |namespace: &str, name: &str, spec: Value| {
+            serde_json::from_value::<OpenMetricsTenant>(serde_json::json!({
+                "apiVersion": "open-metrics.vgs.io/v1",
+                "kind": "OpenMetricsTenant",
+                "metadata": {"name": name, "namespace": namespace},
+                "spec": spec
+            })).unwrap()
+        };
+        let settings = controller.settings_from_resources(vec![
+            resource("team-b", "shared", serde_json::json!({"suspended": true})),
+            resource("team-a", "shared", serde_json::json!({"replicate_to": ["audit", "a/b"]})),
+            resource("team-a", "invalid", serde_json::json!({"tenant_id": "a b"})),
+        ]);
+
+        // the first resource by namespace and name wins
+        assert_eq!(settings.len(), 1);
+        assert_eq!(settings["shared"].resource, "team-a/shared");
+        assert!(!settings["shared"].suspended);
+        // invalid replication targets are left out
+        assert_eq!(settings["shared"].replicate_to, vec![String::from("audit")]);
+    }
+
+    #[test]
+    fn test_normalize_found_tenants() {
+        let rejected = prometheus::IntCounterVec::new(
+            prometheus::Opts::new("rejected_tenant_ids", "help"), &["source", "reason"]).unwrap();
+        let mut controller = crate::controller::controller::IngestionTenantController::new();
+        controller.set_rejected_tenant_ids_counter(rejected.clone());
+
+        let found = controller.normalize_found_tenants(HashSet::from_iter(vec![
+            String::from("tenant1"),
+            String::from(".."),
+            String::from("tenant/2"),
+        ]));
+
+        assert_eq!(found, HashSet::from_iter(vec![String::from("tenant1")]));
+        assert_eq!(rejected.with_label_values(&["kubernetes", "reserved"]).get(), 1);
+        assert_eq!(rejected.with_label_values(&["kubernetes", "invalid_character"]).get(), 1);
+    }
+
+}
\ No newline at end of file
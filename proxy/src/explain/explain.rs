@@ -0,0 +1,189 @@
+use std::collections::{BTreeMap, HashMap};
+
+use kube_metrics_mutli_tenancy_lib::tenant::normalize_tenant_id;
+use serde::Serialize;
+
+use crate::metrics::metrics::process_time_serie;
+use crate::proto::prometheus::{Label, TimeSeries, WriteRequest};
+
+
+// Tenant label found on a series
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MatchedLabel {
+    pub name: String,
+    pub value: String,
+    // tenant ID label value is normalized to, if it is valid
+    pub tenant_id: Option<String>,
+    // why label value is not a valid tenant ID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub invalid: Option<String>,
+    // false when label value is not in allow-list, so series is not routed by this label
+    pub allowed: bool,
+}
+
+// Tenant series would be forwarded to, and why
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TenantRoute {
+    pub tenant_id: String,
+    // tenant label which value routed series to tenant
+    pub matched_label: Option<String>,
+    // whether series is replicated to tenant regardless of labels
+    pub replicated: bool,
+}
+
+// Routing decision for single series
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SeriesExplanation {
+    pub labels: BTreeMap<String, String>,
+    pub matched_labels: Vec<MatchedLabel>,
+    pub tenants: Vec<TenantRoute>,
+}
+
+// Build time serie from label set, as received in JSON explain request.
+pub fn time_serie_from_labels(labels: &BTreeMap<String, String>) -> TimeSeries {
+    let mut time_series = TimeSeries::new();
+    for (name, value) in labels.iter() {
+        let mut label = Label::new();
+        label.name = name.clone();
+        label.value = value.clone();
+        time_series.labels.push(label);
+    }
+    time_series
+}
+
+// Explain routing of single time serie.
+// Tenants are decided by the same process_time_serie() forwarding uses, nothing is sent upstream.
+pub fn explain_time_serie(
+    time_series: &TimeSeries,
+    tenant_labels: &[String],
+    allow_listed_tenants: &[String],
+    does_allow_list: bool,
+    replicate_to: &[String],
+    tenant_replicate_to: &HashMap<String, Vec<String>>,
+) -> SeriesExplanation {
+    let mut tenant_data = HashMap::<String, WriteRequest>::new();
+    process_time_serie(
+        time_series,
+        &tenant_labels.iter().cloned().collect(),
+        &allow_listed_tenants.iter().cloned().collect(),
+        does_allow_list,
+        &replicate_to.to_vec(),
+        tenant_replicate_to,
+        &mut tenant_data,
+    );
+
+    let matched_labels: Vec<MatchedLabel> = time_series
+        .labels
+        .iter()
+        .filter(|label| tenant_labels.contains(&label.name))
+        .map(|label| {
+            let tenant_id = normalize_tenant_id(&label.value);
+            MatchedLabel {
+                name: label.name.clone(),
+                value: label.value.clone(),
+                allowed: match &tenant_id {
+                    Ok(t) => !does_allow_list || allow_listed_tenants.contains(t),
+                    Err(_) => false,
+                },
+                invalid: tenant_id.as_ref().err().map(|e| e.to_string()),
+                tenant_id: tenant_id.ok(),
+            }
+        })
+        .collect();
+
+    let mut tenants: Vec<TenantRoute> = tenant_data
+        .keys()
+        .map(|tenant_id| TenantRoute {
+            tenant_id: tenant_id.clone(),
+            matched_label: matched_labels
+                .iter()
+                .find(|m| m.allowed && m.tenant_id.as_ref() == Some(tenant_id))
+                .map(|m| m.name.clone()),
+            replicated: replicate_to.contains(tenant_id) || tenant_data
+                .keys()
+                .filter_map(|routed| tenant_replicate_to.get(routed))
+                .any(|targets| targets.contains(tenant_id)),
+        })
+        .collect();
+    tenants.sort_by(|a, b| a.tenant_id.cmp(&b.tenant_id));
+
+    SeriesExplanation {
+        labels: time_series
+            .labels
+            .iter()
+            .map(|label| (label.name.clone(), label.value.clone()))
+            .collect(),
+        matched_labels,
+        tenants,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use crate::explain::explain::{explain_time_serie, time_serie_from_labels, TenantRoute};
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_explain_allowed_and_replicated() {
+        let ts = time_serie_from_labels(&labels(&[("__name__", "up"), ("tenant_id", "tenant1")]));
+        let explanation = explain_time_serie(
+            &ts,
+            &strings(&["tenant_id"]),
+            &strings(&["0", "tenant1"]),
+            true,
+            &strings(&["0"]),
+            &HashMap::new(),
+        );
+
+        assert_eq!(explanation.matched_labels.len(), 1);
+        assert!(explanation.matched_labels[0].allowed);
+        assert_eq!(explanation.tenants, vec![
+            TenantRoute { tenant_id: String::from("0"), matched_label: None, replicated: true },
+            TenantRoute {
+                tenant_id: String::from("tenant1"),
+                matched_label: Some(String::from("tenant_id")),
+                replicated: false,
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_explain_filtered_by_allow_list() {
+        let ts = time_serie_from_labels(&labels(&[("__name__", "up"), ("tnt", "tenant9")]));
+        let explanation = explain_time_serie(
+            &ts,
+            &strings(&["tenant_id", "tnt"]),
+            &strings(&["tenant1"]),
+            true,
+            &[],
+            &HashMap::new(),
+        );
+
+        assert_eq!(explanation.matched_labels[0].name, "tnt");
+        assert!(!explanation.matched_labels[0].allowed);
+        assert!(explanation.tenants.is_empty());
+
+        // label value Cortex would refuse is never routed
+        let ts = time_serie_from_labels(&labels(&[("__name__", "up"), ("tnt", "../")]));
+        let explanation = explain_time_serie(&ts, &strings(&["tnt"]), &[], false, &[], &HashMap::new());
+        assert!(!explanation.matched_labels[0].allowed);
+        assert!(explanation.matched_labels[0].invalid.is_some());
+        assert!(explanation.tenants.is_empty());
+
+        let ts = time_serie_from_labels(&labels(&[("__name__", "up"), ("tnt", "tenant9")]));
+        // without allow-listing, label value is used as is
+        let explanation = explain_time_serie(&ts, &strings(&["tnt"]), &[], false, &[], &HashMap::new());
+        assert!(explanation.matched_labels[0].allowed);
+        assert_eq!(explanation.tenants[0].tenant_id, "tenant9");
+        assert_eq!(explanation.tenants[0].matched_label, Some(String::from("tnt")));
+    }
+}
@@ -0,0 +1 @@
+pub mod explain;
\ No newline at end of file
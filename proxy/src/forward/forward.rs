@@ -1,21 +1,39 @@
 #![deny(warnings)]
 use futures::StreamExt;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::Infallible;
 use std::time::Instant;
 use std::sync::Arc;
 
+use kube_metrics_mutli_tenancy_lib::logging::LogEvent;
+use kube_metrics_mutli_tenancy_lib::telemetry;
 use log::debug;
-use prometheus::{Counter, IntCounterVec, Histogram};
+use opentelemetry::trace::{SpanKind, TraceContextExt};
+use opentelemetry::{Context, KeyValue};
+use prometheus::{Counter, IntCounterVec, Histogram, HistogramVec};
 use proto::prometheus::{MetricMetadata, WriteRequest};
 use protobuf::Message;
 use snap;
 use tokio::task::JoinError;
 use warp::http::StatusCode;
 
+use crate::breaker;
+use crate::controller;
+use crate::labels;
+use crate::lifecycle;
+use crate::limits;
 use crate::metrics;
+use crate::mirror;
 use crate::proto;
+use crate::status;
+use breaker::breaker::CircuitBreakers;
+use controller::controller::TenantSnapshot;
+use labels::labels::{inject_external_labels, LabelConflictPolicy};
+use lifecycle::lifecycle::LIFECYCLE;
+use limits::limits::{Admission, LIMITER};
 use metrics::metrics::process_time_serie;
+use mirror::mirror::Mirror;
+use status::status::USAGE;
 
 
 pub enum ForwardingStatistics {
@@ -26,6 +44,12 @@ pub enum ForwardingStatistics {
     TotalRequests = 4,
     NumFailures = 5,
     ProcessingTime = 6,
+    BytesReceived = 7,
+    BytesSent = 8,
+    UpstreamResponses = 9,
+    UpstreamLatency = 10,
+    RejectedTenantIds = 11,
+    DroppedSeries = 12,
 }
 
 // unpacks Snappy payload
@@ -33,49 +57,63 @@ pub enum ForwardingStatistics {
 // sends proxied data to upstreams
 pub async fn process_proxy_payload(
     _client: reqwest::Client,
-    _tenant_labels: Vec<String>,
-    _allow_listed_tenants: Arc<&Vec<String>>,
+    _tenant_labels: HashSet<String>,
+    _snapshot: Arc<TenantSnapshot>,
     _does_allow_list: bool,
     _replicate_to: Vec<String>,
+    _label_conflict_policy: LabelConflictPolicy,
     _ingester_stream_url: String,
+    _named_upstreams: BTreeMap<String, String>,
+    _mirror: Arc<Mirror>,
+    _breakers: Arc<CircuitBreakers>,
     _parallel_request_per_load: u16,
     _internal_stats: &HashMap<u8, Counter>,
     _internal_stats_vec: &HashMap<u8, IntCounterVec>,
     _internal_stats_histograms: &HashMap<u8, Histogram>,
+    _internal_stats_histogram_vecs: &HashMap<u8, HistogramVec>,
+    _request_id: String,
     _bytes: warp::hyper::body::Bytes,
 ) -> Result<impl warp::Reply, Infallible> {
     return {
-        // deserialize prom write request
-
-        // FIXME: factor out deserialization code into separate fn
-
-        let mut uncompressed_pb_message =
-            match snap::raw::Decoder::new().decompress_vec(_bytes.to_vec().as_ref()) {
-                Ok(v) => v,
-                Err(e) => {
-                    // return early to indicate error
-                    return Ok(warp::reply::with_status(
-                        warp::reply::html(e.to_string()),
-                        StatusCode::BAD_REQUEST,
-                    ));
-                }
-            };
+        // keep track of in-flight request, so shutdown can drain it
+        let _in_flight = match LIFECYCLE.track() {
+            Some(guard) => guard,
+            None => {
+                // process is going down, let Prometheus retry on another replica
+                return Ok(warp::reply::with_status(
+                    warp::reply::html(String::from("shutting down")),
+                    StatusCode::SERVICE_UNAVAILABLE,
+                ));
+            }
+        };
 
-        debug!(
-            "::: request length decompressed : {}b",
-            uncompressed_pb_message.len().to_string()
+        let request_cx = telemetry::start_span(
+            &Context::new(),
+            "remote_write",
+            SpanKind::Server,
+            vec![
+                KeyValue::new("http.request_content_length", _bytes.len() as i64),
+                KeyValue::new("request_id", _request_id.clone()),
+            ],
         );
-        let write_request = match WriteRequest::parse_from_bytes(&mut uncompressed_pb_message) {
-            Ok(req) => req,
-            // invalid protobuf in request
+
+        // deserialize prom write request
+        let decode_cx = telemetry::start_span(&request_cx, "decode", SpanKind::Internal, vec![]);
+        let write_request = match decode_write_request(&_bytes) {
+            Ok(req) => {
+                decode_cx.span().set_attribute(KeyValue::new("series", req.timeseries.len() as i64));
+                decode_cx.span().end();
+                req
+            },
             Err(e) => {
-                return Ok(
-                    // return early to indicate error
-                    warp::reply::with_status(
-                        warp::reply::html(e.to_string()),
-                        StatusCode::BAD_REQUEST,
-                    ),
-                )
+                telemetry::record_error(&decode_cx, &e);
+                decode_cx.span().end();
+                telemetry::record_status(&request_cx, StatusCode::BAD_REQUEST.as_u16());
+                // return early to indicate error
+                return Ok(warp::reply::with_status(
+                    warp::reply::html(e),
+                    StatusCode::BAD_REQUEST,
+                ));
             }
         };
 
@@ -106,22 +144,51 @@ pub async fn process_proxy_payload(
             .get(&(ForwardingStatistics::TotalRequests as u8))
             .unwrap();
 
+        let bytes_received: &IntCounterVec = _internal_stats_vec
+            .get(&(ForwardingStatistics::BytesReceived as u8))
+            .unwrap();
+        let bytes_sent: &IntCounterVec = _internal_stats_vec
+            .get(&(ForwardingStatistics::BytesSent as u8))
+            .unwrap();
+        let upstream_responses: &IntCounterVec = _internal_stats_vec
+            .get(&(ForwardingStatistics::UpstreamResponses as u8))
+            .unwrap();
+        let rejected_tenant_ids: &IntCounterVec = _internal_stats_vec
+            .get(&(ForwardingStatistics::RejectedTenantIds as u8))
+            .unwrap();
+        let dropped_series: &IntCounterVec = _internal_stats_vec
+            .get(&(ForwardingStatistics::DroppedSeries as u8))
+            .unwrap();
+
         let histogram: &Histogram = _internal_stats_histograms
             .get(&(ForwardingStatistics::ProcessingTime as u8))
             .unwrap();
+        let upstream_latency: &HistogramVec = _internal_stats_histogram_vecs
+            .get(&(ForwardingStatistics::UpstreamLatency as u8))
+            .unwrap();
+
+        // sizes of whole payload, to estimate tenant share of compressed bytes
+        let received_compressed = _bytes.len() as u64;
+        let received_uncompressed = write_request.compute_size() as u64;
 
         // aggregate metrics by tenant
+        let split_cx = telemetry::start_span(&request_cx, "tenant_split", SpanKind::Internal, vec![]);
         for time_series in write_request.timeseries.into_iter() {
-            let (tenants, labels) = process_time_serie(
+            let (tenants, labels, rejected) = process_time_serie(
                 &time_series,
                 &_tenant_labels,
-                &_allow_listed_tenants,
+                &_snapshot.tenants,
                 _does_allow_list,
                 &_replicate_to,
+                &_snapshot.replicate_to,
                 &mut tenant_data,
             );
             tenants_detected.inc_by(tenants as f64);
             num_labels.inc_by(labels as f64);
+            for e in rejected.iter() {
+                debug!("tenant label value rejected: {}", e);
+                rejected_tenant_ids.with_label_values(&["label", e.reason()]).inc();
+            }
         }
 
         // append metadata for each request
@@ -132,6 +199,76 @@ pub async fn process_proxy_payload(
             num_metadata.inc()
         }
 
+        for (tenant_id, req) in tenant_data.iter() {
+            // tenant share of received payload, before external labels are added
+            let share_uncompressed = req.compute_size() as u64;
+            bytes_received
+                .with_label_values(&[tenant_id.as_str(), "uncompressed"])
+                .inc_by(share_uncompressed);
+            if received_uncompressed > 0 {
+                bytes_received
+                    .with_label_values(&[tenant_id.as_str(), "compressed"])
+                    .inc_by(share_uncompressed * received_compressed / received_uncompressed);
+            }
+        }
+
+        // apply tenant settings: drop suspended tenants, pick upstream, and enforce limits
+        let now = Instant::now();
+        let mut tenant_urls = HashMap::<String, String>::new();
+        let mut rate_limited = 0;
+        tenant_data.retain(|tenant_id, req| {
+            let settings = _snapshot.settings.get(tenant_id);
+            let url = match settings.and_then(|s| s.upstream.as_ref()) {
+                Some(name) => _named_upstreams.get(name),
+                None => Some(&_ingester_stream_url),
+            };
+            let reason = match (settings, url) {
+                (Some(s), _) if s.suspended => Some("suspended"),
+                (_, None) => Some("unknown_upstream"),
+                (Some(s), _) if !s.limits.is_empty() => match LIMITER.admit(tenant_id, &s.limits, req, now) {
+                    Admission::RateLimited => {
+                        rate_limited += 1;
+                        Some("rate_limited")
+                    },
+                    Admission::Accepted { dropped_series: dropped } => {
+                        if dropped > 0 {
+                            dropped_series
+                                .with_label_values(&[tenant_id.as_str(), "series_limit"])
+                                .inc_by(dropped as u64);
+                        }
+                        None
+                    },
+                },
+                _ => None,
+            };
+            if let Some(reason) = reason {
+                debug!("dropping {} series of tenant {}: {}", req.timeseries.len(), tenant_id, reason);
+                dropped_series
+                    .with_label_values(&[tenant_id.as_str(), reason])
+                    .inc_by(req.timeseries.len() as u64);
+                return false;
+            }
+            // it is safe to unwrap, since missing upstream is dropped above
+            tenant_urls.insert(tenant_id.clone(), url.unwrap().clone());
+            !req.timeseries.is_empty()
+        });
+
+        // add external labels after routing, so they never affect tenant detection
+        for (tenant_id, req) in tenant_data.iter_mut() {
+            if let Some(tenant_external_labels) = _snapshot.external_labels.get(tenant_id) {
+                for time_series in req.timeseries.iter_mut() {
+                    inject_external_labels(
+                        time_series,
+                        tenant_external_labels,
+                        _label_conflict_policy,
+                    );
+                }
+            }
+        }
+
+        split_cx.span().set_attribute(KeyValue::new("tenants", tenant_data.len() as i64));
+        split_cx.span().end();
+
         for tenant_id in tenant_data.keys() {
             total_requests
                 .with_label_values(&[tenant_id.as_str()])
@@ -139,43 +276,165 @@ pub async fn process_proxy_payload(
             num_series
                 .with_label_values(&[tenant_id.as_str()])
                 .inc_by(tenant_data.get(tenant_id).unwrap().timeseries.len() as u64);
+            // usage is reported into status of tenant resource
+            if _snapshot.settings.contains_key(tenant_id) {
+                USAGE.record_write(tenant_id, &tenant_data[tenant_id], now);
+            }
+        }
+
+        // let Prometheus back off when every tenant of request is over its rate
+        if tenant_data.is_empty() && rate_limited > 0 {
+            histogram.observe(in_ms.elapsed().as_millis() as f64);
+            telemetry::record_status(&request_cx, StatusCode::TOO_MANY_REQUESTS.as_u16());
+            return Ok(warp::reply::with_status(
+                warp::reply::html(String::from("rate limit exceeded")),
+                StatusCode::TOO_MANY_REQUESTS,
+            ));
         }
 
+        // fail fast while upstream is degraded, instead of piling up requests
+        let sends_requests = !tenant_data.is_empty();
+        // only upstreams which get series take permits, others would hold probe slot
+        let upstream_urls: HashSet<String> = tenant_data.keys().filter_map(|t| tenant_urls.get(t)).cloned().collect();
+        let mut breaker_permits = HashMap::new();
+        let mut open_urls: HashSet<String> = HashSet::new();
+        for url in upstream_urls.iter() {
+            match _breakers.allow(url) {
+                Some(permit) => {
+                    breaker_permits.insert(url.clone(), permit);
+                },
+                None => {
+                    open_urls.insert(url.clone());
+                },
+            };
+        }
+        if sends_requests && open_urls.len() == upstream_urls.len() {
+            debug!("circuit breakers for {:?} are open, rejecting request", open_urls);
+            num_failures.inc();
+            histogram.observe(in_ms.elapsed().as_millis() as f64);
+            telemetry::record_status(&request_cx, StatusCode::SERVICE_UNAVAILABLE.as_u16());
+            return Ok(warp::reply::with_status(
+                warp::reply::html(String::from("circuit breaker is open")),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ));
+        }
+        // tenants of other upstreams are still forwarded, the rest fail
+        let before = tenant_data.len();
+        tenant_data.retain(|tenant_id, _| !open_urls.contains(&tenant_urls[tenant_id]));
+        let breaker_failures = (before - tenant_data.len()) as u16;
+
         let responses = futures::stream::iter(tenant_data.into_iter())
             .map(
                 |(_tenant_id, tenant_request): (String, WriteRequest)| {
                     // save necessary context on a stack
                     let tenant_id_clone = _tenant_id.clone();
                     let r_client = _client.clone();
-                    let url = _ingester_stream_url.clone();
+                    let url = tenant_urls[&_tenant_id].clone();
+                    let result_url = url.clone();
+                    let request_id = _request_id.clone();
+                    let track_usage = _snapshot.settings.contains_key(&_tenant_id);
 
                     // serialize request
                     // it is safe to do unwrap: if the original data didn't offend warp limits,
                     // the replicated data won't offend reqwest limits
                     let tenant_request_serialized = tenant_request.write_to_bytes().unwrap();
-                    let tenant_request_compressed = snap::raw::Encoder::new()
-                        .compress_vec(&tenant_request_serialized)
-                        .unwrap();
+                    let tenant_request_compressed = bytes::Bytes::from(
+                        snap::raw::Encoder::new()
+                            .compress_vec(&tenant_request_serialized)
+                            .unwrap(),
+                    );
+
+                    bytes_sent
+                        .with_label_values(&[tenant_id_clone.as_str(), "uncompressed"])
+                        .inc_by(tenant_request_serialized.len() as u64);
+                    bytes_sent
+                        .with_label_values(&[tenant_id_clone.as_str(), "compressed"])
+                        .inc_by(tenant_request_compressed.len() as u64);
+                    let tenant_responses = upstream_responses.clone();
+                    let tenant_latency = upstream_latency.with_label_values(&[tenant_id_clone.as_str()]);
+
+                    // shadow request to secondary upstreams, never awaited
+                    _mirror.mirror(&r_client, &tenant_id_clone, &tenant_request_compressed);
+
+                    let forward_cx = telemetry::start_span(
+                        &request_cx,
+                        "forward",
+                        SpanKind::Client,
+                        vec![
+                            KeyValue::new("tenant_id", tenant_id_clone.clone()),
+                            KeyValue::new("http.url", url.clone()),
+                            KeyValue::new("series", tenant_request.timeseries.len() as i64),
+                        ],
+                    );
 
                     // spawn origin request in async manner
-                    tokio::spawn(async move {
-                        r_client
+                    let handle = tokio::spawn(async move {
+                        let started = Instant::now();
+                        let mut request = r_client
                             .post(&url)
                             .body(tenant_request_compressed)
-                            .header("X-Scope-OrgID", tenant_id_clone)
-                            .send()
-                            .await
-                    })
+                            .header("X-Scope-OrgID", tenant_id_clone.as_str());
+                        // propagate W3C trace context to upstream
+                        for (name, value) in telemetry::trace_headers(&forward_cx) {
+                            request = request.header(name.as_str(), value);
+                        }
+                        let response = request.send().await;
+                        let elapsed = started.elapsed().as_millis() as u64;
+                        tenant_latency.observe(elapsed as f64);
+                        let access = LogEvent::access("proxy", &request_id)
+                            .tenant(&tenant_id_clone)
+                            .upstream(&url)
+                            .duration_ms(elapsed);
+                        let status = match &response {
+                            Ok(r) => {
+                                if track_usage && !r.status().is_success() {
+                                    USAGE.record_upstream_error(
+                                        &tenant_id_clone,
+                                        &format!("upstream responded with status {}", r.status().as_u16()),
+                                    );
+                                }
+                                telemetry::record_status(&forward_cx, r.status().as_u16());
+                                access.status(r.status().as_u16()).emit();
+                                r.status().as_u16().to_string()
+                            },
+                            Err(e) => {
+                                if track_usage {
+                                    USAGE.record_upstream_error(&tenant_id_clone, &e.to_string());
+                                }
+                                telemetry::record_error(&forward_cx, &e.to_string());
+                                access.status("error").error(&e.to_string()).emit();
+                                String::from("error")
+                            },
+                        };
+                        tenant_responses
+                            .with_label_values(&[tenant_id_clone.as_str(), status.as_str()])
+                            .inc();
+                        forward_cx.span().end();
+                        response
+                    });
+                    async move { (result_url, handle.await) }
                 }, // keep limitation for number of parallel requests to not to overload
                    // distributor backend
             )
             .buffer_unordered(_parallel_request_per_load.into());
 
-        let num_of_failures = responses
-            .then(process_upstream_response)
-            .fold(0, accumulate_errors)
+        // failures by upstream, for breakers
+        let upstream_failures = responses
+            .then(|(url, response)| async move { (url, process_upstream_response(response).await) })
+            .fold(HashMap::<String, u16>::new(), |mut acc, (url, result)| async move {
+                let failures = acc.get(&url).cloned().unwrap_or(0);
+                acc.insert(url, accumulate_errors(failures, result).await);
+                acc
+            })
             .await;
 
+        for (url, failures) in upstream_failures.iter() {
+            if let Some(permit) = breaker_permits.remove(url) {
+                _breakers.record(permit, *failures == 0);
+            }
+        }
+        let num_of_failures = breaker_failures + upstream_failures.values().sum::<u16>();
+
         // report errors to prometheus
         debug!("number of errors while processing: {}", num_of_failures);
         num_failures.inc_by(num_of_failures as f64);
@@ -187,6 +446,9 @@ pub async fn process_proxy_payload(
         } else {
             StatusCode::INTERNAL_SERVER_ERROR
         };
+        request_cx.span().set_attribute(KeyValue::new("upstream_failures", num_of_failures as i64));
+        telemetry::record_status(&request_cx, expose_as.as_u16());
+        request_cx.span().end();
 
         Ok(warp::reply::with_status(
             warp::reply::html(num_of_failures.to_string()),
@@ -195,6 +457,21 @@ pub async fn process_proxy_payload(
     };
 }
 
+// unpacks Snappy payload, and parses Prometheus write request
+pub fn decode_write_request(_bytes: &[u8]) -> Result<WriteRequest, String> {
+    let uncompressed_pb_message = match snap::raw::Decoder::new().decompress_vec(_bytes) {
+        Ok(v) => v,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    debug!(
+        "::: request length decompressed : {}b",
+        uncompressed_pb_message.len().to_string()
+    );
+    // invalid protobuf in request
+    WriteRequest::parse_from_bytes(&uncompressed_pb_message).map_err(|e| e.to_string())
+}
+
 // processes origin response, convert upstream status to integer
 async fn process_upstream_response(
     resp: std::result::Result<std::result::Result<reqwest::Response, reqwest::Error>, JoinError>,
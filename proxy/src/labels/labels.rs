@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::str::FromStr;
+
+use log::debug;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::proto::prometheus::{Label, TimeSeries};
+
+
+// A prefix for existing labels renamed because of conflict
+const EXPORTED_LABEL_PREFIX: &str = "exported_";
+
+// Tenant ID -> (label name -> label value)
+pub type TenantLabels = HashMap<String, HashMap<String, String>>;
+
+// Defines what to do when series already carry an external label
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LabelConflictPolicy {
+    // replace existing label value with external one
+    Override,
+    // leave existing label value untouched
+    Keep,
+    // rename existing label to exported_<name>, and add external one,
+    // exported_ is prefixed again while the name is taken, as Prometheus does
+    Rename,
+}
+
+impl FromStr for LabelConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "override" => Ok(LabelConflictPolicy::Override),
+            "keep" => Ok(LabelConflictPolicy::Keep),
+            "rename" => Ok(LabelConflictPolicy::Rename),
+            _ => Err(format!("unknown label conflict policy: {}", s)),
+        }
+    }
+}
+
+// Load external labels from YAML file of following structure:
+//
+// tenant1:
+//   org: vgs
+//   env: prod
+pub fn load_external_labels_file(path: &str) -> Result<TenantLabels, String> {
+    let content = match read_to_string(path) {
+        Ok(c) => c,
+        Err(e) => return Err(format!("failed to read {}: {}", path, e)),
+    };
+    match serde_yaml::from_str::<TenantLabels>(&content) {
+        Ok(labels) => {
+            debug!("loaded external labels for {} tenants from {}", labels.len(), path);
+            Ok(labels)
+        },
+        Err(e) => Err(format!("failed to parse {}: {}", path, e)),
+    }
+}
+
+// Add external labels to time serie, resolving conflicts according to policy.
+// Labels are kept sorted by name, as required by remote write protocol.
+pub fn inject_external_labels(
+    time_series: &mut TimeSeries,
+    external_labels: &HashMap<String, String>,
+    policy: LabelConflictPolicy,
+) {
+    for (name, value) in external_labels.iter() {
+        let existing = time_series
+            .labels
+            .iter()
+            .position(|label| label.name.as_str() == name.as_str());
+
+        match existing {
+            Some(idx) => match policy {
+                LabelConflictPolicy::Override => {
+                    time_series.labels[idx].value = value.clone();
+                },
+                LabelConflictPolicy::Keep => {},
+                LabelConflictPolicy::Rename => {
+                    let mut exported = format!("{}{}", EXPORTED_LABEL_PREFIX, name);
+                    while time_series.labels.iter().any(|label| label.name == exported) {
+                        exported = format!("{}{}", EXPORTED_LABEL_PREFIX, exported);
+                    }
+                    time_series.labels[idx].name = exported;
+                    time_series.labels.push(new_label(name, value));
+                },
+            },
+            None => {
+                time_series.labels.push(new_label(name, value));
+            },
+        };
+    }
+    time_series.labels.sort_by(|a, b| a.name.cmp(&b.name));
+}
+
+fn new_label(name: &str, value: &str) -> Label {
+    let mut label = Label::new();
+    label.name = String::from(name);
+    label.value = String::from(value);
+    label
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Write;
+
+    use crate::labels::labels::{inject_external_labels, load_external_labels_file, LabelConflictPolicy};
+    use crate::proto::prometheus::{Label, TimeSeries};
+
+    fn time_serie() -> TimeSeries {
+        let mut ts = TimeSeries::new();
+        for (name, value) in [("__name__", "up"), ("env", "dev"), ("tenant_id", "tenant1")] {
+            let mut label = Label::new();
+            label.name = String::from(name);
+            label.value = String::from(value);
+            ts.labels.push(label);
+        }
+        ts
+    }
+
+    fn external_labels() -> HashMap<String, String> {
+        let mut labels = HashMap::new();
+        labels.insert(String::from("env"), String::from("prod"));
+        labels.insert(String::from("org"), String::from("vgs"));
+        labels
+    }
+
+    fn label_pairs(ts: &TimeSeries) -> Vec<(String, String)> {
+        ts.labels.iter().map(|l| (l.name.clone(), l.value.clone())).collect()
+    }
+
+    #[test]
+    fn test_inject_override() {
+        let mut ts = time_serie();
+        inject_external_labels(&mut ts, &external_labels(), LabelConflictPolicy::Override);
+        assert_eq!(label_pairs(&ts), vec![
+            (String::from("__name__"), String::from("up")),
+            (String::from("env"), String::from("prod")),
+            (String::from("org"), String::from("vgs")),
+            (String::from("tenant_id"), String::from("tenant1")),
+        ]);
+    }
+
+    #[test]
+    fn test_inject_keep() {
+        let mut ts = time_serie();
+        inject_external_labels(&mut ts, &external_labels(), LabelConflictPolicy::Keep);
+        assert_eq!(label_pairs(&ts), vec![
+            (String::from("__name__"), String::from("up")),
+            (String::from("env"), String::from("dev")),
+            (String::from("org"), String::from("vgs")),
+            (String::from("tenant_id"), String::from("tenant1")),
+        ]);
+    }
+
+    #[test]
+    fn test_inject_rename() {
+        let mut ts = time_serie();
+        inject_external_labels(&mut ts, &external_labels(), LabelConflictPolicy::Rename);
+        assert_eq!(label_pairs(&ts), vec![
+            (String::from("__name__"), String::from("up")),
+            (String::from("env"), String::from("prod")),
+            (String::from("exported_env"), String::from("dev")),
+            (String::from("org"), String::from("vgs")),
+            (String::from("tenant_id"), String::from("tenant1")),
+        ]);
+    }
+
+    #[test]
+    fn test_inject_rename_exported_taken() {
+        let mut ts = time_serie();
+        let mut label = Label::new();
+        label.name = String::from("exported_env");
+        label.value = String::from("staging");
+        ts.labels.push(label);
+        inject_external_labels(&mut ts, &external_labels(), LabelConflictPolicy::Rename);
+        assert_eq!(label_pairs(&ts), vec![
+            (String::from("__name__"), String::from("up")),
+            (String::from("env"), String::from("prod")),
+            (String::from("exported_env"), String::from("staging")),
+            (String::from("exported_exported_env"), String::from("dev")),
+            (String::from("org"), String::from("vgs")),
+            (String::from("tenant_id"), String::from("tenant1")),
+        ]);
+    }
+
+    #[test]
+    fn test_load_external_labels_file() {
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        write!(f, "tenant1:\n  org: vgs\n  env: prod\ntenant2:\n  cost_center: \"42\"\n").unwrap();
+        let labels = load_external_labels_file(f.path().to_str().unwrap()).unwrap();
+
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels["tenant1"]["org"], "vgs");
+        assert_eq!(labels["tenant1"]["env"], "prod");
+        assert_eq!(labels["tenant2"]["cost_center"], "42");
+    }
+}
@@ -0,0 +1 @@
+pub mod labels;
\ No newline at end of file
@@ -0,0 +1,163 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use kube_metrics_mutli_tenancy_lib::health::HealthCheck;
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
+use tokio::time::{sleep, Instant};
+
+// Interval between in-flight requests checks while draining
+const DRAIN_CHECK_MS: u64 = 100;
+
+// Max time to wait for upstream response when checking readiness
+const UPSTREAM_CHECK_TIMEOUT_MS: u64 = 1000;
+
+// A process lifecycle singleton
+// Tracks in-flight proxy requests, and whether process is going down.
+pub static LIFECYCLE: Lazy<Lifecycle> = Lazy::new(Lifecycle::new);
+
+pub struct Lifecycle {
+    shutting_down: AtomicBool,
+    in_flight: AtomicUsize,
+}
+
+// Marks single in-flight request, released on drop
+pub struct InFlightGuard<'a> {
+    lifecycle: &'a Lifecycle,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.lifecycle.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Lifecycle {
+    pub fn new() -> Lifecycle {
+        Lifecycle {
+            shutting_down: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    // Stop accepting new requests.
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    // Readiness check for process going down.
+    pub fn shutdown_check(&self) -> HealthCheck {
+        if self.is_shutting_down() {
+            HealthCheck::failed("shutting down")
+        } else {
+            HealthCheck::ok("accepting requests")
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    // Register new in-flight request.
+    // Returns None when process is shutting down, and request should be rejected.
+    pub fn track(&self) -> Option<InFlightGuard<'_>> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let guard = InFlightGuard { lifecycle: self };
+        if self.is_shutting_down() {
+            None
+        } else {
+            Some(guard)
+        }
+    }
+
+    // Wait for in-flight requests to complete, but no longer than deadline.
+    // Returns whether all requests completed.
+    pub async fn drain(&self, deadline: Duration) -> bool {
+        let until = Instant::now() + deadline;
+        info!("waiting for {} in-flight requests to complete", self.in_flight());
+        loop {
+            if self.in_flight() == 0 {
+                return true;
+            }
+            let left = until.saturating_duration_since(Instant::now());
+            if left == Duration::from_millis(0) {
+                warn!("drain deadline exceeded, {} requests still in flight", self.in_flight());
+                return false;
+            }
+            sleep(left.min(Duration::from_millis(DRAIN_CHECK_MS))).await;
+        }
+    }
+}
+
+// Check whether upstream is reachable.
+// Any HTTP response means upstream is reachable, response status is not checked.
+pub async fn check_upstream(client: &reqwest::Client, upstream_url: &str) -> HealthCheck {
+    match client
+        .get(upstream_url)
+        .timeout(Duration::from_millis(UPSTREAM_CHECK_TIMEOUT_MS))
+        .send()
+        .await
+    {
+        Ok(resp) => {
+            debug!("upstream {} responded with {}", upstream_url, resp.status());
+            HealthCheck::ok("upstream is reachable")
+        },
+        Err(e) => HealthCheck::failed(&format!("upstream is unreachable: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::sleep;
+
+    use crate::lifecycle::lifecycle::{check_upstream, Lifecycle};
+
+    #[tokio::test]
+    async fn test_drain_waits_for_in_flight() {
+        let lifecycle: &'static Lifecycle = Box::leak(Box::new(Lifecycle::new()));
+
+        let guard = lifecycle.track();
+        assert!(guard.is_some());
+        assert_eq!(lifecycle.in_flight(), 1);
+
+        lifecycle.begin_shutdown();
+        // new requests are rejected once shutdown began
+        assert!(lifecycle.track().is_none());
+        assert_eq!(lifecycle.in_flight(), 1);
+
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(100)).await;
+            drop(guard);
+        });
+        assert!(lifecycle.drain(Duration::from_secs(5)).await);
+        assert_eq!(lifecycle.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_drain_deadline() {
+        let lifecycle = Lifecycle::new();
+        let _guard = lifecycle.track();
+        lifecycle.begin_shutdown();
+        assert!(!lifecycle.drain(Duration::from_millis(100)).await);
+    }
+
+    #[tokio::test]
+    async fn test_check_upstream() {
+        let _m = mockito::mock("GET", "/api/v1/push")
+            .with_status(405)
+            .create();
+        let client = reqwest::Client::new();
+
+        let reachable = check_upstream(&client, &(mockito::server_url() + "/api/v1/push")).await;
+        assert!(reachable.ok);
+
+        let unreachable = check_upstream(&client, "http://127.0.0.1:1/api/v1/push").await;
+        assert!(!unreachable.ok);
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use kube_metrics_mutli_tenancy_lib::locks;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::proto::prometheus::{TimeSeries, WriteRequest};
+
+
+// Series not written for this long are not counted as active anymore
+pub const SERIES_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+// Idle series are looked for at most that often, per tenant
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Limiter singleton, usage is kept per proxy replica
+pub static LIMITER: Lazy<TenantLimiter> = Lazy::new(|| TenantLimiter::new(SERIES_IDLE_TIMEOUT));
+
+// Ingestion limits of single tenant
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct TenantLimits {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub samples_per_second: Option<f64>,
+    // twice samples_per_second when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub samples_burst: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_series: Option<u64>,
+}
+
+impl TenantLimits {
+    pub fn is_empty(&self) -> bool {
+        self.samples_per_second.is_none() && self.max_series.is_none()
+    }
+
+    fn burst(&self, samples_per_second: f64) -> f64 {
+        match self.samples_burst {
+            Some(burst) => burst as f64,
+            None => samples_per_second * 2.0,
+        }
+    }
+}
+
+// Outcome of applying limits to tenant request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Admission {
+    // request is forwarded, without series above series limit
+    Accepted { dropped_series: usize },
+    // whole request is refused, since samples rate is exceeded
+    RateLimited,
+}
+
+// Usage of single tenant
+struct TenantUsage {
+    // samples token bucket
+    tokens: f64,
+    refilled: Instant,
+    // last write time by series fingerprint
+    series: HashMap<u64, Instant>,
+    swept: Instant,
+}
+
+// Per-tenant samples rate and active series limits.
+// Rate is enforced with token bucket, refilled at samples_per_second up to burst.
+// Active series are series written within idle timeout, new ones above max_series are dropped.
+pub struct TenantLimiter {
+    idle_timeout: Duration,
+    tenants: Mutex<HashMap<String, TenantUsage>>,
+}
+
+impl TenantLimiter {
+    pub fn new(idle_timeout: Duration) -> TenantLimiter {
+        TenantLimiter {
+            idle_timeout,
+            tenants: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Apply tenant limits to request, series above series limit are removed from it.
+    pub fn admit(&self, tenant_id: &str, limits: &TenantLimits, request: &mut WriteRequest, now: Instant) -> Admission {
+        let mut tenants = locks::lock(&self.tenants);
+        let usage = tenants.entry(String::from(tenant_id)).or_insert_with(|| TenantUsage {
+            tokens: limits.samples_per_second.map(|r| limits.burst(r)).unwrap_or(0.0),
+            refilled: now,
+            series: HashMap::new(),
+            swept: now,
+        });
+
+        if let Some(samples_per_second) = limits.samples_per_second {
+            let elapsed = now.saturating_duration_since(usage.refilled).as_secs_f64();
+            usage.tokens = (usage.tokens + elapsed * samples_per_second).min(limits.burst(samples_per_second));
+            usage.refilled = now;
+            let samples: usize = request.timeseries.iter().map(|ts| ts.samples.len()).sum();
+            if samples as f64 > usage.tokens {
+                return Admission::RateLimited;
+            }
+        }
+
+        let mut dropped_series = 0;
+        if let Some(max_series) = limits.max_series {
+            if now.saturating_duration_since(usage.swept) >= SWEEP_INTERVAL {
+                let idle_timeout = self.idle_timeout;
+                usage.series.retain(|_, written| now.saturating_duration_since(*written) < idle_timeout);
+                usage.swept = now;
+            }
+            let series = &mut usage.series;
+            let before = request.timeseries.len();
+            request.timeseries.retain(|ts| {
+                let fingerprint = fingerprint(ts);
+                if let Some(written) = series.get_mut(&fingerprint) {
+                    *written = now;
+                    true
+                } else if (series.len() as u64) < max_series {
+                    series.insert(fingerprint, now);
+                    true
+                } else {
+                    false
+                }
+            });
+            dropped_series = before - request.timeseries.len();
+        }
+
+        if limits.samples_per_second.is_some() {
+            let samples: usize = request.timeseries.iter().map(|ts| ts.samples.len()).sum();
+            usage.tokens -= samples as f64;
+        }
+        Admission::Accepted { dropped_series }
+    }
+
+    // Forget usage of tenants which are not limited anymore.
+    pub fn retain_tenants<F: Fn(&str) -> bool>(&self, keep: F) {
+        locks::lock(&self.tenants).retain(|tenant_id, _| keep(tenant_id));
+    }
+
+    // Number of series tenant wrote within idle timeout, as tracked for series limit.
+    pub fn active_series(&self, tenant_id: &str, now: Instant) -> usize {
+        match locks::lock(&self.tenants).get(tenant_id) {
+            Some(usage) => usage
+                .series
+                .values()
+                .filter(|written| now.saturating_duration_since(**written) < self.idle_timeout)
+                .count(),
+            None => 0,
+        }
+    }
+}
+
+// Series identity, labels are sorted by Prometheus already.
+pub fn fingerprint(time_series: &TimeSeries) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for label in time_series.labels.iter() {
+        label.name.hash(&mut hasher);
+        label.value.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::limits::limits::{Admission, TenantLimiter, TenantLimits};
+    use crate::proto::prometheus::{Label, Sample, TimeSeries, WriteRequest};
+
+    fn request(series: &[&str], samples: usize) -> WriteRequest {
+        let mut request = WriteRequest::new();
+        for name in series.iter() {
+            let mut time_series = TimeSeries::new();
+            let mut label = Label::new();
+            label.name = String::from("__name__");
+            label.value = String::from(*name);
+            time_series.labels.push(label);
+            for _ in 0..samples {
+                time_series.samples.push(Sample::new());
+            }
+            request.timeseries.push(time_series);
+        }
+        request
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let limiter = TenantLimiter::new(Duration::from_secs(600));
+        let limits = TenantLimits { samples_per_second: Some(10.0), ..TenantLimits::default() };
+        let now = Instant::now();
+
+        // burst is twice the rate
+        assert_eq!(limiter.admit("t", &limits, &mut request(&["a", "b"], 10), now), Admission::Accepted { dropped_series: 0 });
+        assert_eq!(limiter.admit("t", &limits, &mut request(&["a"], 1), now), Admission::RateLimited);
+        // refilled after a second
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.admit("t", &limits, &mut request(&["a"], 10), later), Admission::Accepted { dropped_series: 0 });
+        assert_eq!(limiter.admit("t", &limits, &mut request(&["a"], 1), later), Admission::RateLimited);
+        // other tenants have own bucket
+        assert_eq!(limiter.admit("u", &limits, &mut request(&["a"], 1), later), Admission::Accepted { dropped_series: 0 });
+    }
+
+    #[test]
+    fn test_series_limit() {
+        let limiter = TenantLimiter::new(Duration::from_secs(600));
+        let limits = TenantLimits { max_series: Some(2), ..TenantLimits::default() };
+        let now = Instant::now();
+
+        let mut req = request(&["a", "b", "c"], 1);
+        assert_eq!(limiter.admit("t", &limits, &mut req, now), Admission::Accepted { dropped_series: 1 });
+        assert_eq!(req.timeseries.len(), 2);
+        // known series are still accepted
+        let mut req = request(&["b", "d", "a"], 1);
+        assert_eq!(limiter.admit("t", &limits, &mut req, now), Admission::Accepted { dropped_series: 1 });
+        assert_eq!(limiter.active_series("t", now), 2);
+
+        // idle series make room for new ones
+        let later = now + Duration::from_secs(700);
+        assert_eq!(limiter.active_series("t", later), 0);
+        let mut req = request(&["c", "d"], 1);
+        assert_eq!(limiter.admit("t", &limits, &mut req, later), Admission::Accepted { dropped_series: 0 });
+
+        limiter.retain_tenants(|_| false);
+        assert_eq!(limiter.active_series("t", later), 0);
+    }
+}
@@ -0,0 +1 @@
+pub mod limits;
\ No newline at end of file
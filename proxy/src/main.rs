@@ -7,27 +7,70 @@ use std::convert::Infallible;
 use std::net::Ipv4Addr;
 use std::process::exit;
 use std::sync::Arc;
+use std::time::Duration;
 
 use argh::FromArgs;
-use env_logger;
 use kube::Client;
-use log::error;
+use kube_metrics_mutli_tenancy_lib::election::{default_identity, LeaderElector};
+use kube_metrics_mutli_tenancy_lib::health::{HealthCheck, HealthReport};
+use kube_metrics_mutli_tenancy_lib::logging::{init_logging, new_request_id, LogFormat};
+use kube_metrics_mutli_tenancy_lib::namespace::NamespaceScope;
+use kube_metrics_mutli_tenancy_lib::selector::RuleSelector;
+use kube_metrics_mutli_tenancy_lib::shutdown::shutdown_signal;
+use kube_metrics_mutli_tenancy_lib::tenant::{init_normalizer, TenantIdNormalizer};
+use kube_metrics_mutli_tenancy_lib::telemetry::{init_tracing, shutdown_tracing, TracingConfig, TracingExporter};
+use log::{error, info, warn};
 use prometheus::{
-    register_histogram, Counter, IntCounterVec, Encoder, Histogram, Opts, Registry, TextEncoder,
+    register_histogram, Counter, IntCounterVec, IntGauge, IntGaugeVec, Encoder, Histogram, HistogramOpts,
+    HistogramVec, Opts, Registry, TextEncoder,
 };
 use reqwest::header::HeaderValue;
 use tokio;
 use warp::log as http_log;
 use warp::Filter;
 
+mod admin;
+mod breaker;
+mod config;
+mod explain;
 mod forward;
+mod labels;
+mod lifecycle;
+mod limits;
 mod metrics;
+mod mirror;
+mod promql;
 mod proto;
+mod query;
+mod remote_read;
+mod status;
 mod controller;
 
 // metrics stream forwarder component
 use forward::forward::process_proxy_payload;
 use forward::forward::ForwardingStatistics;
+use metrics::metrics::parse_buckets;
+
+// configuration component
+use config::config::{ConfigReloader, ProxyConfig, reload_worker};
+
+// external labels component
+use labels::labels::{load_external_labels_file, LabelConflictPolicy};
+
+// upstream health component
+use breaker::breaker::CircuitBreakers;
+
+// process lifecycle component
+use lifecycle::lifecycle::{check_upstream, LIFECYCLE};
+
+// shadow traffic component
+use mirror::mirror::Mirror;
+
+// read path component
+use query::query::QueryProxy;
+
+// tenant status component
+use status::status::{StatusWriter, USAGE};
 
 // controller component
 use controller::controller::CONTROLLER;
@@ -75,9 +118,145 @@ struct OpenMetricsProxyArgs {
     #[argh(option, default = "String::from(\"\")")]
     allow_listed_tenants: String,
 
-    /// start Kubernetes controller for IngestionTenant CRD
+    /// seconds to back off after failed Kubernetes watch of tenants and rules, zero disables Kubernetes controller (default 0)
     #[argh(option, default = "default_k8s_interval()")]
     kubernetes_poll_interval_seconds: u32,
+
+    /// seconds between full Kubernetes resyncs of watched rules, zero disables resync (default 600)
+    #[argh(option, default = "default_k8s_resync_interval()")]
+    kubernetes_resync_interval_seconds: u32,
+
+    /// only use OpenMetricsRule resources matching this label selector (optional)
+    #[argh(option, default = "String::from(\"\")")]
+    rule_label_selector: String,
+
+    /// only use OpenMetricsRule resources matching this field selector (optional)
+    #[argh(option, default = "String::from(\"\")")]
+    rule_field_selector: String,
+
+    /// only use OpenMetricsRule resources with this controller_class, or without class when not set (optional)
+    #[argh(option, default = "String::from(\"\")")]
+    controller_class: String,
+
+    /// observe OpenMetricsTenant resources for per-tenant ingestion settings
+    #[argh(switch)]
+    enable_tenant_resources: bool,
+
+    /// seconds between writes of ingestion statistics into OpenMetricsTenant status, zero disables writes (default 0)
+    #[argh(option, default = "default_status_write_interval_seconds()")]
+    status_write_interval_seconds: u32,
+
+    /// maximum number of OpenMetricsTenant status writes per second (default 5)
+    #[argh(option, default = "default_status_writes_per_second()")]
+    status_writes_per_second: f64,
+
+    /// namespace of Lease electing replica which sums up statuses, falls back to POD_NAMESPACE (default default)
+    #[argh(option, default = "String::from(\"\")")]
+    leader_election_namespace: String,
+
+    /// name of Lease electing replica which sums up statuses (default open-metrics-proxy)
+    #[argh(option, default = "String::from(\"open-metrics-proxy\")")]
+    leader_election_lease_name: String,
+
+    /// seconds elected replica holds Lease without renewing it (default 15)
+    #[argh(option, default = "default_leader_election_lease_seconds()")]
+    leader_election_lease_seconds: u32,
+
+    /// seconds elected replica acts as leader since last Lease renew, below lease seconds (default 10)
+    #[argh(option, default = "default_leader_election_renew_deadline_seconds()")]
+    leader_election_renew_deadline_seconds: u32,
+
+    /// YAML file with per-tenant external labels (optional)
+    #[argh(option, default = "String::from(\"\")")]
+    external_labels_file: String,
+
+    /// external label conflict policy: override, keep or rename (default override)
+    #[argh(option, default = "LabelConflictPolicy::Override")]
+    external_labels_conflict_policy: LabelConflictPolicy,
+
+    /// comma-separated list of secondary upstream urls to mirror traffic to (optional)
+    #[argh(option, default = "String::from(\"\")")]
+    mirror_upstream_url_list: String,
+
+    /// percentage of each tenant requests to mirror (default 0)
+    #[argh(option, default = "default_mirror_percentage()")]
+    mirror_percentage: f64,
+
+    /// maximum number of mirrored requests in flight (default 32)
+    #[argh(option, default = "default_mirror_max_concurrency()")]
+    mirror_max_concurrency: usize,
+
+    /// consecutive upstream failures to open circuit breaker (default 0, breaker disabled)
+    #[argh(option, default = "default_breaker_failure_threshold()")]
+    breaker_failure_threshold: u32,
+
+    /// seconds for circuit breaker to stay open before probing upstream (default 30)
+    #[argh(option, default = "default_breaker_open_seconds()")]
+    breaker_open_seconds: u32,
+
+    /// number of probe requests allowed while circuit breaker is half-open (default 1)
+    #[argh(option, default = "default_breaker_half_open_probes()")]
+    breaker_half_open_probes: u32,
+
+    /// max seconds to wait for in-flight requests on shutdown (default 30)
+    #[argh(option, default = "default_shutdown_drain_seconds()")]
+    shutdown_drain_seconds: u32,
+
+    /// YAML configuration file, overrides routing, upstream and limit options (optional)
+    #[argh(option, default = "String::from(\"\")")]
+    config_file: String,
+
+    /// seconds between configuration file change checks, zero disables checks (default 10)
+    #[argh(option, default = "default_config_check_interval_seconds()")]
+    config_check_interval_seconds: u32,
+
+    /// port for serving admin API separately, zero serves it on main port (default 0)
+    #[argh(option, default = "default_admin_port()")]
+    admin_port: u16,
+
+    /// port for serving query API to read path callers, zero disables it (default 0)
+    #[argh(option, default = "default_query_port()")]
+    query_port: u16,
+
+    /// bearer token required by admin API, falls back to OPEN_METRICS_PROXY_ADMIN_TOKEN (optional)
+    #[argh(option, default = "String::from(\"\")")]
+    admin_token: String,
+
+    /// lowercase tenant IDs before validation
+    #[argh(switch)]
+    tenant_id_lowercase: bool,
+
+    /// replace characters Cortex does not allow in tenant IDs with this one, instead of rejecting them (optional)
+    #[argh(option, default = "String::from(\"\")")]
+    tenant_id_replacement: String,
+
+    /// log format: text or json (default text)
+    #[argh(option, default = "LogFormat::Text")]
+    log_format: LogFormat,
+
+    /// span exporter: none, otlp or file (default none)
+    #[argh(option, default = "TracingExporter::Disabled")]
+    tracing_exporter: TracingExporter,
+
+    /// OTLP collector gRPC endpoint (default http://127.0.0.1:4317)
+    #[argh(option, default = "String::from(\"http://127.0.0.1:4317\")")]
+    tracing_otlp_endpoint: String,
+
+    /// file to append spans to as JSON lines, for file exporter (default traces.json)
+    #[argh(option, default = "String::from(\"traces.json\")")]
+    tracing_file: String,
+
+    /// share of requests to trace, from 0 to 1 (default 1)
+    #[argh(option, default = "default_tracing_sample_ratio()")]
+    tracing_sample_ratio: f64,
+
+    /// comma-separated histogram buckets, in milliseconds, for processing and upstream latency (default 10,50,100,250,500,800,1200,2000)
+    #[argh(option, default = "String::from(\"10,50,100,250,500,800,1200,2000\")")]
+    latency_buckets: String,
+
+    /// print configuration file JSON schema and exit
+    #[argh(switch)]
+    print_config_schema: bool,
 }
 
 // port
@@ -88,62 +267,196 @@ fn default_port() -> u16 {
 // k8s interval
 fn default_k8s_interval() -> u32 { 0 }
 
+// k8s resync interval
+fn default_k8s_resync_interval() -> u32 {
+    600
+}
+
+// status write interval
+fn default_status_write_interval_seconds() -> u32 {
+    0
+}
+
+// status writes rate
+fn default_status_writes_per_second() -> f64 {
+    5.0
+}
+
+// lease duration
+fn default_leader_election_lease_seconds() -> u32 {
+    15
+}
+
+// time leader acts without renewing lease
+fn default_leader_election_renew_deadline_seconds() -> u32 {
+    10
+}
+
 // requests per load
 fn default_parallel_requests_per_load() -> u16 {
     64
 }
 
+// mirror percentage
+fn default_mirror_percentage() -> f64 {
+    0.0
+}
+
+// mirror requests in flight
+fn default_mirror_max_concurrency() -> usize {
+    32
+}
+
+// breaker failures
+fn default_breaker_failure_threshold() -> u32 {
+    0
+}
+
+// breaker open interval
+fn default_breaker_open_seconds() -> u32 {
+    30
+}
+
+// breaker probes
+fn default_breaker_half_open_probes() -> u32 {
+    1
+}
+
+// shutdown drain deadline
+fn default_shutdown_drain_seconds() -> u32 {
+    30
+}
+
 // content length limit
 fn default_content_length_limit() -> u64 {
     100 * 1024 * 1024
 }
 
+// admin port
+fn default_admin_port() -> u16 {
+    0
+}
 
-#[tokio::main]
-async fn main() {
-    env_logger::init();
-    let http_log_wrapper = http_log("Open-Metrics-multi-tenancy-Proxy");
+// query port
+fn default_query_port() -> u16 {
+    0
+}
 
-    let args: OpenMetricsProxyArgs = argh::from_env();
+// traces sample ratio
+fn default_tracing_sample_ratio() -> f64 {
+    1.0
+}
 
-    // Shared variables
-    let tenant_label_list = args.tenant_label_list.clone().to_owned();
-    let replicate_to_list = args.default_tenant_list.clone().to_owned();
-    let tenant_allow_list = args.allow_listed_tenants.clone().to_owned();
-    let ingester_stream_url = args.ingester_upstream_url.clone().to_owned();
-    let k8s_poll_interval_seconds = args.kubernetes_poll_interval_seconds.clone().to_owned();
-    let _parallel_request_per_load = args.max_parallel_request_per_load.clone().to_owned();
-    let interface = args.interface.clone().to_owned();
-    let disable_full_replication = args.disable_full_replication.clone();
+// config file check interval
+fn default_config_check_interval_seconds() -> u32 {
+    10
+}
+
+// Build configuration from command line options.
+fn config_from_args(args: &OpenMetricsProxyArgs) -> Result<ProxyConfig, String> {
+    let mut config = ProxyConfig::default();
 
-    let tenant_labels = tenant_label_list
+    config.upstreams.ingester_url = args.ingester_upstream_url.clone();
+    config.upstreams.mirror_urls = args
+        .mirror_upstream_url_list
         .split(",")
+        .filter(|s| !s.is_empty())
         .map(|s| s.to_string())
         .collect();
+    config.upstreams.mirror_percentage = args.mirror_percentage;
 
-    let replicate_to: Vec<String> = if disable_full_replication {
-        vec![]
-    } else {
-        replicate_to_list
-            .split(",")
-            .map(|s| s.to_string())
-            .collect()
-    };
+    config.tenant_labels = args
+        .tenant_label_list
+        .split(",")
+        .map(|s| s.to_string())
+        .collect();
+
+    config.replication.enabled = !args.disable_full_replication;
+    config.replication.tenants = args
+        .default_tenant_list
+        .split(",")
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect();
 
-    let allowed_tenants: Vec<String> = tenant_allow_list
+    // allow-listing stays on with empty list, only tenants from k8s are allowed then
+    config.allow_list.tenants = args
+        .allow_listed_tenants
         .split(",")
+        .filter(|s| !s.is_empty())
         .map(|s| s.to_string())
         .collect();
-    let uses_allow_listing = !allowed_tenants.clone().is_empty();
-
-    let allow_listed_tenants = if uses_allow_listing {
-        replicate_to
-            .to_vec()
-            .into_iter()
-            .chain(allowed_tenants.into_iter())
-            .collect()
+
+    config.external_labels.conflict_policy = args.external_labels_conflict_policy;
+    if !args.external_labels_file.is_empty() {
+        config.external_labels.tenants = load_external_labels_file(&args.external_labels_file)?;
+    }
+
+    config.limits.content_length_limit = args.content_length_limit;
+    config.limits.max_parallel_request_per_load = args.max_parallel_request_per_load;
+
+    config.validate()?;
+    Ok(config)
+}
+
+
+#[tokio::main]
+async fn main() {
+    let args: OpenMetricsProxyArgs = argh::from_env();
+
+    init_logging("proxy", args.log_format);
+    let http_log_wrapper = http_log("Open-Metrics-multi-tenancy-Proxy");
+
+    // tenant IDs from labels, configuration and k8s are normalized the same way
+    match TenantIdNormalizer::new(args.tenant_id_lowercase, &args.tenant_id_replacement) {
+        Ok(n) => init_normalizer(n),
+        Err(e) => {
+            error!("Invalid tenant ID normalization: {}", e);
+            exit(2);
+        }
+    };
+
+    if args.print_config_schema {
+        println!("{}", ProxyConfig::schema());
+        exit(0);
+    }
+
+    let tracing_config = TracingConfig {
+        exporter: args.tracing_exporter,
+        otlp_endpoint: args.tracing_otlp_endpoint.clone(),
+        file: args.tracing_file.clone(),
+        sample_ratio: args.tracing_sample_ratio,
+    };
+    if let Err(e) = init_tracing("open-metrics-multi-tenancy-proxy", &tracing_config) {
+        error!("Failed to initialize tracing: {}", e);
+        exit(2);
+    }
+
+    // Shared variables
+    let k8s_poll_interval_seconds = args.kubernetes_poll_interval_seconds.clone().to_owned();
+    let interface = args.interface.clone().to_owned();
+    let config_file = args.config_file.clone().to_owned();
+    let admin_token = if args.admin_token.is_empty() {
+        std::env::var("OPEN_METRICS_PROXY_ADMIN_TOKEN").unwrap_or_default()
     } else {
-        allowed_tenants
+        args.admin_token.clone()
+    };
+    if admin_token.is_empty() {
+        warn!("admin API is not protected with token");
+    }
+
+    // configuration file takes precedence over command line options
+    let initial_config = if config_file.is_empty() {
+        config_from_args(&args)
+    } else {
+        ProxyConfig::load(&config_file)
+    };
+    let initial_config = match initial_config {
+        Ok(c) => config::config::swap(c),
+        Err(e) => {
+            error!("Failed to load configuration: {}", e);
+            exit(2);
+        }
     };
 
     // reqwest machinery all safe to unwrap since headers are static
@@ -190,17 +503,184 @@ async fn main() {
     let num_metadata = Counter::with_opts(num_metadata_opts).unwrap();
     r.register(Box::new(num_metadata.clone())).unwrap();
 
+    let latency_buckets = match parse_buckets(&args.latency_buckets) {
+        Ok(b) => b,
+        Err(e) => {
+            error!("Failed to parse latency buckets: {}", e);
+            exit(2);
+        }
+    };
+
     let histogram = register_histogram!(
         "open_metrics_proxy_processing_ms",
         "processing time milliseconds",
-        vec![10.0, 50.0, 100.0, 250.0, 500.0, 800.0, 1200.0, 2000.0]
+        latency_buckets.clone()
     )
     .unwrap();
     r.register(Box::new(histogram.clone())).unwrap();
 
+    let bytes_received_opts = Opts::new(
+        "open_metrics_proxy_received_bytes",
+        "tenant share of received payload bytes, per tenant and encoding",
+    );
+    let bytes_received = IntCounterVec::new(bytes_received_opts, &["tenant_id", "encoding"]).unwrap();
+    r.register(Box::new(bytes_received.clone())).unwrap();
+
+    let bytes_sent_opts = Opts::new(
+        "open_metrics_proxy_sent_bytes",
+        "bytes forwarded to upstream, per tenant and encoding",
+    );
+    let bytes_sent = IntCounterVec::new(bytes_sent_opts, &["tenant_id", "encoding"]).unwrap();
+    r.register(Box::new(bytes_sent.clone())).unwrap();
+
+    let upstream_responses_opts = Opts::new(
+        "open_metrics_proxy_upstream_responses",
+        "number of upstream responses, per tenant and status",
+    );
+    let upstream_responses = IntCounterVec::new(upstream_responses_opts, &["tenant_id", "status"]).unwrap();
+    r.register(Box::new(upstream_responses.clone())).unwrap();
+
+    let upstream_latency_opts = HistogramOpts::new(
+        "open_metrics_proxy_upstream_latency_ms",
+        "upstream request duration milliseconds, per tenant",
+    )
+    .buckets(latency_buckets.clone());
+    let upstream_latency = HistogramVec::new(upstream_latency_opts, &["tenant_id"]).unwrap();
+    r.register(Box::new(upstream_latency.clone())).unwrap();
+
+    let rejected_tenant_ids_opts = Opts::new(
+        "open_metrics_proxy_rejected_tenant_ids",
+        "number of invalid tenant IDs ignored, per source and reason",
+    );
+    let rejected_tenant_ids = IntCounterVec::new(rejected_tenant_ids_opts, &["source", "reason"]).unwrap();
+    r.register(Box::new(rejected_tenant_ids.clone())).unwrap();
+
+    let dropped_series_opts = Opts::new(
+        "open_metrics_proxy_dropped_series",
+        "number of series dropped by tenant settings, per tenant and reason",
+    );
+    let dropped_series = IntCounterVec::new(dropped_series_opts, &["tenant_id", "reason"]).unwrap();
+    r.register(Box::new(dropped_series.clone())).unwrap();
+
+    let status_writes_opts = Opts::new(
+        "open_metrics_proxy_status_writes",
+        "number of OpenMetricsTenant status writes, per result",
+    );
+    let status_writes = IntCounterVec::new(status_writes_opts, &["result"]).unwrap();
+    r.register(Box::new(status_writes.clone())).unwrap();
+
+    let status_writer_leader = IntGauge::new(
+        "open_metrics_proxy_status_writer_leader",
+        "whether replica is elected to sum up OpenMetricsTenant statuses",
+    )
+    .unwrap();
+    r.register(Box::new(status_writer_leader.clone())).unwrap();
+
+    let namespace_rules_opts = Opts::new(
+        "open_metrics_proxy_k8s_rules",
+        "number of OpenMetricsRule resources, per namespace",
+    );
+    let namespace_rules = IntGaugeVec::new(namespace_rules_opts, &["namespace"]).unwrap();
+    r.register(Box::new(namespace_rules.clone())).unwrap();
+
+    // set from lifecycle counter on scrape
+    let in_flight_requests = IntGauge::new(
+        "open_metrics_proxy_in_flight_requests",
+        "number of remote write requests being processed",
+    )
+    .unwrap();
+    r.register(Box::new(in_flight_requests.clone())).unwrap();
+
+    let mirror_requests_opts = Opts::new(
+        "open_metrics_proxy_mirror_requests",
+        "number of mirrored requests, per target and status",
+    );
+    let mirror_requests = IntCounterVec::new(mirror_requests_opts, &["target", "status"]).unwrap();
+    r.register(Box::new(mirror_requests.clone())).unwrap();
+
+    let mirror_dropped_opts = Opts::new(
+        "open_metrics_proxy_mirror_dropped",
+        "number of requests not mirrored due to concurrency limit, per target",
+    );
+    let mirror_dropped = IntCounterVec::new(mirror_dropped_opts, &["target"]).unwrap();
+    r.register(Box::new(mirror_dropped.clone())).unwrap();
+
+    let mirror_latency_opts = HistogramOpts::new(
+        "open_metrics_proxy_mirror_latency_ms",
+        "mirrored request duration milliseconds, per target",
+    )
+    .buckets(vec![10.0, 50.0, 100.0, 250.0, 500.0, 800.0, 1200.0, 2000.0]);
+    let mirror_latency = HistogramVec::new(mirror_latency_opts, &["target"]).unwrap();
+    r.register(Box::new(mirror_latency.clone())).unwrap();
+
+    let breaker_state_opts = Opts::new(
+        "open_metrics_proxy_circuit_breaker_state",
+        "circuit breaker state per upstream: 0 closed, 1 half-open, 2 open",
+    );
+    let breaker_state = IntGaugeVec::new(breaker_state_opts, &["upstream"]).unwrap();
+    r.register(Box::new(breaker_state.clone())).unwrap();
+
+    let breakers = Arc::new(CircuitBreakers::new(
+        args.breaker_failure_threshold,
+        Duration::from_secs(args.breaker_open_seconds.into()),
+        args.breaker_half_open_probes,
+        breaker_state,
+    ));
+
+    let config_reloads_opts = Opts::new(
+        "open_metrics_proxy_config_reloads",
+        "number of configuration reloads, per result",
+    );
+    let config_reloads = IntCounterVec::new(config_reloads_opts, &["result"]).unwrap();
+    r.register(Box::new(config_reloads.clone())).unwrap();
+
+    let config_last_reload_successful = IntGauge::new(
+        "open_metrics_proxy_config_last_reload_successful",
+        "whether last configuration reload succeeded",
+    )
+    .unwrap();
+    r.register(Box::new(config_last_reload_successful.clone())).unwrap();
+
+    let query_requests_opts = Opts::new(
+        "open_metrics_proxy_query_requests",
+        "number of query requests, per caller, endpoint and status",
+    );
+    let query_requests = IntCounterVec::new(query_requests_opts, &["caller", "endpoint", "status"]).unwrap();
+    r.register(Box::new(query_requests.clone())).unwrap();
+
+    let query_latency_opts = HistogramOpts::new(
+        "open_metrics_proxy_query_latency_ms",
+        "query-frontend request duration milliseconds, per caller and endpoint",
+    )
+    .buckets(latency_buckets.clone());
+    let query_latency = HistogramVec::new(query_latency_opts, &["caller", "endpoint"]).unwrap();
+    r.register(Box::new(query_latency.clone())).unwrap();
+
+    let mirror = Arc::new(Mirror::new(
+        initial_config.upstreams.mirror_urls.clone(),
+        initial_config.upstreams.mirror_percentage,
+        args.mirror_max_concurrency,
+        mirror_requests,
+        mirror_latency,
+        mirror_dropped,
+        breakers.clone(),
+    ));
+
+    if mirror.is_enabled() {
+        info!(
+            "mirroring {}% of tenant requests to {:?}",
+            initial_config.upstreams.mirror_percentage, initial_config.upstreams.mirror_urls
+        );
+    }
+
     let mut counter_vecs = HashMap::<u8, IntCounterVec>::new();
     counter_vecs.insert(ForwardingStatistics::TotalRequests as u8, total_requests);
     counter_vecs.insert(ForwardingStatistics::NumSeries as u8, num_series);
+    counter_vecs.insert(ForwardingStatistics::BytesReceived as u8, bytes_received);
+    counter_vecs.insert(ForwardingStatistics::BytesSent as u8, bytes_sent);
+    counter_vecs.insert(ForwardingStatistics::UpstreamResponses as u8, upstream_responses);
+    counter_vecs.insert(ForwardingStatistics::RejectedTenantIds as u8, rejected_tenant_ids.clone());
+    counter_vecs.insert(ForwardingStatistics::DroppedSeries as u8, dropped_series);
 
     let mut counters = HashMap::<u8, Counter>::new();
     counters.insert(ForwardingStatistics::NumFailures as u8, num_failures);
@@ -214,22 +694,19 @@ async fn main() {
     let mut histograms = HashMap::<u8, Histogram>::new();
     histograms.insert(ForwardingStatistics::ProcessingTime as u8, histogram);
 
-    fn with_parameter_bool(
-        param_bool: bool,
-    ) -> impl Filter<Extract = (bool,), Error = Infallible> + Clone {
-        warp::any().map(move || param_bool.clone())
-    }
+    let mut histogram_vecs = HashMap::<u8, HistogramVec>::new();
+    histogram_vecs.insert(ForwardingStatistics::UpstreamLatency as u8, upstream_latency);
 
-    fn with_parameter_vec(
-        param_vec: Vec<String>,
-    ) -> impl Filter<Extract = (Vec<String>,), Error = Infallible> + Clone {
-        warp::any().map(move || param_vec.clone())
+    fn with_mirror(
+        __mirror: Arc<Mirror>,
+    ) -> impl Filter<Extract = (Arc<Mirror>,), Error = Infallible> + Clone {
+        warp::any().map(move || __mirror.clone())
     }
 
-    fn with_ingester_url(
-        ingester_url: String,
-    ) -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
-        warp::any().map(move || ingester_url.clone())
+    fn with_breakers(
+        __breakers: Arc<CircuitBreakers>,
+    ) -> impl Filter<Extract = (Arc<CircuitBreakers>,), Error = Infallible> + Clone {
+        warp::any().map(move || __breakers.clone())
     }
 
     fn with_counters(
@@ -250,54 +727,81 @@ async fn main() {
         warp::any().map(move || __histograms.clone())
     }
 
+    fn with_histogram_vecs(
+        __histogram_vecs: HashMap<u8, HistogramVec>,
+    ) -> impl Filter<Extract = (HashMap<u8, HistogramVec>,), Error = Infallible> + Clone {
+        warp::any().map(move || __histogram_vecs.clone())
+    }
+
     fn with_registry(
         __r: Registry,
     ) -> impl Filter<Extract = (Registry,), Error = Infallible> + Clone {
         warp::any().map(move || __r.clone())
     }
 
+    // readiness check probes the same upstream with the same client
+    let ready_client = client.clone();
+    let query_proxy = Arc::new(QueryProxy::new(client.clone(), query_requests, query_latency));
+    let reload_mirror = mirror.clone();
+
+    // tenants are read from snapshot, so requests never wait for controller lock
+    let snapshot = CONTROLLER.read().await.snapshot();
+
     // match any post request and perform proxying
+    // content length limit set on start is a hard cap, reloaded limit is checked per request
     let proxy = warp::any()
         .and(warp::post())
-        .and(warp::body::content_length_limit(args.content_length_limit))
+        .and(warp::body::content_length_limit(initial_config.limits.content_length_limit))
         .map(move || client.clone())
-        .and(with_parameter_vec(tenant_labels))
-        //.and(with_tenants())
-        .and(with_parameter_bool(uses_allow_listing))
-        .and(with_parameter_vec(replicate_to))
-        .and(with_ingester_url(ingester_stream_url))
+        .and(warp::any().map(move || snapshot.load()))
+        .and(with_mirror(mirror))
+        .and(with_breakers(breakers.clone()))
         .and(with_counters(counters))
         .and(with_counters_vec(counter_vecs))
         .and(with_histograms(histograms))
+        .and(with_histogram_vecs(histogram_vecs))
+        .and(warp::header::optional::<String>("x-request-id"))
         .and(warp::body::bytes())
         .and_then(
             move |_client,
-                  _tenant_labels,
-                  _uses_allow_listing,
-                  _replicate_to,
-                  _ingester_stream_url,
+                  _snapshot,
+                  _mirror,
+                  _breakers,
                   _counters,
                   _counter_vecs,
                   _histograms,
-                  _bytes| async move {
-                // This is safe since ARC have been cloned inside view once
-                let _c = CONTROLLER.read().await;
-                let _tnts = Arc::new(_c.get_tenants());
+                  _histogram_vecs,
+                  _request_id: Option<String>,
+                  _bytes: bytes::Bytes| async move {
+                let _config = config::config::current();
+                if _bytes.len() as u64 > _config.limits.content_length_limit {
+                    return Ok::<Box<dyn warp::Reply>, warp::Rejection>(Box::new(warp::reply::with_status(
+                        String::from("payload too large"),
+                        warp::http::StatusCode::PAYLOAD_TOO_LARGE,
+                    )));
+                }
                 process_proxy_payload(
                     _client,
-                    _tenant_labels,
-                    _tnts,
-                    _uses_allow_listing,
-                    _replicate_to,
-                    _ingester_stream_url,
-                    _parallel_request_per_load,
+                    _config.tenant_labels.iter().cloned().collect(),
+                    _snapshot,
+                    _config.allow_list.enabled,
+                    _config.replicate_to(),
+                    _config.external_labels.conflict_policy,
+                    _config.upstreams.ingester_url.clone(),
+                    _config.upstreams.named.clone(),
+                    _mirror,
+                    _breakers,
+                    _config.limits.max_parallel_request_per_load,
                     &_counters,
                     &_counter_vecs,
                     &_histograms,
+                    &_histogram_vecs,
+                    // correlates access lines of all tenants, caller id is kept when passed
+                    _request_id.unwrap_or_else(new_request_id),
                     _bytes,
                 )
                     .await
-                    .and_then(|response| Ok(response))
+                    .map(|response| Box::new(response) as Box<dyn warp::Reply>)
                     .map_err(|e| {
                         error!("Internal Error: {}", e);
                         warp::reject::reject()
@@ -306,14 +810,70 @@ async fn main() {
         )
         .with(http_log_wrapper);
 
+    // upstream circuit breakers state
+    let breakers_status = warp::path!("status" / "breakers")
+        .and(warp::get())
+        .and(with_breakers(breakers))
+        .map(|_b: Arc<CircuitBreakers>| warp::reply::json(&_b.status()));
+
+    // admin API, served on main port unless separate one is configured
+    let admin_routes = admin::admin::routes(admin_token);
+    let admin_main = if args.admin_port == 0 {
+        admin_routes
+            .clone()
+            .map(|_r| Box::new(_r) as Box<dyn warp::Reply>)
+            .boxed()
+    } else {
+        warp::any()
+            .and_then(|| async { Err::<Box<dyn warp::Reply>, _>(warp::reject::not_found()) })
+            .boxed()
+    };
+
+    // liveness: process is up and serving http
+    let healthy = warp::path!("-" / "healthy")
+        .and(warp::get())
+        .map(|| {
+            let report = HealthReport::new(vec![("process", HealthCheck::ok("serving requests"))]);
+            warp::reply::json(&report)
+        });
+
+    // readiness: k8s tenants synced, upstream reachable, and not shutting down
+    let ready = warp::path!("-" / "ready")
+        .and(warp::get())
+        .and_then(move || {
+            let _client = ready_client.clone();
+            let _ingester_stream_url = config::config::current().upstreams.ingester_url.clone();
+            async move {
+                let sync = CONTROLLER.read().await.sync_check();
+                let report = HealthReport::new(vec![
+                    ("shutdown", LIFECYCLE.shutdown_check()),
+                    ("kubernetes_sync", sync),
+                    ("upstream", check_upstream(&_client, &_ingester_stream_url).await),
+                ]);
+                let status = if report.is_ok() {
+                    warp::http::StatusCode::OK
+                } else {
+                    warp::http::StatusCode::SERVICE_UNAVAILABLE
+                };
+                Ok::<_, Infallible>(warp::reply::with_status(warp::reply::json(&report), status))
+            }
+        });
+
     // match any get request and return status
-    let health = warp::any().and(warp::get()).map(|| "Up\n");
+    let health = warp::any().and(warp::get()).map(|| {
+        if LIFECYCLE.is_shutting_down() {
+            warp::reply::with_status("Shutting down\n", warp::http::StatusCode::SERVICE_UNAVAILABLE)
+        } else {
+            warp::reply::with_status("Up\n", warp::http::StatusCode::OK)
+        }
+    });
 
 
     let metrics = warp::path!("metrics")
         .and(warp::get())
         .and(with_registry(r))
-        .map(|_r: Registry| {
+        .map(move |_r: Registry| {
+            in_flight_requests.set(LIFECYCLE.in_flight() as i64);
             // Gather the metrics.
             let mut buffer = vec![];
             let encoder = TextEncoder::new();
@@ -337,8 +897,17 @@ async fn main() {
 
     // init controller parameters
     let mut c = CONTROLLER.write().await;
-    c.set_initial_allowed_tenants(allow_listed_tenants)
-        .set_k8s_poll_delay((k8s_poll_interval_seconds * 1000) as u64);
+    c.set_initial_allowed_tenants(initial_config.allow_listed_tenants())
+        .set_initial_external_labels(initial_config.tenant_external_labels())
+        .set_k8s_poll_delay((k8s_poll_interval_seconds * 1000) as u64)
+        .set_k8s_resync_interval(args.kubernetes_resync_interval_seconds as u64 * 1000)
+        .set_rejected_tenant_ids_counter(rejected_tenant_ids)
+        .set_namespace_rules_gauge(namespace_rules)
+        .set_namespace_scope(NamespaceScope::from_env(
+            "OPEN_METRICS_PROXY_NAMESPACE", "OPEN_METRICS_PROXY_NAMESPACE_SELECTOR"))
+        .set_rule_selector(RuleSelector::new(
+            &args.rule_label_selector, &args.rule_field_selector, &args.controller_class))
+        .set_tenant_resources(args.enable_tenant_resources);
 
     if k8s_client.is_some() {
         // Initialize tenants from k8s
@@ -350,12 +919,130 @@ async fn main() {
     }
     tokio::task::spawn(worker(k8s_client.clone()));
 
+    // single elected replica writes tenant statuses
+    let elector = match (&k8s_client, args.status_write_interval_seconds) {
+        (Some(k8s_client), interval) if interval > 0 => {
+            if !args.enable_tenant_resources {
+                warn!("status writer has no OpenMetricsTenant resources to write into, --enable-tenant-resources is not set");
+            }
+            let lease_namespace = if args.leader_election_namespace.is_empty() {
+                std::env::var("POD_NAMESPACE").unwrap_or_else(|_| String::from("default"))
+            } else {
+                args.leader_election_namespace.clone()
+            };
+            let elector = Arc::new(match LeaderElector::new(
+                k8s_client.clone(),
+                &lease_namespace,
+                &args.leader_election_lease_name,
+                &default_identity(),
+                Duration::from_secs(args.leader_election_lease_seconds.into()),
+                Duration::from_secs(args.leader_election_renew_deadline_seconds.into()),
+            ) {
+                Ok(elector) => elector,
+                Err(e) => {
+                    error!("Invalid leader election: {}", e);
+                    exit(2);
+                }
+            });
+            USAGE.enable();
+            tokio::task::spawn(elector.clone().run());
+            tokio::task::spawn(StatusWriter::new(
+                k8s_client.clone(),
+                elector.clone(),
+                Duration::from_secs(interval.into()),
+                args.status_writes_per_second,
+                status_writes,
+                status_writer_leader,
+            ).run());
+            Some(elector)
+        },
+        _ => None,
+    };
+
+    if !config_file.is_empty() {
+        let reloader = ConfigReloader::new(&config_file, config_reloads, config_last_reload_successful);
+        tokio::task::spawn(reload_worker(
+            reloader,
+            Duration::from_secs(args.config_check_interval_seconds.into()),
+            reload_mirror,
+        ));
+    }
+
     let listen_addr = interface.parse::<Ipv4Addr>();
 
+    let shutdown_drain_deadline = Duration::from_secs(args.shutdown_drain_seconds.into());
+
     let exit_code = match listen_addr {
         Ok(ip) => {
-            warp::serve(metrics.or(health).or(proxy))
-                .run((ip, args.port)).await;
+            let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+            let (_addr, server) = warp::serve(
+                metrics
+                    .or(healthy)
+                    .or(ready)
+                    .or(breakers_status)
+                    .or(admin_main)
+                    .or(health)
+                    .or(proxy),
+            )
+                .bind_with_graceful_shutdown((ip, args.port), async move {
+                    shutdown_rx.await.ok();
+                });
+            let server_handle = tokio::task::spawn(server);
+
+            let (admin_shutdown_tx, admin_shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+            if args.admin_port > 0 {
+                let (_admin_addr, admin_server) = warp::serve(
+                    admin_routes.with(http_log("Open-Metrics-multi-tenancy-Proxy-Admin")),
+                )
+                    .bind_with_graceful_shutdown((ip, args.admin_port), async move {
+                        admin_shutdown_rx.await.ok();
+                    });
+                tokio::task::spawn(admin_server);
+            }
+
+            let (query_shutdown_tx, query_shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+            if args.query_port > 0 {
+                let (_query_addr, query_server) = warp::serve(
+                    query::query::routes(query_proxy.clone())
+                        .or(remote_read::remote_read::routes(query_proxy))
+                        .with(http_log("Open-Metrics-multi-tenancy-Proxy-Query")),
+                )
+                    .bind_with_graceful_shutdown((ip, args.query_port), async move {
+                        query_shutdown_rx.await.ok();
+                    });
+                tokio::task::spawn(query_server);
+            }
+
+            let signal_name = shutdown_signal().await;
+            info!("received {}, shutting down", signal_name);
+
+            // reject new writes, and report unready
+            LIFECYCLE.begin_shutdown();
+            let drained = LIFECYCLE.drain(shutdown_drain_deadline).await;
+
+            // stop k8s polling between ticks
+            if let Ok(mut c) = tokio::time::timeout(Duration::from_secs(1), CONTROLLER.write()).await {
+                c.stop();
+            }
+
+            // let standby replica take over status writes right away
+            if let Some(elector) = &elector {
+                elector.stop();
+                if let Ok(Err(e)) = tokio::time::timeout(Duration::from_secs(1), elector.release()).await {
+                    warn!("{}", e);
+                }
+            }
+
+            // stop listening, and wait for connections to close unless deadline exceeded
+            let _ = shutdown_tx.send(());
+            let _ = admin_shutdown_tx.send(());
+            let _ = query_shutdown_tx.send(());
+            if drained {
+                let _ = server_handle.await;
+            }
+            // flush pending spans
+            shutdown_tracing().await;
+            info!("shutdown complete");
             0
         }
         Err(e) => {
@@ -1,5 +1,7 @@
 #![deny(warnings)]
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+
+use kube_metrics_mutli_tenancy_lib::tenant::{normalize_tenant_id, TenantIdError};
 
 use crate::proto::prometheus::{TimeSeries, WriteRequest};
 
@@ -29,37 +31,44 @@ fn process_time_serie_for_tenant(
 // processes single time serie
 // aggregate data over tenant
 // populate hashmap with writerequests
-// return number of processed tenants and labels
+// series routed to a tenant are also replicated into tenants of tenant_replicate_to
+// return number of processed tenants and labels, and tenant label values rejected as tenant ID
 pub fn process_time_serie(
     time_series: &TimeSeries,
-    tenant_labels: &Vec<String>,
-    allow_listed_tenants: &Vec<String>,
+    tenant_labels: &HashSet<String>,
+    allow_listed_tenants: &HashSet<String>,
     does_allow_list: bool,
     replicate_to: &Vec<String>,
+    tenant_replicate_to: &HashMap<String, Vec<String>>,
     tenant_data: &mut HashMap<String, WriteRequest>,
-) -> (u16, u16) {
+) -> (u16, u16, Vec<TenantIdError>) {
     let mut label_tenants: Vec<String> = vec![];
+    let mut rejected: Vec<TenantIdError> = vec![];
     let mut tenants_detected = 0 as u16;
     let mut labels_detected = 0 as u16;
 
     for label in &time_series.labels {
+        // every label is checked against every tenant label
+        labels_detected += tenant_labels.len() as u16;
         // find out if label identifies tenant
-        for tenant_label in tenant_labels {
-            labels_detected += 1;
-            if tenant_label.as_str() == label.name.as_str() {
-                // remember tenant id
-                label_tenants.push(label.value.clone());
-                tenants_detected += 1;
-            };
-        }
+        if tenant_labels.contains(label.name.as_str()) {
+            // remember tenant id, unless upstream would refuse it
+            match normalize_tenant_id(&label.value) {
+                Ok(tenant_id) => {
+                    label_tenants.push(tenant_id);
+                    tenants_detected += 1;
+                },
+                Err(e) => rejected.push(e),
+            }
+        };
     }
 
     // remember visited tenants to avoid duplicate requests
     let mut visited_tenants: Vec<String> = vec![];
 
     if does_allow_list {
-        for tenant_id in allow_listed_tenants {
-            if label_tenants.contains(tenant_id) && !visited_tenants.contains(tenant_id) {
+        for tenant_id in label_tenants.iter() {
+            if allow_listed_tenants.contains(tenant_id) && !visited_tenants.contains(tenant_id) {
                 process_time_serie_for_tenant(
                     time_series,
                     tenant_id,
@@ -95,5 +104,138 @@ pub fn process_time_serie(
         }
     };
 
-    (tenants_detected, labels_detected)
+    // replication targets of routed tenants
+    if !tenant_replicate_to.is_empty() {
+        let targets: Vec<String> = visited_tenants
+            .iter()
+            .filter_map(|tenant_id| tenant_replicate_to.get(tenant_id))
+            .flatten()
+            .cloned()
+            .collect();
+        for tenant_id in targets.iter() {
+            if !visited_tenants.contains(tenant_id) {
+                process_time_serie_for_tenant(
+                    time_series,
+                    tenant_id,
+                    tenant_data,
+                    &mut visited_tenants,
+                );
+            };
+        }
+    }
+
+    (tenants_detected, labels_detected, rejected)
+}
+
+
+// Parse comma-separated histogram buckets, they should be increasing numbers.
+pub fn parse_buckets(buckets: &str) -> Result<Vec<f64>, String> {
+    let mut parsed = Vec::new();
+    for bucket in buckets.split(',').map(|b| b.trim()).filter(|b| !b.is_empty()) {
+        let value = bucket
+            .parse::<f64>()
+            .map_err(|e| format!("invalid bucket {}: {}", bucket, e))?;
+        if !value.is_finite() {
+            return Err(format!("invalid bucket {}: should be finite", bucket));
+        }
+        if let Some(previous) = parsed.last() {
+            if value <= *previous {
+                return Err(format!("invalid bucket {}: buckets should be increasing", bucket));
+            }
+        }
+        parsed.push(value);
+    }
+    if parsed.is_empty() {
+        return Err(String::from("at least one bucket is required"));
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::time::Instant;
+
+    use crate::metrics::metrics::{parse_buckets, process_time_serie};
+    use crate::proto::prometheus::{Label, TimeSeries, WriteRequest};
+
+    fn time_serie(labels: &[(&str, &str)]) -> TimeSeries {
+        let mut time_series = TimeSeries::new();
+        for (name, value) in labels.iter() {
+            let mut label = Label::new();
+            label.name = String::from(*name);
+            label.value = String::from(*value);
+            time_series.labels.push(label);
+        }
+        time_series
+    }
+
+    fn tenant_set(count: usize) -> HashSet<String> {
+        (0..count).map(|i| format!("tenant{}", i)).collect()
+    }
+
+    #[test]
+    fn test_process_time_serie() {
+        let tenant_labels: HashSet<String> = vec![String::from("tenant_id"), String::from("team")].into_iter().collect();
+        let allowed = tenant_set(3);
+        let time_series = time_serie(&[("__name__", "up"), ("tenant_id", "tenant1"), ("team", "tenant5")]);
+
+        let mut tenant_data = HashMap::<String, WriteRequest>::new();
+        let (tenants, labels, rejected) = process_time_serie(
+            &time_series, &tenant_labels, &allowed, true, &vec![String::from("audit")], &HashMap::new(), &mut tenant_data);
+        assert_eq!((tenants, labels, rejected.len()), (2, 6, 0));
+        // tenant5 is not allowed
+        let mut routed: Vec<&String> = tenant_data.keys().collect();
+        routed.sort();
+        assert_eq!(routed, vec!["audit", "tenant1"]);
+
+        let mut tenant_data = HashMap::<String, WriteRequest>::new();
+        process_time_serie(&time_series, &tenant_labels, &allowed, false, &vec![], &HashMap::new(), &mut tenant_data);
+        assert_eq!(tenant_data.len(), 2);
+        assert!(tenant_data.contains_key("tenant5"));
+
+        // tenant replication targets get series routed to tenant, once
+        let mut tenant_replicate_to = HashMap::new();
+        tenant_replicate_to.insert(String::from("tenant1"), vec![String::from("team"), String::from("audit")]);
+        let mut tenant_data = HashMap::<String, WriteRequest>::new();
+        process_time_serie(
+            &time_series, &tenant_labels, &allowed, true, &vec![String::from("audit")], &tenant_replicate_to, &mut tenant_data);
+        let mut routed: Vec<&String> = tenant_data.keys().collect();
+        routed.sort();
+        assert_eq!(routed, vec!["audit", "team", "tenant1"]);
+        assert_eq!(tenant_data["audit"].timeseries.len(), 1);
+    }
+
+    // Per-series routing cost should not depend on number of allowed tenants.
+    // Run with: cargo test --release -p open-metrics-multi-tenancy-proxy bench_ -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_process_time_serie() {
+        let tenant_labels: HashSet<String> = vec![String::from("tenant_id")].into_iter().collect();
+        let time_series = time_serie(&[("__name__", "up"), ("job", "node"), ("tenant_id", "tenant7")]);
+        let iterations = 200_000;
+        for count in [10, 1_000, 100_000].iter() {
+            let allowed = tenant_set(*count);
+            let started = Instant::now();
+            for _ in 0..iterations {
+                let mut tenant_data = HashMap::<String, WriteRequest>::new();
+                process_time_serie(&time_series, &tenant_labels, &allowed, true, &vec![], &HashMap::new(), &mut tenant_data);
+            }
+            println!(
+                "{} tenants: {} ns per series",
+                count, started.elapsed().as_nanos() / iterations as u128);
+        }
+    }
+
+    #[test]
+    fn test_parse_buckets() {
+        assert_eq!(parse_buckets("10,50, 100,2000").unwrap(), vec![10.0, 50.0, 100.0, 2000.0]);
+        assert_eq!(parse_buckets("0.5").unwrap(), vec![0.5]);
+
+        assert!(parse_buckets("").is_err());
+        assert!(parse_buckets("10,ten").is_err());
+        assert!(parse_buckets("10,5").is_err());
+        assert!(parse_buckets("10,10").is_err());
+        assert!(parse_buckets("10,inf").is_err());
+    }
 }
@@ -0,0 +1,219 @@
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use bytes::Bytes;
+use kube_metrics_mutli_tenancy_lib::locks;
+use log::{debug, warn};
+use prometheus::{HistogramVec, IntCounterVec};
+use tokio::sync::Semaphore;
+
+use crate::breaker::breaker::CircuitBreakers;
+
+
+// Shadow traffic mirror.
+// Sends a share of tenant requests to secondary upstreams in fire-and-forget manner,
+// the outcome is only reported to Prometheus and never affects the proxy response.
+pub struct Mirror {
+    targets: RwLock<MirrorTargets>,
+    permits: Arc<Semaphore>,
+    requests: IntCounterVec,
+    latency: HistogramVec,
+    dropped: IntCounterVec,
+    breakers: Arc<CircuitBreakers>,
+}
+
+// Mirror targets, replaced on configuration reload
+struct MirrorTargets {
+    targets: Vec<String>,
+    percentage: f64,
+}
+
+impl MirrorTargets {
+    fn new(targets: Vec<String>, percentage: f64) -> MirrorTargets {
+        MirrorTargets {
+            targets: targets.into_iter().filter(|t| !t.is_empty()).collect(),
+            percentage: percentage.clamp(0.0, 100.0),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        !self.targets.is_empty() && self.percentage > 0.0
+    }
+
+    // Decide whether a single tenant request should be mirrored.
+    fn sample(&self) -> bool {
+        self.percentage >= 100.0 || rand::random::<f64>() * 100.0 < self.percentage
+    }
+}
+
+impl Mirror {
+    // var:requests counts mirrored requests by target and status
+    // var:latency observes mirrored request durations by target
+    // var:dropped counts requests not mirrored because of concurrency limit or open breaker, by target
+    pub fn new(
+        targets: Vec<String>,
+        percentage: f64,
+        max_concurrency: usize,
+        requests: IntCounterVec,
+        latency: HistogramVec,
+        dropped: IntCounterVec,
+        breakers: Arc<CircuitBreakers>,
+    ) -> Mirror {
+        Mirror {
+            targets: RwLock::new(MirrorTargets::new(targets, percentage)),
+            permits: Arc::new(Semaphore::new(max_concurrency)),
+            requests,
+            latency,
+            dropped,
+            breakers,
+        }
+    }
+
+    // Whether there is any traffic to mirror.
+    pub fn is_enabled(&self) -> bool {
+        locks::read(&self.targets).is_enabled()
+    }
+
+    // Replace targets and percentage, concurrency limit stays as is.
+    pub fn reconfigure(&self, targets: Vec<String>, percentage: f64) {
+        *locks::write(&self.targets) = MirrorTargets::new(targets, percentage);
+    }
+
+    // Mirror tenant request to every target, if request is sampled.
+    // Returns immediately, mirrored requests are spawned in background.
+    pub fn mirror(&self, client: &reqwest::Client, tenant_id: &str, body: &Bytes) {
+        // take a copy, so lock is not held while spawning requests
+        let targets = {
+            let t = locks::read(&self.targets);
+            if !t.is_enabled() || !t.sample() {
+                return;
+            }
+            t.targets.clone()
+        };
+
+        for target in targets.iter() {
+            // do not queue requests when secondary upstream is slow, drop them
+            let permit = match self.permits.clone().try_acquire_owned() {
+                Ok(p) => p,
+                Err(_) => {
+                    debug!("mirror concurrency limit reached, dropping request to {}", target);
+                    self.dropped.with_label_values(&[target.as_str()]).inc();
+                    continue;
+                }
+            };
+
+            // do not mirror to degraded secondary upstream
+            let breaker_permit = match self.breakers.allow(target) {
+                Some(p) => p,
+                None => {
+                    debug!("circuit breaker for {} is open, dropping mirrored request", target);
+                    self.dropped.with_label_values(&[target.as_str()]).inc();
+                    continue;
+                }
+            };
+
+            let r_client = client.clone();
+            let url = target.clone();
+            let tenant_id_clone = String::from(tenant_id);
+            let payload = body.clone();
+            let requests = self.requests.clone();
+            let latency = self.latency.clone();
+            let breakers = self.breakers.clone();
+
+            tokio::spawn(async move {
+                let in_ms = Instant::now();
+                let status = match r_client
+                    .post(&url)
+                    .body(payload)
+                    .header("X-Scope-OrgID", tenant_id_clone)
+                    .send()
+                    .await
+                {
+                    Ok(resp) => {
+                        breakers.record(breaker_permit, !resp.status().is_server_error());
+                        resp.status().as_u16().to_string()
+                    },
+                    Err(e) => {
+                        warn!("mirror request to {} failed: {}", url, e);
+                        breakers.record(breaker_permit, false);
+                        String::from("error")
+                    }
+                };
+                latency
+                    .with_label_values(&[url.as_str()])
+                    .observe(in_ms.elapsed().as_millis() as f64);
+                requests
+                    .with_label_values(&[url.as_str(), status.as_str()])
+                    .inc();
+                drop(permit);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts};
+    use tokio::time::sleep;
+
+    use crate::breaker::breaker::CircuitBreakers;
+    use crate::mirror::mirror::Mirror;
+
+    fn mirror(targets: Vec<String>, percentage: f64, max_concurrency: usize) -> Mirror {
+        Mirror::new(
+            targets,
+            percentage,
+            max_concurrency,
+            IntCounterVec::new(Opts::new("mirror_requests", "help"), &["target", "status"]).unwrap(),
+            HistogramVec::new(HistogramOpts::new("mirror_latency", "help"), &["target"]).unwrap(),
+            IntCounterVec::new(Opts::new("mirror_dropped", "help"), &["target"]).unwrap(),
+            Arc::new(CircuitBreakers::new(
+                0,
+                Duration::from_secs(1),
+                1,
+                IntGaugeVec::new(Opts::new("breaker_state", "help"), &["upstream"]).unwrap(),
+            )),
+        )
+    }
+
+    #[test]
+    fn test_mirror_disabled() {
+        assert!(!mirror(vec![String::from("")], 100.0, 1).is_enabled());
+        assert!(!mirror(vec![String::from("http://127.0.0.1:1")], 0.0, 1).is_enabled());
+        assert!(mirror(vec![String::from("http://127.0.0.1:1")], 10.0, 1).is_enabled());
+    }
+
+    #[test]
+    fn test_mirror_reconfigure() {
+        let m = mirror(vec![String::from("http://127.0.0.1:1")], 10.0, 1);
+        m.reconfigure(vec![String::from("http://127.0.0.1:1")], 0.0);
+        assert!(!m.is_enabled());
+        m.reconfigure(vec![String::from("http://127.0.0.1:2")], 50.0);
+        assert!(m.is_enabled());
+    }
+
+    #[tokio::test]
+    async fn test_mirror_requests() {
+        let _m = mockito::mock("POST", "/api/v1/push")
+            .match_header("X-Scope-OrgID", "tenant1")
+            .with_status(200)
+            .expect(2)
+            .create();
+
+        let target = mockito::server_url() + "/api/v1/push";
+        let m = mirror(vec![target.clone()], 100.0, 8);
+        let client = reqwest::Client::new();
+
+        m.mirror(&client, "tenant1", &Bytes::from_static(b"payload"));
+        m.mirror(&client, "tenant1", &Bytes::from_static(b"payload"));
+        sleep(Duration::from_millis(500)).await;
+
+        _m.assert();
+        assert_eq!(m.requests.with_label_values(&[target.as_str(), "200"]).get(), 2);
+        assert_eq!(m.latency.with_label_values(&[target.as_str()]).get_sample_count(), 2);
+    }
+}
@@ -0,0 +1 @@
+pub mod mirror;
\ No newline at end of file
@@ -0,0 +1 @@
+pub mod promql;
\ No newline at end of file
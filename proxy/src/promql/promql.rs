@@ -0,0 +1,508 @@
+// Keywords which are never vector selectors
+const KEYWORDS: &[&str] = &[
+    "and", "or", "unless", "atan2", "bool", "offset",
+    "by", "without", "on", "ignoring", "group_left", "group_right",
+];
+
+// Keywords which modify binary operator they follow
+const BINARY_MODIFIERS: &[&str] = &["bool", "on", "ignoring"];
+
+// Keywords followed by list of label names in parentheses
+const GROUPING_KEYWORDS: &[&str] = &["by", "without", "on", "ignoring", "group_left", "group_right"];
+
+// Aggregation operators, which could be followed by grouping before parentheses
+const AGGREGATIONS: &[&str] = &[
+    "sum", "min", "max", "avg", "group", "stddev", "stdvar", "count",
+    "count_values", "bottomk", "topk", "quantile",
+];
+
+// Characters of arithmetic, comparison, matching and modifier operators
+const OPERATOR_CHARACTERS: &str = "+-*/%^=!<>~@";
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Identifier(String),
+    // raw string literal, including quotes
+    Str(String),
+    Number,
+    Operator(String),
+    LeftBrace,
+    RightBrace,
+    LeftParen,
+    RightParen,
+    LeftBracket,
+    RightBracket,
+    Comma,
+}
+
+// Token with byte offsets in query
+#[derive(Clone, Debug)]
+struct Lexeme {
+    token: Token,
+    start: usize,
+    end: usize,
+}
+
+fn is_identifier_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == ':'
+}
+
+fn is_identifier_character(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == ':'
+}
+
+fn lex(query: &str) -> Result<Vec<Lexeme>, String> {
+    let chars: Vec<(usize, char)> = query.char_indices().collect();
+    let offset = |i: usize| chars.get(i).map(|(o, _)| *o).unwrap_or_else(|| query.len());
+    let mut lexemes = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let (start, c) = chars[i];
+        let token = if c.is_whitespace() {
+            i += 1;
+            continue;
+        } else if c == '#' {
+            // comment runs to end of line, carriage return ends it as well
+            while i < chars.len() && chars[i].1 != '\n' && chars[i].1 != '\r' {
+                i += 1;
+            }
+            continue;
+        } else if is_identifier_start(c) {
+            while i < chars.len() && is_identifier_character(chars[i].1) {
+                i += 1;
+            }
+            Token::Identifier(String::from(&query[start..offset(i)]))
+        } else if c.is_ascii_digit()
+            || (c == '.' && chars.get(i + 1).map(|(_, n)| n.is_ascii_digit()).unwrap_or(false))
+        {
+            // numbers, hex numbers, exponents and durations like 1h30m,
+            // e of hex number is a digit, so sign after it is an operator
+            let hex = c == '0' && matches!(chars.get(i + 1), Some((_, 'x')) | Some((_, 'X')));
+            i += 1;
+            while i < chars.len() {
+                let n = chars[i].1;
+                let exponent_sign = !hex && (n == '+' || n == '-') && matches!(chars[i - 1].1, 'e' | 'E');
+                if n.is_ascii_alphanumeric() || n == '.' || n == '_' || exponent_sign {
+                    i += 1;
+                } else {
+                    break;
+                }
+            }
+            Token::Number
+        } else if c == '"' || c == '\'' || c == '`' {
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(format!("unterminated string at position {}", start)),
+                    Some((_, '\n')) if c != '`' => return Err(format!("unterminated string at position {}", start)),
+                    Some((_, '\\')) if c != '`' => i += 2,
+                    Some((_, n)) if *n == c => {
+                        i += 1;
+                        break;
+                    }
+                    Some(_) => i += 1,
+                }
+            }
+            Token::Str(String::from(&query[start..offset(i)]))
+        } else if OPERATOR_CHARACTERS.contains(c) {
+            while i < chars.len() && OPERATOR_CHARACTERS.contains(chars[i].1) {
+                i += 1;
+            }
+            Token::Operator(String::from(&query[start..offset(i)]))
+        } else {
+            i += 1;
+            match c {
+                '{' => Token::LeftBrace,
+                '}' => Token::RightBrace,
+                '(' => Token::LeftParen,
+                ')' => Token::RightParen,
+                '[' => Token::LeftBracket,
+                ']' => Token::RightBracket,
+                ',' => Token::Comma,
+                _ => return Err(format!("unexpected character '{}' at position {}", c, start)),
+            }
+        };
+        lexemes.push(Lexeme { token, start, end: offset(i) });
+    }
+    Ok(lexemes)
+}
+
+// Decode string literal, only escapes which could be compared safely are supported.
+fn unquote(literal: &str) -> Result<String, String> {
+    let quote = literal.chars().next().unwrap_or('"');
+    let inner = &literal[1..literal.len() - 1];
+    if quote == '`' {
+        return Ok(String::from(inner));
+    }
+    let mut value = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => value.push('\n'),
+            Some('t') => value.push('\t'),
+            Some('r') => value.push('\r'),
+            Some(e @ '\\') | Some(e @ '"') | Some(e @ '\'') => value.push(e),
+            _ => return Err(format!("unsupported escape sequence in {}", literal)),
+        }
+    }
+    Ok(value)
+}
+
+// Quote label value as PromQL string literal.
+pub fn quote(value: &str) -> String {
+    let mut literal = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '\\' => literal.push_str("\\\\"),
+            '"' => literal.push_str("\\\""),
+            '\n' => literal.push_str("\\n"),
+            _ => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+// Whether label name is valid Prometheus label name.
+pub fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+// Label matcher injection into every vector selector of a PromQL expression
+struct Injector<'a> {
+    lexemes: Vec<Lexeme>,
+    label_name: &'a str,
+    label_value: &'a str,
+    // byte offset and text to insert there
+    insertions: Vec<(usize, String)>,
+}
+
+impl<'a> Injector<'a> {
+    fn token(&self, i: usize) -> Option<&Token> {
+        self.lexemes.get(i).map(|l| &l.token)
+    }
+
+    // Whether keyword is in position of keyword, otherwise it is a metric name.
+    // Keywords follow operands, binary operator modifiers follow operators,
+    // label list is mandatory for all grouping keywords but group_left and group_right.
+    fn is_keyword(&self, i: usize, keyword: &str) -> bool {
+        if ["by", "without", "on", "ignoring"].contains(&keyword) && self.token(i + 1) != Some(&Token::LeftParen) {
+            return false;
+        }
+        match i.checked_sub(1).and_then(|p| self.token(p)) {
+            None | Some(Token::LeftParen) | Some(Token::LeftBrace) | Some(Token::Comma) => false,
+            Some(Token::Operator(_)) => BINARY_MODIFIERS.contains(&keyword),
+            _ => true,
+        }
+    }
+
+    fn matcher(&self) -> String {
+        format!("{}={}", self.label_name, quote(self.label_value))
+    }
+
+    // Walk expression, return insertions of enforced matcher.
+    fn inject(&mut self) -> Result<(), String> {
+        let mut parens = 0;
+        let mut i = 0;
+        while i < self.lexemes.len() {
+            match self.lexemes[i].token.clone() {
+                Token::Identifier(name) => {
+                    let lower = name.to_ascii_lowercase();
+                    let next = self.token(i + 1).cloned();
+                    if KEYWORDS.contains(&lower.as_str()) && self.is_keyword(i, &lower) {
+                        i = if GROUPING_KEYWORDS.contains(&lower.as_str()) && next == Some(Token::LeftParen) {
+                            self.skip_label_list(i + 1)?
+                        } else {
+                            i + 1
+                        };
+                    } else if lower == "inf" || lower == "nan" || next == Some(Token::LeftParen) {
+                        // number literal, or function and aggregation call
+                        i += 1;
+                    } else if AGGREGATIONS.contains(&lower.as_str())
+                        && matches!(&next, Some(Token::Identifier(n)) if n.eq_ignore_ascii_case("by") || n.eq_ignore_ascii_case("without"))
+                    {
+                        i += 1;
+                    } else if next == Some(Token::LeftBrace) {
+                        i = self.inject_matchers(i + 1)?;
+                    } else {
+                        // bare metric name
+                        let end = self.lexemes[i].end;
+                        self.insertions.push((end, format!("{{{}}}", self.matcher())));
+                        i += 1;
+                    }
+                }
+                Token::LeftBrace => i = self.inject_matchers(i)?,
+                Token::LeftBracket => i = self.skip_range(i)?,
+                Token::LeftParen => {
+                    parens += 1;
+                    i += 1;
+                }
+                Token::RightParen => {
+                    if parens == 0 {
+                        return Err(String::from("unbalanced parentheses"));
+                    }
+                    parens -= 1;
+                    i += 1;
+                }
+                Token::RightBrace | Token::RightBracket => {
+                    return Err(format!("unexpected bracket at position {}", self.lexemes[i].start))
+                }
+                _ => i += 1,
+            }
+        }
+        if parens != 0 {
+            return Err(String::from("unbalanced parentheses"));
+        }
+        Ok(())
+    }
+
+    // Skip label names of grouping, return index after closing parenthesis.
+    fn skip_label_list(&self, open: usize) -> Result<usize, String> {
+        let mut i = open + 1;
+        loop {
+            match self.token(i) {
+                Some(Token::RightParen) => return Ok(i + 1),
+                Some(Token::Identifier(_)) | Some(Token::Comma) => i += 1,
+                _ => return Err(String::from("unsupported grouping label list")),
+            }
+        }
+    }
+
+    // Skip range or subquery duration, return index after closing bracket.
+    fn skip_range(&self, open: usize) -> Result<usize, String> {
+        let mut i = open + 1;
+        loop {
+            match self.token(i) {
+                Some(Token::RightBracket) => return Ok(i + 1),
+                Some(Token::Number) | Some(Token::Identifier(_)) | Some(Token::Operator(_)) => i += 1,
+                _ => return Err(String::from("unsupported range duration")),
+            }
+        }
+    }
+
+    // Add enforced matcher to label matchers, return index after closing brace.
+    // Existing matchers of enforced label are allowed only when they select the same value.
+    fn inject_matchers(&mut self, open: usize) -> Result<usize, String> {
+        let mut i = open + 1;
+        let mut empty = true;
+        let mut enforced = false;
+        loop {
+            match self.token(i).cloned() {
+                Some(Token::RightBrace) => break,
+                Some(Token::Identifier(name)) => {
+                    let operator = match self.token(i + 1) {
+                        Some(Token::Operator(o)) if ["=", "!=", "=~", "!~"].contains(&o.as_str()) => o.clone(),
+                        _ => return Err(format!("unsupported label matcher for {}", name)),
+                    };
+                    let literal = match self.token(i + 2) {
+                        Some(Token::Str(s)) => s.clone(),
+                        _ => return Err(format!("unsupported label matcher for {}", name)),
+                    };
+                    if name == self.label_name && (operator != "=" || unquote(&literal)? != self.label_value) {
+                        return Err(format!("label {} is not allowed to select other than {}", name, quote(self.label_value)));
+                    }
+                    enforced |= name == self.label_name;
+                    empty = false;
+                    i += 3;
+                    match self.token(i) {
+                        Some(Token::Comma) => i += 1,
+                        Some(Token::RightBrace) => {}
+                        _ => return Err(String::from("unsupported label matchers")),
+                    }
+                }
+                _ => return Err(String::from("unsupported label matchers")),
+            }
+        }
+        if !enforced {
+            let matcher = if empty { self.matcher() } else { format!("{}, ", self.matcher()) };
+            self.insertions.push((self.lexemes[open].end, matcher));
+        }
+        Ok(i + 1)
+    }
+}
+
+// Add label_name="label_value" matcher to every vector selector in PromQL expression,
+// so the expression could only select series with that label value.
+// Expressions which could not be rewritten safely are refused.
+pub fn inject_label_matcher(query: &str, label_name: &str, label_value: &str) -> Result<String, String> {
+    let lexemes = lex(query)?;
+    if lexemes.is_empty() {
+        return Err(String::from("empty query"));
+    }
+    let mut injector = Injector { lexemes, label_name, label_value, insertions: vec![] };
+    injector.inject()?;
+
+    let mut rewritten = String::with_capacity(query.len() + injector.insertions.len() * 16);
+    let mut last = 0;
+    for (offset, text) in injector.insertions.iter() {
+        rewritten.push_str(&query[last..*offset]);
+        rewritten.push_str(text);
+        last = *offset;
+    }
+    rewritten.push_str(&query[last..]);
+    Ok(rewritten)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::promql::promql::inject_label_matcher;
+
+    fn inject(query: &str) -> Result<String, String> {
+        inject_label_matcher(query, "team", "a")
+    }
+
+    #[test]
+    fn test_inject_label_matcher() {
+        assert_eq!(inject("up").unwrap(), r#"up{team="a"}"#);
+        assert_eq!(inject(r#"{__name__="up"}"#).unwrap(), r#"{team="a", __name__="up"}"#);
+        assert_eq!(inject("up{}").unwrap(), r#"up{team="a"}"#);
+        assert_eq!(
+            inject(r#"sum by (job) (rate(http_requests_total{code=~"5.."}[5m]))"#).unwrap(),
+            r#"sum by (job) (rate(http_requests_total{team="a", code=~"5.."}[5m]))"#
+        );
+        assert_eq!(
+            inject("sum(a) by (instance) / on(instance) group_left(team) b > bool 1").unwrap(),
+            r#"sum(a{team="a"}) by (instance) / on(instance) group_left(team) b{team="a"} > bool 1"#
+        );
+        assert_eq!(
+            inject("max_over_time(job:up:sum[1h:5m]) offset 1d @ start()").unwrap(),
+            r#"max_over_time(job:up:sum{team="a"}[1h:5m]) offset 1d @ start()"#
+        );
+        assert_eq!(
+            inject(r#"label_replace(up, "dst", "$1", "src", "(.*)") * -1e-3 or vector(Inf)"#).unwrap(),
+            r#"label_replace(up{team="a"}, "dst", "$1", "src", "(.*)") * -1e-3 or vector(Inf)"#
+        );
+        // matcher selecting the same team is kept as is
+        assert_eq!(inject(r#"up{job="x",team='a'}"#).unwrap(), r#"up{job="x",team='a'}"#);
+    }
+
+    #[test]
+    fn test_inject_label_matcher_refused() {
+        // other teams
+        assert!(inject(r#"up{team="b"}"#).is_err());
+        assert!(inject(r#"up{team=~".*"}"#).is_err());
+        assert!(inject(r#"up{team!="a"}"#).is_err());
+        // malformed queries
+        assert!(inject("").is_err());
+        assert!(inject("up{").is_err());
+        assert!(inject("sum(up").is_err());
+        assert!(inject(r#"up{job="a}"#).is_err());
+        assert!(inject("up}").is_err());
+        assert!(inject(r#"up{"job"="a"}"#).is_err());
+    }
+
+    #[test]
+    fn test_inject_label_matcher_selectors() {
+        // metric name only, and name given as matcher
+        assert_eq!(inject("job:up:sum").unwrap(), r#"job:up:sum{team="a"}"#);
+        assert_eq!(inject(r#"{__name__=~"up|down"}"#).unwrap(), r#"{team="a", __name__=~"up|down"}"#);
+        // every selector of expression
+        assert_eq!(
+            inject(r#"a / b{job="x"} + on(job) c and {__name__="d"}"#).unwrap(),
+            r#"a{team="a"} / b{team="a", job="x"} + on(job) c{team="a"} and {team="a", __name__="d"}"#
+        );
+        // keywords in place of operand are metric names
+        assert_eq!(inject("sum(by)").unwrap(), r#"sum(by{team="a"})"#);
+        assert_eq!(inject("offset + on").unwrap(), r#"offset{team="a"} + on{team="a"}"#);
+        assert_eq!(inject("a > bool b").unwrap(), r#"a{team="a"} > bool b{team="a"}"#);
+        assert_eq!(inject("a and by").unwrap(), r#"a{team="a"} and by{team="a"}"#);
+        // hex digit e is not an exponent
+        assert_eq!(inject("0xe-up").unwrap(), r#"0xe-up{team="a"}"#);
+        assert_eq!(inject("1e-3*up").unwrap(), r#"1e-3*up{team="a"}"#);
+    }
+
+    #[test]
+    fn test_inject_label_matcher_modifiers() {
+        // ranges and subqueries
+        assert_eq!(inject("rate(up[5m])").unwrap(), r#"rate(up{team="a"}[5m])"#);
+        assert_eq!(
+            inject("max_over_time(rate(up[5m])[1h:1m])").unwrap(),
+            r#"max_over_time(rate(up{team="a"}[5m])[1h:1m])"#
+        );
+        assert_eq!(inject("min_over_time(up[1h:])").unwrap(), r#"min_over_time(up{team="a"}[1h:])"#);
+        // offset and @ modifiers
+        assert_eq!(inject("up offset -5m").unwrap(), r#"up{team="a"} offset -5m"#);
+        assert_eq!(inject("up @ 1609746000 offset 1h").unwrap(), r#"up{team="a"} @ 1609746000 offset 1h"#);
+        assert_eq!(inject("rate(up[5m] @ end())").unwrap(), r#"rate(up{team="a"}[5m] @ end())"#);
+        // grouping label lists are label names, not selectors
+        assert_eq!(inject("sum without (team, job) (up)").unwrap(), r#"sum without (team, job) (up{team="a"})"#);
+        assert_eq!(inject("count(up) BY (instance)").unwrap(), r#"count(up{team="a"}) BY (instance)"#);
+        assert_eq!(
+            inject("a * ignoring(team) group_right(job) b").unwrap(),
+            r#"a{team="a"} * ignoring(team) group_right(job) b{team="a"}"#
+        );
+        assert!(inject(r#"sum by (up{team="b"}) (up)"#).is_err());
+    }
+
+    #[test]
+    fn test_inject_label_matcher_literals() {
+        // braces in strings and comments are not selectors
+        assert_eq!(
+            inject(r#"label_replace(up, "dst", "}{", "src", `{(.*)}`)"#).unwrap(),
+            r#"label_replace(up{team="a"}, "dst", "}{", "src", `{(.*)}`)"#
+        );
+        assert_eq!(inject("up # {team=\"b\"}").unwrap(), "up{team=\"a\"} # {team=\"b\"}");
+        assert_eq!(inject("up # }\nor down").unwrap(), "up{team=\"a\"} # }\nor down{team=\"a\"}");
+        // comment ends at carriage return, as it does for Prometheus
+        assert_eq!(inject("up #\r+ down").unwrap(), "up{team=\"a\"} #\r+ down{team=\"a\"}");
+        // escaped quotes inside label values
+        assert_eq!(inject(r#"up{job="a\"}{"}"#).unwrap(), r#"up{team="a", job="a\"}{"}"#);
+        assert_eq!(inject(r#"up{job='\'}', team="a"}"#).unwrap(), r#"up{job='\'}', team="a"}"#);
+        assert!(inject(r#"up{team="\x61"}"#).is_err());
+        assert!(inject(r#"up{job="a\"}"#).is_err());
+        // quoted strings do not span lines
+        assert!(inject("up{job=\"a\n\"}").is_err());
+    }
+
+    #[test]
+    fn test_inject_label_matcher_enforced_label() {
+        assert_eq!(inject(r#"up{team="a"}"#).unwrap(), r#"up{team="a"}"#);
+        assert_eq!(inject(r#"up{team=`a`}"#).unwrap(), r#"up{team=`a`}"#);
+        assert!(inject(r#"up{team=~"a"}"#).is_err());
+        assert!(inject(r#"up{team=~"a|b"}"#).is_err());
+        assert!(inject(r#"up{team!="b"}"#).is_err());
+        assert!(inject(r#"up{team!~"a"}"#).is_err());
+        assert!(inject(r#"up{team="a", team="b"}"#).is_err());
+        assert!(inject(r#"up{team="a"} or {team="b"}"#).is_err());
+        assert!(inject(r#"up{team = "a\\"}"#).is_err());
+        // matcher of other label with the same name prefix is not enforced one
+        assert_eq!(inject(r#"up{teams="b"}"#).unwrap(), r#"up{team="a", teams="b"}"#);
+    }
+
+    #[test]
+    fn test_inject_label_matcher_malformed() {
+        for query in [
+            "{",
+            "}",
+            "up{job=\"a\"",
+            "up{job=\"a\",",
+            "sum(up{job=\"a\")",
+            "up{job}",
+            "up{job=}",
+            "up{job==\"a\"}",
+            "up{job=\"a\" team=\"a\"}",
+            "up{{job=\"a\"}}",
+            "up[5m",
+            "up[5m}",
+            "rate(up[5m)]",
+            "sum by (job (up)",
+            "up)",
+            "(up",
+            "up{job=\"a\"}}",
+            "up{job=`a}",
+            "up ¬ down",
+        ]
+        .iter()
+        {
+            assert!(inject(query).is_err(), "{}", query);
+        }
+    }
+}
@@ -0,0 +1,513 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::Bytes;
+use kube_metrics_mutli_tenancy_lib::logging::{new_request_id, LogEvent};
+use kube_metrics_mutli_tenancy_lib::telemetry;
+use log::warn;
+use opentelemetry::trace::{SpanKind, TraceContextExt};
+use opentelemetry::{Context, KeyValue};
+use prometheus::{HistogramVec, IntCounterVec};
+use warp::http::{Method, Response, StatusCode};
+use warp::path::FullPath;
+use warp::{Filter, Rejection, Reply};
+
+use crate::config::config::{self, QueryCaller};
+use crate::promql::promql::{inject_label_matcher, quote};
+
+
+// Caller name in metrics of requests without valid token
+const UNAUTHENTICATED: &str = "unauthenticated";
+
+// Parameters holding PromQL, their selectors are restricted to enforced label
+const PROMQL_PARAMETERS: &[&str] = &["query", "match[]"];
+
+// Endpoints returning every series unless restricted with match[]
+const MATCH_ENDPOINTS: &[&str] = &["series", "labels", "label_values"];
+
+// The only POST body whose PromQL parameters are rewritten
+const FORM_MEDIA_TYPE: &str = "application/x-www-form-urlencoded";
+
+// Read path proxy in front of Cortex query-frontend.
+// Callers are authenticated with bearer token, and queries are sent with
+// X-Scope-OrgID of tenants caller is allowed to read.
+pub struct QueryProxy {
+    client: reqwest::Client,
+    requests: IntCounterVec,
+    latency: HistogramVec,
+}
+
+// Incoming query request, as matched by routes
+struct QueryRequest {
+    endpoint: &'static str,
+    method: Method,
+    path: String,
+    query: String,
+    authorization: Option<String>,
+    content_type: Option<String>,
+    request_id: String,
+    body: Bytes,
+}
+
+impl QueryProxy {
+    // var:requests counts query requests by caller, endpoint and status
+    // var:latency observes query-frontend request durations by caller and endpoint
+    pub fn new(client: reqwest::Client, requests: IntCounterVec, latency: HistogramVec) -> QueryProxy {
+        QueryProxy { client, requests, latency }
+    }
+
+    // Find caller by bearer token in Authorization header, requests without valid token are counted as rejected.
+    pub fn authenticate(&self, authorization: Option<&str>, endpoint: &str) -> Option<QueryCaller> {
+        let token = authorization
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .unwrap_or("");
+        let caller = config::current().query_caller(token).cloned();
+        if caller.is_none() {
+            warn!("{} request rejected: invalid token", endpoint);
+            self.count(UNAUTHENTICATED, endpoint, StatusCode::UNAUTHORIZED.as_str());
+        }
+        caller
+    }
+
+    // Count request of caller to endpoint.
+    pub fn count(&self, caller: &str, endpoint: &str, status: &str) {
+        self.requests.with_label_values(&[caller, endpoint, status]).inc();
+    }
+
+    // Observe upstream request duration.
+    pub fn observe(&self, caller: &str, endpoint: &str, elapsed_ms: u64) {
+        self.latency.with_label_values(&[caller, endpoint]).observe(elapsed_ms as f64);
+    }
+
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    async fn handle(&self, mut request: QueryRequest) -> Response<Bytes> {
+        let caller = match self.authenticate(request.authorization.as_deref(), request.endpoint) {
+            Some(c) => c,
+            None => return reply(StatusCode::UNAUTHORIZED, None, Bytes::from("unauthorized")),
+        };
+        let config = config::current();
+
+        if request.body.len() as u64 > config.limits.content_length_limit {
+            self.count(&caller.name, request.endpoint, StatusCode::PAYLOAD_TOO_LARGE.as_str());
+            return reply(StatusCode::PAYLOAD_TOO_LARGE, None, Bytes::from("payload too large"));
+        }
+
+        if !config.query.enforced_label.is_empty() {
+            if let Err(e) = check_form_body(&request) {
+                warn!("query request of {} refused: {}", caller.name, e);
+                self.count(&caller.name, request.endpoint, StatusCode::UNSUPPORTED_MEDIA_TYPE.as_str());
+                let error = serde_json::json!({"status": "error", "errorType": "bad_data", "error": e});
+                return reply(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    Some(String::from("application/json")),
+                    Bytes::from(error.to_string()),
+                );
+            }
+            if let Err(e) = enforce_label(&mut request, &config.query.enforced_label, caller.enforced_label_value()) {
+                warn!("query request of {} refused: {}", caller.name, e);
+                self.count(&caller.name, request.endpoint, StatusCode::BAD_REQUEST.as_str());
+                let error = serde_json::json!({"status": "error", "errorType": "bad_data", "error": e});
+                return reply(
+                    StatusCode::BAD_REQUEST,
+                    Some(String::from("application/json")),
+                    Bytes::from(error.to_string()),
+                );
+            }
+        }
+
+        // path is appended to frontend url as is, incoming Authorization header is not forwarded
+        let mut url = format!("{}{}", config.query.frontend_url.trim_end_matches('/'), request.path);
+        if !request.query.is_empty() {
+            url = format!("{}?{}", url, request.query);
+        }
+        let org_id = caller.org_id();
+
+        let cx = telemetry::start_span(
+            &Context::new(),
+            "query",
+            SpanKind::Client,
+            vec![
+                KeyValue::new("caller", caller.name.clone()),
+                KeyValue::new("endpoint", request.endpoint),
+                KeyValue::new("request_id", request.request_id.clone()),
+            ],
+        );
+        let mut upstream = self
+            .client
+            .request(request.method, &url)
+            .header("X-Scope-OrgID", org_id.as_str())
+            .body(request.body);
+        if let Some(content_type) = &request.content_type {
+            upstream = upstream.header("content-type", content_type.as_str());
+        }
+        for (name, value) in telemetry::trace_headers(&cx) {
+            upstream = upstream.header(name.as_str(), value);
+        }
+
+        let started = Instant::now();
+        let result = match upstream.send().await {
+            Ok(r) => {
+                let status = r.status();
+                let content_type = r
+                    .headers()
+                    .get("content-type")
+                    .and_then(|v| v.to_str().ok())
+                    .map(String::from);
+                r.bytes().await.map(|body| (status, content_type, body))
+            }
+            Err(e) => Err(e),
+        };
+        let elapsed = started.elapsed().as_millis() as u64;
+        self.observe(&caller.name, request.endpoint, elapsed);
+
+        let access = LogEvent::access("proxy", &request.request_id)
+            .tenant(&org_id)
+            .upstream(&url)
+            .duration_ms(elapsed);
+        let (status, content_type, body) = match result {
+            Ok(r) => {
+                telemetry::record_status(&cx, r.0.as_u16());
+                access.status(r.0.as_u16()).emit();
+                r
+            }
+            Err(e) => {
+                telemetry::record_error(&cx, &e.to_string());
+                access.status("error").error(&e.to_string()).emit();
+                (StatusCode::BAD_GATEWAY, None, Bytes::from("query-frontend request failed"))
+            }
+        };
+        cx.span().end();
+        self.count(&caller.name, request.endpoint, status.as_str());
+        reply(status, content_type, body)
+    }
+}
+
+// Restrict PromQL parameters of request to series with enforced label value,
+// parameters are read from query string, and from body of form encoded POST requests.
+fn enforce_label(request: &mut QueryRequest, name: &str, value: &str) -> Result<(), String> {
+    let (query, mut has_match) = enforce_label_parameters(&request.query, name, value)?;
+    request.query = query;
+    if !request.body.is_empty() {
+        let body = std::str::from_utf8(&request.body).map_err(|_| String::from("invalid form body"))?;
+        let (body, body_has_match) = enforce_label_parameters(body, name, value)?;
+        request.body = Bytes::from(body);
+        has_match |= body_has_match;
+    }
+    // series and label names of other teams would be listed otherwise
+    if MATCH_ENDPOINTS.contains(&request.endpoint) && !has_match {
+        let selector = format!("{{{}={}}}", name, quote(value));
+        let mut serializer = form_urlencoded::Serializer::new(request.query.clone());
+        serializer.append_pair("match[]", &selector);
+        request.query = serializer.finish();
+    }
+    Ok(())
+}
+
+// Media type of Content-Type header, lowercase and without parameters.
+fn media_type(content_type: Option<&str>) -> Option<String> {
+    content_type
+        .and_then(|c| c.split(';').next())
+        .map(|m| m.trim().to_ascii_lowercase())
+}
+
+// Query-frontend reads PromQL parameters from multipart bodies too, and matches media types case-insensitively,
+// so any body but form encoded one, which is rewritten, would bypass enforced label.
+fn check_form_body(request: &QueryRequest) -> Result<(), String> {
+    if request.body.is_empty() || media_type(request.content_type.as_deref()).as_deref() == Some(FORM_MEDIA_TYPE) {
+        return Ok(());
+    }
+    Err(format!(
+        "unsupported content type {:?}, request body must be {}",
+        request.content_type.as_deref().unwrap_or(""),
+        FORM_MEDIA_TYPE
+    ))
+}
+
+// Rewrite PromQL parameters of form encoded string, return it along with whether match[] was found.
+fn enforce_label_parameters(params: &str, name: &str, value: &str) -> Result<(String, bool), String> {
+    let mut has_match = false;
+    let mut serializer = form_urlencoded::Serializer::new(String::new());
+    for (k, v) in form_urlencoded::parse(params.as_bytes()) {
+        if PROMQL_PARAMETERS.contains(&k.as_ref()) {
+            has_match |= k == "match[]";
+            let rewritten = inject_label_matcher(&v, name, value).map_err(|e| format!("{}: {}", k, e))?;
+            serializer.append_pair(&k, &rewritten);
+        } else {
+            serializer.append_pair(&k, &v);
+        }
+    }
+    Ok((serializer.finish(), has_match))
+}
+
+pub fn reply(status: StatusCode, content_type: Option<String>, body: Bytes) -> Response<Bytes> {
+    let mut response = Response::builder().status(status);
+    if let Some(content_type) = content_type {
+        response = response.header("content-type", content_type);
+    }
+    // it is safe to unwrap, status and header come from valid http values
+    response.body(body).unwrap()
+}
+
+// Query API routes, proxied to query-frontend:
+//
+// GET|POST /api/v1/query
+// GET|POST /api/v1/query_range
+// GET|POST /api/v1/series
+// GET|POST /api/v1/labels
+// GET      /api/v1/label/<name>/values
+//
+// caller token is expected as bearer token in Authorization header
+pub fn routes(proxy: Arc<QueryProxy>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let query = warp::path!("api" / "v1" / "query").map(|| "query");
+    let query_range = warp::path!("api" / "v1" / "query_range").map(|| "query_range");
+    let series = warp::path!("api" / "v1" / "series").map(|| "series");
+    let labels = warp::path!("api" / "v1" / "labels").map(|| "labels");
+    let label_values = warp::path!("api" / "v1" / "label" / String / "values")
+        .and(warp::get())
+        .map(|_name: String| "label_values");
+    let endpoint = query
+        .or(query_range)
+        .unify()
+        .or(series)
+        .unify()
+        .or(labels)
+        .unify()
+        .or(label_values)
+        .unify();
+
+    // form encoded parameters are passed in body of POST requests,
+    // content length limit set on start is a hard cap, reloaded limit is checked per request
+    let body = warp::get()
+        .map(Bytes::new)
+        .or(warp::post()
+            .and(warp::body::content_length_limit(config::current().limits.content_length_limit))
+            .and(warp::body::bytes()))
+        .unify();
+    let query_string = warp::query::raw()
+        .or(warp::any().map(String::new))
+        .unify();
+
+    endpoint
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(query_string)
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<String>("x-request-id"))
+        .and(body)
+        .and_then(
+            move |endpoint: &'static str,
+                  method: Method,
+                  path: FullPath,
+                  query: String,
+                  authorization: Option<String>,
+                  content_type: Option<String>,
+                  request_id: Option<String>,
+                  body: Bytes| {
+                let proxy = proxy.clone();
+                async move {
+                    let request = QueryRequest {
+                        endpoint,
+                        method,
+                        path: String::from(path.as_str()),
+                        query,
+                        authorization,
+                        content_type,
+                        request_id: request_id.unwrap_or_else(new_request_id),
+                        body,
+                    };
+                    Ok::<_, Infallible>(proxy.handle(request).await)
+                }
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use mockito::{mock, Matcher};
+    use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, Opts};
+    use serial_test::serial;
+    use warp::http::StatusCode;
+
+    use crate::config::config::{swap, ProxyConfig};
+    use crate::query::query::{routes, QueryProxy};
+
+    fn query_proxy() -> Arc<QueryProxy> {
+        let requests = IntCounterVec::new(Opts::new("requests", "requests"), &["caller", "endpoint", "status"]).unwrap();
+        let latency = HistogramVec::new(HistogramOpts::new("latency", "latency"), &["caller", "endpoint"]).unwrap();
+        Arc::new(QueryProxy::new(reqwest::Client::new(), requests, latency))
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_query_proxy() {
+        swap(ProxyConfig::parse(&format!(r#"
+query:
+  frontend_url: {}/prometheus/
+  callers:
+    - {{name: team-a, token: token-a, tenants: [tenant1, tenant2]}}
+"#, mockito::server_url())).unwrap());
+
+        let frontend = mock("GET", "/prometheus/api/v1/label/job/values")
+            .match_header("x-scope-orgid", "tenant1|tenant2")
+            .match_header("authorization", Matcher::Missing)
+            .match_query(Matcher::UrlEncoded(String::from("start"), String::from("1")))
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"status":"success","data":["node"]}"#)
+            .create();
+
+        let proxy = query_proxy();
+        let api = routes(proxy.clone());
+
+        let resp = warp::test::request()
+            .path("/api/v1/label/job/values?start=1")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = warp::test::request()
+            .path("/api/v1/label/job/values?start=1")
+            .header("Authorization", "Bearer token-a")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "application/json");
+        assert_eq!(resp.body(), r#"{"status":"success","data":["node"]}"#);
+        frontend.assert();
+
+        assert_eq!(proxy.requests.with_label_values(&["unauthenticated", "label_values", "401"]).get(), 1);
+        assert_eq!(proxy.requests.with_label_values(&["team-a", "label_values", "200"]).get(), 1);
+
+        // other paths are not handled by query routes
+        let resp = warp::test::request()
+            .path("/api/v1/push")
+            .header("Authorization", "Bearer token-a")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // reloaded content length limit applies to routes built before reload
+        swap(ProxyConfig::parse(&format!(r#"
+limits: {{content_length_limit: 8}}
+query:
+  frontend_url: {}/prometheus/
+  callers:
+    - {{name: team-a, token: token-a, tenants: [tenant1, tenant2]}}
+"#, mockito::server_url())).unwrap());
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/api/v1/labels")
+            .header("Authorization", "Bearer token-a")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body("match[]=up&start=1")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(proxy.requests.with_label_values(&["team-a", "labels", "413"]).get(), 1);
+
+        swap(ProxyConfig::default());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_query_proxy_enforced_label() {
+        swap(ProxyConfig::parse(&format!(r#"
+query:
+  frontend_url: {}/
+  enforced_label: team
+  callers:
+    - {{name: team-a, token: token-a, tenants: [shared]}}
+"#, mockito::server_url())).unwrap());
+
+        let query = mock("POST", "/api/v1/query")
+            .match_header("x-scope-orgid", "shared")
+            .match_body(Matcher::UrlEncoded(String::from("query"), String::from(r#"sum(up{team="team-a"})"#)))
+            .create();
+        let cased_query = mock("POST", "/api/v1/query")
+            .match_body(Matcher::UrlEncoded(String::from("query"), String::from(r#"count(up{team="team-a"})"#)))
+            .create();
+        let series = mock("GET", "/api/v1/series")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded(String::from("match[]"), String::from(r#"up{team="team-a", job="node"}"#)),
+                Matcher::UrlEncoded(String::from("start"), String::from("1")),
+            ]))
+            .create();
+        let labels = mock("GET", "/api/v1/labels")
+            .match_query(Matcher::UrlEncoded(String::from("match[]"), String::from(r#"{team="team-a"}"#)))
+            .create();
+
+        let proxy = query_proxy();
+        let api = routes(proxy.clone());
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/api/v1/query")
+            .header("Authorization", "Bearer token-a")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body("query=sum%28up%29")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        query.assert();
+
+        // media type is matched case-insensitively
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/api/v1/query")
+            .header("Authorization", "Bearer token-a")
+            .header("content-type", "Application/X-WWW-Form-Urlencoded; charset=UTF-8")
+            .body("query=count%28up%29")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        cased_query.assert();
+
+        // multipart body is not rewritten, so it is refused
+        let multipart = mock("POST", "/api/v1/query").match_body(Matcher::Regex(String::from("up"))).expect(0).create();
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/api/v1/query")
+            .header("Authorization", "Bearer token-a")
+            .header("content-type", "multipart/form-data; boundary=b")
+            .body("--b\r\nContent-Disposition: form-data; name=\"query\"\r\n\r\nup\r\n--b--\r\n")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(proxy.requests.with_label_values(&["team-a", "query", "415"]).get(), 1);
+        multipart.assert();
+
+        let resp = warp::test::request()
+            .path("/api/v1/series?match%5B%5D=up%7Bjob%3D%22node%22%7D&start=1")
+            .header("Authorization", "Bearer token-a")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        series.assert();
+
+        // labels of whole tenant are restricted to caller team
+        let resp = warp::test::request()
+            .path("/api/v1/labels")
+            .header("Authorization", "Bearer token-a")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        labels.assert();
+
+        // selecting other team is refused
+        let resp = warp::test::request()
+            .path("/api/v1/query?query=up%7Bteam%3D%22team-b%22%7D")
+            .header("Authorization", "Bearer token-a")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(proxy.requests.with_label_values(&["team-a", "query", "400"]).get(), 1);
+
+        swap(ProxyConfig::default());
+    }
+}
@@ -0,0 +1 @@
+pub mod remote_read;
\ No newline at end of file
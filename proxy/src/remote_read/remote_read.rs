@@ -0,0 +1,635 @@
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::Bytes;
+use futures::future::join_all;
+use hyper::Body;
+use kube_metrics_mutli_tenancy_lib::logging::{new_request_id, LogEvent};
+use kube_metrics_mutli_tenancy_lib::telemetry;
+use log::warn;
+use opentelemetry::trace::{SpanKind, TraceContextExt};
+use opentelemetry::{Context, KeyValue};
+use protobuf::{Message, RepeatedField};
+use warp::http::{Response, StatusCode};
+use warp::{Filter, Rejection, Reply};
+
+use crate::config::config::{self, QueryCaller};
+use crate::proto::prometheus::{
+    ChunkedReadResponse, ChunkedSeries, Label, LabelMatcher, LabelMatcher_Type, QueryResult, ReadRequest,
+    ReadResponse, TimeSeries,
+};
+use crate::query::query::QueryProxy;
+
+
+// Endpoint name in query metrics
+const ENDPOINT: &str = "read";
+
+// Content type of STREAMED_XOR_CHUNKS response
+pub const STREAMED_CONTENT_TYPE: &str = "application/x-streamed-protobuf; proto=prometheus.ChunkedReadResponse";
+
+// Content type of SAMPLES response, which is Snappy-compressed
+const SAMPLES_CONTENT_TYPE: &str = "application/x-protobuf";
+
+// Max size of merged series in single frame, the same Prometheus uses by default
+const MAX_BYTES_IN_FRAME: usize = 1024 * 1024;
+
+// CRC32 Castagnoli polynomial, reversed, frames are checksummed with
+const CASTAGNOLI: u32 = 0x82f6_3b78;
+
+// Decoded remote read response of single tenant
+enum TenantResponse {
+    Samples(ReadResponse),
+    Chunked(Vec<ChunkedReadResponse>),
+}
+
+// Remote read route, proxied to query-frontend for each tenant caller may read:
+//
+// POST /api/v1/read
+//
+// caller token is expected as bearer token in Authorization header
+pub fn routes(proxy: Arc<QueryProxy>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("api" / "v1" / "read")
+        .and(warp::post())
+        // content length limit set on start is a hard cap, reloaded limit is checked per request
+        .and(warp::body::content_length_limit(config::current().limits.content_length_limit))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>("x-request-id"))
+        .and(warp::body::bytes())
+        .and_then(move |authorization: Option<String>, request_id: Option<String>, body: Bytes| {
+            let proxy = proxy.clone();
+            async move {
+                let request_id = request_id.unwrap_or_else(new_request_id);
+                Ok::<_, Infallible>(remote_read(&proxy, authorization.as_deref(), &request_id, body).await)
+            }
+        })
+}
+
+// Forward read request to every tenant of caller.
+// Single tenant response is passed through as is, so streamed chunks are not buffered,
+// responses of several tenants are merged.
+async fn remote_read(proxy: &QueryProxy, authorization: Option<&str>, request_id: &str, body: Bytes) -> Response<Body> {
+    let caller = match proxy.authenticate(authorization, ENDPOINT) {
+        Some(c) => c,
+        None => return error_reply(StatusCode::UNAUTHORIZED, String::from("unauthorized")),
+    };
+    let config = config::current();
+
+    if body.len() as u64 > config.limits.content_length_limit {
+        proxy.count(&caller.name, ENDPOINT, StatusCode::PAYLOAD_TOO_LARGE.as_str());
+        return error_reply(StatusCode::PAYLOAD_TOO_LARGE, String::from("payload too large"));
+    }
+
+    let mut read_request = match decode_read_request(&body) {
+        Ok(r) => r,
+        Err(e) => {
+            proxy.count(&caller.name, ENDPOINT, StatusCode::BAD_REQUEST.as_str());
+            return error_reply(StatusCode::BAD_REQUEST, e);
+        }
+    };
+    if !config.query.enforced_label.is_empty() {
+        if let Err(e) = enforce_label_matchers(&mut read_request, &config.query.enforced_label, caller.enforced_label_value()) {
+            warn!("read request of {} refused: {}", caller.name, e);
+            proxy.count(&caller.name, ENDPOINT, StatusCode::BAD_REQUEST.as_str());
+            return error_reply(StatusCode::BAD_REQUEST, e);
+        }
+    }
+    // it is safe to unwrap, request was just decoded
+    let payload = Bytes::from(
+        snap::raw::Encoder::new()
+            .compress_vec(&read_request.write_to_bytes().unwrap())
+            .unwrap(),
+    );
+    let url = format!("{}/api/v1/read", config.query.frontend_url.trim_end_matches('/'));
+
+    let tenants = caller.tenant_ids();
+    let responses = join_all(
+        tenants
+            .iter()
+            .map(|tenant_id| read_tenant(proxy, &caller, tenant_id, &url, payload.clone(), request_id)),
+    )
+    .await;
+
+    let mut decoded = vec![];
+    for response in responses {
+        let response = match response {
+            Ok(r) => r,
+            Err(e) => {
+                proxy.count(&caller.name, ENDPOINT, StatusCode::BAD_GATEWAY.as_str());
+                return error_reply(StatusCode::BAD_GATEWAY, format!("query-frontend request failed: {}", e));
+            }
+        };
+        if tenants.len() == 1 || !response.status().is_success() {
+            // first failed tenant fails the whole request, nothing would be merged then
+            proxy.count(&caller.name, ENDPOINT, response.status().as_str());
+            return stream_reply(response);
+        }
+        match decode_tenant_response(response).await {
+            Ok(r) => decoded.push(r),
+            Err(e) => {
+                proxy.count(&caller.name, ENDPOINT, StatusCode::BAD_GATEWAY.as_str());
+                return error_reply(StatusCode::BAD_GATEWAY, format!("invalid query-frontend response: {}", e));
+            }
+        }
+    }
+
+    let reply = if decoded.iter().all(|r| matches!(r, TenantResponse::Chunked(_))) {
+        let frames = decoded
+            .into_iter()
+            .filter_map(|r| match r {
+                TenantResponse::Chunked(f) => Some(f),
+                _ => None,
+            })
+            .collect();
+        Response::builder()
+            .header("content-type", STREAMED_CONTENT_TYPE)
+            .body(Body::from(encode_frames(&merge_chunked_responses(frames))))
+    } else if decoded.iter().all(|r| matches!(r, TenantResponse::Samples(_))) {
+        let responses = decoded
+            .into_iter()
+            .filter_map(|r| match r {
+                TenantResponse::Samples(s) => Some(s),
+                _ => None,
+            })
+            .collect();
+        // it is safe to unwrap, response is built from decoded messages
+        let merged = merge_read_responses(responses).write_to_bytes().unwrap();
+        Response::builder()
+            .header("content-type", SAMPLES_CONTENT_TYPE)
+            .header("content-encoding", "snappy")
+            .body(Body::from(snap::raw::Encoder::new().compress_vec(&merged).unwrap()))
+    } else {
+        proxy.count(&caller.name, ENDPOINT, StatusCode::BAD_GATEWAY.as_str());
+        return error_reply(StatusCode::BAD_GATEWAY, String::from("tenants responded with different response types"));
+    };
+    proxy.count(&caller.name, ENDPOINT, StatusCode::OK.as_str());
+    // it is safe to unwrap, headers are static
+    reply.unwrap()
+}
+
+// Send read request of single tenant.
+async fn read_tenant(
+    proxy: &QueryProxy,
+    caller: &QueryCaller,
+    tenant_id: &str,
+    url: &str,
+    payload: Bytes,
+    request_id: &str,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let cx = telemetry::start_span(
+        &Context::new(),
+        "remote_read",
+        SpanKind::Client,
+        vec![
+            KeyValue::new("caller", caller.name.clone()),
+            KeyValue::new("tenant_id", String::from(tenant_id)),
+            KeyValue::new("request_id", String::from(request_id)),
+        ],
+    );
+    let mut request = proxy
+        .client()
+        .post(url)
+        .header("X-Scope-OrgID", tenant_id)
+        .header("X-Prometheus-Remote-Read-Version", "0.1.0")
+        .header("content-type", SAMPLES_CONTENT_TYPE)
+        .header("content-encoding", "snappy")
+        .body(payload);
+    for (name, value) in telemetry::trace_headers(&cx) {
+        request = request.header(name.as_str(), value);
+    }
+
+    let started = Instant::now();
+    let response = request.send().await;
+    let elapsed = started.elapsed().as_millis() as u64;
+    proxy.observe(&caller.name, ENDPOINT, elapsed);
+
+    let access = LogEvent::access("proxy", request_id)
+        .tenant(tenant_id)
+        .upstream(url)
+        .duration_ms(elapsed);
+    match &response {
+        Ok(r) => {
+            telemetry::record_status(&cx, r.status().as_u16());
+            access.status(r.status().as_u16()).emit();
+        }
+        Err(e) => {
+            telemetry::record_error(&cx, &e.to_string());
+            access.status("error").error(&e.to_string()).emit();
+        }
+    }
+    cx.span().end();
+    response
+}
+
+fn error_reply(status: StatusCode, message: String) -> Response<Body> {
+    // it is safe to unwrap, status is valid
+    Response::builder().status(status).body(Body::from(message)).unwrap()
+}
+
+// Pass upstream response through, body is relayed chunk by chunk as it arrives.
+fn stream_reply(mut response: reqwest::Response) -> Response<Body> {
+    let mut reply = Response::builder().status(response.status());
+    for name in ["content-type", "content-encoding"].iter() {
+        if let Some(value) = response.headers().get(*name) {
+            reply = reply.header(*name, value.clone());
+        }
+    }
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        loop {
+            match response.chunk().await {
+                Ok(Some(chunk)) => {
+                    // caller went away
+                    if sender.send_data(chunk).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("failed to relay read response: {}", e);
+                    sender.abort();
+                    break;
+                }
+            }
+        }
+    });
+    // it is safe to unwrap, status and headers come from upstream response
+    reply.body(body).unwrap()
+}
+
+async fn decode_tenant_response(response: reqwest::Response) -> Result<TenantResponse, String> {
+    let streamed = response
+        .headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .map(|c| c.starts_with("application/x-streamed-protobuf"))
+        .unwrap_or(false);
+    let body = response.bytes().await.map_err(|e| e.to_string())?;
+    if streamed {
+        decode_frames(&body).map(TenantResponse::Chunked)
+    } else {
+        let message = snap::raw::Decoder::new().decompress_vec(&body).map_err(|e| e.to_string())?;
+        ReadResponse::parse_from_bytes(&message)
+            .map(TenantResponse::Samples)
+            .map_err(|e| e.to_string())
+    }
+}
+
+// unpacks Snappy payload, and parses Prometheus read request
+pub fn decode_read_request(bytes: &[u8]) -> Result<ReadRequest, String> {
+    let message = snap::raw::Decoder::new().decompress_vec(bytes).map_err(|e| e.to_string())?;
+    ReadRequest::parse_from_bytes(&message).map_err(|e| e.to_string())
+}
+
+// Add name="value" matcher to every query of read request.
+// Queries selecting other value of the label are refused.
+pub fn enforce_label_matchers(request: &mut ReadRequest, name: &str, value: &str) -> Result<(), String> {
+    for query in request.queries.iter_mut() {
+        let mut enforced = false;
+        for matcher in query.matchers.iter().filter(|m| m.name == name) {
+            if matcher.field_type != LabelMatcher_Type::EQ || matcher.value != value {
+                return Err(format!("label {} is not allowed to select other than {:?}", name, value));
+            }
+            enforced = true;
+        }
+        if !enforced {
+            let mut matcher = LabelMatcher::new();
+            matcher.field_type = LabelMatcher_Type::EQ;
+            matcher.name = String::from(name);
+            matcher.value = String::from(value);
+            query.matchers.push(matcher);
+        }
+    }
+    Ok(())
+}
+
+fn label_key(labels: &[Label]) -> Vec<(String, String)> {
+    let mut key: Vec<(String, String)> = labels.iter().map(|l| (l.name.clone(), l.value.clone())).collect();
+    key.sort();
+    key
+}
+
+// Merge SAMPLES responses of several tenants, query by query.
+// Series with the same labels are merged into one, duplicate samples are dropped.
+pub fn merge_read_responses(responses: Vec<ReadResponse>) -> ReadResponse {
+    let mut queries: Vec<BTreeMap<Vec<(String, String)>, TimeSeries>> = vec![];
+    for response in responses {
+        for (i, result) in response.results.into_iter().enumerate() {
+            if queries.len() <= i {
+                queries.push(BTreeMap::new());
+            }
+            for series in result.timeseries.into_iter() {
+                match queries[i].entry(label_key(&series.labels)) {
+                    Entry::Vacant(e) => {
+                        e.insert(series);
+                    }
+                    Entry::Occupied(mut e) => {
+                        let merged = e.get_mut();
+                        for sample in series.samples.into_iter() {
+                            merged.samples.push(sample);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let mut merged = ReadResponse::new();
+    for series in queries {
+        let mut result = QueryResult::new();
+        for (_, mut s) in series {
+            let mut samples = s.samples.into_vec();
+            samples.sort_by_key(|s| s.timestamp);
+            samples.dedup_by_key(|s| s.timestamp);
+            s.samples = RepeatedField::from_vec(samples);
+            result.timeseries.push(s);
+        }
+        merged.results.push(result);
+    }
+    merged
+}
+
+// Merge STREAMED_XOR_CHUNKS responses of several tenants, query by query.
+// Series with the same labels are merged into one, identical chunks are dropped.
+pub fn merge_chunked_responses(responses: Vec<Vec<ChunkedReadResponse>>) -> Vec<ChunkedReadResponse> {
+    let mut queries: BTreeMap<i64, BTreeMap<Vec<(String, String)>, ChunkedSeries>> = BTreeMap::new();
+    for frames in responses {
+        for frame in frames {
+            let query = queries.entry(frame.query_index).or_default();
+            for series in frame.chunked_series.into_iter() {
+                match query.entry(label_key(&series.labels)) {
+                    Entry::Vacant(e) => {
+                        e.insert(series);
+                    }
+                    Entry::Occupied(mut e) => {
+                        let merged = e.get_mut();
+                        for chunk in series.chunks.into_iter() {
+                            merged.chunks.push(chunk);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let mut merged = vec![];
+    for (query_index, series) in queries {
+        let mut frame = ChunkedReadResponse::new();
+        frame.query_index = query_index;
+        let mut size = 0;
+        for (_, mut s) in series {
+            let mut chunks = s.chunks.into_vec();
+            chunks.sort_by_key(|c| (c.min_time_ms, c.max_time_ms));
+            chunks.dedup_by(|a, b| a.min_time_ms == b.min_time_ms && a.max_time_ms == b.max_time_ms && a.data == b.data);
+            s.chunks = RepeatedField::from_vec(chunks);
+
+            let series_size = s.compute_size() as usize;
+            if size > 0 && size + series_size > MAX_BYTES_IN_FRAME {
+                let mut next = ChunkedReadResponse::new();
+                next.query_index = query_index;
+                merged.push(std::mem::replace(&mut frame, next));
+                size = 0;
+            }
+            size += series_size;
+            frame.chunked_series.push(s);
+        }
+        if !frame.chunked_series.is_empty() {
+            merged.push(frame);
+        }
+    }
+    merged
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ CASTAGNOLI } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// Frame messages as Prometheus does: uvarint size, big-endian CRC32 Castagnoli checksum, and message.
+pub fn encode_frames(frames: &[ChunkedReadResponse]) -> Vec<u8> {
+    let mut encoded = vec![];
+    for frame in frames {
+        // it is safe to unwrap, frame is built from decoded messages
+        let message = frame.write_to_bytes().unwrap();
+        let mut size = message.len() as u64;
+        while size >= 0x80 {
+            encoded.push((size as u8) | 0x80);
+            size >>= 7;
+        }
+        encoded.push(size as u8);
+        encoded.extend_from_slice(&crc32c(&message).to_be_bytes());
+        encoded.extend_from_slice(&message);
+    }
+    encoded
+}
+
+// Parse framed messages of streamed response, checksums are verified.
+pub fn decode_frames(mut bytes: &[u8]) -> Result<Vec<ChunkedReadResponse>, String> {
+    let mut frames = vec![];
+    while !bytes.is_empty() {
+        let mut size: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = match bytes.first() {
+                Some(b) if shift < 64 => *b,
+                _ => return Err(String::from("invalid frame size")),
+            };
+            bytes = &bytes[1..];
+            size |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+            if byte < 0x80 {
+                break;
+            }
+        }
+        let size = size as usize;
+        if bytes.len() < 4 || bytes.len() - 4 < size {
+            return Err(String::from("truncated frame"));
+        }
+        let checksum = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let message = &bytes[4..4 + size];
+        if crc32c(message) != checksum {
+            return Err(String::from("frame checksum mismatch"));
+        }
+        frames.push(ChunkedReadResponse::parse_from_bytes(message).map_err(|e| e.to_string())?);
+        bytes = &bytes[4 + size..];
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use mockito::mock;
+    use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, Opts};
+    use protobuf::Message;
+    use serial_test::serial;
+    use warp::http::StatusCode;
+
+    use crate::config::config::{swap, ProxyConfig};
+    use crate::proto::prometheus::{
+        Chunk, ChunkedReadResponse, ChunkedSeries, Label, LabelMatcher_Type, Query, QueryResult, ReadRequest,
+        ReadResponse, Sample, TimeSeries,
+    };
+    use crate::query::query::QueryProxy;
+    use crate::remote_read::remote_read::{
+        crc32c, decode_frames, decode_read_request, encode_frames, enforce_label_matchers, merge_chunked_responses,
+        merge_read_responses, routes,
+    };
+
+    fn labels(values: &[(&str, &str)]) -> Vec<Label> {
+        values
+            .iter()
+            .map(|(name, value)| {
+                let mut label = Label::new();
+                label.name = String::from(*name);
+                label.value = String::from(*value);
+                label
+            })
+            .collect()
+    }
+
+    fn series(name: &str, timestamps: &[i64]) -> TimeSeries {
+        let mut series = TimeSeries::new();
+        series.labels = labels(&[("__name__", name)]).into();
+        for t in timestamps {
+            let mut sample = Sample::new();
+            sample.timestamp = *t;
+            sample.value = 1.0;
+            series.samples.push(sample);
+        }
+        series
+    }
+
+    fn read_response(series: Vec<TimeSeries>) -> ReadResponse {
+        let mut result = QueryResult::new();
+        result.timeseries = series.into();
+        let mut response = ReadResponse::new();
+        response.results.push(result);
+        response
+    }
+
+    fn snappy(message: &[u8]) -> Vec<u8> {
+        snap::raw::Encoder::new().compress_vec(message).unwrap()
+    }
+
+    #[test]
+    fn test_merge_read_responses() {
+        let merged = merge_read_responses(vec![
+            read_response(vec![series("up", &[1, 2]), series("b", &[1])]),
+            read_response(vec![series("up", &[2, 3])]),
+        ]);
+        let result = &merged.results[0];
+        assert_eq!(result.timeseries.len(), 2);
+        // series are sorted by labels, and samples by time without duplicates
+        assert_eq!(result.timeseries[0].labels[0].value, "b");
+        let timestamps: Vec<i64> = result.timeseries[1].samples.iter().map(|s| s.timestamp).collect();
+        assert_eq!(timestamps, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_chunked_frames() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+
+        let frame = |min_time_ms: i64| {
+            let mut chunk = Chunk::new();
+            chunk.min_time_ms = min_time_ms;
+            chunk.max_time_ms = min_time_ms + 10;
+            chunk.data = vec![1; 200];
+            let mut series = ChunkedSeries::new();
+            series.labels = labels(&[("__name__", "up")]).into();
+            series.chunks.push(chunk);
+            let mut frame = ChunkedReadResponse::new();
+            frame.chunked_series.push(series);
+            frame
+        };
+        let merged = merge_chunked_responses(vec![vec![frame(20), frame(0)], vec![frame(0)]]);
+        assert_eq!(merged.len(), 1);
+        let chunks: Vec<i64> = merged[0].chunked_series[0].chunks.iter().map(|c| c.min_time_ms).collect();
+        assert_eq!(chunks, vec![0, 20]);
+
+        let encoded = encode_frames(&merged);
+        assert_eq!(decode_frames(&encoded).unwrap(), merged);
+        assert!(decode_frames(&encoded[..encoded.len() - 1]).is_err());
+        let mut corrupted = encoded.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(decode_frames(&corrupted).is_err());
+    }
+
+    #[test]
+    fn test_enforce_label_matchers() {
+        let mut request = ReadRequest::new();
+        request.queries.push(Query::new());
+        enforce_label_matchers(&mut request, "team", "a").unwrap();
+        assert_eq!(request.queries[0].matchers[0].name, "team");
+        assert_eq!(request.queries[0].matchers[0].field_type, LabelMatcher_Type::EQ);
+        // enforced matcher is not added twice
+        enforce_label_matchers(&mut request, "team", "a").unwrap();
+        assert_eq!(request.queries[0].matchers.len(), 1);
+        assert!(enforce_label_matchers(&mut request, "team", "b").is_err());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_remote_read() {
+        swap(ProxyConfig::parse(&format!(r#"
+query:
+  frontend_url: {}/prometheus/
+  callers:
+    - {{name: team-a, token: token-a, tenants: [tenant1, tenant2]}}
+"#, mockito::server_url())).unwrap());
+
+        let tenant1 = mock("POST", "/prometheus/api/v1/read")
+            .match_header("x-scope-orgid", "tenant1")
+            .with_header("content-type", "application/x-protobuf")
+            .with_body(snappy(&read_response(vec![series("up", &[1])]).write_to_bytes().unwrap()))
+            .create();
+        let tenant2 = mock("POST", "/prometheus/api/v1/read")
+            .match_header("x-scope-orgid", "tenant2")
+            .with_header("content-type", "application/x-protobuf")
+            .with_body(snappy(&read_response(vec![series("up", &[2])]).write_to_bytes().unwrap()))
+            .create();
+
+        let requests = IntCounterVec::new(Opts::new("requests", "requests"), &["caller", "endpoint", "status"]).unwrap();
+        let latency = HistogramVec::new(HistogramOpts::new("latency", "latency"), &["caller", "endpoint"]).unwrap();
+        let api = routes(Arc::new(QueryProxy::new(reqwest::Client::new(), requests, latency)));
+
+        let mut request = ReadRequest::new();
+        request.queries.push(Query::new());
+        let body = snappy(&request.write_to_bytes().unwrap());
+        assert!(decode_read_request(&body).is_ok());
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/api/v1/read")
+            .body(body.clone())
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/api/v1/read")
+            .header("Authorization", "Bearer token-a")
+            .body(body)
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        tenant1.assert();
+        tenant2.assert();
+        let message = snap::raw::Decoder::new().decompress_vec(resp.body()).unwrap();
+        let merged = ReadResponse::parse_from_bytes(&message).unwrap();
+        let timestamps: Vec<i64> = merged.results[0].timeseries[0].samples.iter().map(|s| s.timestamp).collect();
+        assert_eq!(timestamps, vec![1, 2]);
+
+        swap(ProxyConfig::default());
+    }
+}
@@ -0,0 +1 @@
+pub mod status;
\ No newline at end of file
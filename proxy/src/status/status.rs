@@ -0,0 +1,548 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use kube::api::{Patch, PatchParams};
+use kube::{Api, Client};
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
+use prometheus::{IntCounterVec, IntGauge};
+use tokio::time::sleep;
+
+use kube_metrics_mutli_tenancy_lib as kube_lib;
+use kube_lib::election::LeaderElector;
+use kube_lib::locks;
+use kube_lib::{OpenMetricsTenant, OpenMetricsTenantStatus, ReplicaUsage};
+
+use crate::controller::controller::{TenantSettings, CONTROLLER};
+use crate::lifecycle::lifecycle::LIFECYCLE;
+use crate::limits::limits::{fingerprint, SERIES_IDLE_TIMEOUT};
+use crate::proto::prometheus::WriteRequest;
+
+
+// Idle series are looked for at most that often, per tenant
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Replica shares not reported within that many status write intervals are dropped
+const STALE_INTERVALS: u32 = 3;
+
+// Usage tracker singleton, usage is kept per proxy replica
+pub static USAGE: Lazy<UsageTracker> = Lazy::new(|| UsageTracker::new(SERIES_IDLE_TIMEOUT));
+
+// Writes of single tenant
+struct TenantWrites {
+    samples_total: u64,
+    last_write_time: Option<DateTime<Utc>>,
+    // last write time by series fingerprint
+    series: HashMap<u64, Instant>,
+    swept: Instant,
+    last_upstream_error: Option<(String, DateTime<Utc>)>,
+}
+
+// Usage of single tenant, as reported in tenant status
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TenantUsage {
+    pub samples_total: u64,
+    pub last_write_time: Option<DateTime<Utc>>,
+    pub active_series: u64,
+    pub last_upstream_error: Option<(String, DateTime<Utc>)>,
+}
+
+// Per-tenant writes accepted by proxy replica, and upstream errors.
+// Only tracked when status writer is enabled.
+pub struct UsageTracker {
+    enabled: AtomicBool,
+    idle_timeout: Duration,
+    tenants: Mutex<HashMap<String, TenantWrites>>,
+}
+
+impl UsageTracker {
+    pub fn new(idle_timeout: Duration) -> UsageTracker {
+        UsageTracker {
+            enabled: AtomicBool::new(false),
+            idle_timeout,
+            tenants: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    fn with_tenant<F: FnOnce(&mut TenantWrites)>(&self, tenant_id: &str, now: Instant, f: F) {
+        let mut tenants = locks::lock(&self.tenants);
+        let writes = tenants.entry(String::from(tenant_id)).or_insert_with(|| TenantWrites {
+            samples_total: 0,
+            last_write_time: None,
+            series: HashMap::new(),
+            swept: now,
+            last_upstream_error: None,
+        });
+        f(writes)
+    }
+
+    // Account request accepted for tenant.
+    pub fn record_write(&self, tenant_id: &str, request: &WriteRequest, now: Instant) {
+        if !self.is_enabled() {
+            return;
+        }
+        let idle_timeout = self.idle_timeout;
+        self.with_tenant(tenant_id, now, |writes| {
+            writes.samples_total += request.timeseries.iter().map(|ts| ts.samples.len() as u64).sum::<u64>();
+            writes.last_write_time = Some(Utc::now());
+            if now.saturating_duration_since(writes.swept) >= SWEEP_INTERVAL {
+                writes.series.retain(|_, written| now.saturating_duration_since(*written) < idle_timeout);
+                writes.swept = now;
+            }
+            for time_series in request.timeseries.iter() {
+                writes.series.insert(fingerprint(time_series), now);
+            }
+        });
+    }
+
+    // Remember last error of forwarding tenant series upstream.
+    pub fn record_upstream_error(&self, tenant_id: &str, error: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.with_tenant(tenant_id, Instant::now(), |writes| {
+            writes.last_upstream_error = Some((String::from(error), Utc::now()));
+        });
+    }
+
+    // Usage of tenant, series written within idle timeout are counted as active.
+    pub fn usage(&self, tenant_id: &str, now: Instant) -> TenantUsage {
+        match locks::lock(&self.tenants).get(tenant_id) {
+            Some(writes) => TenantUsage {
+                samples_total: writes.samples_total,
+                last_write_time: writes.last_write_time,
+                active_series: writes
+                    .series
+                    .values()
+                    .filter(|written| now.saturating_duration_since(**written) < self.idle_timeout)
+                    .count() as u64,
+                last_upstream_error: writes.last_upstream_error.clone(),
+            },
+            None => TenantUsage::default(),
+        }
+    }
+
+    // Forget usage of tenants without resource to report it to.
+    pub fn retain_tenants<F: Fn(&str) -> bool>(&self, keep: F) {
+        locks::lock(&self.tenants).retain(|tenant_id, _| keep(tenant_id));
+    }
+}
+
+// Share of tenant usage seen by replica, samples rate is computed against previous observation.
+// Replica which accepted nothing since then, and has no active series, has no share.
+pub fn replica_usage(usage: &TenantUsage, previous: (u64, Instant), now: Instant, report_time: DateTime<Utc>) -> Option<ReplicaUsage> {
+    let (previous_total, observed) = previous;
+    let samples = usage.samples_total.saturating_sub(previous_total);
+    if samples == 0 && usage.active_series == 0 {
+        return None;
+    }
+    let elapsed = now.saturating_duration_since(observed).as_secs_f64();
+    let samples_per_second = if elapsed > 0.0 {
+        Some(round_rate(samples as f64 / elapsed))
+    } else {
+        None
+    };
+    Some(ReplicaUsage {
+        last_write_time: usage.last_write_time,
+        samples_per_second,
+        active_series: Some(usage.active_series),
+        last_upstream_error: usage.last_upstream_error.as_ref().map(|(e, _)| e.clone()),
+        last_upstream_error_time: usage.last_upstream_error.as_ref().map(|(_, t)| *t),
+        report_time: Some(report_time),
+    })
+}
+
+fn round_rate(rate: f64) -> f64 {
+    (rate * 100.0).round() / 100.0
+}
+
+// Status of tenant summed up over replica shares reported since stale_before, the rest are dropped.
+// A series written through several replicas is counted by each of them.
+// Last write and last upstream error are kept once every replica went idle.
+pub fn aggregate_status(
+    current: &OpenMetricsTenantStatus,
+    replicas: &BTreeMap<String, ReplicaUsage>,
+    reporter: &str,
+    stale_before: DateTime<Utc>,
+) -> OpenMetricsTenantStatus {
+    let fresh: BTreeMap<String, ReplicaUsage> = replicas
+        .iter()
+        .filter(|(_, share)| share.report_time.map(|t| t >= stale_before).unwrap_or(false))
+        .map(|(replica, share)| (replica.clone(), share.clone()))
+        .collect();
+    let last_write_time = fresh
+        .values()
+        .filter_map(|share| share.last_write_time)
+        .chain(current.last_write_time)
+        .max();
+    let last_upstream_error = fresh
+        .values()
+        .filter_map(|share| share.last_upstream_error.clone().zip(share.last_upstream_error_time))
+        .chain(current.last_upstream_error.clone().zip(current.last_upstream_error_time))
+        .max_by_key(|(_, t)| *t);
+    OpenMetricsTenantStatus {
+        last_write_time,
+        samples_per_second: Some(round_rate(fresh.values().filter_map(|share| share.samples_per_second).sum())),
+        active_series: Some(fresh.values().filter_map(|share| share.active_series).sum()),
+        last_upstream_error: last_upstream_error.as_ref().map(|(e, _)| e.clone()),
+        last_upstream_error_time: last_upstream_error.map(|(_, t)| t),
+        reporter: Some(String::from(reporter)),
+        replicas: fresh,
+    }
+}
+
+// Periodically writes usage of tenants into status of their OpenMetricsTenant resources.
+// Every replica writes its own share into status replicas, elected replica sums them up.
+pub struct StatusWriter {
+    k8s_client: Client,
+    elector: Arc<LeaderElector>,
+    interval: Duration,
+    // pause between two status patches
+    write_pause: Duration,
+    writes: IntCounterVec,
+    leader: IntGauge,
+    // samples total and time it was observed at, by tenant
+    observed: HashMap<String, (u64, Instant)>,
+    // tenants replica has share written for
+    published: HashSet<String>,
+}
+
+impl StatusWriter {
+    pub fn new(k8s_client: Client,
+               elector: Arc<LeaderElector>,
+               interval: Duration,
+               writes_per_second: f64,
+               writes: IntCounterVec,
+               leader: IntGauge) -> StatusWriter {
+        StatusWriter {
+            k8s_client,
+            elector,
+            interval,
+            write_pause: Duration::from_secs_f64(1.0 / writes_per_second.max(0.01)),
+            writes,
+            leader,
+            observed: HashMap::new(),
+            published: HashSet::new(),
+        }
+    }
+
+    // Shares not reported within that many intervals belong to idle or gone replicas.
+    fn stale_before(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        // it is safe to unwrap, interval is set from u32 seconds
+        now - chrono::Duration::from_std(self.interval * STALE_INTERVALS).unwrap()
+    }
+
+    // Write share of replica into statuses of tenants with resource, leader sums shares up as well.
+    // At most write rate patches are sent per second.
+    pub async fn write_statuses(&mut self, usage: &UsageTracker, settings: &HashMap<String, TenantSettings>, leader: bool, now: Instant) {
+        // sorted, so every tenant gets its turn in the same order
+        let settings: BTreeMap<&String, &TenantSettings> = settings.iter().collect();
+        self.observed.retain(|tenant_id, _| settings.contains_key(tenant_id));
+        self.published.retain(|tenant_id| settings.contains_key(tenant_id));
+        usage.retain_tenants(|tenant_id| settings.contains_key(&String::from(tenant_id)));
+        let identity = String::from(self.elector.identity());
+
+        for (tenant_id, tenant_settings) in settings {
+            let tenant_usage = usage.usage(tenant_id, now);
+            // rate needs two observations
+            let previous = self.observed.insert(tenant_id.clone(), (tenant_usage.samples_total, now));
+            let previous = match previous {
+                Some(p) => p,
+                None => continue,
+            };
+            let report_time = Utc::now();
+            let share = replica_usage(&tenant_usage, previous, now, report_time);
+
+            let mut parts = tenant_settings.resource.splitn(2, '/');
+            let (namespace, name) = match (parts.next(), parts.next()) {
+                (Some(namespace), Some(name)) => (namespace, name),
+                _ => continue,
+            };
+            let api: Api<OpenMetricsTenant> = Api::namespaced(self.k8s_client.clone(), namespace);
+
+            let patch = if leader {
+                let current = match api.get_status(name).await {
+                    Ok(resource) => resource.status.unwrap_or_default(),
+                    Err(e) => {
+                        warn!("failed to read status of tenant {} from {}: {}", tenant_id, tenant_settings.resource, e);
+                        self.writes.with_label_values(&["failed"]).inc();
+                        continue;
+                    },
+                };
+                let mut replicas = current.replicas.clone();
+                match &share {
+                    Some(share) => replicas.insert(identity.clone(), share.clone()),
+                    None => replicas.remove(&identity),
+                };
+                let status = aggregate_status(&current, &replicas, &identity, self.stale_before(report_time));
+                if status == current {
+                    self.writes.with_label_values(&["unchanged"]).inc();
+                    continue;
+                }
+                // merge patch keeps keys which are not set, so dropped shares are set to null
+                let mut value = serde_json::json!(status);
+                let dropped: Vec<&String> = current.replicas.keys().filter(|r| !status.replicas.contains_key(*r)).collect();
+                if !dropped.is_empty() {
+                    let shares = value
+                        .as_object_mut()
+                        .map(|status| status.entry("replicas").or_insert_with(|| serde_json::json!({})));
+                    if let Some(serde_json::Value::Object(shares)) = shares {
+                        for replica in dropped {
+                            shares.insert(replica.clone(), serde_json::Value::Null);
+                        }
+                    }
+                }
+                value
+            } else {
+                match &share {
+                    Some(share) => serde_json::json!({ "replicas": { identity.as_str(): share } }),
+                    // share of idle replica is dropped right away, so it is not summed up anymore
+                    None if self.published.contains(tenant_id) => serde_json::json!({ "replicas": { identity.as_str(): null } }),
+                    None => {
+                        self.writes.with_label_values(&["unchanged"]).inc();
+                        continue;
+                    },
+                }
+            };
+
+            let patch = Patch::Merge(serde_json::json!({ "status": patch }));
+            match api.patch_status(name, &PatchParams::default(), &patch).await {
+                Ok(_) => {
+                    debug!("wrote status of tenant {} into {}", tenant_id, tenant_settings.resource);
+                    self.writes.with_label_values(&["ok"]).inc();
+                    if share.is_some() {
+                        self.published.insert(tenant_id.clone());
+                    } else {
+                        self.published.remove(tenant_id);
+                    }
+                },
+                Err(e) => {
+                    warn!("failed to write status of tenant {} into {}: {}", tenant_id, tenant_settings.resource, e);
+                    self.writes.with_label_values(&["failed"]).inc();
+                },
+            }
+            sleep(self.write_pause).await;
+        }
+    }
+
+    // Write statuses every interval, shares are summed up while replica is leader.
+    pub async fn run(mut self) {
+        info!("writing tenant statuses every {}s", self.interval.as_secs());
+        loop {
+            sleep(self.interval).await;
+            if LIFECYCLE.is_shutting_down() {
+                break;
+            }
+            let leader = self.elector.is_leader();
+            self.leader.set(leader as i64);
+            let snapshot = CONTROLLER.read().await.snapshot().load();
+            self.write_statuses(&USAGE, &snapshot.settings, leader, Instant::now()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use chrono::{DateTime, Duration as ChronoDuration, SecondsFormat, TimeZone, Utc};
+    use futures::pin_mut;
+    use http::{Method, Request, Response};
+    use hyper::Body;
+    use kube::{Client, Service};
+    use kube_metrics_mutli_tenancy_lib::election::LeaderElector;
+    use kube_metrics_mutli_tenancy_lib::{OpenMetricsTenantStatus, ReplicaUsage};
+    use prometheus::{IntCounterVec, IntGauge, Opts};
+    use tower_test::mock;
+
+    use crate::controller::controller::TenantSettings;
+    use crate::proto::prometheus::{Label, Sample, TimeSeries, WriteRequest};
+    use crate::status::status::{aggregate_status, replica_usage, StatusWriter, UsageTracker};
+
+    fn request(series: &[&str], samples: usize) -> WriteRequest {
+        let mut request = WriteRequest::new();
+        for name in series.iter() {
+            let mut time_series = TimeSeries::new();
+            let mut label = Label::new();
+            label.name = String::from("__name__");
+            label.value = String::from(*name);
+            time_series.labels.push(label);
+            for _ in 0..samples {
+                time_series.samples.push(Sample::new());
+            }
+            request.timeseries.push(time_series);
+        }
+        request
+    }
+
+    #[test]
+    fn test_replica_usage() {
+        let usage = UsageTracker::new(Duration::from_secs(600));
+        let now = Instant::now();
+        // nothing is tracked until enabled
+        usage.record_write("t", &request(&["a"], 1), now);
+        assert_eq!(usage.usage("t", now).samples_total, 0);
+
+        usage.enable();
+        usage.record_write("t", &request(&["a", "b"], 10), now);
+        usage.record_write("t", &request(&["b", "c"], 5), now);
+        usage.record_upstream_error("t", "upstream responded with status 500");
+        let later = now + Duration::from_secs(4);
+        let tenant_usage = usage.usage("t", later);
+        assert_eq!(tenant_usage.samples_total, 30);
+        assert_eq!(tenant_usage.active_series, 3);
+
+        let report_time = Utc::now();
+        let share = replica_usage(&tenant_usage, (10, now), later, report_time).unwrap();
+        assert_eq!(share.samples_per_second, Some(5.0));
+        assert_eq!(share.active_series, Some(3));
+        assert_eq!(share.last_upstream_error, Some(String::from("upstream responded with status 500")));
+        assert!(share.last_write_time.is_some());
+        assert_eq!(share.report_time, Some(report_time));
+
+        // series idle for too long are not active, and idle replica has no share
+        let idle = now + Duration::from_secs(700);
+        assert_eq!(usage.usage("t", idle).active_series, 0);
+        assert_eq!(replica_usage(&usage.usage("t", idle), (30, later), idle, report_time), None);
+        usage.retain_tenants(|_| false);
+        assert_eq!(usage.usage("t", later).samples_total, 0);
+    }
+
+    #[test]
+    fn test_aggregate_status() {
+        let now = Utc.ymd(2021, 5, 1).and_hms(10, 0, 0);
+        let share = |rate: f64, series: u64, report_time: DateTime<Utc>| ReplicaUsage {
+            last_write_time: Some(report_time),
+            samples_per_second: Some(rate),
+            active_series: Some(series),
+            report_time: Some(report_time),
+            ..ReplicaUsage::default()
+        };
+        let mut replicas = BTreeMap::new();
+        replicas.insert(String::from("proxy-0"), share(2.5, 10, now));
+        replicas.insert(String::from("proxy-1"), ReplicaUsage {
+            last_upstream_error: Some(String::from("upstream responded with status 502")),
+            last_upstream_error_time: Some(now - ChronoDuration::seconds(10)),
+            ..share(1.25, 5, now - ChronoDuration::seconds(30))
+        });
+        // gone replica
+        replicas.insert(String::from("proxy-2"), share(100.0, 1000, now - ChronoDuration::minutes(10)));
+        let current = OpenMetricsTenantStatus {
+            last_upstream_error: Some(String::from("upstream responded with status 500")),
+            last_upstream_error_time: Some(now - ChronoDuration::minutes(5)),
+            ..OpenMetricsTenantStatus::default()
+        };
+
+        let status = aggregate_status(&current, &replicas, "proxy-0", now - ChronoDuration::minutes(2));
+        assert_eq!(status.samples_per_second, Some(3.75));
+        assert_eq!(status.active_series, Some(15));
+        assert_eq!(status.last_write_time, Some(now));
+        assert_eq!(status.last_upstream_error, Some(String::from("upstream responded with status 502")));
+        assert_eq!(status.reporter, Some(String::from("proxy-0")));
+        assert_eq!(status.replicas.keys().collect::<Vec<_>>(), vec!["proxy-0", "proxy-1"]);
+
+        // last write and error are kept once every replica is idle
+        let idle = aggregate_status(&status, &BTreeMap::new(), "proxy-0", now);
+        assert_eq!((idle.samples_per_second, idle.active_series), (Some(0.0), Some(0)));
+        assert_eq!(idle.last_write_time, Some(now));
+        assert_eq!(idle.last_upstream_error, status.last_upstream_error);
+        assert!(idle.replicas.is_empty());
+    }
+
+    async fn body(request: Request<Body>) -> serde_json::Value {
+        serde_json::from_slice(&hyper::body::to_bytes(request.into_body()).await.unwrap()).unwrap()
+    }
+
+    fn resource(status: serde_json::Value) -> Response<Body> {
+        let resource = serde_json::json!({
+            "apiVersion": "open-metrics.vgs.io/v1",
+            "kind": "OpenMetricsTenant",
+            "metadata": { "name": "tenant1", "namespace": "team-a" },
+            "spec": {},
+            "status": status
+        });
+        Response::builder().body(Body::from(resource.to_string())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_write_statuses() {
+        const STATUS_PATH: &str = "/apis/open-metrics.vgs.io/v1/namespaces/team-a/openmetricstenants/tenant1/status";
+        let (mock_service, handle) = mock::pair::<Request<Body>, Response<Body>>();
+        let spawned = tokio::spawn(async move {
+            pin_mut!(handle);
+            // leader reads shares of other replicas, and sums them up
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert_eq!(request.method(), Method::GET);
+            assert_eq!(request.uri().path(), STATUS_PATH);
+            let fresh = Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true);
+            send.send_response(resource(serde_json::json!({
+                "replicas": {
+                    "proxy-1": { "samples_per_second": 3.0, "active_series": 2, "report_time": fresh },
+                    "proxy-2": { "samples_per_second": 50.0, "active_series": 20, "report_time": "2021-05-01T10:00:00Z" }
+                }
+            })));
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert_eq!(request.method(), Method::PATCH);
+            assert_eq!(request.uri().path(), STATUS_PATH);
+            let patch = body(request).await;
+            assert_eq!(patch["status"]["samples_per_second"], 5.0);
+            assert_eq!(patch["status"]["active_series"], 3);
+            assert_eq!(patch["status"]["reporter"], "proxy-0");
+            assert_eq!(patch["status"]["replicas"]["proxy-0"]["samples_per_second"], 2.0);
+            assert_eq!(patch["status"]["replicas"]["proxy-1"]["active_series"], 2);
+            // stale share is removed
+            assert_eq!(patch["status"]["replicas"]["proxy-2"], serde_json::Value::Null);
+            assert!(patch["status"]["replicas"].as_object().unwrap().contains_key("proxy-2"));
+            send.send_response(resource(serde_json::json!({})));
+
+            // other replica only writes its share
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert_eq!(request.method(), Method::PATCH);
+            let patch = body(request).await;
+            assert_eq!(patch["status"].as_object().unwrap().len(), 1);
+            assert_eq!(patch["status"]["replicas"]["proxy-0"]["samples_per_second"], 1.0);
+            send.send_response(resource(patch["status"].clone()));
+        });
+
+        let client = Client::new(Service::new(mock_service));
+        let elector = Arc::new(LeaderElector::new(
+            client.clone(), "default", "proxy", "proxy-0", Duration::from_secs(15), Duration::from_secs(10)).unwrap());
+        let writes = IntCounterVec::new(Opts::new("status_writes", "status writes"), &["result"]).unwrap();
+        let leader = IntGauge::new("status_writer_leader", "leader").unwrap();
+        let mut writer = StatusWriter::new(
+            client, elector, Duration::from_secs(30), 1000.0, writes.clone(), leader);
+
+        let usage = UsageTracker::new(Duration::from_secs(600));
+        usage.enable();
+        let mut settings = HashMap::new();
+        settings.insert(String::from("tenant1"), TenantSettings {
+            resource: String::from("team-a/tenant1"),
+            ..TenantSettings::default()
+        });
+
+        // first observation only sets the baseline
+        let now = Instant::now();
+        writer.write_statuses(&usage, &settings, true, now).await;
+        usage.record_write("tenant1", &request(&["a"], 60), now);
+        let later = now + Duration::from_secs(30);
+        writer.write_statuses(&usage, &settings, true, later).await;
+        usage.record_write("tenant1", &request(&["a"], 30), later);
+        writer.write_statuses(&usage, &settings, false, later + Duration::from_secs(30)).await;
+        assert_eq!(writes.with_label_values(&["ok"]).get(), 2);
+
+        spawned.await.unwrap();
+    }
+}